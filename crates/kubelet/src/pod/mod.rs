@@ -8,8 +8,8 @@ pub use handle::{key_from_pod, pod_key, Handle};
 pub(crate) use queue::Queue;
 pub(crate) use status::initialize_pod_container_statuses;
 pub use status::{
-    make_registered_status, make_status, make_status_with_containers, patch_status, Phase, Status,
-    StatusMessage,
+    make_pod_conditions, make_registered_status, make_status, make_status_with_conditions,
+    make_status_with_containers, patch_status, Phase, Status, StatusMessage,
 };
 
 use crate::container::{Container, ContainerKey};
@@ -49,6 +49,11 @@ impl Pod {
             .unwrap_or("default")
     }
 
+    /// Get the pod's UID, if the API server has assigned one
+    pub fn uid(&self) -> Option<&str> {
+        self.kube_pod.metadata.uid.as_deref()
+    }
+
     /// Get the pod's node_selector map
     pub fn node_selector(&self) -> Option<&std::collections::BTreeMap<String, String>> {
         self.kube_pod.spec.as_ref()?.node_selector.as_ref()
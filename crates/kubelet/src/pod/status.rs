@@ -2,8 +2,11 @@
 
 use super::Pod;
 use crate::container::{make_initial_container_status, ContainerMap, Status as ContainerStatus};
+use chrono::Utc;
 use k8s_openapi::api::core::v1::ContainerStatus as KubeContainerStatus;
 use k8s_openapi::api::core::v1::Pod as KubePod;
+use k8s_openapi::api::core::v1::PodCondition;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
 use kube::api::PatchParams;
 use kube::Api;
 use log::{debug, warn};
@@ -147,6 +150,65 @@ pub fn make_status(phase: Phase, reason: &str) -> anyhow::Result<serde_json::Val
     ))
 }
 
+/// Create Pod status patch with the given conditions.
+pub fn make_status_with_conditions(
+    phase: Phase,
+    reason: &str,
+    conditions: Vec<PodCondition>,
+) -> anyhow::Result<serde_json::Value> {
+    Ok(serde_json::json!(
+       {
+           "metadata": {
+               "resourceVersion": "",
+           },
+           "status": {
+               "phase": phase,
+               "reason": reason,
+               "conditions": conditions,
+           }
+       }
+    ))
+}
+
+/// Create the `Initialized`, `ContainersReady` and `Ready` conditions of a
+/// Pod, which is ready exactly when its containers are.
+///
+/// A condition keeps the transition time in the Pod's current status while
+/// its value is unchanged, and takes the current time when it changes.
+pub fn make_pod_conditions(
+    pod: &Pod,
+    initialized: bool,
+    containers_ready: bool,
+) -> Vec<PodCondition> {
+    let current = pod
+        .as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref());
+    let now = Utc::now();
+    [
+        ("Initialized", initialized),
+        ("ContainersReady", containers_ready),
+        ("Ready", containers_ready),
+    ]
+    .iter()
+    .map(|(type_, value)| {
+        let status = if *value { "True" } else { "False" }.to_owned();
+        let last_transition_time = current
+            .and_then(|conditions| conditions.iter().find(|c| c.type_ == *type_))
+            .filter(|c| c.status == status)
+            .and_then(|c| c.last_transition_time.clone())
+            .unwrap_or_else(|| Time(now));
+        PodCondition {
+            type_: (*type_).to_owned(),
+            status,
+            last_transition_time: Some(last_transition_time),
+            ..Default::default()
+        }
+    })
+    .collect()
+}
+
 /// Create basic Pod status patch.
 pub fn make_status_with_containers(
     phase: Phase,
@@ -199,7 +261,7 @@ impl Default for StatusMessage {
 /// Describe the lifecycle phase of a workload.
 ///
 /// This is specified by Kubernetes itself.
-#[derive(Clone, Debug, serde::Serialize)]
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
 pub enum Phase {
     /// The pod is being created.
     Pending,
@@ -2,8 +2,10 @@
 
 use crate::state::prelude::*;
 
+use super::error::Error;
 use super::registered::Registered;
-use super::{BackoffSequence, GenericPodState, GenericProvider};
+use super::status::GenericState;
+use super::{record_state, vetoed, BackoffSequence, GenericPodState, GenericProvider};
 
 /// The pod is backing off after repeated failures and retries.
 pub struct CrashLoopBackoff<P: GenericProvider> {
@@ -28,11 +30,23 @@ impl<P: GenericProvider> Default for CrashLoopBackoff<P> {
 impl<P: GenericProvider> State<P::ProviderState, P::PodState> for CrashLoopBackoff<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> Transition<P::ProviderState, P::PodState> {
+        record_state::<P>(&provider_state, pod_state, pod, "CrashLoopBackoff").await;
         pod_state.backoff(BackoffSequence::CrashLoop).await;
+        if let Some(error) = vetoed::<P>(
+            &provider_state,
+            pod_state,
+            "CrashLoopBackoff",
+            "Registered",
+            pod,
+        )
+        .await
+        {
+            return Transition::next(self, error);
+        }
         let next = Registered::<P>::default();
         Transition::next(self, next)
     }
@@ -40,10 +54,11 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for CrashLoopBacko
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "CrashLoopBackoff")
+        GenericState::CrashLoopBackoff.status(pod, "CrashLoopBackoff")
     }
 }
 
+impl<P: GenericProvider> TransitionTo<Error<P>> for CrashLoopBackoff<P> {}
 impl<P: GenericProvider> TransitionTo<Registered<P>> for CrashLoopBackoff<P> {}
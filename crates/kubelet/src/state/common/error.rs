@@ -4,46 +4,94 @@ use crate::state::prelude::*;
 
 use super::crash_loop_backoff::CrashLoopBackoff;
 use super::registered::Registered;
-use super::{GenericPodState, GenericProvider, ThresholdTrigger};
+use super::status::GenericState;
+use super::{
+    notify_transition, update_checkpoint, GenericPodState, GenericProvider, ThresholdTrigger,
+};
 
 /// The Pod failed to run.
 pub struct Error<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
-    message: String,
+    source: Option<String>,
+    cause: anyhow::Error,
 }
 
 impl<P: GenericProvider> std::fmt::Debug for Error<P> {
     fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let text = format!("Error: {}", self.message);
+        let text = match &self.source {
+            Some(source) => format!("Error in {}: {:#}", source, self.cause),
+            None => format!("Error: {:#}", self.cause),
+        };
         text.fmt(formatter)
     }
 }
 
 impl<P: GenericProvider> Error<P> {
     /// Creates an instance of the Error state.
+    ///
+    /// Prefer `from_state`, which records which state failed.
     pub fn new(message: String) -> Self {
         Self {
             phantom: std::marker::PhantomData,
-            message,
+            source: None,
+            cause: anyhow::anyhow!(message),
         }
     }
+
+    /// Creates an instance of the Error state for a failure in the state
+    /// named `source`, such as `VolumeMount`.
+    pub fn from_state(source: &str, cause: anyhow::Error) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            source: Some(source.to_owned()),
+            cause,
+        }
+    }
+
+    /// The name of the state that failed, if it was recorded.
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// What went wrong.
+    pub fn cause(&self) -> &anyhow::Error {
+        &self.cause
+    }
 }
 
 #[async_trait::async_trait]
 impl<P: GenericProvider> State<P::ProviderState, P::PodState> for Error<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> Transition<P::ProviderState, P::PodState> {
+        // Every path back through the generic states passes through here, and
+        // the volumes are mounted afresh on the way, so the recorded volumes
+        // must not be reused.
+        update_checkpoint::<P, _>(&provider_state, pod_state, pod, |c| {
+            c.state = "Error".to_owned();
+            c.mounted_volumes = None;
+        })
+        .await;
         match pod_state.record_error() {
             ThresholdTrigger::Triggered => {
+                notify_transition::<P>(
+                    &provider_state,
+                    pod_state,
+                    "Error",
+                    "CrashLoopBackoff",
+                    pod,
+                )
+                .await;
                 let next = CrashLoopBackoff::<P>::default();
                 Transition::next(self, next)
             }
             ThresholdTrigger::Untriggered => {
                 tokio::time::delay_for(std::time::Duration::from_secs(5)).await;
+                notify_transition::<P>(&provider_state, pod_state, "Error", "Registered", pod)
+                    .await;
                 let next = Registered::<P>::default();
                 Transition::next(self, next)
             }
@@ -53,9 +101,18 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for Error<P> {
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, &self.message)
+        match &self.source {
+            // The reason names the failed state, and the message carries the
+            // whole chain of causes, so that `kubectl describe` shows both.
+            Some(source) => GenericState::Error.status_with_message(
+                pod,
+                &format!("{}Failed", source),
+                &format!("{:#}", self.cause),
+            ),
+            None => GenericState::Error.status(pod, &self.cause.to_string()),
+        }
     }
 }
 
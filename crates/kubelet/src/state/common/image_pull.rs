@@ -2,15 +2,22 @@
 
 use crate::state::prelude::*;
 
+use super::error::Error;
 use super::image_pull_backoff::ImagePullBackoff;
+use super::status::GenericState;
 use super::volume_mount::VolumeMount;
-use super::{BackoffSequence, GenericPodState, GenericProvider, GenericProviderState};
+use super::{
+    notify_transition, record_state, vetoed, BackoffSequence, GenericPodState, GenericProvider,
+    GenericProviderState,
+};
 
 use log::error;
+use oci_distribution::errors::ClientError;
 
 /// Kubelet is pulling container images.
 pub struct ImagePull<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    failed_attempts: u32,
 }
 
 impl<P: GenericProvider> std::fmt::Debug for ImagePull<P> {
@@ -23,10 +30,35 @@ impl<P: GenericProvider> Default for ImagePull<P> {
     fn default() -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            failed_attempts: 0,
         }
     }
 }
 
+impl<P: GenericProvider> ImagePull<P> {
+    /// Creates an instance of the ImagePull state that retries a pull that
+    /// has already failed the given number of times.
+    pub(crate) fn retry(failed_attempts: u32) -> Self {
+        Self {
+            phantom: std::marker::PhantomData,
+            failed_attempts,
+        }
+    }
+}
+
+/// Returns whether a pull failed in a way that retrying cannot fix, such as
+/// an image that does not exist or that the pod may not pull.
+fn is_permanent_failure(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<ClientError>(),
+            Some(ClientError::NotFound(_))
+                | Some(ClientError::Denied(_))
+                | Some(ClientError::IncompatibleMediaTypes { .. })
+        )
+    })
+}
+
 #[async_trait::async_trait]
 impl<P: GenericProvider> State<P::ProviderState, P::PodState> for ImagePull<P> {
     async fn next(
@@ -35,6 +67,7 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for ImagePull<P> {
         pod_state: &mut P::PodState,
         pod: &Pod,
     ) -> Transition<P::ProviderState, P::PodState> {
+        record_state::<P>(&provider_state, pod_state, pod, "ImagePull").await;
         let (client, store) = {
             // Minimise the amount of time we hold any locks
             let state_reader = provider_state.read().await;
@@ -45,22 +78,71 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for ImagePull<P> {
             Ok(m) => m,
             Err(e) => {
                 error!("{:?}", e);
-                return Transition::next(self, ImagePullBackoff::<P>::default());
+                if is_permanent_failure(&e) {
+                    notify_transition::<P>(&provider_state, pod_state, "ImagePull", "Error", pod)
+                        .await;
+                    return Transition::next(self, Error::<P>::from_state("ImagePull", e));
+                }
+                if let Some(error) = vetoed::<P>(
+                    &provider_state,
+                    pod_state,
+                    "ImagePull",
+                    "ImagePullBackoff",
+                    pod,
+                )
+                .await
+                {
+                    return Transition::next(self, error);
+                }
+                let next = ImagePullBackoff::<P>::new(self.failed_attempts + 1, &e);
+                return Transition::next(self, next);
             }
         };
         pod_state.set_modules(modules);
         pod_state.reset_backoff(BackoffSequence::ImagePull);
+        if let Some(error) =
+            vetoed::<P>(&provider_state, pod_state, "ImagePull", "VolumeMount", pod).await
+        {
+            return Transition::next(self, error);
+        }
         Transition::next(self, VolumeMount::<P>::default())
     }
 
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "ImagePull")
+        GenericState::ImagePull.status(pod, "ImagePull")
     }
 }
 
+impl<P: GenericProvider> TransitionTo<Error<P>> for ImagePull<P> {}
 impl<P: GenericProvider> TransitionTo<ImagePullBackoff<P>> for ImagePull<P> {}
 impl<P: GenericProvider> TransitionTo<VolumeMount<P>> for ImagePull<P> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_and_denied_images_are_permanent_failures() {
+        let not_found: anyhow::Error = ClientError::NotFound("manifest unknown".to_owned()).into();
+        assert!(is_permanent_failure(&not_found));
+        let denied: anyhow::Error = ClientError::Denied("denied".to_owned()).into();
+        assert!(is_permanent_failure(
+            &denied.context("Failed to pull image")
+        ));
+    }
+
+    #[test]
+    fn unreachable_registries_are_transient_failures() {
+        let unreachable: anyhow::Error =
+            ClientError::RegistryUnreachable("example.com".to_owned()).into();
+        assert!(!is_permanent_failure(&unreachable));
+        let unauthorized: anyhow::Error =
+            ClientError::Unauthorized("token expired".to_owned()).into();
+        assert!(!is_permanent_failure(&unauthorized));
+        assert!(!is_permanent_failure(&anyhow::anyhow!("Server error")));
+    }
+}
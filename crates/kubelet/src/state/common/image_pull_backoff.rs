@@ -2,24 +2,36 @@
 
 use crate::state::prelude::*;
 
+use super::error::Error;
 use super::image_pull::ImagePull;
-use super::{BackoffSequence, GenericPodState, GenericProvider};
+use super::status::GenericState;
+use super::{record_state, vetoed, BackoffSequence, GenericPodState, GenericProvider};
 
 /// Kubelet encountered an error when pulling container image.
+///
+/// Pulls that fail in a way that may pass, such as an unreachable registry,
+/// are retried after a delay that grows with each failure.
 pub struct ImagePullBackoff<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
+    failed_attempts: u32,
+    reason: String,
 }
 
 impl<P: GenericProvider> std::fmt::Debug for ImagePullBackoff<P> {
     fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        "ImagePullBackoff".fmt(formatter)
+        let text = format!("ImagePullBackoff (attempt {})", self.failed_attempts);
+        text.fmt(formatter)
     }
 }
 
-impl<P: GenericProvider> Default for ImagePullBackoff<P> {
-    fn default() -> Self {
+impl<P: GenericProvider> ImagePullBackoff<P> {
+    /// Creates an instance of the ImagePullBackoff state after the pull has
+    /// failed `failed_attempts` times in a row, the last time with `error`.
+    pub fn new(failed_attempts: u32, error: &anyhow::Error) -> Self {
         Self {
             phantom: std::marker::PhantomData,
+            failed_attempts,
+            reason: format!("{:#}", error),
         }
     }
 }
@@ -28,21 +40,42 @@ impl<P: GenericProvider> Default for ImagePullBackoff<P> {
 impl<P: GenericProvider> State<P::ProviderState, P::PodState> for ImagePullBackoff<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
+        provider_state: SharedState<P::ProviderState>,
         pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> Transition<P::ProviderState, P::PodState> {
+        record_state::<P>(&provider_state, pod_state, pod, "ImagePullBackoff").await;
         pod_state.backoff(BackoffSequence::ImagePull).await;
-        Transition::next(self, ImagePull::<P>::default())
+        if let Some(error) = vetoed::<P>(
+            &provider_state,
+            pod_state,
+            "ImagePullBackoff",
+            "ImagePull",
+            pod,
+        )
+        .await
+        {
+            return Transition::next(self, error);
+        }
+        let next = ImagePull::<P>::retry(self.failed_attempts);
+        Transition::next(self, next)
     }
 
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "ImagePullBackoff")
+        GenericState::ImagePullBackoff.status_with_message(
+            pod,
+            "ImagePullBackoff",
+            &format!(
+                "Back-off pulling image after {} failed attempts: {}",
+                self.failed_attempts, self.reason
+            ),
+        )
     }
 }
 
+impl<P: GenericProvider> TransitionTo<Error<P>> for ImagePullBackoff<P> {}
 impl<P: GenericProvider> TransitionTo<ImagePull<P>> for ImagePullBackoff<P> {}
@@ -3,15 +3,21 @@
 //! states in many providers; instead, the provider need only implement the
 //! GenericProviderState and GenericPodState traits for its state types.
 
-use crate::state::State;
+use crate::state::{SharedState, State};
 
+use log::{error, info, warn};
 use std::collections::HashMap;
+use std::sync::Arc;
+
+use state_store::{checkpoint_key, Checkpoint, NoopStateStore, StateStore};
 
 pub mod crash_loop_backoff;
 pub mod error;
 pub mod image_pull;
 pub mod image_pull_backoff;
 pub mod registered;
+pub mod state_store;
+pub mod status;
 pub mod terminated;
 pub mod volume_mount;
 
@@ -45,6 +51,11 @@ pub trait GenericProviderState: 'static + Send + Sync {
     /// Stops the specified pod. This typically involves tearing down a
     /// runtime or other execution environment.
     async fn stop(&self, pod: &crate::pod::Pod) -> anyhow::Result<()>;
+    /// Gets the `StateStore` in which the generic states checkpoint pod
+    /// progress. The default implementation keeps no checkpoints.
+    fn state_store(&self) -> Arc<dyn StateStore> {
+        Arc::new(NoopStateStore)
+    }
 }
 
 /// Exposes pod state in a way that can be consumed by
@@ -65,6 +76,11 @@ pub trait GenericPodState: 'static + Send + Sync {
     /// Increments an error count and returns whether the number of errors
     /// has passed the provider's threshold for entering CrashLoopBackoff.
     fn record_error(&mut self) -> ThresholdTrigger;
+    /// Gets the pod's checkpoint. The generic states read it from the
+    /// provider's `StateStore` when the pod starts, and keep this copy up to
+    /// date as they save it. Typically your implementation can just keep a
+    /// `Checkpoint` member field, starting from its default.
+    fn checkpoint(&mut self) -> &mut Checkpoint;
 }
 
 /// A provider that wants to use the generic states implemented in this
@@ -103,4 +119,410 @@ pub trait GenericProvider: 'static + Send + Sync {
         }
         Ok(())
     }
+
+    /// Called by the generic states just before a pod moves from one state
+    /// to another. `from` and `to` are the state names reported in the pod
+    /// status, except that the provider's run state is named `RunState`. The
+    /// provider's and the pod's state are passed so that providers can track
+    /// or act on the transition. Providers are never instantiated by the
+    /// generic states, so there is no `self`; anything the hook needs from
+    /// the provider belongs in its provider state.
+    ///
+    /// Returning an Err value vetoes the transition, and the pod moves to the
+    /// `Error` state with the error as its message instead. Transitions into
+    /// and out of the `Error` state cannot be vetoed; errors returned for
+    /// them are logged and otherwise ignored.
+    ///
+    /// The default implementation allows every transition.
+    fn on_transition(
+        _provider_state: &Self::ProviderState,
+        _pod_state: &mut Self::PodState,
+        _from: &str,
+        _to: &str,
+        _pod: &crate::pod::Pod,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Loads the checkpoint for a pod into its pod state, treating failures as a
+/// missing checkpoint. Only the first call for a pod loads anything, so the
+/// checkpoint is read once, when the pod's state machine starts.
+pub(crate) async fn restore_checkpoint<P: GenericProvider>(
+    provider_state: &SharedState<P::ProviderState>,
+    pod_state: &mut P::PodState,
+    pod: &crate::pod::Pod,
+) {
+    // Once a state has been recorded, the copy in the pod state is current
+    if !pod_state.checkpoint().state.is_empty() {
+        return;
+    }
+    let store = provider_state.read().await.state_store();
+    let checkpoint = match store.load(&checkpoint_key(pod)).await {
+        Ok(checkpoint) => checkpoint.unwrap_or_default(),
+        Err(e) => {
+            warn!("Unable to load checkpoint for pod {}: {:?}", pod.name(), e);
+            Checkpoint::default()
+        }
+    };
+    if !checkpoint.state.is_empty() {
+        info!(
+            "Resuming pod {}, which was in state {} before the Kubelet restarted",
+            pod.name(),
+            checkpoint.state
+        );
+    }
+    *pod_state.checkpoint() = checkpoint;
+}
+
+/// Updates the checkpoint for a pod and saves it. Failures are logged rather
+/// than failing the pod, since checkpoints only save work after a restart.
+pub(crate) async fn update_checkpoint<P, F>(
+    provider_state: &SharedState<P::ProviderState>,
+    pod_state: &mut P::PodState,
+    pod: &crate::pod::Pod,
+    update: F,
+) where
+    P: GenericProvider,
+    F: FnOnce(&mut Checkpoint) + Send,
+{
+    update(pod_state.checkpoint());
+    let store = provider_state.read().await.state_store();
+    if let Err(e) = store
+        .save(&checkpoint_key(pod), pod_state.checkpoint())
+        .await
+    {
+        warn!("Unable to save checkpoint for pod {}: {:?}", pod.name(), e);
+    }
+}
+
+/// Records that a pod has entered the named generic state.
+pub(crate) async fn record_state<P: GenericProvider>(
+    provider_state: &SharedState<P::ProviderState>,
+    pod_state: &mut P::PodState,
+    pod: &crate::pod::Pod,
+    state: &str,
+) {
+    let state = state.to_owned();
+    update_checkpoint::<P, _>(provider_state, pod_state, pod, move |c| c.state = state).await
+}
+
+/// Runs the provider's transition hook, returning the `Error` state the pod
+/// should move to instead if the provider vetoes the transition.
+pub(crate) async fn vetoed<P: GenericProvider>(
+    provider_state: &SharedState<P::ProviderState>,
+    pod_state: &mut P::PodState,
+    from: &str,
+    to: &str,
+    pod: &crate::pod::Pod,
+) -> Option<error::Error<P>> {
+    let result = P::on_transition(&*provider_state.read().await, pod_state, from, to, pod);
+    match result {
+        Ok(()) => None,
+        Err(e) => {
+            error!(
+                "Provider vetoed transition of pod {} from {} to {}: {:?}",
+                pod.name(),
+                from,
+                to,
+                e
+            );
+            Some(error::Error::from_state(from, e))
+        }
+    }
+}
+
+/// Runs the provider's transition hook for a transition that cannot be
+/// vetoed.
+pub(crate) async fn notify_transition<P: GenericProvider>(
+    provider_state: &SharedState<P::ProviderState>,
+    pod_state: &mut P::PodState,
+    from: &str,
+    to: &str,
+    pod: &crate::pod::Pod,
+) {
+    let result = P::on_transition(&*provider_state.read().await, pod_state, from, to, pod);
+    if let Err(e) = result {
+        warn!(
+            "Ignoring veto of transition of pod {} from {} to {}: {:?}",
+            pod.name(),
+            from,
+            to,
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::container::PullPolicy;
+    use crate::pod::Pod;
+    use crate::state::Transition;
+    use k8s_openapi::api::core::v1::{Pod as KubePod, PodSpec};
+    use kube::api::ObjectMeta;
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+
+    struct TestProvider;
+
+    struct ProviderState {
+        volume_path: std::path::PathBuf,
+        // Transitions into this state are vetoed
+        vetoed_state: Option<&'static str>,
+    }
+
+    #[derive(Default)]
+    struct PodState {
+        transitions: Vec<(String, String)>,
+        checkpoint: Checkpoint,
+    }
+
+    struct EmptyStore;
+
+    #[derive(Debug, Default)]
+    struct Finished;
+
+    #[async_trait::async_trait]
+    impl crate::store::Store for EmptyStore {
+        async fn get(
+            &self,
+            image_ref: &Reference,
+            _pull_policy: PullPolicy,
+            _auth: &RegistryAuth,
+        ) -> anyhow::Result<Vec<u8>> {
+            Err(anyhow::anyhow!("No module for {}", image_ref))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GenericProviderState for ProviderState {
+        fn client(&self) -> kube::Client {
+            // The generic states make no API requests for a pod without
+            // volumes or image pull secrets, and nothing can listen on port 0
+            kube::Client::new(kube::Config::new(
+                reqwest::Url::parse("http://127.0.0.1:0").unwrap(),
+            ))
+        }
+        fn store(&self) -> Arc<dyn crate::store::Store + Sync + Send> {
+            Arc::new(EmptyStore)
+        }
+        fn volume_path(&self) -> std::path::PathBuf {
+            self.volume_path.clone()
+        }
+        async fn stop(&self, _pod: &Pod) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl GenericPodState for PodState {
+        fn set_modules(&mut self, _modules: HashMap<String, Vec<u8>>) {}
+        fn set_volumes(&mut self, _volumes: HashMap<String, crate::volume::Ref>) {}
+        async fn backoff(&mut self, _sequence: BackoffSequence) {}
+        fn reset_backoff(&mut self, _sequence: BackoffSequence) {}
+        fn record_error(&mut self) -> ThresholdTrigger {
+            ThresholdTrigger::Untriggered
+        }
+        fn checkpoint(&mut self) -> &mut Checkpoint {
+            &mut self.checkpoint
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl State<ProviderState, PodState> for Finished {
+        async fn next(
+            self: Box<Self>,
+            _provider_state: SharedState<ProviderState>,
+            _pod_state: &mut PodState,
+            _pod: &Pod,
+        ) -> Transition<ProviderState, PodState> {
+            Transition::Complete(Ok(()))
+        }
+
+        async fn json_status(
+            &self,
+            _pod_state: &mut PodState,
+            pod: &Pod,
+        ) -> anyhow::Result<serde_json::Value> {
+            crate::pod::make_status_with_conditions(
+                crate::pod::Phase::Running,
+                "Running",
+                crate::pod::make_pod_conditions(pod, true, true),
+            )
+        }
+    }
+
+    impl GenericProvider for TestProvider {
+        type ProviderState = ProviderState;
+        type PodState = PodState;
+        type RunState = Finished;
+
+        fn validate_pod_runnable(_pod: &Pod) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn validate_container_runnable(
+            _container: &crate::container::Container,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn on_transition(
+            provider_state: &ProviderState,
+            pod_state: &mut PodState,
+            from: &str,
+            to: &str,
+            _pod: &Pod,
+        ) -> anyhow::Result<()> {
+            if provider_state.vetoed_state == Some(to) {
+                return Err(anyhow::anyhow!("{} is not allowed", to));
+            }
+            pod_state.transitions.push((from.to_owned(), to.to_owned()));
+            Ok(())
+        }
+    }
+
+    fn test_pod() -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("transitions".to_owned()),
+                namespace: Some("default".to_owned()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec::default()),
+            ..Default::default()
+        })
+    }
+
+    fn test_provider_state(
+        dir: &tempfile::TempDir,
+        vetoed_state: Option<&'static str>,
+    ) -> SharedState<ProviderState> {
+        SharedState::new(ProviderState {
+            volume_path: dir.path().join("volumes"),
+            vetoed_state,
+        })
+    }
+
+    /// Runs the state machine like `run_to_completion`, without a Kubernetes
+    /// API server: the status patch of each state is collected rather than
+    /// sent.
+    async fn drive(
+        state: impl State<ProviderState, PodState>,
+        provider_state: SharedState<ProviderState>,
+        pod_state: &mut PodState,
+        pod: &Pod,
+    ) -> Vec<serde_json::Value> {
+        let mut patches = Vec::new();
+        let mut state: Box<dyn State<ProviderState, PodState>> = Box::new(state);
+        loop {
+            patches.push(
+                state
+                    .json_status(pod_state, pod)
+                    .await
+                    .expect("status patch"),
+            );
+            match state.next(provider_state.clone(), pod_state, pod).await {
+                Transition::Next(s) => state = s.state,
+                Transition::Complete(result) => {
+                    result.expect("state machine should succeed");
+                    return patches;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn transitions_are_reported_with_the_pod_state() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let mut pod_state = PodState::default();
+
+        drive(
+            registered::Registered::<TestProvider>::default(),
+            test_provider_state(&dir, None),
+            &mut pod_state,
+            &test_pod(),
+        )
+        .await;
+
+        let transitions: Vec<(&str, &str)> = pod_state
+            .transitions
+            .iter()
+            .map(|(from, to)| (from.as_str(), to.as_str()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("Registered", "ImagePull"),
+                ("ImagePull", "VolumeMount"),
+                ("VolumeMount", "RunState"),
+            ],
+            transitions
+        );
+        assert_eq!("VolumeMount", pod_state.checkpoint.state);
+    }
+
+    #[tokio::test]
+    async fn transitions_can_be_vetoed_from_the_provider_state() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let provider_state = test_provider_state(&dir, Some("VolumeMount"));
+        let mut pod_state = PodState::default();
+        let pod = test_pod();
+
+        assert!(vetoed::<TestProvider>(
+            &provider_state,
+            &mut pod_state,
+            "Registered",
+            "ImagePull",
+            &pod
+        )
+        .await
+        .is_none());
+        assert!(vetoed::<TestProvider>(
+            &provider_state,
+            &mut pod_state,
+            "ImagePull",
+            "VolumeMount",
+            &pod
+        )
+        .await
+        .is_some());
+        assert_eq!(
+            vec![("Registered".to_owned(), "ImagePull".to_owned())],
+            pod_state.transitions
+        );
+    }
+
+    #[tokio::test]
+    async fn pods_that_reach_their_run_state_are_ready() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let mut pod_state = PodState::default();
+
+        let patches = drive(
+            registered::Registered::<TestProvider>::default(),
+            test_provider_state(&dir, None),
+            &mut pod_state,
+            &test_pod(),
+        )
+        .await;
+
+        // Status patches merge conditions by type, so each condition keeps
+        // the value of the last patch that set it
+        let mut conditions = HashMap::new();
+        for patch in &patches {
+            for condition in patch["status"]["conditions"].as_array().unwrap() {
+                assert!(condition["lastTransitionTime"].is_string());
+                conditions.insert(
+                    condition["type"].as_str().unwrap().to_owned(),
+                    condition["status"].as_str().unwrap().to_owned(),
+                );
+            }
+        }
+        assert_eq!("Pending", patches[0]["status"]["phase"]);
+        assert_eq!("False", patches[0]["status"]["conditions"][2]["status"]);
+        assert_eq!("Running", patches.last().unwrap()["status"]["phase"]);
+        for type_ in &["Initialized", "ContainersReady", "Ready"] {
+            assert_eq!(Some("True"), conditions.get(*type_).map(|s| s.as_str()));
+        }
+    }
 }
@@ -6,7 +6,8 @@ use log::{debug, error, info};
 
 use super::error::Error;
 use super::image_pull::ImagePull;
-use super::GenericProvider;
+use super::status::GenericState;
+use super::{notify_transition, record_state, restore_checkpoint, vetoed, GenericProvider};
 
 /// The Kubelet is aware of the Pod.
 pub struct Registered<P: GenericProvider> {
@@ -31,20 +32,29 @@ impl<P: GenericProvider> Default for Registered<P> {
 impl<P: GenericProvider> State<P::ProviderState, P::PodState> for Registered<P> {
     async fn next(
         self: Box<Self>,
-        _provider_state: SharedState<P::ProviderState>,
-        _pod_state: &mut P::PodState,
+        provider_state: SharedState<P::ProviderState>,
+        pod_state: &mut P::PodState,
         pod: &Pod,
     ) -> Transition<P::ProviderState, P::PodState> {
         debug!("Preparing to register pod: {}", pod.name());
+        restore_checkpoint::<P>(&provider_state, pod_state, pod).await;
+        record_state::<P>(&provider_state, pod_state, pod, "Registered").await;
         match P::validate_pod_and_containers_runnable(&pod) {
             Ok(_) => (),
             Err(e) => {
                 error!("{:?}", e);
-                let next = Error::<P>::new(e.to_string());
+                notify_transition::<P>(&provider_state, pod_state, "Registered", "Error", pod)
+                    .await;
+                let next = Error::<P>::from_state("Registered", e);
                 return Transition::next(self, next);
             }
         }
         info!("Pod registered: {}", pod.name());
+        if let Some(error) =
+            vetoed::<P>(&provider_state, pod_state, "Registered", "ImagePull", pod).await
+        {
+            return Transition::next(self, error);
+        }
         let next = ImagePull::<P>::default();
         Transition::next(self, next)
     }
@@ -52,9 +62,9 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for Registered<P>
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "Registered")
+        GenericState::Registered.status(pod, "Registered")
     }
 }
 
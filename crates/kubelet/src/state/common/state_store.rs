@@ -0,0 +1,142 @@
+//! Checkpointing of pod progress through the generic states, so that a
+//! restarted Kubelet can resume pods rather than redoing completed work.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::pod::Pod;
+
+/// The last known progress of a pod through the generic states.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Checkpoint {
+    /// The name of the last generic state the pod entered.
+    pub state: String,
+    /// The specs of the volumes that were mounted for the pod, keyed by
+    /// volume name (see `crate::volume::volume_specs`). While this is set, a
+    /// restarted Kubelet reuses the mounted volumes instead of mounting them
+    /// again, as long as the pod still has the same volumes.
+    #[serde(default)]
+    pub mounted_volumes: Option<BTreeMap<String, serde_json::Value>>,
+}
+
+/// Stores pod checkpoints, keyed by pod UID.
+#[async_trait::async_trait]
+pub trait StateStore: Send + Sync {
+    /// Saves the checkpoint for a pod, replacing any previous one.
+    async fn save(&self, pod_uid: &str, checkpoint: &Checkpoint) -> anyhow::Result<()>;
+    /// Loads the checkpoint for a pod, if one was saved.
+    async fn load(&self, pod_uid: &str) -> anyhow::Result<Option<Checkpoint>>;
+    /// Removes the checkpoint for a pod. Removing a missing checkpoint is
+    /// not an error.
+    async fn remove(&self, pod_uid: &str) -> anyhow::Result<()>;
+}
+
+/// A `StateStore` that keeps nothing, so pods always start from scratch.
+#[derive(Clone, Debug, Default)]
+pub struct NoopStateStore;
+
+#[async_trait::async_trait]
+impl StateStore for NoopStateStore {
+    async fn save(&self, _pod_uid: &str, _checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn load(&self, _pod_uid: &str) -> anyhow::Result<Option<Checkpoint>> {
+        Ok(None)
+    }
+
+    async fn remove(&self, _pod_uid: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// A `StateStore` that keeps one JSON file per pod in a directory.
+#[derive(Clone, Debug)]
+pub struct FileStateStore {
+    root_dir: PathBuf,
+}
+
+impl FileStateStore {
+    /// Create a new `FileStateStore` that keeps checkpoints in `root_dir`.
+    pub fn new<T: AsRef<std::path::Path>>(root_dir: T) -> Self {
+        FileStateStore {
+            root_dir: root_dir.as_ref().to_owned(),
+        }
+    }
+
+    fn checkpoint_path(&self, pod_uid: &str) -> PathBuf {
+        // UIDs are plain identifiers, but guard against path separators anyway
+        let file_name = pod_uid.replace(|c| c == '/' || c == '\\', "_");
+        self.root_dir.join(format!("{}.json", file_name))
+    }
+}
+
+#[async_trait::async_trait]
+impl StateStore for FileStateStore {
+    async fn save(&self, pod_uid: &str, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.root_dir).await?;
+        let path = self.checkpoint_path(pod_uid);
+        // Write to a temporary file first so that a crash never leaves a
+        // partially written checkpoint behind
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(checkpoint)?).await?;
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn load(&self, pod_uid: &str) -> anyhow::Result<Option<Checkpoint>> {
+        match tokio::fs::read(self.checkpoint_path(pod_uid)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn remove(&self, pod_uid: &str) -> anyhow::Result<()> {
+        match tokio::fs::remove_file(self.checkpoint_path(pod_uid)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Returns the key under which a pod's checkpoint is stored. This is the pod
+/// UID, falling back to the namespace and name for pods without one.
+pub fn checkpoint_key(pod: &Pod) -> String {
+    match pod.uid() {
+        Some(uid) => uid.to_owned(),
+        None => format!("{}-{}", pod.namespace(), pod.name()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_state_store_roundtrips_checkpoints() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let store = FileStateStore::new(dir.path().join("checkpoints"));
+
+        assert_eq!(None, store.load("abc").await.expect("load missing"));
+
+        let checkpoint = Checkpoint {
+            state: "VolumeMount".to_owned(),
+            mounted_volumes: Some(
+                vec![("config".to_owned(), serde_json::json!({"name": "config"}))]
+                    .into_iter()
+                    .collect(),
+            ),
+        };
+        store.save("abc", &checkpoint).await.expect("save");
+        assert_eq!(
+            Some(checkpoint),
+            store.load("abc").await.expect("load saved")
+        );
+
+        store.remove("abc").await.expect("remove");
+        assert_eq!(None, store.load("abc").await.expect("load removed"));
+        store.remove("abc").await.expect("remove missing");
+    }
+}
@@ -0,0 +1,265 @@
+//! The pod phase and conditions reported for each generic state.
+
+use k8s_openapi::api::core::v1::PodCondition;
+
+use crate::pod::{make_pod_conditions, make_status_with_conditions, Phase, Pod};
+
+/// The generic states, for looking up how a pod in each of them is reported
+/// to Kubernetes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GenericState {
+    /// The Kubelet is aware of the pod.
+    Registered,
+    /// The pod's images are being pulled.
+    ImagePull,
+    /// Pulling an image failed and is being retried after a delay.
+    ImagePullBackoff,
+    /// The pod's volumes are being mounted.
+    VolumeMount,
+    /// The pod failed repeatedly and is being restarted after a delay.
+    CrashLoopBackoff,
+    /// The pod hit an error and is being retried.
+    Error,
+    /// The pod was deleted.
+    Terminated {
+        /// Whether any of the pod's containers started before it was
+        /// deleted.
+        started: bool,
+    },
+}
+
+impl GenericState {
+    /// The state of a deleted pod, taking whether its containers started
+    /// from the container statuses reported for it.
+    pub fn terminated(pod: &Pod) -> Self {
+        GenericState::Terminated {
+            started: containers_started(pod),
+        }
+    }
+
+    /// The pod phase reported while in this state.
+    ///
+    /// A pod deleted before any of its containers started will never run,
+    /// so it is reported as failed, as a state machine that exits early is,
+    /// rather than as having succeeded.
+    pub fn phase(self) -> Phase {
+        match self {
+            GenericState::Terminated { started: true } => Phase::Succeeded,
+            GenericState::Terminated { started: false } => Phase::Failed,
+            _ => Phase::Pending,
+        }
+    }
+
+    /// The `Initialized`, `ContainersReady` and `Ready` conditions reported
+    /// for the pod while in this state.
+    ///
+    /// No containers run in any generic state, so the pod is never ready.
+    /// It only counts as initialized once it has terminated after its
+    /// containers started, since its init containers must have completed
+    /// for them to start. Providers report the conditions of their own
+    /// states with `make_pod_conditions`.
+    pub fn conditions(self, pod: &Pod) -> Vec<PodCondition> {
+        let initialized = self == GenericState::Terminated { started: true };
+        make_pod_conditions(pod, initialized, false)
+    }
+
+    /// A status patch for the pod with this state's phase and conditions and
+    /// the given reason.
+    pub fn status(self, pod: &Pod, reason: &str) -> anyhow::Result<serde_json::Value> {
+        make_status_with_conditions(self.phase(), reason, self.conditions(pod))
+    }
+
+    /// A status patch for the pod with this state's phase and conditions, the
+    /// given reason, and a human-readable message explaining it.
+    pub fn status_with_message(
+        self,
+        pod: &Pod,
+        reason: &str,
+        message: &str,
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut patch = self.status(pod, reason)?;
+        patch["status"]["message"] = serde_json::Value::String(message.to_owned());
+        Ok(patch)
+    }
+}
+
+/// Returns whether any of the pod's containers is running or has run.
+fn containers_started(pod: &Pod) -> bool {
+    pod.as_kube_pod()
+        .status
+        .as_ref()
+        .and_then(|s| s.container_statuses.as_ref())
+        .map(|statuses| {
+            statuses.iter().any(|s| match &s.state {
+                Some(state) => state.running.is_some() || state.terminated.is_some(),
+                None => false,
+            })
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::api::core::v1::{
+        ContainerState, ContainerStateRunning, ContainerStateTerminated, ContainerStateWaiting,
+        ContainerStatus, Pod as KubePod, PodStatus,
+    };
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    fn condition_status(state: GenericState, type_: &str) -> String {
+        state
+            .conditions(&Pod::from(KubePod::default()))
+            .into_iter()
+            .find(|c| c.type_ == type_)
+            .map(|c| c.status)
+            .unwrap_or_else(|| panic!("{:?} has no {} condition", state, type_))
+    }
+
+    #[test]
+    fn generic_states_are_never_ready() {
+        for state in &[
+            GenericState::Registered,
+            GenericState::ImagePull,
+            GenericState::ImagePullBackoff,
+            GenericState::VolumeMount,
+            GenericState::CrashLoopBackoff,
+            GenericState::Error,
+            GenericState::Terminated { started: false },
+            GenericState::Terminated { started: true },
+        ] {
+            assert_eq!("False", condition_status(*state, "Ready"));
+            assert_eq!("False", condition_status(*state, "ContainersReady"));
+        }
+    }
+
+    fn pod_with_container_state(state: Option<ContainerState>) -> Pod {
+        Pod::from(KubePod {
+            status: Some(PodStatus {
+                container_statuses: Some(vec![ContainerStatus {
+                    name: "main".to_owned(),
+                    state,
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn only_terminated_pods_that_ran_are_initialized_and_succeeded() {
+        let started = GenericState::Terminated { started: true };
+        let never_started = GenericState::Terminated { started: false };
+        assert_eq!(
+            "False",
+            condition_status(GenericState::VolumeMount, "Initialized")
+        );
+        assert_eq!("True", condition_status(started, "Initialized"));
+        assert_eq!("False", condition_status(never_started, "Initialized"));
+        assert_eq!(Phase::Pending, GenericState::ImagePull.phase());
+        assert_eq!(Phase::Succeeded, started.phase());
+        assert_eq!(Phase::Failed, never_started.phase());
+
+        let patch = never_started
+            .status(&Pod::from(KubePod::default()), "Terminated")
+            .expect("status patch");
+        assert_eq!("Failed", patch["status"]["phase"]);
+        assert_eq!("Terminated", patch["status"]["reason"]);
+    }
+
+    #[test]
+    fn conditions_keep_their_transition_time_until_they_change() {
+        let then = Time(Utc.ymd(2020, 1, 1).and_hms(0, 0, 0));
+        let pod = Pod::from(KubePod {
+            status: Some(PodStatus {
+                conditions: Some(vec![
+                    PodCondition {
+                        type_: "Initialized".to_owned(),
+                        status: "False".to_owned(),
+                        last_transition_time: Some(then.clone()),
+                        ..Default::default()
+                    },
+                    PodCondition {
+                        type_: "Ready".to_owned(),
+                        status: "True".to_owned(),
+                        last_transition_time: Some(then.clone()),
+                        ..Default::default()
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let conditions = GenericState::VolumeMount.conditions(&pod);
+        let transition_time = |type_: &str| {
+            conditions
+                .iter()
+                .find(|c| c.type_ == type_)
+                .and_then(|c| c.last_transition_time.clone())
+                .expect("transition time")
+        };
+        assert_eq!(then, transition_time("Initialized"));
+        assert!(transition_time("Ready").0 > then.0);
+        assert!(transition_time("ContainersReady").0 > then.0);
+    }
+
+    #[test]
+    fn pods_deleted_before_their_containers_started_never_ran() {
+        let waiting = pod_with_container_state(Some(ContainerState {
+            waiting: Some(ContainerStateWaiting::default()),
+            ..Default::default()
+        }));
+        assert_eq!(
+            GenericState::Terminated { started: false },
+            GenericState::terminated(&waiting)
+        );
+        assert_eq!(
+            GenericState::Terminated { started: false },
+            GenericState::terminated(&Pod::from(KubePod::default()))
+        );
+
+        let running = pod_with_container_state(Some(ContainerState {
+            running: Some(ContainerStateRunning::default()),
+            ..Default::default()
+        }));
+        assert_eq!(
+            GenericState::Terminated { started: true },
+            GenericState::terminated(&running)
+        );
+        let terminated = pod_with_container_state(Some(ContainerState {
+            terminated: Some(ContainerStateTerminated::default()),
+            ..Default::default()
+        }));
+        assert_eq!(
+            GenericState::Terminated { started: true },
+            GenericState::terminated(&terminated)
+        );
+    }
+
+    #[test]
+    fn status_patch_includes_conditions() {
+        let patch = GenericState::ImagePull
+            .status(&Pod::from(KubePod::default()), "ImagePull")
+            .expect("status patch");
+        assert_eq!("Pending", patch["status"]["phase"]);
+        assert_eq!("ImagePull", patch["status"]["reason"]);
+        assert_eq!(3, patch["status"]["conditions"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn status_patch_can_carry_message() {
+        let patch = GenericState::Error
+            .status_with_message(
+                &Pod::from(KubePod::default()),
+                "VolumeMountFailed",
+                "configmap foo not found",
+            )
+            .expect("status patch");
+        assert_eq!("Pending", patch["status"]["phase"]);
+        assert_eq!("VolumeMountFailed", patch["status"]["reason"]);
+        assert_eq!("configmap foo not found", patch["status"]["message"]);
+    }
+}
@@ -2,8 +2,12 @@
 
 use crate::state::prelude::*;
 
+use super::state_store::checkpoint_key;
+use super::status::GenericState;
 use super::{GenericProvider, GenericProviderState};
 
+use log::warn;
+
 /// Pod was deleted.
 pub struct Terminated<P: GenericProvider> {
     phantom: std::marker::PhantomData<P>,
@@ -32,6 +36,17 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for Terminated<P>
         pod: &Pod,
     ) -> Transition<P::ProviderState, P::PodState> {
         let state_reader = provider_state.read().await;
+        if let Err(e) = state_reader
+            .state_store()
+            .remove(&checkpoint_key(pod))
+            .await
+        {
+            warn!(
+                "Unable to remove checkpoint for pod {}: {:?}",
+                pod.name(),
+                e
+            );
+        }
         // TODO: In original code, pod key was stored in state rather than
         // re-derived.  Is this important e.g. could pod mutate in ways
         // that invalidate the key assigned on startup?
@@ -42,8 +57,8 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for Terminated<P>
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Succeeded, "Terminated")
+        GenericState::terminated(pod).status(pod, "Terminated")
     }
 }
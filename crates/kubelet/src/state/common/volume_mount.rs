@@ -1,11 +1,16 @@
 //! Kubelet is pulling container images.
 
-use log::error;
+use log::{error, info};
+use std::path::Path;
 
 use crate::state::prelude::*;
-use crate::volume::Ref;
+use crate::volume::{volume_specs, Ref};
 
-use super::{GenericPodState, GenericProvider, GenericProviderState};
+use super::status::GenericState;
+use super::{
+    notify_transition, update_checkpoint, vetoed, GenericPodState, GenericProvider,
+    GenericProviderState,
+};
 use crate::state::common::error::Error;
 
 /// Kubelet is pulling container images.
@@ -39,25 +44,143 @@ impl<P: GenericProvider> State<P::ProviderState, P::PodState> for VolumeMount<P>
             let state_reader = provider_state.read().await;
             (state_reader.client(), state_reader.volume_path())
         };
-        let volumes = match Ref::volumes_from_pod(&volume_path, &pod, &client).await {
-            Ok(v) => v,
-            Err(e) => {
-                error!("{:?}", e);
-                let next = Error::<P>::new(e.to_string());
-                return Transition::next(self, next);
+        if let Err(e) = ensure_writable(&volume_path).await {
+            error!("{:?}", e);
+            notify_transition::<P>(&provider_state, pod_state, "VolumeMount", "Error", pod).await;
+            let next = Error::<P>::from_state("VolumeMount", e);
+            return Transition::next(self, next);
+        }
+        // After a restart, reuse volumes that were already mounted rather than
+        // mounting them again
+        let restored = match &pod_state.checkpoint().mounted_volumes {
+            Some(mounted) => Ref::volumes_from_checkpoint(&volume_path, &pod, mounted).await,
+            None => None,
+        };
+        let volumes = match restored {
+            Some(v) => {
+                info!("Reusing mounted volumes for pod {}", pod.name());
+                v
             }
+            None => match Ref::volumes_from_pod(&volume_path, &pod, &client).await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{:?}", e);
+                    notify_transition::<P>(&provider_state, pod_state, "VolumeMount", "Error", pod)
+                        .await;
+                    let next = Error::<P>::from_state("VolumeMount", e);
+                    return Transition::next(self, next);
+                }
+            },
         };
         pod_state.set_volumes(volumes);
+        update_checkpoint::<P, _>(&provider_state, pod_state, pod, |c| {
+            c.state = "VolumeMount".to_owned();
+            c.mounted_volumes = Some(volume_specs(pod));
+        })
+        .await;
+        if let Some(error) =
+            vetoed::<P>(&provider_state, pod_state, "VolumeMount", "RunState", pod).await
+        {
+            return Transition::next(self, error);
+        }
         Transition::next_unchecked(self, P::RunState::default())
     }
 
     async fn json_status(
         &self,
         _pod_state: &mut P::PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "VolumeMount")
+        GenericState::VolumeMount.status(pod, "VolumeMount")
     }
 }
 
 impl<P: GenericProvider> TransitionTo<Error<P>> for VolumeMount<P> {}
+
+/// Makes sure the volume directory exists and can be written to, creating it
+/// if it is missing, so that a misconfigured node fails with an error that
+/// names the directory rather than partway through mounting volumes.
+async fn ensure_writable(volume_path: &Path) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(volume_path).await.map_err(|e| {
+        anyhow::anyhow!(
+            "Volume directory {} does not exist and could not be created: {}. Check that the kubelet's data directory is writable.",
+            volume_path.display(),
+            e
+        )
+    })?;
+    let unwritable = |reason: String| {
+        anyhow::anyhow!(
+            "Volume directory {} is not writable: {}. Check the directory's permissions.",
+            volume_path.display(),
+            reason
+        )
+    };
+    let metadata = tokio::fs::metadata(volume_path)
+        .await
+        .map_err(|e| unwritable(e.to_string()))?;
+    if !metadata.is_dir() {
+        return Err(unwritable("it is not a directory".to_owned()));
+    }
+    if metadata.permissions().readonly() {
+        return Err(unwritable("it is read-only".to_owned()));
+    }
+    // Permissions alone don't account for ownership or read-only mounts, so
+    // try writing to it
+    let probe = volume_path.join(".krustlet-write-check");
+    tokio::fs::write(&probe, b"")
+        .await
+        .map_err(|e| unwritable(e.to_string()))?;
+    if let Err(e) = tokio::fs::remove_file(&probe).await {
+        error!("Unable to remove {}: {:?}", probe.display(), e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn missing_volume_directories_are_created() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let volume_path = dir.path().join("volumes").join("nested");
+        ensure_writable(&volume_path)
+            .await
+            .expect("missing directory should be created");
+        assert!(volume_path.is_dir());
+        assert_eq!(0, std::fs::read_dir(&volume_path).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn read_only_volume_directories_are_rejected() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let volume_path = dir.path().join("volumes");
+        std::fs::create_dir(&volume_path).unwrap();
+        let mut permissions = std::fs::metadata(&volume_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&volume_path, permissions.clone()).unwrap();
+
+        let result = ensure_writable(&volume_path).await;
+
+        permissions.set_readonly(false);
+        std::fs::set_permissions(&volume_path, permissions).unwrap();
+        let message = result
+            .expect_err("read-only directory should be rejected")
+            .to_string();
+        assert!(message.contains("is not writable"), "{}", message);
+        assert!(
+            message.contains(&volume_path.display().to_string()),
+            "{}",
+            message
+        );
+    }
+
+    #[tokio::test]
+    async fn volume_paths_that_are_files_are_rejected() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert!(ensure_writable(&file).await.is_err());
+        assert!(ensure_writable(&file.join("volumes")).await.is_err());
+    }
+}
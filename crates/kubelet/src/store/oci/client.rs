@@ -59,7 +59,7 @@ pub trait Client {
 #[async_trait]
 impl Client for oci_distribution::Client {
     async fn pull(&mut self, image: &Reference, auth: &RegistryAuth) -> anyhow::Result<ImageData> {
-        self.pull(image, auth, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+        self.pull(image, auth, manifest::wasm_layer_media_types())
             .await
     }
 
@@ -89,7 +89,7 @@ impl Storer for FileStorer {
         if image_data.layers.is_empty() {
             return Err(anyhow::anyhow!("No module layer present in image data"));
         }
-        tokio::fs::write(&module_path, &image_data.layers[0].data).await?;
+        tokio::fs::write(&module_path, image_data.layers[0].data()).await?;
         if let Some(d) = image_data.digest {
             tokio::fs::write(&digest_path, d).await?;
         }
@@ -178,6 +178,7 @@ mod test {
                     ImageData {
                         layers: vec![ImageLayer::oci_v1(content)],
                         digest: Some(digest.to_owned()),
+                        ..Default::default()
                     },
                 );
             }
@@ -194,6 +195,7 @@ mod test {
                 ImageData {
                     layers: vec![ImageLayer::oci_v1(content)],
                     digest: Some(digest.to_owned()),
+                    ..Default::default()
                 },
             );
         }
@@ -0,0 +1,276 @@
+//! A registry client shared by everything on a node that pulls or pushes
+//! images
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use oci_distribution::client::{ImageData, PushResponse};
+use oci_distribution::manifest::{self, OciManifest};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::Reference;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::client::Client;
+
+/// Limits on the image operations a `SharedClient` runs at once
+#[derive(Clone, Debug, Default)]
+pub struct SharedClientLimits {
+    /// The most image pulls to run at once. Further pulls wait for one to
+    /// finish. `None` means unlimited.
+    pub max_concurrent_pulls: Option<NonZeroUsize>,
+    /// The most image pushes to run at once. Further pushes wait for one to
+    /// finish. `None` means unlimited.
+    pub max_concurrent_pushes: Option<NonZeroUsize>,
+}
+
+/// An OCI client to share between every pod on a node
+///
+/// A node starting many pods at once would otherwise pull all of their
+/// images at the same time, which can overwhelm both the registry and the
+/// node. A `SharedClient` limits how many pulls and pushes run at once, on top
+/// of the request limit, circuit breakers and token cache of the
+/// `oci_distribution::Client` it wraps.
+///
+/// Create one when the provider starts, keep it in the provider state, and
+/// hand clones of it to the module store and anything else that talks to a
+/// registry. Clones share the limits, so they only work if every caller uses
+/// a clone of the same `SharedClient` rather than creating its own.
+///
+/// ```rust,no_run
+/// use kubelet::store::oci::{FileStore, SharedClient, SharedClientLimits};
+/// use std::num::NonZeroUsize;
+///
+/// let client = SharedClient::new(
+///     oci_distribution::Client::default(),
+///     SharedClientLimits {
+///         max_concurrent_pulls: NonZeroUsize::new(4),
+///         ..Default::default()
+///     },
+/// );
+/// let store = FileStore::new(client.clone(), "/var/lib/krustlet/.oci/modules");
+/// ```
+#[derive(Clone)]
+pub struct SharedClient {
+    client: oci_distribution::Client,
+    pulls: Option<Arc<Semaphore>>,
+    pushes: Option<Arc<Semaphore>>,
+}
+
+impl SharedClient {
+    /// Create a shared client that sends its requests with `client`
+    pub fn new(client: oci_distribution::Client, limits: SharedClientLimits) -> Self {
+        SharedClient {
+            client,
+            pulls: limits
+                .max_concurrent_pulls
+                .map(|n| Arc::new(Semaphore::new(n.get()))),
+            pushes: limits
+                .max_concurrent_pushes
+                .map(|n| Arc::new(Semaphore::new(n.get()))),
+        }
+    }
+
+    /// The wrapped client, for operations that are not limited
+    pub fn client(&self) -> &oci_distribution::Client {
+        &self.client
+    }
+
+    /// Pull an image, waiting first if the most pulls allowed are already
+    /// running
+    pub async fn pull(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+    ) -> anyhow::Result<ImageData> {
+        let _permit = permit(&self.pulls).await;
+        self.client.pull(image, auth, accepted_media_types).await
+    }
+
+    /// Push an image, waiting first if the most pushes allowed are already
+    /// running
+    ///
+    /// The arguments are the same as for `oci_distribution::Client::push`.
+    pub async fn push(
+        &self,
+        image_ref: &Reference,
+        image_data: &ImageData,
+        config_data: &[u8],
+        config_media_type: &str,
+        auth: &RegistryAuth,
+        image_manifest: Option<OciManifest>,
+    ) -> anyhow::Result<PushResponse> {
+        let _permit = permit(&self.pushes).await;
+        self.client
+            .push(
+                image_ref,
+                image_data,
+                config_data,
+                config_media_type,
+                auth,
+                image_manifest,
+            )
+            .await
+    }
+}
+
+async fn permit(limiter: &Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match limiter {
+        Some(limiter) => Some(limiter.clone().acquire_owned().await),
+        None => None,
+    }
+}
+
+#[async_trait]
+impl Client for SharedClient {
+    async fn pull(&mut self, image: &Reference, auth: &RegistryAuth) -> anyhow::Result<ImageData> {
+        SharedClient::pull(self, image, auth, manifest::wasm_layer_media_types()).await
+    }
+
+    async fn fetch_digest(
+        &mut self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<String> {
+        self.client.fetch_manifest_digest(image, auth).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use oci_distribution::client::ClientConfig;
+    use oci_distribution::digest::{Digest, DigestAlgorithm};
+    use oci_distribution::transport::Transport;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    const MODULE: &[u8] = b"iamawebassemblymodule";
+    const CONFIG: &[u8] = b"{}";
+
+    fn digest(data: &[u8]) -> String {
+        Digest::compute(DigestAlgorithm::Sha256, data).to_string()
+    }
+
+    /// Serves one WASM image for any reference, holding each manifest
+    /// request open for a while and recording how many were open at once
+    #[derive(Default)]
+    struct SlowRegistry {
+        // The manifest requests open now, and the most there have been
+        in_flight: Mutex<(usize, usize)>,
+    }
+
+    impl SlowRegistry {
+        fn most_in_flight(&self) -> usize {
+            self.in_flight.lock().unwrap().1
+        }
+
+        fn manifest() -> String {
+            serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                "config": {
+                    "mediaType": manifest::WASM_CONFIG_MEDIA_TYPE,
+                    "size": CONFIG.len(),
+                    "digest": digest(CONFIG),
+                },
+                "layers": [{
+                    "mediaType": manifest::WASM_LAYER_MEDIA_TYPE,
+                    "size": MODULE.len(),
+                    "digest": digest(MODULE),
+                }],
+            })
+            .to_string()
+        }
+    }
+
+    #[async_trait]
+    impl Transport for SlowRegistry {
+        async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+            let path = request.url().path().to_owned();
+            let (content_type, body) = if path.contains("/manifests/") {
+                {
+                    let mut in_flight = self.in_flight.lock().unwrap();
+                    in_flight.0 += 1;
+                    in_flight.1 = in_flight.1.max(in_flight.0);
+                }
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+                self.in_flight.lock().unwrap().0 -= 1;
+                (
+                    manifest::OCI_IMAGE_MEDIA_TYPE,
+                    Self::manifest().into_bytes(),
+                )
+            } else if path.ends_with(&digest(MODULE)) {
+                ("application/octet-stream", MODULE.to_vec())
+            } else if path.ends_with(&digest(CONFIG)) {
+                ("application/octet-stream", CONFIG.to_vec())
+            } else {
+                ("application/json", b"{}".to_vec())
+            };
+            Ok(http::Response::builder()
+                .status(200)
+                .header("Content-Type", content_type)
+                .header("Content-Length", body.len())
+                .body(body)?
+                .into())
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_pulls_are_limited_across_clones() {
+        let registry = Arc::new(SlowRegistry::default());
+        let shared = SharedClient::new(
+            oci_distribution::Client::with_transport(ClientConfig::default(), registry.clone()),
+            SharedClientLimits {
+                max_concurrent_pulls: NonZeroUsize::new(2),
+                ..Default::default()
+            },
+        );
+
+        let pulls = (0..6).map(|i| {
+            let mut client = shared.clone();
+            async move {
+                let image: Reference = format!("registry.example.com/module:v{}", i)
+                    .parse()
+                    .unwrap();
+                Client::pull(&mut client, &image, &RegistryAuth::Anonymous).await
+            }
+        });
+        let results = futures::future::join_all(pulls).await;
+
+        for result in results {
+            let image_data = result.expect("pull should succeed");
+            assert_eq!(MODULE, image_data.layers[0].data());
+        }
+        assert_eq!(2, registry.most_in_flight());
+    }
+
+    #[tokio::test]
+    async fn pulls_are_unlimited_by_default() {
+        let registry = Arc::new(SlowRegistry::default());
+        let shared = SharedClient::new(
+            oci_distribution::Client::with_transport(ClientConfig::default(), registry.clone()),
+            SharedClientLimits::default(),
+        );
+
+        let pulls = (0..3).map(|i| {
+            let shared = shared.clone();
+            async move {
+                let image: Reference = format!("registry.example.com/module:v{}", i)
+                    .parse()
+                    .unwrap();
+                shared
+                    .pull(
+                        &image,
+                        &RegistryAuth::Anonymous,
+                        vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                    )
+                    .await
+            }
+        });
+        for result in futures::future::join_all(pulls).await {
+            result.expect("pull should succeed");
+        }
+        assert_eq!(3, registry.most_in_flight());
+    }
+}
@@ -1,6 +1,6 @@
 //! A module for use in managing volumes in providers. Use of this module is not mandatory to create
 //! a Provider, but it does provide common implementation logic for supported volume providers.
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Deref;
 use std::path::PathBuf;
 
@@ -72,6 +72,52 @@ impl Ref {
     }
 }
 
+impl Ref {
+    /// Rebuilds the volume references for a pod whose volumes were mounted
+    /// by an earlier run of the Kubelet, without fetching their contents
+    /// again. `mounted` holds the `volume_specs` recorded when the volumes
+    /// were mounted. Returns None if the pod's volumes no longer match those
+    /// specs or any volume is not present on disk, in which case the volumes
+    /// should be mounted with `volumes_from_pod`.
+    pub async fn volumes_from_checkpoint(
+        volume_dir: &PathBuf,
+        pod: &Pod,
+        mounted: &BTreeMap<String, serde_json::Value>,
+    ) -> Option<HashMap<String, Self>> {
+        if volume_specs(pod) != *mounted {
+            debug!(
+                "volumes of pod {} changed since they were mounted",
+                pod.name()
+            );
+            return None;
+        }
+        let base_path = volume_dir.join(pod_dir_name(pod));
+        let mut refs = HashMap::new();
+        for v in pod.volumes().map(|v| v.as_slice()).unwrap_or_default() {
+            let (host_path, volume_type) = if v.config_map.is_some() {
+                (base_path.join(&v.name), Type::ConfigMap)
+            } else if v.secret.is_some() {
+                (base_path.join(&v.name), Type::Secret)
+            } else if let Some(hostpath) = &v.host_path {
+                (PathBuf::from(&hostpath.path), Type::HostPath)
+            } else {
+                return None;
+            };
+            if tokio::fs::metadata(&host_path).await.is_err() {
+                return None;
+            }
+            refs.insert(
+                v.name.to_owned(),
+                Ref {
+                    host_path,
+                    volume_type,
+                },
+            );
+        }
+        Some(refs)
+    }
+}
+
 impl AsRef<PathBuf> for Ref {
     fn as_ref(&self) -> &PathBuf {
         &self.host_path
@@ -214,6 +260,23 @@ async fn populate_from_config_map(
     Ok(Type::ConfigMap)
 }
 
+/// Returns the spec of each volume of a pod, keyed by volume name. Recording
+/// these when volumes are mounted lets `Ref::volumes_from_checkpoint` tell
+/// whether the mounted volumes still match the pod.
+pub fn volume_specs(pod: &Pod) -> BTreeMap<String, serde_json::Value> {
+    pod.volumes()
+        .map(|v| v.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|v| {
+            (
+                v.name.to_owned(),
+                serde_json::to_value(v).unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
 fn pod_dir_name(pod: &Pod) -> String {
     format!("{}-{}", pod.name(), pod.namespace())
 }
@@ -243,3 +306,59 @@ impl From<Option<String>> for ItemMount {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k8s_openapi::api::core::v1::{HostPathVolumeSource, Pod as KubePod, PodSpec};
+    use kube::api::ObjectMeta;
+
+    fn host_path_pod(path: &std::path::Path) -> Pod {
+        Pod::from(KubePod {
+            metadata: ObjectMeta {
+                name: Some("volumes".to_owned()),
+                namespace: Some("default".to_owned()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                volumes: Some(vec![KubeVolume {
+                    name: "data".to_owned(),
+                    host_path: Some(HostPathVolumeSource {
+                        path: path.display().to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn checkpointed_volumes_are_reused_while_the_pod_matches() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let pod = host_path_pod(dir.path());
+        let mounted = volume_specs(&pod);
+
+        let volumes = Ref::volumes_from_checkpoint(&dir.path().to_owned(), &pod, &mounted)
+            .await
+            .expect("matching volumes should be reused");
+        assert_eq!(dir.path(), volumes["data"].as_path());
+    }
+
+    #[tokio::test]
+    async fn checkpointed_volumes_are_not_reused_after_the_pod_changes() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let other = tempfile::tempdir().expect("create temp dir");
+        let mounted = volume_specs(&host_path_pod(dir.path()));
+
+        let pod = host_path_pod(other.path());
+        let restored = Ref::volumes_from_checkpoint(&dir.path().to_owned(), &pod, &mounted).await;
+        assert!(restored.is_none());
+
+        let restored =
+            Ref::volumes_from_checkpoint(&dir.path().to_owned(), &pod, &BTreeMap::new()).await;
+        assert!(restored.is_none());
+    }
+}
@@ -0,0 +1,328 @@
+//! Import and export of `ImageData` as a `docker save` style tar archive
+//!
+//! The archive holds one `<hex>/layer.tar` file per layer, a config file, and
+//! a `manifest.json` listing them. Layer media types are recorded in the
+//! manifest's `LayerSources` so that they survive a roundtrip.
+
+use crate::client::{sha256_digest, ImageData, ImageLayer};
+use crate::manifest::{OciDescriptor, IMAGE_LAYER_MEDIA_TYPE};
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Vec<String>,
+    layers: Vec<String>,
+    #[serde(default)]
+    layer_sources: HashMap<String, OciDescriptor>,
+}
+
+impl ImageData {
+    /// Writes the image as a `docker save` style tar archive
+    ///
+    /// The generated config only records the layer digests as the rootfs
+    /// diff IDs, which is accurate for uncompressed layers.
+    pub fn write_tar<W: Write>(&self, w: W) -> anyhow::Result<()> {
+        let mut builder = tar::Builder::new(w);
+        let mut layers = Vec::with_capacity(self.layers.len());
+        let mut layer_sources = HashMap::new();
+        let mut diff_ids = Vec::with_capacity(self.layers.len());
+
+        for layer in &self.layers {
+            let digest = layer.digest().to_owned();
+            let path = format!("{}/layer.tar", digest_hex(&digest));
+            append_file(&mut builder, &path, layer.data())?;
+            layer_sources.insert(
+                digest.clone(),
+                OciDescriptor {
+                    media_type: layer.media_type.clone(),
+                    digest: digest.clone(),
+                    size: layer.data().len() as i64,
+                    urls: None,
+                    annotations: None,
+                    platform: None,
+                },
+            );
+            diff_ids.push(digest);
+            layers.push(path);
+        }
+
+        let config = serde_json::to_vec(&serde_json::json!({
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": diff_ids,
+            }
+        }))?;
+        let config_path = format!("{}.json", digest_hex(&sha256_digest(&config)));
+        append_file(&mut builder, &config_path, &config)?;
+
+        let manifest = vec![ArchiveManifest {
+            config: config_path,
+            repo_tags: vec![],
+            layers,
+            layer_sources,
+        }];
+        append_file(&mut builder, MANIFEST_FILE, &serde_json::to_vec(&manifest)?)?;
+
+        builder.finish()?;
+        Ok(())
+    }
+
+    /// Reads an image from a `docker save` style tar archive
+    ///
+    /// Only the first image in the archive is loaded. Layers without a
+    /// recorded media type are assumed to be uncompressed OCI layers.
+    pub fn from_tar<R: Read>(r: R) -> anyhow::Result<Self> {
+        let mut archive = tar::Archive::new(r);
+        let mut files = HashMap::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            files.insert(path, data);
+        }
+
+        let manifest_bytes = files
+            .get(MANIFEST_FILE)
+            .ok_or_else(|| anyhow::anyhow!("archive does not contain {}", MANIFEST_FILE))?;
+        let mut manifests: Vec<ArchiveManifest> = serde_json::from_slice(manifest_bytes)
+            .with_context(|| format!("Failed to parse {}", MANIFEST_FILE))?;
+        if manifests.is_empty() {
+            return Err(anyhow::anyhow!("archive does not contain any images"));
+        }
+        let manifest = manifests.remove(0);
+
+        let mut layers = Vec::with_capacity(manifest.layers.len());
+        for path in &manifest.layers {
+            let data = files
+                .remove(path)
+                .ok_or_else(|| anyhow::anyhow!("archive is missing layer {}", path))?;
+            let media_type = manifest
+                .layer_sources
+                .get(&sha256_digest(&data))
+                .map(|d| d.media_type.clone())
+                .unwrap_or_else(|| IMAGE_LAYER_MEDIA_TYPE.to_owned());
+            layers.push(ImageLayer::new(data, media_type));
+        }
+
+        Ok(ImageData {
+            layers,
+            ..Default::default()
+        })
+    }
+}
+
+impl ImageLayer {
+    /// Checks that the layer is a well-formed tar archive that is safe to
+    /// extract
+    ///
+    /// Compressed layers are decompressed first, as `decompressed` would.
+    /// Every entry must parse, and no entry path or link target may be
+    /// absolute or contain `..`, so that extracting the layer cannot write
+    /// outside the target directory.
+    pub fn validate_tar(&self) -> anyhow::Result<()> {
+        validate_tar_entries(self.decoder()?)
+    }
+}
+
+fn validate_tar_entries<R: Read>(r: R) -> anyhow::Result<()> {
+    let mut archive = tar::Archive::new(r);
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        let entry = entry.context("Failed to read tar entry")?;
+        let path = entry.path().context("Failed to read tar entry path")?;
+        check_entry_path(&path)?;
+        if let Some(target) = entry
+            .link_name()
+            .context("Failed to read tar entry link target")?
+        {
+            check_link_target(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fails if a tar entry path is absolute or contains `..`, so that it could
+/// be written outside of the directory it is extracted into
+pub(crate) fn check_entry_path(path: &Path) -> anyhow::Result<()> {
+    if escapes(path) {
+        return Err(anyhow::anyhow!(
+            "tar entry {} escapes the extraction directory",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if the target of a symlink or hardlink entry is absolute or contains
+/// `..`, so that the link could point outside of the extraction directory
+fn check_link_target(path: &Path, target: &Path) -> anyhow::Result<()> {
+    if escapes(target) {
+        return Err(anyhow::anyhow!(
+            "tar entry {} links to {}, outside of the extraction directory",
+            path.display(),
+            target.display()
+        ));
+    }
+    Ok(())
+}
+
+fn escapes(path: &Path) -> bool {
+    path.components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &str,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data)?;
+    Ok(())
+}
+
+fn digest_hex(digest: &str) -> &str {
+    digest.splitn(2, ':').nth(1).unwrap_or(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::IMAGE_LAYER_ZSTD_MEDIA_TYPE;
+
+    #[test]
+    fn can_roundtrip_image_data() {
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::wasm(b"iamawebassemblymodule".to_vec()),
+                ImageLayer::oci_v1(b"anotherlayer".to_vec()),
+            ],
+            ..Default::default()
+        };
+
+        let mut archive = Vec::new();
+        image_data.write_tar(&mut archive).expect("write archive");
+        let loaded = ImageData::from_tar(archive.as_slice()).expect("read archive");
+
+        assert_eq!(2, loaded.layers.len());
+        for (original, loaded) in image_data.layers.iter().zip(loaded.layers.iter()) {
+            assert_eq!(original.data(), loaded.data());
+            assert_eq!(original.media_type, loaded.media_type);
+        }
+        assert_eq!(image_data.sha256_digest(), loaded.sha256_digest());
+    }
+
+    #[test]
+    fn well_formed_layers_pass_validation() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append_file(&mut builder, "app/hello.txt", b"hello").unwrap();
+        let data = builder.into_inner().unwrap();
+
+        ImageLayer::oci_v1(data.clone())
+            .validate_tar()
+            .expect("plain layer should be valid");
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        ImageLayer::oci_v1_gzip(encoder.finish().unwrap())
+            .validate_tar()
+            .expect("gzipped layer should be valid");
+    }
+
+    #[test]
+    fn path_traversal_entries_are_rejected() {
+        // The tar builder refuses `..` paths, so write the name directly
+        let mut header = tar::Header::new_old();
+        let name = b"../../etc/passwd";
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b"evil"[..]).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let err = ImageLayer::oci_v1(data)
+            .validate_tar()
+            .expect_err("traversal should be rejected");
+        assert!(err.to_string().contains("../../etc/passwd"));
+    }
+
+    #[test]
+    fn zstd_layers_are_validated() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append_file(&mut builder, "app/hello.txt", b"hello").unwrap();
+        let data = builder.into_inner().unwrap();
+
+        let compressed = zstd::encode_all(data.as_slice(), 0).unwrap();
+        ImageLayer::new(compressed, IMAGE_LAYER_ZSTD_MEDIA_TYPE.to_owned())
+            .validate_tar()
+            .expect("zstd layer should be valid");
+        assert!(
+            ImageLayer::new(data, IMAGE_LAYER_ZSTD_MEDIA_TYPE.to_owned())
+                .validate_tar()
+                .is_err(),
+            "uncompressed data is not a zstd layer"
+        );
+    }
+
+    fn link_layer(entry_type: tar::EntryType, target: &str) -> ImageLayer {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_link_name(target).unwrap();
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_data(&mut header, "app/link", std::io::empty())
+            .unwrap();
+        ImageLayer::oci_v1(builder.into_inner().unwrap())
+    }
+
+    #[test]
+    fn links_out_of_the_layer_are_rejected() {
+        link_layer(tar::EntryType::Symlink, "lib/libc.so")
+            .validate_tar()
+            .expect("relative symlink should be valid");
+
+        for (entry_type, target) in &[
+            (tar::EntryType::Symlink, "/etc/passwd"),
+            (tar::EntryType::Symlink, "../../etc/passwd"),
+            (tar::EntryType::Link, "/etc/shadow"),
+            (tar::EntryType::Link, "app/../../etc/shadow"),
+        ] {
+            let err = link_layer(*entry_type, target)
+                .validate_tar()
+                .expect_err("link out of the layer should be rejected");
+            assert!(err.to_string().contains(target), "{}", err);
+        }
+    }
+
+    #[test]
+    fn malformed_layers_are_rejected() {
+        assert!(ImageLayer::oci_v1_gzip(b"not gzip".to_vec())
+            .validate_tar()
+            .is_err());
+    }
+
+    #[test]
+    fn archive_without_manifest_is_rejected() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append_file(&mut builder, "something.txt", b"hello").unwrap();
+        let archive = builder.into_inner().unwrap();
+        assert!(ImageData::from_tar(archive.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! Bandwidth limiting for layer downloads
+//!
+//! A single token bucket is shared by every download a client makes, so the
+//! cap applies to the client as a whole rather than to each layer. The bucket
+//! holds up to one second's worth of bytes, which allows short bursts after a
+//! quiet period without going over the rate for long.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that can be read without waiting. This goes negative when bytes
+    /// are taken on credit, and the reader then waits until it is repaid.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Paces downloads to at most a given number of bytes per second
+#[derive(Debug)]
+pub(crate) struct BandwidthLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl BandwidthLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        BandwidthLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `bytes` more bytes can be read without going over the rate
+    pub(crate) async fn consume(&self, bytes: usize) {
+        let wait = self.reserve_at(bytes, Instant::now());
+        if wait > Duration::from_secs(0) {
+            tokio::time::delay_for(wait).await;
+        }
+    }
+
+    /// Takes `bytes` from the bucket and returns how long the caller must
+    /// wait before the bytes are paid for
+    ///
+    /// Bytes are taken up front, even on credit, so that concurrent readers
+    /// queue up behind each other instead of all waking at once.
+    fn reserve_at(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now
+            .saturating_duration_since(bucket.refilled_at)
+            .as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        bucket.refilled_at = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bursts_up_to_one_second_of_bytes() {
+        let limiter = BandwidthLimiter::new(1000);
+        let now = Instant::now();
+        assert_eq!(Duration::from_secs(0), limiter.reserve_at(1000, now));
+        assert_eq!(Duration::from_millis(500), limiter.reserve_at(500, now));
+    }
+
+    #[test]
+    fn sustained_reads_are_held_to_the_rate() {
+        let limiter = BandwidthLimiter::new(1000);
+        let start = Instant::now();
+        // Read 10 chunks back to back, each as soon as the last one's wait
+        // is over
+        let mut now = start;
+        for _ in 0..10 {
+            now += limiter.reserve_at(500, now);
+        }
+        // The first second's worth comes from the initial burst
+        let elapsed = now.duration_since(start).as_secs_f64();
+        assert!((elapsed - 4.0).abs() < 0.01, "took {}s", elapsed);
+    }
+
+    #[test]
+    fn idle_time_refills_the_bucket() {
+        let limiter = BandwidthLimiter::new(1000);
+        let now = Instant::now();
+        limiter.reserve_at(1000, now);
+        assert_eq!(
+            Duration::from_secs(0),
+            limiter.reserve_at(1000, now + Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn concurrent_readers_share_the_cap() {
+        let limiter = BandwidthLimiter::new(1000);
+        let now = Instant::now();
+        limiter.reserve_at(1000, now);
+        // Two downloads reading at the same moment wait one after the other
+        assert_eq!(Duration::from_secs(1), limiter.reserve_at(1000, now));
+        assert_eq!(Duration::from_secs(2), limiter.reserve_at(1000, now));
+    }
+}
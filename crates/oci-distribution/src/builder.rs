@@ -0,0 +1,296 @@
+//! Assembly of images to push from files and in-memory data
+//!
+//! `ImageDataBuilder` collects layers and an optional config, reads any
+//! files when the image is built, and produces the `ImageData`, config data
+//! and config media type that `Client::push` takes.
+
+use crate::client::{generate_config, media_type_matches, sha256_digest, ImageData, ImageLayer};
+use crate::manifest::{
+    OciDescriptor, Platform, IMAGE_CONFIG_MEDIA_TYPE, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_DOCKER_LAYER_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE, IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE,
+    WASM_CONFIG_MEDIA_TYPE, WASM_LAYER_MEDIA_TYPE,
+};
+
+use anyhow::Context;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Uncompressed tar layer media types and their gzip equivalents
+const GZIP_MEDIA_TYPES: &[(&str, &str)] = &[
+    (IMAGE_LAYER_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE),
+    (
+        IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE,
+        IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE,
+    ),
+    (
+        IMAGE_DOCKER_LAYER_MEDIA_TYPE,
+        IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+    ),
+];
+
+/// Where the data of a layer or config comes from
+enum Source {
+    Data(Vec<u8>),
+    File(PathBuf),
+}
+
+impl Source {
+    fn read(self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Source::Data(data) => Ok(data),
+            Source::File(path) => {
+                std::fs::read(&path).with_context(|| format!("Failed to read {:?}", path))
+            }
+        }
+    }
+}
+
+/// Builds the image data for a push from files and byte buffers
+///
+/// Files are only read when `build` is called. Layer digests are computed
+/// when they are first needed, as with any `ImageLayer`.
+///
+/// ```no_run
+/// use oci_distribution::builder::ImageDataBuilder;
+/// use oci_distribution::manifest::WASM_LAYER_MEDIA_TYPE;
+///
+/// let image = ImageDataBuilder::new()
+///     .layer_file("module.wasm", WASM_LAYER_MEDIA_TYPE)
+///     .build()
+///     .expect("failed to build image");
+/// ```
+#[derive(Default)]
+pub struct ImageDataBuilder {
+    layers: Vec<(Source, String)>,
+    config: Option<(Source, String)>,
+    gzip: bool,
+}
+
+/// An image assembled by `ImageDataBuilder`, ready to push
+pub struct BuiltImage {
+    /// The image layers. The config descriptor is set to describe
+    /// `config_data`.
+    pub image_data: ImageData,
+    /// The config blob
+    pub config_data: Vec<u8>,
+    /// The media type of the config blob
+    pub config_media_type: String,
+}
+
+impl ImageDataBuilder {
+    /// Create a builder for an image with no layers
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a layer with the given data
+    pub fn layer(mut self, data: Vec<u8>, media_type: &str) -> Self {
+        self.layers
+            .push((Source::Data(data), media_type.to_owned()));
+        self
+    }
+
+    /// Add a layer with the contents of a file
+    pub fn layer_file(mut self, path: impl AsRef<Path>, media_type: &str) -> Self {
+        self.layers.push((
+            Source::File(path.as_ref().to_owned()),
+            media_type.to_owned(),
+        ));
+        self
+    }
+
+    /// Set the config blob
+    ///
+    /// Without one, WASM modules get an empty WASM config, and other images
+    /// an OCI image config generated for the current platform.
+    pub fn config(mut self, data: Vec<u8>, media_type: &str) -> Self {
+        self.config = Some((Source::Data(data), media_type.to_owned()));
+        self
+    }
+
+    /// Set the config blob to the contents of a file
+    pub fn config_file(mut self, path: impl AsRef<Path>, media_type: &str) -> Self {
+        self.config = Some((
+            Source::File(path.as_ref().to_owned()),
+            media_type.to_owned(),
+        ));
+        self
+    }
+
+    /// Gzip uncompressed tar layers, changing their media types to the gzip
+    /// variants
+    ///
+    /// Layers of other media types, such as WASM modules, are left as they
+    /// are.
+    pub fn gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Read the layer and config data and assemble the image
+    ///
+    /// Fails if a file cannot be read, a media type is malformed, or there
+    /// are no layers.
+    pub fn build(self) -> anyhow::Result<BuiltImage> {
+        if self.layers.is_empty() {
+            return Err(anyhow::anyhow!("an image needs at least one layer"));
+        }
+        let gzip = self.gzip;
+        let layers = self
+            .layers
+            .into_iter()
+            .map(|(source, media_type)| {
+                let data = source.read()?;
+                let compressed = GZIP_MEDIA_TYPES
+                    .iter()
+                    .find(|(plain, _)| gzip && media_type_matches(&media_type, plain));
+                match compressed {
+                    Some((_, gzip_media_type)) => {
+                        ImageLayer::with_media_type(gzip_data(&data)?, gzip_media_type)
+                    }
+                    None => ImageLayer::with_media_type(data, &media_type),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut image_data = ImageData {
+            layers,
+            ..Default::default()
+        };
+
+        let (config_data, config_media_type) = match self.config {
+            Some((source, media_type)) => (source.read()?, media_type),
+            None if image_data
+                .layers
+                .iter()
+                .all(|l| media_type_matches(&l.media_type, WASM_LAYER_MEDIA_TYPE)) =>
+            {
+                (b"{}".to_vec(), WASM_CONFIG_MEDIA_TYPE.to_owned())
+            }
+            None => (
+                generate_config(&image_data, &Platform::current())?,
+                IMAGE_CONFIG_MEDIA_TYPE.to_owned(),
+            ),
+        };
+        image_data.config_descriptor = Some(OciDescriptor {
+            media_type: config_media_type.clone(),
+            digest: sha256_digest(&config_data),
+            size: config_data.len() as i64,
+            ..Default::default()
+        });
+
+        Ok(BuiltImage {
+            image_data,
+            config_data,
+            config_media_type,
+        })
+    }
+}
+
+fn gzip_data(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockRegistry;
+    use crate::secrets::RegistryAuth;
+    use crate::Reference;
+    use std::io::Read;
+
+    #[test]
+    fn layers_are_read_from_files_and_buffers() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let module = dir.path().join("module.wasm");
+        std::fs::write(&module, b"iamawebassemblymodule").unwrap();
+
+        let built = ImageDataBuilder::new()
+            .layer_file(&module, WASM_LAYER_MEDIA_TYPE)
+            .layer(b"second".to_vec(), WASM_LAYER_MEDIA_TYPE)
+            .build()
+            .expect("build image");
+
+        let layers = &built.image_data.layers;
+        assert_eq!(&b"iamawebassemblymodule"[..], layers[0].data());
+        assert_eq!(&b"second"[..], layers[1].data());
+        assert_eq!(sha256_digest(b"second"), layers[1].digest());
+        // WASM modules get the empty WASM config by default
+        assert_eq!(b"{}".to_vec(), built.config_data);
+        assert_eq!(WASM_CONFIG_MEDIA_TYPE, built.config_media_type);
+        let descriptor = built.image_data.config_descriptor.unwrap();
+        assert_eq!(sha256_digest(b"{}"), descriptor.digest);
+        assert_eq!(2, descriptor.size);
+    }
+
+    #[test]
+    fn tar_layers_are_gzipped_on_request() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let layer = dir.path().join("layer.tar");
+        std::fs::write(&layer, b"not really a tarball").unwrap();
+        let config = dir.path().join("config.json");
+        std::fs::write(&config, b"{\"os\":\"linux\"}").unwrap();
+
+        let built = ImageDataBuilder::new()
+            .layer_file(&layer, IMAGE_LAYER_MEDIA_TYPE)
+            .layer(b"module".to_vec(), WASM_LAYER_MEDIA_TYPE)
+            .config_file(&config, IMAGE_CONFIG_MEDIA_TYPE)
+            .gzip(true)
+            .build()
+            .expect("build image");
+
+        let gzipped = &built.image_data.layers[0];
+        assert_eq!(IMAGE_LAYER_GZIP_MEDIA_TYPE, gzipped.media_type);
+        let mut unpacked = Vec::new();
+        flate2::read::GzDecoder::new(gzipped.data())
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert_eq!(b"not really a tarball".to_vec(), unpacked);
+        // Only tar layers have a gzip media type to change to
+        assert_eq!(WASM_LAYER_MEDIA_TYPE, built.image_data.layers[1].media_type);
+        assert_eq!(b"{\"os\":\"linux\"}".to_vec(), built.config_data);
+    }
+
+    #[test]
+    fn missing_files_and_bad_media_types_fail_the_build() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        assert!(ImageDataBuilder::new()
+            .layer_file(dir.path().join("missing"), WASM_LAYER_MEDIA_TYPE)
+            .build()
+            .is_err());
+        assert!(ImageDataBuilder::new()
+            .layer(b"data".to_vec(), "not a media type")
+            .build()
+            .is_err());
+        assert!(ImageDataBuilder::new().build().is_err());
+    }
+
+    #[tokio::test]
+    async fn built_images_can_be_pushed() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/built:v1".parse().unwrap();
+        let built = ImageDataBuilder::new()
+            .layer(b"iamawebassemblymodule".to_vec(), WASM_LAYER_MEDIA_TYPE)
+            .build()
+            .expect("build image");
+
+        registry
+            .client()
+            .push(
+                &image,
+                &built.image_data,
+                &built.config_data,
+                &built.config_media_type,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push built image");
+        assert_eq!(
+            Some(built.config_data.clone()),
+            registry.blob(&sha256_digest(&built.config_data))
+        );
+    }
+}
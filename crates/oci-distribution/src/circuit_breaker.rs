@@ -0,0 +1,155 @@
+//! Per-registry circuit breakers
+//!
+//! After a registry fails a number of requests in a row, its breaker opens
+//! and requests to it fail immediately with `ClientError::RegistryUnavailable`
+//! instead of waiting on a registry that is down. Once the cooldown has
+//! passed, requests are let through again to probe the registry. A success
+//! closes the breaker, and a failure opens it for another cooldown.
+
+use crate::errors::ClientError;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When a registry's circuit breaker opens, and for how long
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// How many consecutive failed requests open the breaker
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before requests probe the registry
+    /// again
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// The circuit breakers of all registries a client talks to
+#[derive(Debug)]
+pub(crate) struct CircuitBreakers {
+    config: CircuitBreakerConfig,
+    states: Mutex<HashMap<String, BreakerState>>,
+}
+
+impl CircuitBreakers {
+    pub(crate) fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreakers {
+            config,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fails with `ClientError::RegistryUnavailable` if the registry's
+    /// breaker is open
+    pub(crate) fn check(&self, registry: &str) -> Result<(), ClientError> {
+        self.check_at(registry, Instant::now())
+    }
+
+    fn check_at(&self, registry: &str, now: Instant) -> Result<(), ClientError> {
+        let states = self.states.lock().unwrap();
+        let opened_at = states.get(registry).and_then(|s| s.opened_at);
+        match opened_at {
+            Some(opened_at) if now.duration_since(opened_at) < self.config.cooldown => {
+                Err(ClientError::RegistryUnavailable(registry.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records a request that the registry answered, closing its breaker
+    pub(crate) fn record_success(&self, registry: &str) {
+        self.states.lock().unwrap().remove(registry);
+    }
+
+    /// Records a request that failed because of the registry, opening its
+    /// breaker once enough have failed in a row
+    pub(crate) fn record_failure(&self, registry: &str) {
+        self.record_failure_at(registry, Instant::now())
+    }
+
+    fn record_failure_at(&self, registry: &str, now: Instant) {
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(registry.to_owned()).or_default();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= self.config.failure_threshold {
+            // This also restarts the cooldown when a probe fails
+            state.opened_at = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn breakers() -> CircuitBreakers {
+        CircuitBreakers::new(CircuitBreakerConfig {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(10),
+        })
+    }
+
+    #[test]
+    fn breaker_opens_after_consecutive_failures() {
+        let b = breakers();
+        let now = Instant::now();
+        b.record_failure_at("a.io", now);
+        b.record_failure_at("a.io", now);
+        assert!(b.check_at("a.io", now).is_ok());
+
+        b.record_failure_at("a.io", now);
+        assert_eq!(
+            Err(ClientError::RegistryUnavailable("a.io".to_owned())),
+            b.check_at("a.io", now + Duration::from_secs(9))
+        );
+        // Other registries are unaffected
+        assert!(b.check_at("b.io", now).is_ok());
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let b = breakers();
+        let now = Instant::now();
+        b.record_failure_at("a.io", now);
+        b.record_failure_at("a.io", now);
+        b.record_success("a.io");
+        b.record_failure_at("a.io", now);
+        b.record_failure_at("a.io", now);
+        assert!(b.check_at("a.io", now).is_ok());
+    }
+
+    #[test]
+    fn breaker_probes_after_cooldown() {
+        let b = breakers();
+        let opened = Instant::now();
+        for _ in 0..3 {
+            b.record_failure_at("a.io", opened);
+        }
+        let probe = opened + Duration::from_secs(10);
+        assert!(b.check_at("a.io", probe).is_ok());
+
+        // A failed probe opens the breaker for another cooldown
+        b.record_failure_at("a.io", probe);
+        assert!(b.check_at("a.io", probe + Duration::from_secs(5)).is_err());
+
+        // A successful probe closes it
+        let probe = probe + Duration::from_secs(10);
+        assert!(b.check_at("a.io", probe).is_ok());
+        b.record_success("a.io");
+        b.record_failure_at("a.io", probe);
+        assert!(b.check_at("a.io", probe).is_ok());
+    }
+}
@@ -3,47 +3,148 @@
 //! *Note*: This client is very feature poor. We hope to expand this to be a complete
 //! OCI distribution client in the future.
 
+use crate::bandwidth::BandwidthLimiter;
+use crate::circuit_breaker::{CircuitBreakerConfig, CircuitBreakers};
+use crate::decompress::{Decompressor, Decompressors, Gzip, Zstd};
+use crate::digest::{Digest, DigestAlgorithm, Hasher};
 use crate::errors::*;
+use crate::extract::{extract_layer, StagingDir};
 use crate::manifest::{
-    OciDescriptor, OciManifest, Versioned, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
-    IMAGE_MANIFEST_MEDIA_TYPE,
+    default_image_layer_media_types, History, OciDescriptor, OciImageConfig, OciImageIndex,
+    OciManifest, Platform, RootFs, Versioned, ANNOTATION_TITLE, IMAGE_CONFIG_MEDIA_TYPE,
+    IMAGE_DOCKER_CONFIG_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE,
+    IMAGE_LAYER_MEDIA_TYPES, IMAGE_MANIFEST_LIST_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE,
+    OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, WASM_CONFIG_MEDIA_TYPE,
+    WASM_LAYER_MEDIA_TYPE,
 };
+use crate::retry::RetryPolicy;
 use crate::secrets::RegistryAuth;
 use crate::secrets::*;
+use crate::transport::{ReqwestTransport, Transport};
 use crate::Reference;
 
 use anyhow::Context;
 use futures_util::future;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{Stream, StreamExt, TryStreamExt};
 use hyperx::header::Header;
-use log::debug;
+use log::{debug, warn};
+use once_cell::sync::OnceCell;
 use reqwest::header::HeaderMap;
-use sha2::Digest;
+use sha2::Digest as _;
 use std::collections::HashMap;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 use www_authenticate::{Challenge, ChallengeFields, RawChallenge, WwwAuthenticate};
 
 /// The data for an image or module.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ImageData {
     /// The layers of the image or module.
     pub layers: Vec<ImageLayer>,
     /// The digest of the image or module.
     pub digest: Option<String>,
+    /// The descriptor of the image config, as listed in the pulled manifest.
+    ///
+    /// This can be used to fetch the config blob for the image.
+    pub config_descriptor: Option<OciDescriptor>,
+    /// The media type of the pulled manifest, if the registry set one.
+    pub manifest_media_type: Option<String>,
+}
+
+/// The outcome of pushing an image
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PushResponse {
+    /// Pullable URL for the uploaded image data
+    pub image_url: String,
+    /// Pullable URL for the manifest
+    pub manifest_url: String,
+    /// The digest of the pushed manifest, which can be used to pull exactly
+    /// this image regardless of later changes to its tag
+    pub manifest_digest: String,
+    /// Digests of the blobs whose data was uploaded
+    pub uploaded: Vec<String>,
+    /// Digests of the blobs made available by mounting them from another
+    /// repository, without uploading their data
+    pub mounted: Vec<String>,
+    /// Digests of the blobs that were already present in the repository and
+    /// were not uploaded again
+    pub skipped: Vec<String>,
+}
+
+/// The tags of a repository, as listed by the registry
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct TagList {
+    /// The name of the repository
+    pub name: String,
+    /// The tags in the repository, in lexical order
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub tags: Vec<String>,
+}
+
+/// The repositories of a registry, as listed by its catalog
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct Catalog {
+    /// The names of the repositories, in lexical order
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub repositories: Vec<String>,
+}
+
+/// What kind of artifact an image holds, for picking a runtime
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageKind {
+    /// A WebAssembly module
+    Wasm,
+    /// A container image made of filesystem tar layers
+    Container,
+    /// The config and layer media types match neither kind
+    Unknown,
 }
 
 impl ImageData {
+    /// Classifies the image from its config and layer media types
+    ///
+    /// A WASM config media type, or layers that are all WASM modules, make
+    /// a WASM image. An image or Docker config media type, or layers that are
+    /// all tar archives, make a container image.
+    pub fn kind(&self) -> ImageKind {
+        let config_type = self
+            .config_descriptor
+            .as_ref()
+            .map(|d| d.media_type.as_str());
+        let config_is = |types: &[&str]| {
+            config_type.map_or(false, |c| types.iter().any(|t| media_type_matches(c, t)))
+        };
+        let layers_are = |types: &[&str]| {
+            !self.layers.is_empty()
+                && self
+                    .layers
+                    .iter()
+                    .all(|l| types.iter().any(|t| media_type_matches(&l.media_type, t)))
+        };
+
+        if config_is(&[WASM_CONFIG_MEDIA_TYPE]) || layers_are(&[WASM_LAYER_MEDIA_TYPE]) {
+            ImageKind::Wasm
+        } else if config_is(&[IMAGE_CONFIG_MEDIA_TYPE, IMAGE_DOCKER_CONFIG_MEDIA_TYPE])
+            || layers_are(IMAGE_LAYER_MEDIA_TYPES)
+        {
+            ImageKind::Container
+        } else {
+            ImageKind::Unknown
+        }
+    }
+
     /// Helper function to compute the digest of the image layers
     pub fn sha256_digest(&self) -> String {
-        sha256_digest(
-            &self
-                .layers
-                .iter()
-                .cloned()
-                .map(|l| l.data)
-                .flatten()
-                .collect::<Vec<u8>>(),
-        )
+        let mut hasher = sha2::Sha256::new();
+        for layer in &self.layers {
+            hasher.update(&layer.data);
+        }
+        format!("sha256:{:x}", hasher.finalize())
     }
 
     /// Returns the image digest, either the value in the field or by computing it
@@ -56,16 +157,39 @@ impl ImageData {
 /// The data and media type for an image layer
 #[derive(Clone)]
 pub struct ImageLayer {
-    /// The data of this layer
-    pub data: Vec<u8>,
+    data: Vec<u8>,
     /// The media type of this layer
     pub media_type: String,
+    /// Where the layer data came from when it was pulled.
+    ///
+    /// This is `None` for layers that were not pulled, and is never part of
+    /// the layer's digest.
+    pub source: Option<LayerSource>,
+    digest: OnceCell<String>,
+}
+
+/// Where the data of a pulled layer came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayerSource {
+    /// Read from local storage, such as an on-disk image layout, without
+    /// contacting a registry
+    Cache,
+    /// Downloaded from the registry
+    Network,
+    /// Made available in the repository by a cross-repository blob mount,
+    /// without transferring the data
+    Mounted,
 }
 
 impl ImageLayer {
     /// Constructs a new ImageLayer struct with provided data and media type
     pub fn new(data: Vec<u8>, media_type: String) -> Self {
-        ImageLayer { data, media_type }
+        ImageLayer {
+            data,
+            media_type,
+            source: None,
+            digest: OnceCell::new(),
+        }
     }
 
     /// Constructs a new ImageLayer struct with provided data and
@@ -78,10 +202,82 @@ impl ImageLayer {
     pub fn oci_v1_gzip(data: Vec<u8>) -> Self {
         Self::new(data, IMAGE_LAYER_GZIP_MEDIA_TYPE.to_string())
     }
+    /// Constructs a new ImageLayer struct with provided data and
+    /// media type application/vnd.wasm.content.layer.v1+wasm
+    pub fn wasm(data: Vec<u8>) -> Self {
+        Self::new(data, WASM_LAYER_MEDIA_TYPE.to_string())
+    }
+
+    /// Constructs a new ImageLayer struct with provided data and media type,
+    /// checking that the media type is well formed
+    ///
+    /// The media type must be a `type/subtype` pair as described in RFC 6838,
+    /// optionally followed by parameters. Use `new` to skip this check.
+    pub fn with_media_type(data: Vec<u8>, media_type: &str) -> anyhow::Result<Self> {
+        if !is_valid_media_type(media_type) {
+            return Err(anyhow::anyhow!("invalid media type: {:?}", media_type));
+        }
+        Ok(Self::new(data, media_type.to_owned()))
+    }
+
+    /// Returns the data of this layer
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Replaces the data of this layer
+    pub fn set_data(&mut self, data: Vec<u8>) {
+        self.data = data;
+        self.digest = OnceCell::new();
+    }
+
+    /// Consumes the layer, returning its data
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
 
     /// Helper function to compute the sha256 digest of an image layer
     pub fn sha256_digest(self) -> String {
-        sha256_digest(&self.data)
+        self.digest().to_owned()
+    }
+
+    /// Returns the sha256 digest of the layer data
+    ///
+    /// The digest is computed on the first call and cached until the data is
+    /// replaced with `set_data`.
+    pub fn digest(&self) -> &str {
+        self.digest.get_or_init(|| sha256_digest(&self.data))
+    }
+
+    /// Returns the layer data with its compression undone
+    ///
+    /// The compression is taken from the media type: the layer types that
+    /// the default `Decompressors` know, and any other type ending in
+    /// `+gzip` or `+zstd`, are decompressed. The data of other media types
+    /// is returned as it is.
+    pub fn decompressed(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut self.decoder()?, &mut data)
+            .with_context(|| format!("Failed to decompress layer of type {}", self.media_type))?;
+        Ok(data)
+    }
+
+    /// Returns a reader of the layer data with its compression undone, as
+    /// `decompressed` decides it
+    pub(crate) fn decoder(&self) -> anyhow::Result<Box<dyn std::io::Read + '_>> {
+        let data: Box<dyn std::io::Read + '_> = Box::new(self.data.as_slice());
+        let decompressors = Decompressors::default();
+        if decompressors.handles(&self.media_type) {
+            return decompressors.decoder(&self.media_type, data);
+        }
+        let essence = media_type_essence(&self.media_type);
+        if essence.ends_with("+gzip") {
+            Gzip.decoder(data)
+        } else if essence.ends_with("+zstd") {
+            Zstd.decoder(data)
+        } else {
+            Ok(data)
+        }
     }
 }
 
@@ -99,11 +295,25 @@ impl ImageLayer {
 ///
 /// For true anonymous access, you can skip `auth()`. This is not recommended
 /// unless you are sure that the remote registry does not require Oauth2.
-#[derive(Default)]
+///
+/// Cloning a client is cheap. Clones share their cached tokens, connection
+/// pool, request limit and circuit breakers, so one client can be cloned into
+/// many tasks that pull concurrently.
+#[derive(Clone)]
 pub struct Client {
     config: ClientConfig,
-    tokens: HashMap<String, RegistryToken>,
+    tokens: Arc<Mutex<HashMap<String, CachedToken>>>,
     client: reqwest::Client,
+    transport: Arc<dyn Transport>,
+    request_limiter: Option<Arc<Semaphore>>,
+    circuit_breakers: Option<Arc<CircuitBreakers>>,
+    download_limiter: Option<Arc<BandwidthLimiter>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new(ClientConfig::default())
+    }
 }
 
 /// A source that can provide a `ClientConfig`.
@@ -116,901 +326,9005 @@ pub trait ClientConfigSource {
 
 impl Client {
     /// Create a new client with the supplied config
+    ///
+    /// Panics if the TLS settings are invalid, for example if a certificate
+    /// cannot be parsed. Use `try_new` to handle that as an error.
     pub fn new(config: ClientConfig) -> Self {
+        Self::try_new(config).expect("failed to build HTTP client")
+    }
+
+    /// Create a new client with the supplied config, failing if the HTTP
+    /// client cannot be built with its TLS settings
+    pub fn try_new(config: ClientConfig) -> anyhow::Result<Self> {
+        let mut builder = http_client_builder().redirect(config.redirect_policy.to_reqwest());
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = config.reqwest_proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        let client = config
+            .tls
+            .apply(builder)?
+            .build()
+            .context("Failed to build HTTP client")?;
+        let transport = Arc::new(ReqwestTransport::new(client.clone()));
+        Ok(Self::with_parts(config, client, transport))
+    }
+
+    /// Create a new client with the supplied config that sends its requests
+    /// with the given `reqwest::Client`
+    ///
+    /// This lets several clients share one connection pool, or use a client
+    /// set up with its own DNS resolver, proxy or TLS settings. Settings that
+    /// the `reqwest::Client` fixes when it is built, such as the redirect
+    /// policy and the connect timeout, are taken from it rather than from the
+    /// config.
+    ///
+    /// ```rust
+    /// use oci_distribution::client::{Client, ClientConfig};
+    ///
+    /// let http = reqwest::Client::new();
+    /// let first = Client::new_with_client(ClientConfig::default(), http.clone());
+    /// let second = Client::new_with_client(ClientConfig::default(), http);
+    /// ```
+    pub fn new_with_client(config: ClientConfig, client: reqwest::Client) -> Self {
+        let transport = Arc::new(ReqwestTransport::new(client.clone()));
+        Self::with_parts(config, client, transport)
+    }
+
+    /// Create a new client with the supplied config that sends its requests
+    /// over the given transport instead of the network
+    ///
+    /// Settings that only apply to the network transport, such as the
+    /// redirect policy and the TLS settings, are left to the transport. Like
+    /// `reqwest::Client::new`, this only panics if the TLS backend cannot be
+    /// initialized at all.
+    pub fn with_transport(config: ClientConfig, transport: impl Transport + 'static) -> Self {
+        let client = http_client_builder()
+            .build()
+            .expect("failed to build HTTP client");
+        Self::with_parts(config, client, Arc::new(transport))
+    }
+
+    fn with_parts(
+        config: ClientConfig,
+        client: reqwest::Client,
+        transport: Arc<dyn Transport>,
+    ) -> Self {
+        let request_limiter = config
+            .max_concurrent_requests
+            .map(|n| Arc::new(Semaphore::new(n)));
+        let circuit_breakers = config
+            .circuit_breaker
+            .clone()
+            .map(|c| Arc::new(CircuitBreakers::new(c)));
+        let download_limiter = config
+            .max_download_bytes_per_sec
+            .map(|rate| Arc::new(BandwidthLimiter::new(rate)));
         Self {
             config,
-            tokens: HashMap::new(),
-            client: reqwest::Client::new(),
+            tokens: Arc::new(Mutex::new(HashMap::new())),
+            client,
+            transport,
+            request_limiter,
+            circuit_breakers,
+            download_limiter,
         }
     }
 
     /// Create a new client with the supplied config
+    ///
+    /// Panics if the TLS settings are invalid, as `new` does. Use
+    /// `try_from_source` to handle that as an error.
     pub fn from_source(config_source: &impl ClientConfigSource) -> Self {
         Self::new(config_source.client_config())
     }
 
+    /// Create a new client with the supplied config, failing if the HTTP
+    /// client cannot be built with its TLS settings
+    pub fn try_from_source(config_source: &impl ClientConfigSource) -> anyhow::Result<Self> {
+        Self::try_new(config_source.client_config())
+    }
+
+    /// Drop the cached token for a registry, so that the next request to it
+    /// authenticates again
+    pub fn clear_token(&self, registry: &str) {
+        self.tokens.lock().unwrap().remove(registry);
+    }
+
+    /// Drop the cached tokens for all registries
+    pub fn clear_all_tokens(&self) {
+        self.tokens.lock().unwrap().clear();
+    }
+
     /// Pull an image and return the bytes
     ///
     /// The client will check if it's already been authenticated and if
     /// not will attempt to do.
     pub async fn pull(
-        &mut self,
+        &self,
         image: &Reference,
         auth: &RegistryAuth,
         accepted_media_types: Vec<&str>,
     ) -> anyhow::Result<ImageData> {
-        debug!("Pulling image: {:?}", image);
-
-        if !self.tokens.contains_key(image.registry()) {
-            self.auth(image, auth, &RegistryOperation::Pull).await?;
-        }
-
-        let (manifest, digest) = self.pull_manifest(image).await?;
-
-        self.validate_layers(&manifest, accepted_media_types)
-            .await?;
-
-        let layers = manifest.layers.into_iter().map(|layer| {
-            // This avoids moving `self` which is &mut Self
-            // into the async block. We only want to capture
-            // as &Self
-            let this = &self;
-            async move {
-                let mut out: Vec<u8> = Vec::new();
-                debug!("Pulling image layer");
-                this.pull_layer(image, &layer.digest, &mut out).await?;
-                Ok::<_, anyhow::Error>(ImageLayer::new(out, layer.media_type))
-            }
-        });
-
-        let layers = future::try_join_all(layers).await?;
+        self.pull_inner(image, auth, accepted_media_types, None, None, None)
+            .await
+    }
 
-        Ok(ImageData {
-            layers,
-            digest: Some(digest),
-        })
+    /// Pull an image and return the bytes, stopping early if `cancel` is
+    /// cancelled
+    ///
+    /// The token is checked before each request and between each chunk of
+    /// layer data, and cancelling it interrupts a wait for the next chunk, so
+    /// in-flight blob writes are abandoned promptly even when the registry
+    /// has stalled. A cancelled pull returns `ClientError::Cancelled`.
+    pub async fn pull_with_cancellation(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<ImageData> {
+        self.pull_inner(image, auth, accepted_media_types, Some(cancel), None, None)
+            .await
     }
 
-    /// Push an image and return the uploaded URL of the image
+    /// Pull an image and return the bytes, overriding the client's
+    /// `verify_digests` setting for this pull
     ///
-    /// The client will check if it's already been authenticated and if
-    /// not will attempt to do.
+    /// With `verify` set, each layer is hashed as it is downloaded and the
+    /// pull fails with `ClientError::DigestMismatch` if it does not match its
+    /// digest in the manifest. Turning it off saves the hashing for images
+    /// from trusted registries.
+    pub async fn pull_with_verification(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        verify: bool,
+    ) -> anyhow::Result<ImageData> {
+        self.pull_inner(image, auth, accepted_media_types, None, Some(verify), None)
+            .await
+    }
+
+    /// Pull the image for a platform
     ///
-    /// If a manifest is not provided, the client will attempt to generate
-    /// it from the provided image and config data.
+    /// When the reference points at an image index or a Docker manifest
+    /// list, the image is the entry that best matches `platform`, as chosen
+    /// by `OciImageIndex::manifest_for_platform`. Other pulls resolve
+    /// indexes with `ClientConfig::platform`, which defaults to the platform
+    /// the client runs on.
     ///
-    /// Returns pullable URL for the image
-    pub async fn push(
-        &mut self,
-        image_ref: &Reference,
-        image_data: &ImageData,
-        config_data: &[u8],
-        config_media_type: &str,
+    /// As for any pull, the digest of the returned image data is that of
+    /// the manifest the reference points at, which is the index itself for
+    /// a multi-platform image.
+    pub async fn pull_for_platform(
+        &self,
+        image: &Reference,
         auth: &RegistryAuth,
-        image_manifest: Option<OciManifest>,
-    ) -> anyhow::Result<String> {
-        debug!("Pushing image: {:?}", image_ref);
-
-        if !self.tokens.contains_key(image_ref.registry()) {
-            self.auth(image_ref, auth, &RegistryOperation::Push).await?;
-        }
-
-        // Start push session
-        let mut location = self.begin_push_session(image_ref).await?;
-
-        // Upload layers
-        let mut start_byte = 0;
-        for layer in &image_data.layers {
-            // Destructuring assignment is not yet supported
-            let (next_location, next_byte) = self
-                .push_layer(&location, &image_ref, layer.data.to_vec(), start_byte)
-                .await?;
-            location = next_location;
-            start_byte = next_byte;
-        }
-
-        // End push session, upload manifest
-        let image_url = self
-            .end_push_session(&location, &image_ref, &image_data.digest())
-            .await?;
+        accepted_media_types: Vec<&str>,
+        platform: &Platform,
+    ) -> anyhow::Result<ImageData> {
+        self.pull_inner(
+            image,
+            auth,
+            accepted_media_types,
+            None,
+            None,
+            Some(platform),
+        )
+        .await
+    }
 
-        // Push config and manifest to registry
-        let manifest: OciManifest = match image_manifest {
-            Some(m) => m,
-            None => self.generate_manifest(&image_data, &config_data, config_media_type),
+    /// Pull an image along with its config blob
+    ///
+    /// The image is pulled as by `pull`, then the blob that the manifest's
+    /// config descriptor points at is downloaded and checked against its
+    /// digest. The config of a Wasm module carries metadata that its layers
+    /// do not.
+    ///
+    /// Returns the image data and the bytes of the config blob.
+    pub async fn pull_manifest_and_config(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+    ) -> anyhow::Result<(ImageData, Vec<u8>)> {
+        let image_data = self.pull(image, auth, accepted_media_types).await?;
+        let config_digest = match &image_data.config_descriptor {
+            Some(config) => config.digest.clone(),
+            None => return Err(anyhow::anyhow!("pulled manifest has no config")),
         };
-        self.push_config(image_ref, &config_data, &manifest.config.digest)
-            .await?;
-        self.push_manifest(&image_ref, &manifest).await?;
-
-        Ok(image_url)
+        let mut config = Vec::new();
+        self.pull_layer(image, auth, &config_digest, &mut config)
+            .await
+            .with_context(|| format!("Failed to pull config {} of '{:?}'", config_digest, image))?;
+        Ok((image_data, config))
     }
 
-    /// Perform an OAuth v2 auth request if necessary.
+    /// Pull a single blob of an image, such as one of its layers, writing it
+    /// to `out`
     ///
-    /// This performs authorization and then stores the token internally to be used
-    /// on other requests.
-    async fn auth(
-        &mut self,
+    /// The data is checked against `digest` as it is written unless
+    /// `ClientConfig::verify_digests` is turned off. A mismatch, reported as
+    /// `ClientError::DigestMismatch`, is only found once everything has been
+    /// written, so callers must discard what was written if this fails.
+    ///
+    /// If the registry is unavailable before any data has been written, the
+    /// blob is pulled from the registry's mirrors instead, as for `pull`.
+    pub async fn pull_layer<T: AsyncWrite + Unpin>(
+        &self,
         image: &Reference,
-        authentication: &RegistryAuth,
-        operation: &RegistryOperation,
+        auth: &RegistryAuth,
+        digest: &str,
+        out: T,
     ) -> anyhow::Result<()> {
-        debug!("Authorizing for image: {:?}", image);
-        // The version request will tell us where to go.
-        let url = format!(
-            "{}://{}/v2/",
-            self.config.protocol.scheme_for(image.registry()),
-            image.registry()
-        );
-        let res = self.client.get(&url).send().await?;
-        let dist_hdr = match res.headers().get(reqwest::header::WWW_AUTHENTICATE) {
-            Some(h) => h,
-            None => return Ok(()),
-        };
-
-        let auth = WwwAuthenticate::parse_header(&dist_hdr.as_bytes().into())?;
-        // If challenge_opt is not set it means that no challenge was present, even though the header
-        // was present. Since we do not handle basic auth, it could be the case that the upstream service
-        // is in compatibility mode with a Docker v1 registry.
-        let challenge_opt = match auth.get::<BearerChallenge>() {
-            Some(co) => co,
-            None => return Ok(()),
-        };
+        self.pull_layer_with_verification(image, auth, digest, out, None)
+            .await
+    }
 
-        // Allow for either push or pull authentication
-        let scope = match operation {
-            RegistryOperation::Pull => format!("repository:{}:pull", image.repository()),
-            RegistryOperation::Push => format!("repository:{}:pull,push", image.repository()),
+    /// Pull a single blob like `pull_layer`, deciding for this call whether
+    /// the data is checked against `digest`
+    ///
+    /// `Some(true)` and `Some(false)` turn verification on and off whatever
+    /// `ClientConfig::verify_digests` says, and `None` leaves it to the client
+    /// setting.
+    pub async fn pull_layer_with_verification<T: AsyncWrite + Unpin>(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        out: T,
+        verify: Option<bool>,
+    ) -> anyhow::Result<()> {
+        let mut out = CountingWriter {
+            inner: out,
+            written: 0,
         };
-
-        let challenge = &challenge_opt[0];
-        let realm = challenge.realm.as_ref().unwrap();
-        let service = challenge.service.as_ref().unwrap();
-
-        // TODO: At some point in the future, we should support sending a secret to the
-        // server for auth. This particular workflow is for read-only public auth.
-        debug!("Making authentication call to {}", realm);
-        let auth_res = self
-            .client
-            .get(realm)
-            .query(&[("service", service), ("scope", &scope)])
-            .apply_authentication(authentication)
-            .send()
-            .await?;
-
-        match auth_res.status() {
-            reqwest::StatusCode::OK => {
-                let text = auth_res.text().await?;
-                debug!("Received response from auth request: {}", text);
-                let token: RegistryToken = serde_json::from_str(&text)
-                    .context("Failed to decode registry token from auth request")?;
-                debug!("Succesfully authorized for image '{:?}'", image);
-                self.tokens.insert(image.registry().to_owned(), token);
-                Ok(())
-            }
-            _ => {
-                let reason = auth_res.text().await?;
-                debug!("Failed to authenticate for image '{:?}': {}", image, reason);
-                Err(anyhow::anyhow!("failed to authenticate: {}", reason))
+        let mut candidates = self.mirror_candidates(image).into_iter().peekable();
+        loop {
+            let candidate = candidates.next().expect("the image is always a candidate");
+            let result = self
+                .pull_layer_from(
+                    &candidate,
+                    mirror_auth(image, &candidate, auth),
+                    digest,
+                    &mut out,
+                    verify,
+                )
+                .await;
+            match (result, candidates.peek()) {
+                (Err(e), Some(mirror)) if out.written == 0 && is_unavailable(&e) => {
+                    warn!(
+                        "Failed to pull blob {} of {:?} ({:#}), trying mirror {}",
+                        digest,
+                        candidate,
+                        e,
+                        mirror.registry()
+                    );
+                }
+                (result, _) => return result,
             }
         }
     }
 
-    /// Fetch a manifest's digest from the remote OCI Distribution service.
-    ///
-    /// If the connection has already gone through authentication, this will
-    /// use the bearer token. Otherwise, this will attempt an anonymous pull.
-    pub async fn fetch_manifest_digest(
-        &mut self,
+    async fn pull_layer_from<T: AsyncWrite + Unpin>(
+        &self,
         image: &Reference,
         auth: &RegistryAuth,
-    ) -> anyhow::Result<String> {
-        if !self.tokens.contains_key(image.registry()) {
+        digest: &str,
+        out: T,
+        verify: Option<bool>,
+    ) -> anyhow::Result<()> {
+        if !self.has_token_for(image.registry(), auth) {
             self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
+        self.download_layer(image, digest, out, None, None, verify)
+            .await
+    }
 
-        let url = self.to_v2_manifest_url(image);
-        debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
-
-        let res = request.headers(self.auth_headers(image)).send().await?;
-
-        // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
-        // Obviously, HTTP servers are going to send other codes. This tries to catch the
-        // obvious ones (200, 4XX, 5XX). Anything else is just treated as an error.
-        match res.status() {
-            reqwest::StatusCode::OK => digest_header_value(&res),
-            s if s.is_client_error() => {
-                // According to the OCI spec, we should see an error in the message body.
-                let err = res.json::<OciEnvelope>().await?;
-                // FIXME: This should not have to wrap the error.
-                Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
+    /// Pull a single layer into the file at `path`
+    ///
+    /// Without `resume`, the file is created or overwritten and the whole
+    /// blob is downloaded. With `resume`, whatever the file already holds is
+    /// taken to be the start of the blob, and only the rest is requested with
+    /// a `Range` header. A download that is interrupted partway through is
+    /// then resumed the same way, up to `MAX_RESUME_ATTEMPTS` times. If the
+    /// registry ignores the range, or cannot satisfy it, the file is
+    /// truncated and the whole blob is downloaded instead.
+    ///
+    /// The file is checked against the digest once it is complete, if the
+    /// client verifies digests. On a mismatch the file is left as it is, so
+    /// callers should remove it before resuming into it again.
+    ///
+    /// If the registry is unavailable, the blob is pulled from the
+    /// registry's mirrors instead, as for `pull`.
+    pub async fn pull_layer_to_file(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        digest: &str,
+        path: &Path,
+        resume: bool,
+    ) -> anyhow::Result<()> {
+        let digest: Digest = digest.parse()?;
+        let what = format!("pull blob {} of", digest);
+        let digest = &digest;
+        self.with_mirrors(image, &what, move |candidate| async move {
+            let auth = mirror_auth(image, &candidate, auth);
+            self.pull_layer_to_file_from(&candidate, auth, digest, path, resume)
+                .await
+        })
+        .await?;
+
+        if self.config.verify_digests {
+            let actual = hash_file(path, digest.algorithm()).await?;
+            if actual != *digest {
+                return Err(ClientError::DigestMismatch {
+                    expected: digest.to_string(),
+                    actual: actual.to_string(),
+                }
+                .into());
             }
-            s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
-            s => Err(anyhow::anyhow!(
-                "An unexpected error occured: code={}, message='{}'",
-                s,
-                res.text().await?
-            )),
         }
+        Ok(())
     }
 
-    async fn validate_layers(
+    async fn pull_layer_to_file_from(
         &self,
-        manifest: &OciManifest,
-        accepted_media_types: Vec<&str>,
+        image: &Reference,
+        auth: &RegistryAuth,
+        digest: &Digest,
+        path: &Path,
+        resume: bool,
     ) -> anyhow::Result<()> {
-        if manifest.layers.is_empty() {
-            return Err(anyhow::anyhow!("no layers to pull"));
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
-
-        for layer in &manifest.layers {
-            if !accepted_media_types.iter().any(|i| i.eq(&layer.media_type)) {
-                return Err(anyhow::anyhow!(
-                    "incompatible layer media type: {}",
-                    layer.media_type
-                ));
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(!resume)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open {:?}", path))?;
+
+        let mut attempts = 0;
+        loop {
+            let result = self.download_layer_to_file(image, digest, &mut file).await;
+            match result {
+                Err(e) if resume && attempts < MAX_RESUME_ATTEMPTS && is_interrupted(&e) => {
+                    attempts += 1;
+                    warn!(
+                        "Download of blob {} was interrupted ({:#}), resuming (attempt {})",
+                        digest, e, attempts
+                    );
+                }
+                result => return result,
             }
         }
+    }
 
+    /// Downloads the part of a blob that `file` does not yet hold onto its
+    /// end, starting over if the registry sends the whole blob instead
+    async fn download_layer_to_file(
+        &self,
+        image: &Reference,
+        digest: &Digest,
+        file: &mut tokio::fs::File,
+    ) -> anyhow::Result<()> {
+        let url = self.to_v2_blob_url(image.registry(), image.repository(), digest);
+        let _permit = self.request_permit().await;
+        let offset = file.metadata().await?.len();
+        let request = self.client.get(&url).headers(self.auth_headers(image));
+        let request = if offset > 0 {
+            debug!("Resuming download of blob {} at byte {}", digest, offset);
+            request.header(reqwest::header::RANGE, format!("bytes={}-", offset))
+        } else {
+            request
+        };
+        let mut res = self
+            .send(image.registry(), request)
+            .await
+            .with_context(|| format!("Failed to download blob {} from {}", digest, url))?;
+        if res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            debug!(
+                "Registry cannot resume blob {} at byte {}, downloading all of it",
+                digest, offset
+            );
+            res = self
+                .send(
+                    image.registry(),
+                    self.client.get(&url).headers(self.auth_headers(image)),
+                )
+                .await
+                .with_context(|| format!("Failed to download blob {} from {}", digest, url))?;
+        }
+        let res = successful_blob_response(res, &url).await?;
+        let start = if res.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            file.seek(std::io::SeekFrom::Start(offset)).await?;
+            offset
+        } else {
+            // The registry sent the whole blob
+            file.set_len(0).await?;
+            file.seek(std::io::SeekFrom::Start(0)).await?;
+            0
+        };
+        let digest_text = digest.to_string();
+        let mut progress = self.config.progress.as_ref().map(|p| {
+            p.track(
+                &digest_text,
+                start,
+                res.content_length().map(|len| start + len),
+            )
+        });
+        let stream = res.bytes_stream().inspect(|chunk| {
+            if let (Some(progress), Ok(chunk)) = (progress.as_mut(), chunk) {
+                progress.advance(chunk.len());
+            }
+        });
+        write_stream(
+            stream,
+            file,
+            None,
+            None,
+            self.download_limiter.as_deref(),
+            self.config.read_timeout,
+        )
+        .await
+        .with_context(|| format!("Failed to download blob {} from {}", digest, url))?;
         Ok(())
     }
 
-    /// Pull a manifest from the remote OCI Distribution service.
+    /// Estimate how many bytes pulling an image will download
     ///
-    /// If the connection has already gone through authentication, this will
-    /// use the bearer token. Otherwise, this will attempt an anonymous pull.
-    async fn pull_manifest(&self, image: &Reference) -> anyhow::Result<(OciManifest, String)> {
-        let url = self.to_v2_manifest_url(image);
-        debug!("Pulling image manifest from {}", url);
-        let request = self.client.get(&url);
-
-        let res = request.headers(self.auth_headers(image)).send().await?;
-
-        // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
-        // Obviously, HTTP servers are going to send other codes. This tries to catch the
-        // obvious ones (200, 4XX, 5XX). Anything else is just treated as an error.
-        match res.status() {
-            reqwest::StatusCode::OK => {
-                let digest = digest_header_value(&res)?;
-                let text = res.text().await?;
-
-                self.validate_image_manifest(&text).await?;
+    /// This is the sum of the sizes recorded in the image manifest for the
+    /// config and every layer, so only the manifest is fetched. For
+    /// multi-platform images the manifest for the current platform is used.
+    /// Layers are counted as stored in the registry, usually compressed;
+    /// manifests do not record unpacked sizes, so those are not estimated.
+    pub async fn estimated_size(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<u64> {
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
 
-                debug!("Parsing response as OciManifest: {}", text);
-                let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
-                    format!(
-                        "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
-                        image
+        let (manifest, _) = self.resolve_manifest(image).await?;
+        std::iter::once(&manifest.config)
+            .chain(&manifest.layers)
+            .try_fold(0u64, |total, descriptor| {
+                let size = u64::try_from(descriptor.size).map_err(|_| {
+                    anyhow::anyhow!(
+                        "manifest records a negative size {} for {}",
+                        descriptor.size,
+                        descriptor.digest
                     )
                 })?;
-                Ok((manifest, digest))
+                total.checked_add(size).ok_or_else(|| {
+                    anyhow::anyhow!("manifest sizes add up to more than {}", u64::MAX)
+                })
+            })
+    }
+
+    /// Pulls an image, failing with `ClientError::TimedOut` if the pull is
+    /// still running when `ClientConfig::pull_timeout` runs out
+    async fn pull_inner(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        cancel: Option<&CancellationToken>,
+        verify: Option<bool>,
+        platform: Option<&Platform>,
+    ) -> anyhow::Result<ImageData> {
+        let pull =
+            self.pull_with_mirrors(image, auth, accepted_media_types, cancel, verify, platform);
+        match self.config.pull_timeout {
+            Some(deadline) => tokio::time::timeout(deadline, pull)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(anyhow::Error::new(ClientError::TimedOut(deadline))
+                        .context(format!("Failed to pull {} in time", image.whole())))
+                }),
+            None => pull.await,
+        }
+    }
+
+    /// Pulls an image from its registry, falling back to the registry's
+    /// mirrors in turn while the pull fails because a registry is unavailable
+    async fn pull_with_mirrors(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        cancel: Option<&CancellationToken>,
+        verify: Option<bool>,
+        platform: Option<&Platform>,
+    ) -> anyhow::Result<ImageData> {
+        self.with_mirrors(image, "pull", move |candidate| {
+            let accepted_media_types = accepted_media_types.clone();
+            async move {
+                self.pull_from(
+                    &candidate,
+                    mirror_auth(image, &candidate, auth),
+                    accepted_media_types,
+                    cancel,
+                    verify,
+                    platform,
+                )
+                .await
             }
-            s if s.is_client_error() => {
-                // According to the OCI spec, we should see an error in the message body.
-                let err = res.json::<OciEnvelope>().await?;
-                // FIXME: This should not have to wrap the error.
-                Err(anyhow::anyhow!("{} on {}", err.errors[0], url))
+        })
+        .await
+    }
+
+    /// Runs `attempt` against an image's registry, then against each of the
+    /// registry's mirrors in turn while it fails because a registry is
+    /// unavailable
+    ///
+    /// `what` describes the attempt for the log, as in "Failed to `what`
+    /// image".
+    async fn with_mirrors<T, F, Fut>(
+        &self,
+        image: &Reference,
+        what: &str,
+        mut attempt: F,
+    ) -> anyhow::Result<T>
+    where
+        F: FnMut(Reference) -> Fut,
+        Fut: std::future::Future<Output = anyhow::Result<T>>,
+    {
+        let mut candidates = self.mirror_candidates(image).into_iter().peekable();
+        loop {
+            let candidate = candidates.next().expect("the image is always a candidate");
+            let result = attempt(candidate.clone()).await;
+            match (result, candidates.peek()) {
+                (Err(e), Some(mirror)) if is_unavailable(&e) => {
+                    warn!(
+                        "Failed to {} {:?} ({:#}), trying mirror {}",
+                        what,
+                        candidate,
+                        e,
+                        mirror.registry()
+                    );
+                }
+                (result, _) => return result,
             }
-            s if s.is_server_error() => Err(anyhow::anyhow!("Server error at {}", url)),
-            s => Err(anyhow::anyhow!(
-                "An unexpected error occured: code={}, message='{}'",
-                s,
-                res.text().await?
-            )),
         }
     }
 
-    async fn validate_image_manifest(&self, text: &str) -> anyhow::Result<()> {
-        debug!("validating manifest: {}", text);
-        let versioned: Versioned = serde_json::from_str(&text)
-            .with_context(|| "Failed to parse manifest as a Versioned object")?;
-        if versioned.schema_version != 2 {
-            return Err(anyhow::anyhow!(
-                "unsupported schema version: {}",
-                versioned.schema_version
-            ));
+    /// The references to pull an image from: the image itself, then the
+    /// same repository on each of the mirrors configured for its registry
+    ///
+    /// References by tag are only mirrored if `ClientConfig::mirror_tags` is
+    /// set, since mirrors may not agree on what a tag points at. Mirrors are
+    /// accessed anonymously, as `mirror_auth` decides.
+    fn mirror_candidates(&self, image: &Reference) -> Vec<Reference> {
+        let mut candidates = vec![image.clone()];
+        if image.digest().is_none() && !self.config.mirror_tags {
+            return candidates;
         }
-        if let Some(media_type) = versioned.media_type {
-            // TODO: support manifest lists?
-            if media_type != IMAGE_MANIFEST_MEDIA_TYPE {
-                return Err(anyhow::anyhow!("unsupported media type: {}", media_type));
-            }
+        if let Some(mirrors) = self.config.mirrors.get(image.registry()) {
+            candidates.extend(mirrors.iter().map(|m| image.with_registry(m)));
         }
-
-        Ok(())
+        candidates
     }
 
-    /// Pull a single layer from an OCI registy.
-    ///
-    /// This pulls the layer for a particular image that is identified by
-    /// the given digest. The image reference is used to find the
-    /// repository and the registry, but it is not used to verify that
-    /// the digest is a layer inside of the image. (The manifest is
-    /// used for that.)
-    async fn pull_layer<T: AsyncWrite + Unpin>(
+    async fn pull_from(
         &self,
         image: &Reference,
-        digest: &str,
-        mut out: T,
-    ) -> anyhow::Result<()> {
-        let url = self.to_v2_blob_url(image.registry(), image.repository(), digest);
-        let mut stream = self
-            .client
-            .get(&url)
-            .headers(self.auth_headers(image))
-            .send()
-            .await?
-            .bytes_stream();
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        cancel: Option<&CancellationToken>,
+        verify: Option<bool>,
+        platform: Option<&Platform>,
+    ) -> anyhow::Result<ImageData> {
+        debug!("Pulling image: {:?}", image);
 
-        while let Some(bytes) = stream.next().await {
-            out.write_all(&bytes?).await?;
+        check_cancelled(cancel)?;
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
 
-        Ok(())
-    }
+        check_cancelled(cancel)?;
+        let (manifest, digest) = match platform {
+            Some(platform) => self.pull_platform_manifest(image, platform).await?,
+            None => self.resolve_manifest(image).await?,
+        };
 
-    /// Begins a session to push an image to registry
-    ///
-    /// Returns URL with session UUID
-    async fn begin_push_session(&self, image: &Reference) -> anyhow::Result<String> {
-        let url = &self.to_v2_blob_upload_url(image);
-        let mut headers = self.auth_headers(image);
-        headers.insert("Content-Length", "0".parse().unwrap());
+        self.validate_layers(&manifest, accepted_media_types)
+            .await?;
 
-        let res = self.client.post(url).headers(headers).send().await?;
+        let config_descriptor = manifest.config;
+        let manifest_media_type = manifest.media_type;
+        let layers = manifest.layers.into_iter().map(|layer| {
+            // This avoids moving `self` which is &mut Self
+            // into the async block. We only want to capture
+            // as &Self
+            let this = &self;
+            async move {
+                let mut out: Vec<u8> = Vec::new();
+                debug!("Pulling image layer");
+                this.download_layer_of_size(
+                    image,
+                    &layer.digest,
+                    layer_size(&layer),
+                    &mut out,
+                    cancel,
+                    None,
+                    verify,
+                )
+                .await?;
+                let (data, media_type) = this.maybe_decompress(out, layer.media_type).await?;
+                Ok::<_, anyhow::Error>(ImageLayer {
+                    source: Some(LayerSource::Network),
+                    ..ImageLayer::new(data, media_type)
+                })
+            }
+        });
 
-        // OCI spec requires the status code be 202 Accepted to successfully begin the push process
-        self.extract_location_header(&image, res, &reqwest::StatusCode::ACCEPTED)
-            .await
+        let layers = download_all(layers, self.config.max_concurrent_downloads).await?;
+
+        Ok(ImageData {
+            layers,
+            digest: Some(digest),
+            config_descriptor: Some(config_descriptor),
+            manifest_media_type,
+        })
     }
 
-    /// Closes the push session
+    /// Pull an image, reusing the layers it shares with `previous` instead of
+    /// downloading them again
     ///
-    /// Returns the pullable URL for the image
-    async fn end_push_session(
+    /// Layers are matched by digest, so a layer is only reused if its data in
+    /// `previous` is exactly what the new manifest lists. Layers that were
+    /// decompressed when `previous` was pulled no longer match and are
+    /// downloaded again. Reused layers have the source `LayerSource::Cache`.
+    ///
+    /// As with `pull`, the image is rejected before anything is downloaded
+    /// if any of its layers is not of one of the `accepted_media_types`.
+    pub async fn pull_delta(
         &self,
-        location: &str,
         image: &Reference,
-        digest: &str,
-    ) -> anyhow::Result<String> {
-        let url = format!("{}&digest={}", location, digest);
-        let mut close_headers = self.auth_headers(image);
-        close_headers.insert("Content-Length", "0".parse().unwrap());
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        previous: &ImageData,
+    ) -> anyhow::Result<ImageData> {
+        debug!("Pulling changed layers of image: {:?}", image);
 
-        let res = self.client.put(&url).headers(close_headers).send().await?;
-        self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
-            .await
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        let (manifest, digest) = self.resolve_manifest(image).await?;
+        self.validate_layers(&manifest, accepted_media_types)
+            .await?;
+        let reused = reusable_layers(&manifest, previous);
+        debug!(
+            "Reusing {} of {} layers",
+            reused.iter().filter(|l| l.is_some()).count(),
+            reused.len()
+        );
+
+        let this = &self;
+        let layers = manifest
+            .layers
+            .into_iter()
+            .zip(reused)
+            .map(|(layer, reused)| async move {
+                if let Some(reused) = reused {
+                    return Ok(reused);
+                }
+                let mut out: Vec<u8> = Vec::new();
+                debug!("Pulling image layer {}", layer.digest);
+                this.download_layer_of_size(
+                    image,
+                    &layer.digest,
+                    layer_size(&layer),
+                    &mut out,
+                    None,
+                    None,
+                    None,
+                )
+                .await?;
+                let (data, media_type) = this.maybe_decompress(out, layer.media_type).await?;
+                Ok::<_, anyhow::Error>(ImageLayer {
+                    source: Some(LayerSource::Network),
+                    ..ImageLayer::new(data, media_type)
+                })
+            });
+        let layers = download_all(layers, self.config.max_concurrent_downloads).await?;
+
+        Ok(ImageData {
+            layers,
+            digest: Some(digest),
+            config_descriptor: Some(manifest.config),
+            manifest_media_type: manifest.media_type,
+        })
     }
 
-    /// Pushes a single layer (blob) of an image to registry
+    /// Decompresses pulled layer data if `decompress_layers` is set and a
+    /// decompressor is registered for its media type
     ///
-    /// Returns the URL location for the next layer
-    async fn push_layer(
+    /// Decompression runs on a blocking thread rather than holding up the
+    /// executor. Returns the data and media type to store in the layer.
+    async fn maybe_decompress(
         &self,
-        location: &str,
-        image: &Reference,
-        layer: Vec<u8>,
-        start_byte: usize,
-    ) -> anyhow::Result<(String, usize)> {
-        if layer.is_empty() {
-            return Err(anyhow::anyhow!("cannot push a layer without data"));
-        };
-        let end_byte = start_byte + layer.len() - 1;
-        let mut headers = self.auth_headers(image);
-        headers.insert(
-            "Content-Range",
-            format!("{}-{}", start_byte, end_byte).parse().unwrap(),
-        );
-        headers.insert(
-            "Content-Length",
-            format!("{}", layer.len()).parse().unwrap(),
-        );
-        headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
-
-        let res = self
-            .client
-            .patch(location)
-            .headers(headers)
-            .body(layer)
-            .send()
-            .await?;
+        data: Vec<u8>,
+        media_type: String,
+    ) -> anyhow::Result<(Vec<u8>, String)> {
+        if !self.decompresses(&media_type) {
+            return Ok((data, media_type));
+        }
+        let decompressors = self.config.decompressors.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<(Vec<u8>, String)> {
+            match decompressors
+                .decompress(&media_type, &data)
+                .with_context(|| format!("Failed to decompress layer of type {}", media_type))?
+            {
+                Some(decompressed) => Ok(decompressed),
+                None => Ok((data, media_type)),
+            }
+        })
+        .await
+        .context("Failed to run layer decompression")?
+    }
 
-        // Returns location for next chunk and the start byte for the next range
-        Ok((
-            self.extract_location_header(&image, res, &reqwest::StatusCode::ACCEPTED)
-                .await?,
-            end_byte + 1,
-        ))
+    /// Returns whether pulled layers of `media_type` are decompressed
+    fn decompresses(&self, media_type: &str) -> bool {
+        self.config.decompress_layers && self.config.decompressors.handles(media_type)
     }
 
-    /// Pushes the config as a blob to the registry
+    /// Decompresses the layer staged at `path` into `sink`
     ///
-    /// Returns the pullable location of the config
-    async fn push_config(
+    /// The decoder reads the staged file and writes the decompressed data
+    /// to a second staged file on a blocking thread, which is then copied
+    /// into `sink`, so neither the compressed nor the decompressed layer is
+    /// held in memory.
+    async fn decompress_staged<T: AsyncWrite + Unpin>(
         &self,
-        image: &Reference,
-        config_data: &[u8],
-        config_digest: &str,
-    ) -> anyhow::Result<String> {
-        let location = self.begin_push_session(image).await?;
-        let (end_location, _) = self
-            .push_layer(&location, &image, config_data.to_vec(), 0)
-            .await?;
-        self.end_push_session(&end_location, &image, config_digest)
+        media_type: &str,
+        path: &Path,
+        mut sink: T,
+    ) -> anyhow::Result<()> {
+        let decompressors = self.config.decompressors.clone();
+        let media_type = media_type.to_owned();
+        let compressed = path.to_owned();
+        let decompressed = path.with_extension("decompressed");
+        let out = decompressed.clone();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let file = std::fs::File::open(&compressed)
+                .with_context(|| format!("Failed to open {:?}", compressed))?;
+            let mut decoder =
+                decompressors.decoder(&media_type, Box::new(std::io::BufReader::new(file)))?;
+            let mut file = std::fs::File::create(&out)
+                .with_context(|| format!("Failed to create {:?}", out))?;
+            std::io::copy(&mut decoder, &mut file)
+                .with_context(|| format!("Failed to decompress layer of type {}", media_type))?;
+            Ok(())
+        })
+        .await
+        .context("Failed to run layer decompression")??;
+
+        let mut file = tokio::fs::File::open(&decompressed)
             .await
+            .with_context(|| format!("Failed to open {:?}", decompressed))?;
+        tokio::io::copy(&mut file, &mut sink)
+            .await
+            .context("Failed to write decompressed layer")?;
+        sink.flush()
+            .await
+            .context("Failed to write decompressed layer")?;
+        Ok(())
     }
 
-    /// Pushes the manifest for a specified image
+    /// Pull an image, streaming each layer into its own writer instead of
+    /// holding the layers in memory
     ///
-    /// Returns pullable manifest URL
-    async fn push_manifest(
+    /// `sink_for` is called once per layer, in manifest order, before any
+    /// layer is downloaded. Layers are downloaded concurrently. If
+    /// `max_buffered_bytes` is set in the config, downloaded data that has
+    /// not yet been written and flushed to its sink counts against that
+    /// budget across all layers, and downloads pause while it is used up.
+    ///
+    /// If `decompress_layers` is set, layers the configured decompressors
+    /// handle are staged under the system temp directory as they download,
+    /// and decompressed into their sinks once they are complete.
+    ///
+    /// Returns the image manifest and its digest.
+    pub async fn pull_to<F, T>(
         &self,
         image: &Reference,
-        manifest: &OciManifest,
-    ) -> anyhow::Result<String> {
-        let url = self.to_v2_manifest_url(image);
-
-        let mut headers = self.auth_headers(image);
-        headers.insert(
-            "Content-Type",
-            "application/vnd.oci.image.manifest.v1+json"
-                .parse()
-                .unwrap(),
-        );
+        auth: &RegistryAuth,
+        accepted_media_types: Vec<&str>,
+        mut sink_for: F,
+    ) -> anyhow::Result<(OciManifest, String)>
+    where
+        F: FnMut(&OciDescriptor) -> anyhow::Result<T>,
+        T: AsyncWrite + Unpin,
+    {
+        debug!("Pulling image to sinks: {:?}", image);
+
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
 
-        let res = self
-            .client
-            .put(&url)
-            .headers(headers)
-            .body(serde_json::to_string(manifest)?)
-            .send()
+        let (manifest, digest) = self.resolve_manifest(image).await?;
+        self.validate_layers(&manifest, accepted_media_types)
             .await?;
 
-        self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
-            .await
+        let sinks = manifest
+            .layers
+            .iter()
+            .map(&mut sink_for)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let budget = self.config.max_buffered_bytes.map(ByteBudget::new);
+        let staging = if manifest
+            .layers
+            .iter()
+            .any(|l| self.decompresses(&l.media_type))
+        {
+            Some(StagingDir::new()?)
+        } else {
+            None
+        };
+        let this = &self;
+        let layers = manifest
+            .layers
+            .iter()
+            .zip(sinks)
+            .enumerate()
+            .map(|(index, (layer, sink))| {
+                let budget = budget.as_ref();
+                let staging = staging.as_ref();
+                async move {
+                    debug!("Pulling image layer {}", layer.digest);
+                    let staging = match staging {
+                        Some(staging) if this.decompresses(&layer.media_type) => staging,
+                        _ => {
+                            return this
+                                .download_layer_of_size(
+                                    image,
+                                    &layer.digest,
+                                    layer_size(layer),
+                                    sink,
+                                    None,
+                                    budget,
+                                    None,
+                                )
+                                .await
+                        }
+                    };
+                    let path = staging.path().join(index.to_string());
+                    let file = tokio::fs::File::create(&path)
+                        .await
+                        .with_context(|| format!("Failed to create {:?}", path))?;
+                    this.download_layer_of_size(
+                        image,
+                        &layer.digest,
+                        layer_size(layer),
+                        file,
+                        None,
+                        budget,
+                        None,
+                    )
+                    .await?;
+                    this.decompress_staged(&layer.media_type, &path, sink).await
+                }
+            });
+        download_all(layers, self.config.max_concurrent_downloads).await?;
+
+        Ok((manifest, digest))
     }
 
-    async fn extract_location_header(
+    /// Pull an image and extract its layers into `dest` as the image's root
+    /// filesystem
+    ///
+    /// Layers are streamed to a staging directory under the system temp
+    /// directory, then unpacked into `dest` in manifest order, with whiteouts
+    /// from later layers deleting what earlier layers added. Compressed
+    /// layers are decompressed with the configured decompressors, whether or
+    /// not `decompress_layers` is set. Entries that would be written outside
+    /// of `dest` fail the extraction, which may leave `dest` partially
+    /// populated.
+    ///
+    /// Returns the image manifest and its digest.
+    pub async fn pull_and_extract(
         &self,
         image: &Reference,
-        res: reqwest::Response,
-        expected_status: &reqwest::StatusCode,
-    ) -> anyhow::Result<String> {
-        if res.status().eq(expected_status) {
-            let location_header = res.headers().get("Location");
-            match location_header {
-                None => Err(anyhow::anyhow!("registry did not return a location header")),
-                Some(lh) => self.location_header_to_url(&image, &lh),
-            }
-        } else {
-            Err(anyhow::anyhow!(
-                "An unexpected error occured: code={}, message='{}'",
-                res.status(),
-                res.text().await?
-            ))
-        }
+        auth: &RegistryAuth,
+        dest: &Path,
+    ) -> anyhow::Result<(OciManifest, String)> {
+        let staging = StagingDir::new()?;
+        let mut staged = Vec::new();
+        let (manifest, digest) = self
+            .pull_to(image, auth, default_image_layer_media_types(), |layer| {
+                let path = staging.path().join(staged.len().to_string());
+                let file = std::fs::File::create(&path)
+                    .with_context(|| format!("Failed to create {:?}", path))?;
+                // Layers that `pull_to` decompresses arrive with the media
+                // type of their decompressed data
+                let media_type = match self
+                    .config
+                    .decompressors
+                    .decompressed_media_type(&layer.media_type)
+                {
+                    Some(t) if self.decompresses(&layer.media_type) => t.to_owned(),
+                    _ => layer.media_type.clone(),
+                };
+                staged.push((path, media_type));
+                Ok(tokio::fs::File::from_std(file))
+            })
+            .await?;
+
+        // Extraction reads and writes files synchronously, so it runs on a
+        // blocking thread rather than holding up the executor
+        let decompressors = self.config.decompressors.clone();
+        let dest = dest.to_owned();
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            std::fs::create_dir_all(&dest)
+                .with_context(|| format!("Failed to create {:?}", dest))?;
+            for (path, media_type) in staged {
+                debug!("Extracting layer of type {} into {:?}", media_type, dest);
+                let file = std::fs::File::open(&path)
+                    .with_context(|| format!("Failed to open {:?}", path))?;
+                extract_layer(
+                    &decompressors,
+                    &media_type,
+                    Box::new(std::io::BufReader::new(file)),
+                    &dest,
+                )?;
+            }
+            Ok(())
+        })
+        .await
+        .context("Failed to run layer extraction")??;
+
+        Ok((manifest, digest))
     }
 
-    /// Helper function to convert location header to URL
+    /// Push an image and return the uploaded URL of the image
     ///
-    /// Location may be absolute (containing the protocol and/or hostname), or relative (containing just the URL path)
-    /// Returns a properly formatted absolute URL
-    fn location_header_to_url(
+    /// The client will check if it's already been authenticated and if
+    /// not will attempt to do.
+    ///
+    /// If a manifest is not provided, the client will attempt to generate
+    /// it from the provided image and config data, without layer titles. Use
+    /// `generate_manifest_with_titles` to generate one with titles instead.
+    /// An image that was pulled and is pushed with the config it was pulled
+    /// with keeps its pulled config and manifest media types, whatever
+    /// `config_media_type` says.
+    ///
+    /// Returns the pullable URLs of the image and manifest, the manifest
+    /// digest, and which blobs were uploaded
+    pub async fn push(
         &self,
-        image: &Reference,
-        location_header: &reqwest::header::HeaderValue,
-    ) -> anyhow::Result<String> {
-        let lh = location_header.to_str().map_err(anyhow::Error::new)?;
-        if lh.starts_with("/v2/") {
-            Ok(format!(
-                "{}://{}{}",
-                self.config.protocol.scheme_for(image.registry()),
-                image.registry(),
-                lh
-            ))
-        } else {
-            Ok(lh.to_string())
-        }
+        image_ref: &Reference,
+        image_data: &ImageData,
+        config_data: &[u8],
+        config_media_type: &str,
+        auth: &RegistryAuth,
+        image_manifest: Option<OciManifest>,
+    ) -> anyhow::Result<PushResponse> {
+        self.push_with_options(
+            image_ref,
+            image_data,
+            config_data,
+            config_media_type,
+            auth,
+            PushOptions {
+                manifest: image_manifest,
+                ..Default::default()
+            },
+        )
+        .await
     }
 
-    fn generate_manifest(
+    /// Push an image like `push`, with the manifest, upload session and
+    /// conditions given in `options`
+    ///
+    /// If an upload session is given, the upload of the image layers can be
+    /// resumed: its location is kept in the session's store while the upload
+    /// is in progress, and a later push with the same session key continues
+    /// from where the registry left off instead of starting over. Registries
+    /// expire idle upload sessions, and some cannot report how much of a
+    /// session they have received. In both cases a new session is started.
+    pub async fn push_with_options(
         &self,
+        image_ref: &Reference,
         image_data: &ImageData,
         config_data: &[u8],
         config_media_type: &str,
-    ) -> OciManifest {
-        let mut manifest = OciManifest::default();
+        auth: &RegistryAuth,
+        options: PushOptions<'_>,
+    ) -> anyhow::Result<PushResponse> {
+        debug!("Pushing image: {:?}", image_ref);
 
-        manifest.config.media_type = config_media_type.to_string();
-        manifest.config.size = config_data.len() as i64;
-        manifest.config.digest = sha256_digest(config_data);
+        let manifest: OciManifest = match options.manifest {
+            Some(m) => {
+                validate_manifest_layers(&m, image_data)?;
+                m
+            }
+            None => generate_manifest(&image_data, &config_data, config_media_type),
+        };
 
-        for layer in image_data.layers.clone() {
-            let mut descriptor: OciDescriptor = OciDescriptor::default();
-            descriptor.size = layer.data.len() as i64;
-            descriptor.digest = sha256_digest(&layer.data);
-            descriptor.media_type = layer.media_type;
+        if !self.has_token_for(image_ref.registry(), auth) {
+            self.auth(image_ref, auth, &RegistryOperation::Push).await?;
+        }
+        if options.if_absent {
+            self.ensure_absent(image_ref).await?;
+        }
 
-            //TODO: Determine necessity of generating an image title
-            let mut annotations = HashMap::new();
-            annotations.insert(
-                "org.opencontainers.image.title".to_string(),
-                descriptor.digest.to_string(),
-            );
-            descriptor.annotations = Some(annotations);
+        let mut response = self
+            .push_or_mount_image_data(image_ref, image_data, options.session)
+            .await?;
 
-            manifest.layers.push(descriptor);
+        // Push config and manifest to registry
+        self.push_config(
+            image_ref,
+            &config_data,
+            &manifest.config.digest,
+            &mut response,
+        )
+        .await?;
+        if options.if_absent {
+            self.ensure_absent(image_ref).await?;
         }
+        response.manifest_url = self
+            .push_manifest_repairing_blobs(
+                image_ref,
+                &manifest,
+                image_data,
+                config_data,
+                &mut response.uploaded,
+            )
+            .await?;
+        response.manifest_digest = manifest_digest_of(&manifest)?;
+        Ok(response)
+    }
 
-        manifest
+    /// Push an image like `push`, but only if the reference does not already
+    /// exist in the registry
+    ///
+    /// If the tag or digest is already present, nothing is uploaded and
+    /// `ClientError::AlreadyExists` is returned.
+    ///
+    /// The registry protocol has no conditional manifest upload, so the check
+    /// and the push are separate requests. Another client may still create
+    /// the reference in between, in which case it is overwritten. To keep that
+    /// window small, authentication happens before the check, and the check is
+    /// repeated right before the manifest is uploaded.
+    pub async fn push_if_absent(
+        &self,
+        image_ref: &Reference,
+        image_data: &ImageData,
+        config_data: &[u8],
+        config_media_type: &str,
+        auth: &RegistryAuth,
+        image_manifest: Option<OciManifest>,
+    ) -> anyhow::Result<PushResponse> {
+        self.push_with_options(
+            image_ref,
+            image_data,
+            config_data,
+            config_media_type,
+            auth,
+            PushOptions {
+                manifest: image_manifest,
+                if_absent: true,
+                ..Default::default()
+            },
+        )
+        .await
     }
 
-    /// Convert a Reference to a v2 manifest URL.
-    fn to_v2_manifest_url(&self, reference: &Reference) -> String {
-        if let Some(digest) = reference.digest() {
-            format!(
-                "{}://{}/v2/{}/manifests/{}",
-                self.config.protocol.scheme_for(reference.registry()),
-                reference.registry(),
-                reference.repository(),
-                digest,
+    /// Delete the manifest that a digest reference points at
+    ///
+    /// Many registries only delete manifests by digest, so a reference
+    /// without one is rejected before anything is sent. Deleting a manifest
+    /// also removes every tag that points at it.
+    pub async fn delete_manifest(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<()> {
+        let digest = image.digest().ok_or_else(|| {
+            anyhow::anyhow!(
+                "manifests can only be deleted by digest, but {} has none",
+                image.whole()
             )
-        } else {
-            format!(
-                "{}://{}/v2/{}/manifests/{}",
-                self.config.protocol.scheme_for(reference.registry()),
-                reference.registry(),
-                reference.repository(),
-                reference.tag().unwrap_or("latest")
+        })?;
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Delete).await?;
+        }
+
+        let url = self.to_v2_manifest_url(image);
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.delete(&url).headers(self.auth_headers(image)),
             )
+            .await
+            .with_context(|| format!("Failed to delete manifest {} at {}", digest, url))?;
+        match res.status() {
+            reqwest::StatusCode::ACCEPTED => Ok(()),
+            s if s.is_client_error() => Err(registry_error(s, &res.text().await?, &url)),
+            s => Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                s,
+                res.text().await?
+            )),
         }
     }
 
-    /// Convert a Reference to a v2 blob (layer) URL.
-    fn to_v2_blob_url(&self, registry: &str, repository: &str, digest: &str) -> String {
-        format!(
-            "{}://{}/v2/{}/blobs/{}",
-            self.config.protocol.scheme_for(registry),
-            registry,
-            repository,
-            digest,
-        )
+    /// Delete a blob from the image's repository
+    ///
+    /// Registries with blob deletion disabled answer with `405 Method Not
+    /// Allowed`, which is returned as `ClientError::DeleteUnsupported`.
+    ///
+    /// The client does not authenticate here. If the registry requires it, the
+    /// client must already hold a token for the registry.
+    pub async fn delete_blob(&self, image: &Reference, digest: &str) -> anyhow::Result<()> {
+        let url = self.blob_location(image, digest)?;
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.delete(&url).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to delete blob {} at {}", digest, url))?;
+        match res.status() {
+            reqwest::StatusCode::ACCEPTED => Ok(()),
+            reqwest::StatusCode::METHOD_NOT_ALLOWED => {
+                Err(ClientError::DeleteUnsupported(url).into())
+            }
+            s if s.is_client_error() => Err(registry_error(s, &res.text().await?, &url)),
+            s => Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                s,
+                res.text().await?
+            )),
+        }
     }
 
-    /// Convert a Reference to a v2 blob upload URL.
-    fn to_v2_blob_upload_url(&self, reference: &Reference) -> String {
-        self.to_v2_blob_url(&reference.registry(), &reference.repository(), "uploads/")
+    /// List the tags of the image's repository
+    ///
+    /// Registries may return the tags in pages. `n` asks for at most that
+    /// many tags, and `last` for the tags that come after it, so passing the
+    /// last tag of one page fetches the next. `list_all_tags` does this until
+    /// every tag has been listed.
+    pub async fn list_tags(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        n: Option<usize>,
+        last: Option<&str>,
+    ) -> anyhow::Result<TagList> {
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+        let url = with_page_params(&self.to_v2_tags_url(image), n, last)?;
+        let (tags, _) = self.fetch_list_page(image, &url).await?;
+        Ok(tags)
     }
 
-    /// Generate the headers necessary for authentication.
+    /// List every tag of the image's repository, following the registry's
+    /// pagination links
     ///
-    /// If the struct has Some(bearer), this will insert the bearer token in an
-    /// Authorization header. It will also set the Accept header, which must
-    /// be set on all OCI Registry request.
-    fn auth_headers(&self, image: &Reference) -> HeaderMap {
-        let mut headers = HeaderMap::new();
-        headers.insert("Accept", "application/vnd.docker.distribution.manifest.v2+json,application/vnd.docker.distribution.manifest.list.v2+json,application/vnd.oci.image.manifest.v1+json".parse().unwrap());
-
-        if let Some(token) = self.tokens.get(image.registry()) {
-            headers.insert("Authorization", token.bearer_token().parse().unwrap());
+    /// `n` is the most tags to ask for in each request. `None` leaves the
+    /// page size to the registry.
+    pub async fn list_all_tags(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        n: Option<usize>,
+    ) -> anyhow::Result<Vec<String>> {
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
         }
-        headers
+        let mut tags = Vec::new();
+        let mut next = Some(with_page_params(&self.to_v2_tags_url(image), n, None)?);
+        while let Some(url) = next {
+            let (page, link): (TagList, _) = self.fetch_list_page(image, &url).await?;
+            tags.extend(page.tags);
+            next = link;
+        }
+        Ok(tags)
     }
-}
 
-/// A client configuration
-#[derive(Debug, Clone, Default)]
-pub struct ClientConfig {
-    /// Which protocol the client should use
-    pub protocol: ClientProtocol,
-}
-
-/// The protocol that the client should use to connect
-#[derive(Debug, Clone, PartialEq)]
-pub enum ClientProtocol {
-    #[allow(missing_docs)]
-    Http,
-    #[allow(missing_docs)]
-    Https,
-    #[allow(missing_docs)]
-    HttpsExcept(Vec<String>),
-}
+    /// List the repositories of a registry from its `_catalog` endpoint
+    ///
+    /// Pages are requested with `n` and `last` as for `list_tags`. Many
+    /// registries only serve the catalog to clients granted the
+    /// `registry:catalog:*` scope, and some not at all.
+    pub async fn list_repositories(
+        &self,
+        registry: &str,
+        auth: &RegistryAuth,
+        n: Option<usize>,
+        last: Option<&str>,
+    ) -> anyhow::Result<Catalog> {
+        let root = Reference::registry_root(registry);
+        if !self.has_token_for(registry, auth) {
+            self.auth(&root, auth, &RegistryOperation::Catalog).await?;
+        }
+        let url = with_page_params(&self.to_v2_catalog_url(registry), n, last)?;
+        let (catalog, _) = self.fetch_list_page(&root, &url).await?;
+        Ok(catalog)
+    }
 
-impl Default for ClientProtocol {
-    fn default() -> Self {
-        ClientProtocol::Https
+    /// List every repository of a registry, following the registry's
+    /// pagination links
+    ///
+    /// `n` is the most repositories to ask for in each request. `None` leaves
+    /// the page size to the registry.
+    pub async fn list_all_repositories(
+        &self,
+        registry: &str,
+        auth: &RegistryAuth,
+        n: Option<usize>,
+    ) -> anyhow::Result<Vec<String>> {
+        let root = Reference::registry_root(registry);
+        if !self.has_token_for(registry, auth) {
+            self.auth(&root, auth, &RegistryOperation::Catalog).await?;
+        }
+        let mut repositories = Vec::new();
+        let mut next = Some(with_page_params(
+            &self.to_v2_catalog_url(registry),
+            n,
+            None,
+        )?);
+        while let Some(url) = next {
+            let (page, link): (Catalog, _) = self.fetch_list_page(&root, &url).await?;
+            repositories.extend(page.repositories);
+            next = link;
+        }
+        Ok(repositories)
     }
-}
 
-impl ClientProtocol {
-    fn scheme_for(&self, registry: &str) -> &str {
-        match self {
-            ClientProtocol::Https => "https",
-            ClientProtocol::Http => "http",
-            ClientProtocol::HttpsExcept(exceptions) => {
-                if exceptions.contains(&registry.to_owned()) {
-                    "http"
-                } else {
-                    "https"
-                }
+    /// Fetches one page of a paginated listing
+    ///
+    /// Returns the page and the URL of the next one, if the registry linked
+    /// to one
+    async fn fetch_list_page<T: serde::de::DeserializeOwned>(
+        &self,
+        image: &Reference,
+        url: &str,
+    ) -> anyhow::Result<(T, Option<String>)> {
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.get(url).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to list {}", url))?;
+        match res.status() {
+            reqwest::StatusCode::OK => {
+                let next = next_link(res.headers())
+                    .map(|link| self.to_absolute_url(image.registry(), &link));
+                let body = res
+                    .text()
+                    .await
+                    .with_context(|| format!("Failed to read listing from {}", url))?;
+                let page = serde_json::from_str(&body)
+                    .with_context(|| format!("Failed to parse listing from {}", url))?;
+                Ok((page, next))
             }
+            s if s.is_client_error() => Err(registry_error(s, &res.text().await?, url)),
+            s => Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                s,
+                res.text().await?
+            )),
         }
     }
-}
 
-/// A token granted during the OAuth2-like workflow for OCI registries.
-#[derive(serde::Deserialize, Default)]
-struct RegistryToken {
-    #[serde(alias = "access_token")]
-    token: String,
-}
+    /// Check whether a manifest exists for the reference's tag or digest
+    pub async fn manifest_exists(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<bool> {
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+        self.head_manifest(image).await
+    }
 
-impl RegistryToken {
-    fn bearer_token(&self) -> String {
-        format!("Bearer {}", self.token)
+    async fn head_manifest(&self, image: &Reference) -> anyhow::Result<bool> {
+        let url = self.to_v2_manifest_url(image);
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.head(&url).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to check for manifest at {}", url))?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            s => Err(anyhow::anyhow!(
+                "An unexpected status checking for manifest {}: {}",
+                url,
+                s
+            )),
+        }
     }
-}
 
-#[derive(Clone)]
-struct BearerChallenge {
-    pub realm: Option<String>,
-    pub service: Option<String>,
-    pub scope: Option<String>,
-}
+    /// Get the size of a blob in bytes without downloading it
+    ///
+    /// This sends a `HEAD` request for the blob and reads its
+    /// `Content-Length`. If the registry does not answer the `HEAD`, or
+    /// leaves out the length, the size recorded for the blob in the image
+    /// manifest is used instead.
+    ///
+    /// The client does not authenticate here. If the registry requires it, the
+    /// client must already hold a pull token for the registry.
+    pub async fn blob_size(&self, image: &Reference, digest: &str) -> anyhow::Result<u64> {
+        let parsed: Digest = digest.parse()?;
+        let url = self.to_v2_blob_url(image.registry(), image.repository(), &parsed);
+        let res = {
+            // Release the permit before falling back to the manifest
+            let _permit = self.request_permit().await;
+            self.send(
+                image.registry(),
+                self.client.head(&url).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to get size of blob {} from {}", digest, url))?
+        };
+        match res.status() {
+            reqwest::StatusCode::NOT_FOUND => {
+                return Err(anyhow::anyhow!("blob {} not found at {}", digest, url))
+            }
+            s if s.is_success() => {
+                if let Some(len) = content_length(res.headers()) {
+                    return Ok(len);
+                }
+                debug!("Registry did not return a length for blob {}", digest);
+            }
+            s => debug!("Registry answered HEAD for blob {} with {}", digest, s),
+        }
 
-impl Challenge for BearerChallenge {
-    fn challenge_name() -> &'static str {
-        "Bearer"
+        let (manifest, _) = self.resolve_manifest(image).await?;
+        descriptor_size(&manifest, digest)
     }
 
-    fn from_raw(raw: RawChallenge) -> Option<Self> {
-        match raw {
-            RawChallenge::Token68(_) => None,
-            RawChallenge::Fields(mut map) => Some(BearerChallenge {
-                realm: map.remove("realm"),
-                scope: map.remove("scope"),
-                service: map.remove("service"),
-            }),
+    /// Check whether the image's repository holds a blob, without
+    /// downloading it
+    ///
+    /// The client does not authenticate here. If the registry requires it, the
+    /// client must already hold a token for the registry.
+    pub async fn blob_exists(&self, image: &Reference, digest: &str) -> anyhow::Result<bool> {
+        let url = self.blob_location(image, digest)?;
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.head(&url).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to check for blob {} at {}", digest, url))?;
+        match res.status() {
+            reqwest::StatusCode::OK => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            s => Err(anyhow::anyhow!(
+                "An unexpected status checking for blob {}: {}",
+                url,
+                s
+            )),
         }
     }
 
-    fn into_raw(self) -> RawChallenge {
-        let mut map = ChallengeFields::new();
-        if let Some(realm) = self.realm {
-            map.insert_static_quoting("realm", realm);
-        }
-        if let Some(scope) = self.scope {
-            map.insert_static_quoting("scope", scope);
-        }
-        if let Some(service) = self.service {
-            map.insert_static_quoting("service", service);
-        }
-        RawChallenge::Fields(map)
+    async fn ensure_absent(&self, image: &Reference) -> anyhow::Result<()> {
+        if self.head_manifest(image).await? {
+            return Err(ClientError::AlreadyExists(image.whole()).into());
+        }
+        Ok(())
+    }
+
+    /// Push a single blob and return its digest
+    ///
+    /// This runs a complete upload session for the given bytes, independent of
+    /// the full `push` flow, so that arbitrary artifacts can be uploaded and
+    /// later referenced from a custom manifest.
+    ///
+    /// The client does not authenticate here. If the registry requires it, the
+    /// client must already hold a push token for the registry.
+    ///
+    /// Returns the `sha256:...` digest of the uploaded blob
+    pub async fn push_blob(&self, image: &Reference, data: &[u8]) -> anyhow::Result<String> {
+        self.push_blob_with_algorithm(image, data, DigestAlgorithm::Sha256)
+            .await
+    }
+
+    /// Push a single blob like `push_blob`, addressing it by a digest
+    /// computed with the given algorithm
+    ///
+    /// Returns the digest of the uploaded blob, such as `sha512:...`
+    pub async fn push_blob_with_algorithm(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        algorithm: DigestAlgorithm,
+    ) -> anyhow::Result<String> {
+        let digest = Digest::compute(algorithm, data).to_string();
+        self.push_single_blob(image, data, &digest).await?;
+        Ok(digest)
+    }
+
+    /// Push a single blob like `push_blob`, resuming an earlier interrupted
+    /// upload of it if there is one
+    ///
+    /// This is stateful: the location of the upload session is saved in
+    /// `uploads` under `upload_id`, or under the blob digest if no id is
+    /// given, and is only removed once the upload completes. When a location
+    /// is found, the registry is asked how much of the blob it has received
+    /// and only the rest is sent. If the registry no longer knows the session,
+    /// a new one is started. Callers that want uploads to survive a restart
+    /// must keep `uploads` somewhere persistent.
+    ///
+    /// Returns the `sha256:...` digest of the uploaded blob
+    pub async fn push_blob_resumable(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        uploads: &dyn UploadStore,
+        upload_id: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let digest = sha256_digest(data);
+        if data.is_empty() {
+            self.push_monolithic(image, data, &digest).await?;
+            return Ok(digest);
+        }
+        let key = upload_id.unwrap_or(&digest);
+        let session = UploadSession {
+            store: uploads,
+            key,
+        };
+
+        let (mut location, offset) = self
+            .begin_or_resume_push_session(image, Some(session), data.len())
+            .await?;
+
+        if offset < data.len() {
+            let (next_location, _) = self
+                .push_layer(&location, image, data[offset..].to_vec(), offset)
+                .await?;
+            location = next_location;
+            uploads.save(key, &location)?;
+        }
+
+        self.end_push_session(&location, image, &digest).await?;
+        uploads.remove(key)?;
+        Ok(digest)
+    }
+
+    /// Asks the registry how much of an upload session it has received
+    ///
+    /// Returns the current location of the session and the offset to continue
+    /// from, or `None` if the registry does not know the session
+    async fn upload_status(
+        &self,
+        image: &Reference,
+        location: &str,
+    ) -> anyhow::Result<Option<(String, usize)>> {
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.get(location).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to get status of upload at {}", location))?;
+        match res.status() {
+            reqwest::StatusCode::NO_CONTENT => {
+                let offset = match res.headers().get("Range") {
+                    Some(range) => upload_range_offset(range.to_str()?)?,
+                    None => 0,
+                };
+                let location = match res.headers().get("Location") {
+                    Some(lh) => self.location_header_to_url(image, lh)?,
+                    None => location.to_owned(),
+                };
+                Ok(Some((location, offset)))
+            }
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            s => Err(anyhow::anyhow!(
+                "An unexpected status checking upload {}: {}",
+                location,
+                s
+            )),
+        }
+    }
+
+    /// Makes the image layers available in the repository
+    ///
+    /// Each layer is handled by itself: layers the repository already has are
+    /// skipped, the others are mounted from the repositories in
+    /// `ClientConfig::mount_from` where possible, and only the rest are
+    /// uploaded.
+    ///
+    /// Each uploaded layer keeps its upload session in `session` under its own
+    /// key, made of the session key and the layer digest.
+    ///
+    /// Returns a response with the pullable URL for the image data and the
+    /// digests of the uploaded, mounted and skipped blobs
+    async fn push_or_mount_image_data(
+        &self,
+        image_ref: &Reference,
+        image_data: &ImageData,
+        session: Option<UploadSession<'_>>,
+    ) -> anyhow::Result<PushResponse> {
+        let mut response = PushResponse::default();
+        for layer in &image_data.layers {
+            let digest = layer.digest();
+            if response.uploaded.iter().any(|d| d == digest) {
+                continue;
+            }
+            let key = session.map(|s| layer_session_key(s.key, digest));
+            let layer_session = session.and_then(|s| {
+                key.as_ref().map(|key| UploadSession {
+                    store: s.store,
+                    key,
+                })
+            });
+
+            if self.blob_exists(image_ref, digest).await? {
+                debug!("Blob {} already exists, skipping upload", digest);
+                response.image_url = self.blob_location(image_ref, digest)?;
+                response.skipped.push(digest.to_owned());
+            } else if let Some(location) = self.mount_layer(image_ref, digest).await? {
+                response.image_url = location;
+                response.mounted.push(digest.to_owned());
+            } else {
+                response.image_url = self
+                    .push_image_layer(image_ref, layer, layer_session)
+                    .await?;
+                response.uploaded.push(digest.to_owned());
+                continue;
+            }
+            if let Some(session) = layer_session {
+                // Nothing is left to resume
+                session.store.remove(session.key)?;
+            }
+        }
+        Ok(response)
+    }
+
+    /// The pullable URL of a blob in the image's repository
+    fn blob_location(&self, image: &Reference, digest: &str) -> anyhow::Result<String> {
+        let parsed: Digest = digest.parse()?;
+        Ok(self.to_v2_blob_url(image.registry(), image.repository(), &parsed))
+    }
+
+    /// Mounts a blob from the first repository in `ClientConfig::mount_from`
+    /// that has it
+    ///
+    /// Returns the pullable location of the blob, or `None` if no repository
+    /// could provide it
+    async fn mount_layer(&self, image: &Reference, digest: &str) -> anyhow::Result<Option<String>> {
+        for from in &self.config.mount_from {
+            if let Some(location) = self.mount_blob(image, digest, from).await? {
+                debug!("Mounted blob {} from {}", digest, from);
+                return Ok(Some(location));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Asks the registry to mount a blob from another repository
+    ///
+    /// A registry that cannot mount the blob, for example because the source
+    /// repository does not have it or the client may not pull from it, starts
+    /// an upload session instead. That session is left to expire.
+    ///
+    /// Returns the pullable location of the blob, or `None` if it was not
+    /// mounted
+    async fn mount_blob(
+        &self,
+        image: &Reference,
+        digest: &str,
+        from: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let url = format!(
+            "{}?mount={}&from={}",
+            self.to_v2_blob_upload_url(image),
+            digest,
+            normalize_repository(from)
+        );
+        let mut headers = self.auth_headers(image);
+        headers.insert("Content-Length", "0".parse().unwrap());
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(image.registry(), self.client.post(&url).headers(headers))
+            .await
+            .with_context(|| format!("Failed to mount blob {} from {}", digest, from))?;
+        if res.status() != reqwest::StatusCode::CREATED {
+            debug!(
+                "Registry did not mount blob {} from {}: {}",
+                digest,
+                from,
+                res.status()
+            );
+            return Ok(None);
+        }
+        self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
+            .await
+            .map(Some)
+    }
+
+    /// Uploads an image layer, falling back to a monolithic upload if the
+    /// registry does not support chunked uploads
+    ///
+    /// Returns the pullable URL for the uploaded layer
+    async fn push_image_layer(
+        &self,
+        image_ref: &Reference,
+        layer: &ImageLayer,
+        session: Option<UploadSession<'_>>,
+    ) -> anyhow::Result<String> {
+        let layers = std::slice::from_ref(layer);
+        let url = match self
+            .push_image_layers(image_ref, layers, layer.digest(), session)
+            .await
+        {
+            Err(e) if is_content_range_unsupported(&e) => {
+                warn!(
+                    "Registry rejected chunked upload for image {:?} ({}), falling back to monolithic upload",
+                    image_ref, e
+                );
+                self.push_monolithic(image_ref, &layer.data, layer.digest())
+                    .await?
+            }
+            result => result?,
+        };
+        if let Some(session) = session {
+            session.store.remove(session.key)?;
+        }
+        Ok(url)
+    }
+
+    /// Uploads layers as one blob with the given digest in a single push
+    /// session, resuming the given upload session if the registry still knows
+    /// it
+    ///
+    /// Returns the pullable URL for the uploaded data
+    async fn push_image_layers(
+        &self,
+        image_ref: &Reference,
+        layers: &[ImageLayer],
+        digest: &str,
+        session: Option<UploadSession<'_>>,
+    ) -> anyhow::Result<String> {
+        let len: usize = layers.iter().map(|l| l.data.len()).sum();
+        let (mut location, offset) = self
+            .begin_or_resume_push_session(image_ref, session, len)
+            .await?;
+
+        for (start_byte, chunk) in chunks_from(layers, offset) {
+            // Destructuring assignment is not yet supported
+            let (next_location, _) = self
+                .push_layer(&location, &image_ref, chunk.to_vec(), start_byte)
+                .await?;
+            location = next_location;
+            if let Some(session) = session {
+                session.store.save(session.key, &location)?;
+            }
+        }
+
+        // End push session
+        self.end_push_session(&location, &image_ref, digest).await
+    }
+
+    /// Begins a push session, or resumes the one saved in `session` if the
+    /// registry still knows it and has received no more than `len` bytes
+    ///
+    /// The location of the session is saved in `session`. Returns the
+    /// location and the offset to continue uploading from.
+    async fn begin_or_resume_push_session(
+        &self,
+        image: &Reference,
+        session: Option<UploadSession<'_>>,
+        len: usize,
+    ) -> anyhow::Result<(String, usize)> {
+        let session = match session {
+            Some(session) => session,
+            None => return Ok((self.begin_push_session(image).await?, 0)),
+        };
+        let resumed = match session.store.load(session.key)? {
+            Some(location) => self
+                .upload_status(image, &location)
+                .await?
+                .filter(|(_, offset)| *offset <= len),
+            None => None,
+        };
+        let (location, offset) = match resumed {
+            Some((location, offset)) => {
+                debug!(
+                    "Resuming upload {} at byte {} of {}",
+                    session.key, offset, len
+                );
+                (location, offset)
+            }
+            None => (self.begin_push_session(image).await?, 0),
+        };
+        session.store.save(session.key, &location)?;
+        Ok((location, offset))
+    }
+
+    /// Uploads one blob, choosing between a chunked and a monolithic upload
+    ///
+    /// Zero-length blobs, and blobs for registries that reject the ranged
+    /// PATCH, are uploaded in a single request. The data is checked against
+    /// the digest first, so a mismatch fails before anything is sent.
+    ///
+    /// Returns the pullable location of the blob
+    async fn push_single_blob(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        digest: &str,
+    ) -> anyhow::Result<String> {
+        verify_blob(digest, data)?;
+        if data.is_empty() {
+            return self.push_monolithic(image, data, digest).await;
+        }
+        let location = self.begin_push_session(image).await?;
+        match self.push_layer(&location, image, data.to_vec(), 0).await {
+            Ok((end_location, _)) => self.end_push_session(&end_location, image, digest).await,
+            Err(e) if is_content_range_unsupported(&e) => {
+                warn!(
+                    "Registry rejected chunked upload of blob {} ({}), falling back to monolithic upload",
+                    digest, e
+                );
+                self.push_monolithic(image, data, digest).await
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Perform an OAuth v2 auth request if necessary.
+    ///
+    /// This performs authorization and then stores the token internally to be used
+    /// on other requests. Anonymous access to a registry configured as public
+    /// is recorded without asking the registry.
+    async fn auth(
+        &self,
+        image: &Reference,
+        authentication: &RegistryAuth,
+        operation: &RegistryOperation,
+    ) -> anyhow::Result<()> {
+        debug!("Authorizing for image: {:?}", image);
+        if matches!(authentication, RegistryAuth::Anonymous)
+            && self.config.is_public(image.registry())
+        {
+            debug!(
+                "Registry {} is configured as public, skipping authentication",
+                image.registry()
+            );
+            self.store_token(
+                image.registry(),
+                authentication,
+                RegistryTokenType::Anonymous,
+            );
+            return Ok(());
+        }
+        // The version request will tell us where to go.
+        let url = self.to_v2_url(image.registry());
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(image.registry(), self.client.get(&url))
+            .await
+            .with_context(|| format!("Failed to reach registry at {}", url))?;
+        // The challenge is honored whatever the status code, since some
+        // registries send it along with a 200.
+        // A Bearer challenge is preferred when a registry offers both, since
+        // it also grants anonymous access.
+        let challenge = match bearer_challenge(res.headers())? {
+            Some(c) => c,
+            None => {
+                let token = match (basic_challenge(res.headers())?, authentication) {
+                    (Some(_), RegistryAuth::Basic(..)) => {
+                        debug!(
+                            "Registry {} requested basic authentication",
+                            image.registry()
+                        );
+                        RegistryTokenType::Basic
+                    }
+                    _ => {
+                        // Record the anonymous access so that it isn't retried
+                        // for every request, and so that no stale token is sent.
+                        debug!(
+                            "Registry {} did not request authentication",
+                            image.registry()
+                        );
+                        RegistryTokenType::Anonymous
+                    }
+                };
+                self.store_token(image.registry(), authentication, token);
+                return Ok(());
+            }
+        };
+
+        // Allow for push, pull, or caller-specified authentication scopes
+        let scope = operation.scope(image.repository());
+
+        let (realm, service) = self.token_endpoint(image.registry(), &challenge)?;
+
+        // Credentials go to the token endpoint as Basic auth, which is how
+        // registries such as GHCR grant tokens for private repositories
+        let token = self
+            .request_token(
+                image.registry(),
+                &realm,
+                Some(&service),
+                &scope,
+                authentication,
+            )
+            .await?;
+        debug!("Succesfully authorized for image '{:?}'", image);
+        self.store_token(
+            image.registry(),
+            authentication,
+            RegistryTokenType::Bearer(token),
+        );
+        Ok(())
+    }
+
+    /// Asks a registry's token endpoint for a token with the given scope
+    async fn request_token(
+        &self,
+        registry: &str,
+        realm: &str,
+        service: Option<&str>,
+        scope: &str,
+        authentication: &RegistryAuth,
+    ) -> anyhow::Result<RegistryToken> {
+        debug!("Making authentication call to {}", realm);
+        let mut request = self.client.get(realm);
+        if let Some(service) = service {
+            request = request.query(&[("service", service)]);
+        }
+        // Several scopes are asked for with one parameter each
+        for scope in scope.split_whitespace() {
+            request = request.query(&[("scope", scope)]);
+        }
+        let auth_res = self
+            .dispatch(
+                registry,
+                request.apply_authentication(authentication).build()?,
+                false,
+            )
+            .await
+            .with_context(|| format!("Failed to request a token from {}", realm))?;
+
+        match auth_res.status() {
+            reqwest::StatusCode::OK => {
+                let text = auth_res
+                    .text()
+                    .await
+                    .with_context(|| format!("Failed to read token from {}", realm))?;
+                let mut token = RegistryToken::parse(&text)?;
+                token.request = Some(TokenRequest {
+                    realm: realm.to_owned(),
+                    service: service.map(|s| s.to_owned()),
+                    scope: scope.to_owned(),
+                });
+                // Never log the response body itself, it carries the token
+                debug!("Received token from auth request: {:?}", token);
+                Ok(token)
+            }
+            _ => {
+                let reason = auth_res.text().await?;
+                debug!("Failed to authenticate to {}: {}", registry, reason);
+                Err(anyhow::anyhow!("failed to authenticate: {}", reason))
+            }
+        }
+    }
+
+    /// Check that a registry is reachable and accepts the given credentials,
+    /// without pulling anything
+    ///
+    /// This pings the registry's `/v2/` endpoint and, if the registry asks for
+    /// a token, requests one without a repository scope, which checks the
+    /// credentials the same way `docker login` does. The token is not kept.
+    ///
+    /// Failures to resolve or connect to the registry are reported as
+    /// `ClientError::RegistryUnresolvable` and
+    /// `ClientError::RegistryUnreachable`, and rejected credentials as
+    /// `ClientError::AuthenticationFailed`.
+    pub async fn check(&self, registry: &str, auth: &RegistryAuth) -> anyhow::Result<()> {
+        let url = self.to_v2_url(registry);
+        debug!("Checking registry at {}", url);
+        let res = {
+            let _permit = self.request_permit().await;
+            self.execute(registry, self.client.get(&url).apply_authentication(auth))
+                .await
+                .with_context(|| format!("Failed to reach registry at {}", url))?
+        };
+
+        let challenge = match bearer_challenge(res.headers())? {
+            Some(c) => c,
+            None => {
+                return match res.status() {
+                    s if s.is_success() => Ok(()),
+                    reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                        Err(ClientError::AuthenticationFailed(res.text().await?).into())
+                    }
+                    s => Err(anyhow::anyhow!(
+                        "registry {} answered the version check with {}",
+                        registry,
+                        s
+                    )),
+                };
+            }
+        };
+
+        let (realm, service) = self.token_endpoint(registry, &challenge)?;
+        let request = self.client.get(&realm).query(&[("service", service)]);
+        let _permit = self.request_permit().await;
+        let auth_res = self
+            .execute(registry, request.apply_authentication(auth))
+            .await
+            .with_context(|| format!("Failed to request a token from {}", realm))?;
+        match auth_res.status() {
+            reqwest::StatusCode::OK => {
+                RegistryToken::parse(&auth_res.text().await?)?;
+                Ok(())
+            }
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                Err(ClientError::AuthenticationFailed(auth_res.text().await?).into())
+            }
+            s => Err(anyhow::anyhow!(
+                "token endpoint for registry {} answered with {}",
+                registry,
+                s
+            )),
+        }
+    }
+
+    /// Fetch a manifest's digest from the remote OCI Distribution service.
+    ///
+    /// If the connection has already gone through authentication, this will
+    /// use the bearer token. Otherwise, this will attempt an anonymous pull.
+    pub async fn fetch_manifest_digest(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<String> {
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        let url = self.to_v2_manifest_url(image);
+        debug!("Pulling image manifest from {}", url);
+        let request = self.client.get(&url);
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(image.registry(), request.headers(self.auth_headers(image)))
+            .await
+            .with_context(|| format!("Failed to fetch manifest from {}", url))?;
+
+        // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
+        // Obviously, HTTP servers are going to send other codes. This tries to catch the
+        // obvious ones (200, 4XX, 5XX). Anything else is just treated as an error.
+        match res.status() {
+            reqwest::StatusCode::OK => {
+                let headers = res.headers().clone();
+                let body = res
+                    .bytes()
+                    .await
+                    .with_context(|| format!("Failed to read manifest from {}", url))?;
+                manifest_digest(&headers, &body)
+            }
+            s if s.is_client_error() => {
+                // According to the OCI spec, we should see an error in the message body.
+                Err(registry_error(s, &res.text().await?, &url))
+            }
+            s if s.is_server_error() => Err(server_error(s, &url)),
+            s => Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                s,
+                res.text().await?
+            )),
+        }
+    }
+
+    /// Send an authenticated GET request to an arbitrary URL on the image's
+    /// registry and return the raw response.
+    ///
+    /// This is an escape hatch for registry endpoints that the client does not
+    /// model. It only takes care of authentication: the client authenticates
+    /// for pull if it holds no token for the registry, and re-authenticates
+    /// once if the registry answers 401. Status codes, error bodies, and the
+    /// response body are left entirely to the caller. Prefer the typed methods
+    /// whenever one covers the endpoint.
+    ///
+    /// Credentials are only sent to the image's registry. A URL on any other
+    /// host, such as a redirect target, is fetched without them.
+    pub async fn authenticated_get(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+        url: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        let parsed =
+            reqwest::Url::parse(url).with_context(|| format!("Failed to parse URL {}", url))?;
+        if !is_registry_url(image.registry(), &parsed) {
+            debug!(
+                "{} is not on registry {}, sending no credentials",
+                url,
+                image.registry()
+            );
+            let host = parsed.host_str().unwrap_or_default();
+            let _permit = self.request_permit().await;
+            return self
+                .send(host, self.client.get(url))
+                .await
+                .with_context(|| format!("Failed to send request to {}", url));
+        }
+
+        if !self.has_token_for(image.registry(), auth) {
+            self.auth(image, auth, &RegistryOperation::Pull).await?;
+        }
+
+        let res = self.send_authenticated_get(image, url).await?;
+        if res.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+
+        debug!(
+            "Token for {} was rejected, re-authenticating",
+            image.registry()
+        );
+        self.clear_token(image.registry());
+        self.auth(image, auth, &RegistryOperation::Pull).await?;
+        self.send_authenticated_get(image, url).await
+    }
+
+    async fn send_authenticated_get(
+        &self,
+        image: &Reference,
+        url: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        let _permit = self.request_permit().await;
+        self.send(
+            image.registry(),
+            self.client.get(url).headers(self.auth_headers(image)),
+        )
+        .await
+        .with_context(|| format!("Failed to send request to {}", url))
+    }
+
+    async fn validate_layers(
+        &self,
+        manifest: &OciManifest,
+        accepted_media_types: Vec<&str>,
+    ) -> anyhow::Result<()> {
+        if manifest.layers.is_empty() {
+            // Metadata-only artifacts legitimately have no layers
+            if self.config.allow_empty_layers && manifest.is_artifact() {
+                return Ok(());
+            }
+            return Err(anyhow::anyhow!("no layers to pull"));
+        }
+
+        // Reject the image before any layer is downloaded, naming every type
+        // the image actually has so that the mismatch is obvious
+        let incompatible = manifest.layers.iter().any(|layer| {
+            !accepted_media_types
+                .iter()
+                .any(|i| media_type_matches(i, &layer.media_type))
+        });
+        if incompatible {
+            let mut found: Vec<String> = Vec::new();
+            for layer in &manifest.layers {
+                if !found.contains(&layer.media_type) {
+                    found.push(layer.media_type.clone());
+                }
+            }
+            return Err(ClientError::IncompatibleMediaTypes {
+                found,
+                accepted: accepted_media_types
+                    .iter()
+                    .map(|t| (*t).to_owned())
+                    .collect(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Pull an image's manifest without pulling its layers
+    ///
+    /// The client authenticates for pull first if it holds no token for the
+    /// registry. An image index or manifest list is resolved to the manifest
+    /// for the configured platform, or for the current one if none is
+    /// configured.
+    ///
+    /// If the registry is unavailable, the manifest is pulled from the
+    /// registry's mirrors instead, as for `pull`.
+    ///
+    /// Returns the manifest and the digest of the manifest the reference
+    /// points at.
+    pub async fn pull_manifest(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<(OciManifest, String)> {
+        self.with_mirrors(image, "pull the manifest of", move |candidate| async move {
+            let auth = mirror_auth(image, &candidate, auth);
+            if !self.has_token_for(candidate.registry(), auth) {
+                self.auth(&candidate, auth, &RegistryOperation::Pull)
+                    .await?;
+            }
+            self.resolve_manifest(&candidate).await
+        })
+        .await
+    }
+
+    /// Pull the manifest a reference points at exactly as the registry
+    /// stores it
+    ///
+    /// Nothing is parsed or resolved, so this works for any kind of manifest,
+    /// including indexes and media types that `OciManifest` does not model.
+    /// The client authenticates for pull first if it holds no token for the
+    /// registry.
+    ///
+    /// If the registry is unavailable, the manifest is pulled from the
+    /// registry's mirrors instead, as for `pull`.
+    ///
+    /// Returns the manifest's bytes and the `Content-Type` the registry gave
+    /// them, if any.
+    pub async fn pull_manifest_raw(
+        &self,
+        image: &Reference,
+        auth: &RegistryAuth,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>)> {
+        self.with_mirrors(image, "pull the manifest of", move |candidate| async move {
+            let auth = mirror_auth(image, &candidate, auth);
+            if !self.has_token_for(candidate.registry(), auth) {
+                self.auth(&candidate, auth, &RegistryOperation::Pull)
+                    .await?;
+            }
+            let (body, content_type, _) = self.fetch_manifest_bytes(&candidate).await?;
+            Ok((body, content_type))
+        })
+        .await
+    }
+
+    /// Pull a manifest from the remote OCI Distribution service.
+    ///
+    /// If the connection has already gone through authentication, this will
+    /// use the bearer token. Otherwise, this will attempt an anonymous pull.
+    ///
+    /// An image index or manifest list is resolved to the manifest for the
+    /// configured platform.
+    async fn resolve_manifest(&self, image: &Reference) -> anyhow::Result<(OciManifest, String)> {
+        let platform = match &self.config.platform {
+            Some(platform) => platform.clone(),
+            None => Platform::current(),
+        };
+        self.pull_platform_manifest(image, &platform).await
+    }
+
+    /// Pull the manifest of an image, resolving an image index or manifest
+    /// list to the manifest of its entry for `platform`
+    ///
+    /// The digest returned is that of the manifest the reference points at,
+    /// so that it matches what `fetch_manifest_digest` returns.
+    async fn pull_platform_manifest(
+        &self,
+        image: &Reference,
+        platform: &Platform,
+    ) -> anyhow::Result<(OciManifest, String)> {
+        let (mut text, mut content_type, digest) = self.fetch_manifest(image).await?;
+        if is_manifest_list(&text, content_type.as_deref()) {
+            let index: OciImageIndex = serde_json::from_str(&text)
+                .with_context(|| format!("Failed to parse image index for '{:?}'", image))?;
+            let entry = index.manifest_for_platform(platform).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "image index for '{:?}' has no manifest for {}",
+                    image,
+                    platform_name(platform)
+                )
+            })?;
+            let child: Reference = format!(
+                "{}/{}@{}",
+                image.registry(),
+                image.repository(),
+                entry.digest
+            )
+            .parse()?;
+            debug!(
+                "Resolved {} in image index for '{:?}' to {}",
+                platform_name(platform),
+                image,
+                entry.digest
+            );
+            let (child_body, child_content_type, _) = self.fetch_manifest_bytes(&child).await?;
+            // The index is what vouches for the child, so the child must be
+            // exactly what its entry names
+            let expected: Digest = entry.digest.parse()?;
+            if !expected.verify(&child_body) {
+                return Err(ClientError::DigestMismatch {
+                    expected: expected.to_string(),
+                    actual: Digest::compute(expected.algorithm(), &child_body).to_string(),
+                }
+                .into());
+            }
+            text = String::from_utf8(child_body)
+                .with_context(|| format!("Manifest for '{:?}' is not valid UTF-8", child))?;
+            content_type = child_content_type;
+        }
+
+        self.validate_image_manifest(&text, content_type.as_deref())
+            .await?;
+
+        debug!("Parsing response as OciManifest: {}", text);
+        let manifest: OciManifest = serde_json::from_str(&text).with_context(|| {
+            format!(
+                "Failed to parse response from pulling manifest for '{:?}' as an OciManifest",
+                image
+            )
+        })?;
+        Ok((manifest, digest))
+    }
+
+    /// Fetch the manifest for a reference, returning its body, content type
+    /// and digest without checking what kind of manifest it is.
+    async fn fetch_manifest(
+        &self,
+        image: &Reference,
+    ) -> anyhow::Result<(String, Option<String>, String)> {
+        let (body, content_type, digest) = self.fetch_manifest_bytes(image).await?;
+        let text = String::from_utf8(body)
+            .with_context(|| format!("Manifest for '{:?}' is not valid UTF-8", image))?;
+        Ok((text, content_type, digest))
+    }
+
+    /// Fetch the manifest for a reference as it is stored in the registry,
+    /// returning its bytes, content type and digest
+    async fn fetch_manifest_bytes(
+        &self,
+        image: &Reference,
+    ) -> anyhow::Result<(Vec<u8>, Option<String>, String)> {
+        let url = self.to_v2_manifest_url(image);
+        debug!("Pulling image manifest from {}", url);
+        let request = self.client.get(&url);
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(image.registry(), request.headers(self.auth_headers(image)))
+            .await
+            .with_context(|| format!("Failed to fetch manifest from {}", url))?;
+
+        // The OCI spec technically does not allow any codes but 200, 500, 401, and 404.
+        // Obviously, HTTP servers are going to send other codes. This tries to catch the
+        // obvious ones (200, 4XX, 5XX). Anything else is just treated as an error.
+        match res.status() {
+            reqwest::StatusCode::OK => {
+                let headers = res.headers().clone();
+                let content_type = headers
+                    .get("Content-Type")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_owned());
+                let body = res
+                    .bytes()
+                    .await
+                    .with_context(|| format!("Failed to read manifest from {}", url))?
+                    .to_vec();
+                let digest = manifest_digest(&headers, &body)?;
+                Ok((body, content_type, digest))
+            }
+            s if s.is_client_error() => {
+                // According to the OCI spec, we should see an error in the message body.
+                Err(registry_error(s, &res.text().await?, &url))
+            }
+            s if s.is_server_error() => Err(server_error(s, &url)),
+            s => Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                s,
+                res.text().await?
+            )),
+        }
+    }
+
+    /// Checks that a pulled manifest is an image manifest that can be parsed
+    /// as an `OciManifest`
+    ///
+    /// The media type is taken from the manifest itself, falling back to the
+    /// response's content type when that is one of the advertised manifest
+    /// types. Docker v2 and OCI image manifests share the same shape, so
+    /// either is accepted whichever one the registry chose to serve.
+    async fn validate_image_manifest(
+        &self,
+        text: &str,
+        content_type: Option<&str>,
+    ) -> anyhow::Result<()> {
+        debug!("validating manifest: {}", text);
+        let versioned: Versioned = serde_json::from_str(&text)
+            .with_context(|| "Failed to parse manifest as a Versioned object")?;
+        if versioned.schema_version != 2 {
+            return Err(anyhow::anyhow!(
+                "unsupported schema version: {}",
+                versioned.schema_version
+            ));
+        }
+        let media_type = versioned.media_type.or_else(|| {
+            content_type
+                .filter(|t| {
+                    MANIFEST_ACCEPT_TYPES
+                        .iter()
+                        .any(|accepted| media_type_matches(t, accepted))
+                })
+                .map(|t| t.to_owned())
+        });
+        if let Some(media_type) = media_type {
+            let is_image_manifest = IMAGE_MANIFEST_MEDIA_TYPES
+                .iter()
+                .any(|t| media_type_matches(&media_type, t));
+            if is_image_manifest {
+                return Ok(());
+            }
+            // Indexes are resolved to a platform's manifest before this
+            // point, so one here is nested in another
+            let is_manifest_list = [IMAGE_MANIFEST_LIST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE]
+                .iter()
+                .any(|t| media_type_matches(&media_type, t));
+            if is_manifest_list {
+                return Err(anyhow::anyhow!(
+                    "nested manifest lists are not supported: {}",
+                    media_type
+                ));
+            }
+            return Err(anyhow::anyhow!("unsupported media type: {}", media_type));
+        }
+
+        Ok(())
+    }
+
+    /// Pull a single layer from an OCI registy.
+    ///
+    /// This pulls the layer for a particular image that is identified by
+    /// the given digest. The image reference is used to find the
+    /// repository and the registry, but it is not used to verify that
+    /// the digest is a layer inside of the image. (The manifest is
+    /// used for that.)
+    ///
+    /// If a cancellation token is given, it is checked between each chunk
+    /// written to `out`. If a byte budget is given, each chunk holds a share
+    /// of it until it has been written and flushed.
+    ///
+    /// The data is hashed as it is written when `verify` is set, or when it
+    /// is `None` and the client is configured to verify digests. A mismatch
+    /// is only found once everything has been written to `out`, so callers
+    /// must discard what was written if this fails.
+    async fn download_layer<T: AsyncWrite + Unpin>(
+        &self,
+        image: &Reference,
+        digest: &str,
+        out: T,
+        cancel: Option<&CancellationToken>,
+        budget: Option<&ByteBudget>,
+        verify: Option<bool>,
+    ) -> anyhow::Result<()> {
+        self.download_layer_of_size(image, digest, None, out, cancel, budget, verify)
+            .await
+    }
+
+    /// Downloads a layer as `download_layer` does, reporting `size` to the
+    /// progress reporter as the layer's total size instead of the response's
+    /// `Content-Length`
+    #[allow(clippy::too_many_arguments)]
+    async fn download_layer_of_size<T: AsyncWrite + Unpin>(
+        &self,
+        image: &Reference,
+        digest: &str,
+        size: Option<u64>,
+        out: T,
+        cancel: Option<&CancellationToken>,
+        budget: Option<&ByteBudget>,
+        verify: Option<bool>,
+    ) -> anyhow::Result<()> {
+        let digest: Digest = digest.parse()?;
+        let url = self.to_v2_blob_url(image.registry(), image.repository(), &digest);
+        // Hold the permit until the whole body has been streamed
+        let _permit = self.request_permit().await;
+        check_cancelled(cancel)?;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.get(&url).headers(self.auth_headers(image)),
+            )
+            .await
+            .with_context(|| format!("Failed to download blob {} from {}", digest, url))?;
+        let res = successful_blob_response(res, &url).await?;
+        let digest_text = digest.to_string();
+        let mut progress = self
+            .config
+            .progress
+            .as_ref()
+            .map(|p| p.track(&digest_text, 0, size.or_else(|| res.content_length())));
+        let stream = res.bytes_stream();
+
+        let mut hasher = if verify.unwrap_or(self.config.verify_digests) {
+            Some(Hasher::new(digest.algorithm()))
+        } else {
+            None
+        };
+        let stream = stream.inspect(|chunk| {
+            if let (Some(hasher), Ok(chunk)) = (hasher.as_mut(), chunk) {
+                hasher.update(chunk);
+            }
+            if let (Some(progress), Ok(chunk)) = (progress.as_mut(), chunk) {
+                progress.advance(chunk.len());
+            }
+        });
+
+        let started = Instant::now();
+        let bytes = write_stream(
+            stream,
+            out,
+            cancel,
+            budget,
+            self.download_limiter.as_deref(),
+            self.config.read_timeout,
+        )
+        .await
+        .with_context(|| format!("Failed to download blob {} from {}", digest, url))?;
+        if let Some(hasher) = hasher {
+            let actual = hasher.finish();
+            if actual != digest {
+                return Err(ClientError::DigestMismatch {
+                    expected: digest.to_string(),
+                    actual: actual.to_string(),
+                }
+                .into());
+            }
+        }
+        let elapsed = started.elapsed();
+        debug!(
+            "Pulled layer {} ({} bytes) in {:?}, {:.1} KiB/s",
+            digest,
+            bytes,
+            elapsed,
+            bytes as f64 / 1024.0 / elapsed.as_secs_f64().max(0.001)
+        );
+        Ok(())
+    }
+
+    /// Begins a session to push an image to registry
+    ///
+    /// Returns URL with session UUID
+    async fn begin_push_session(&self, image: &Reference) -> anyhow::Result<String> {
+        let url = &self.to_v2_blob_upload_url(image);
+        let mut headers = self.auth_headers(image);
+        headers.insert("Content-Length", "0".parse().unwrap());
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(image.registry(), self.client.post(url).headers(headers))
+            .await
+            .with_context(|| format!("Failed to start upload at {}", url))?;
+
+        // OCI spec requires the status code be 202 Accepted to successfully begin the push process
+        self.extract_location_header(&image, res, &reqwest::StatusCode::ACCEPTED)
+            .await
+    }
+
+    /// Closes the push session
+    ///
+    /// Returns the pullable URL for the image
+    async fn end_push_session(
+        &self,
+        location: &str,
+        image: &Reference,
+        digest: &str,
+    ) -> anyhow::Result<String> {
+        let url = format!("{}&digest={}", location, digest);
+        let mut close_headers = self.auth_headers(image);
+        close_headers.insert("Content-Length", "0".parse().unwrap());
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.put(&url).headers(close_headers),
+            )
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to complete upload of blob {} at {}",
+                    digest, location
+                )
+            })?;
+        self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
+            .await
+    }
+
+    /// Pushes a single layer (blob) of an image to registry
+    ///
+    /// The layer is sent in one PATCH request, or in several consecutive
+    /// ones of at most `max_chunk_size` bytes if that is set.
+    ///
+    /// Returns the URL location for the next layer
+    async fn push_layer(
+        &self,
+        location: &str,
+        image: &Reference,
+        layer: Vec<u8>,
+        start_byte: usize,
+    ) -> anyhow::Result<(String, usize)> {
+        if layer.is_empty() {
+            return Err(anyhow::anyhow!("cannot push a layer without data"));
+        };
+        let chunk_size = match self.config.max_chunk_size {
+            Some(size) if size > 0 && size < layer.len() => size,
+            _ => return self.push_chunk(location, image, layer, start_byte).await,
+        };
+        let mut location = location.to_owned();
+        let mut next_byte = start_byte;
+        for chunk in layer.chunks(chunk_size) {
+            let (next_location, next) = self
+                .push_chunk(&location, image, chunk.to_vec(), next_byte)
+                .await?;
+            location = next_location;
+            next_byte = next;
+        }
+        Ok((location, next_byte))
+    }
+
+    /// Uploads one range of a blob with a PATCH request
+    ///
+    /// Returns the location to upload the next range to and where it starts
+    async fn push_chunk(
+        &self,
+        location: &str,
+        image: &Reference,
+        layer: Vec<u8>,
+        start_byte: usize,
+    ) -> anyhow::Result<(String, usize)> {
+        let end_byte = start_byte + layer.len() - 1;
+        let mut headers = self.auth_headers(image);
+        headers.insert(
+            "Content-Range",
+            format!("{}-{}", start_byte, end_byte).parse().unwrap(),
+        );
+        headers.insert(
+            "Content-Length",
+            format!("{}", layer.len()).parse().unwrap(),
+        );
+        headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send(
+                image.registry(),
+                self.client.patch(location).headers(headers).body(layer),
+            )
+            .await
+            .with_context(|| format!("Failed to upload layer data to {}", location))?;
+
+        if is_range_rejection(res.status()) {
+            return Err(ClientError::ContentRangeUnsupported(res.status().as_u16()).into());
+        }
+
+        // Returns location for next chunk and the start byte for the next range
+        Ok((
+            self.extract_location_header(&image, res, &reqwest::StatusCode::ACCEPTED)
+                .await?,
+            end_byte + 1,
+        ))
+    }
+
+    /// Pushes a blob in a single POST request, including the digest
+    ///
+    /// This is used for zero-length blobs, which the chunked PATCH flow
+    /// cannot express.
+    ///
+    /// Returns the pullable location of the blob
+    async fn push_monolithic(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        digest: &str,
+    ) -> anyhow::Result<String> {
+        let res = self.post_blob(image, data, digest).await?;
+        self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
+            .await
+    }
+
+    /// Pushes a blob in a single POST request, finishing the upload in
+    /// chunks if the registry does not complete it
+    ///
+    /// Registries without monolithic uploads ignore the data and answer
+    /// with 202 Accepted and the location of a new upload session instead
+    /// of 201 Created. The data is checked against the digest first.
+    ///
+    /// Returns the pullable location of the blob
+    async fn push_blob_monolithic(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        digest: &str,
+    ) -> anyhow::Result<String> {
+        verify_blob(digest, data)?;
+        let res = self.post_blob(image, data, digest).await?;
+        if res.status() != reqwest::StatusCode::ACCEPTED {
+            return self
+                .extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
+                .await;
+        }
+        debug!(
+            "Registry started an upload session for blob {} instead of storing it, uploading it in chunks",
+            digest
+        );
+        let location = self
+            .extract_location_header(&image, res, &reqwest::StatusCode::ACCEPTED)
+            .await?;
+        let end_location = if data.is_empty() {
+            location
+        } else {
+            self.push_layer(&location, image, data.to_vec(), 0).await?.0
+        };
+        self.end_push_session(&end_location, image, digest).await
+    }
+
+    /// Sends a blob and its digest in a single POST request
+    async fn post_blob(
+        &self,
+        image: &Reference,
+        data: &[u8],
+        digest: &str,
+    ) -> anyhow::Result<reqwest::Response> {
+        let url = format!("{}?digest={}", self.to_v2_blob_upload_url(image), digest);
+        let mut headers = self.auth_headers(image);
+        headers.insert(
+            "Content-Length",
+            format!("{}", data.len()).parse().unwrap(),
+        );
+        headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
+
+        let _permit = self.request_permit().await;
+        self.send(
+            image.registry(),
+            self.client.post(&url).headers(headers).body(data.to_vec()),
+        )
+        .await
+        .with_context(|| format!("Failed to upload blob {} to {}", digest, url))
+    }
+
+    /// Pushes the config as a blob to the registry, unless the repository
+    /// already has it
+    ///
+    /// Configs are small, so they are sent in a single request where the
+    /// registry allows it. The digest is recorded in `response` as uploaded
+    /// or skipped.
+    async fn push_config(
+        &self,
+        image: &Reference,
+        config_data: &[u8],
+        config_digest: &str,
+        response: &mut PushResponse,
+    ) -> anyhow::Result<()> {
+        if self.blob_exists(image, config_digest).await? {
+            debug!("Config {} already exists, skipping upload", config_digest);
+            response.skipped.push(config_digest.to_owned());
+            return Ok(());
+        }
+        self.push_blob_monolithic(image, config_data, config_digest)
+            .await?;
+        response.uploaded.push(config_digest.to_owned());
+        Ok(())
+    }
+
+    /// Pushes the manifest, re-pushing any blobs the registry reports as
+    /// unknown and retrying once
+    ///
+    /// Registries that commit blobs with eventual consistency can briefly
+    /// reject a manifest whose blobs were just uploaded. Digests of re-pushed
+    /// blobs are added to `uploaded` if they are not already listed.
+    async fn push_manifest_repairing_blobs(
+        &self,
+        image: &Reference,
+        manifest: &OciManifest,
+        image_data: &ImageData,
+        config_data: &[u8],
+        uploaded: &mut Vec<String>,
+    ) -> anyhow::Result<String> {
+        let digests = match self.push_manifest(image, manifest).await {
+            Err(e) => match e.downcast_ref::<ClientError>() {
+                Some(ClientError::ManifestBlobUnknown(digests)) => digests.clone(),
+                _ => return Err(e),
+            },
+            result => return result,
+        };
+
+        warn!(
+            "Registry reported blobs {:?} unknown for manifest of {:?}, pushing them again",
+            digests, image
+        );
+        for (data, digest) in blobs_for_digests(&digests, image_data, config_data)? {
+            self.push_single_blob(image, data, &digest).await?;
+            if !uploaded.contains(&digest) {
+                uploaded.push(digest);
+            }
+        }
+        self.push_manifest(image, manifest).await
+    }
+
+    /// Pushes the manifest for a specified image
+    ///
+    /// Returns pullable manifest URL
+    async fn push_manifest(
+        &self,
+        image: &Reference,
+        manifest: &OciManifest,
+    ) -> anyhow::Result<String> {
+        let url = self.to_v2_manifest_url(image);
+
+        let mut headers = self.auth_headers(image);
+        headers.insert(
+            "Content-Type",
+            manifest_content_type(manifest).parse().unwrap(),
+        );
+
+        let _permit = self.request_permit().await;
+        let res = self
+            .send_idempotent(
+                image.registry(),
+                self.client
+                    .put(&url)
+                    .headers(headers)
+                    .body(serde_json::to_string(manifest)?),
+            )
+            .await
+            .with_context(|| format!("Failed to push manifest to {}", url))?;
+
+        if res.status() == reqwest::StatusCode::BAD_REQUEST {
+            let body = res.text().await?;
+            let missing = serde_json::from_str::<OciEnvelope>(&body)
+                .ok()
+                .and_then(|envelope| envelope.manifest_blob_unknown_digests());
+            return match missing {
+                Some(digests) => Err(ClientError::ManifestBlobUnknown(digests).into()),
+                None => Err(registry_error(
+                    reqwest::StatusCode::BAD_REQUEST,
+                    &body,
+                    &url,
+                )),
+            };
+        }
+
+        self.extract_location_header(&image, res, &reqwest::StatusCode::CREATED)
+            .await
+    }
+
+    async fn extract_location_header(
+        &self,
+        image: &Reference,
+        res: reqwest::Response,
+        expected_status: &reqwest::StatusCode,
+    ) -> anyhow::Result<String> {
+        if res.status().eq(expected_status) {
+            let location_header = res.headers().get("Location");
+            match location_header {
+                None => Err(anyhow::anyhow!("registry did not return a location header")),
+                Some(lh) => self.location_header_to_url(&image, &lh),
+            }
+        } else if res.status().is_client_error() {
+            let status = res.status();
+            let url = res.url().to_string();
+            Err(registry_error(status, &res.text().await?, &url))
+        } else {
+            Err(anyhow::anyhow!(
+                "An unexpected error occured: code={}, message='{}'",
+                res.status(),
+                res.text().await?
+            ))
+        }
+    }
+
+    /// Helper function to convert location header to URL
+    ///
+    /// Location may be absolute (containing the protocol and/or hostname), or relative (containing just the URL path)
+    /// Returns a properly formatted absolute URL
+    fn location_header_to_url(
+        &self,
+        image: &Reference,
+        location_header: &reqwest::header::HeaderValue,
+    ) -> anyhow::Result<String> {
+        let lh = location_header.to_str().map_err(anyhow::Error::new)?;
+        Ok(self.to_absolute_url(image.registry(), lh))
+    }
+
+    /// Resolves a URL that a registry returned, which may be just the path
+    /// of a URL on the registry itself
+    fn to_absolute_url(&self, registry: &str, url: &str) -> String {
+        if url.starts_with("/v2/") {
+            format!("{}{}", self.to_v2_url(registry), &url["/v2/".len()..])
+        } else {
+            url.to_string()
+        }
+    }
+
+    /// The URL of the token endpoint and the service name that a registry's
+    /// Bearer challenge points at
+    ///
+    /// A realm may be an absolute URL, a host and path without a scheme, such
+    /// as `auth.example.com:5001/token`, or a path relative to the registry,
+    /// such as `/token`. Realms without a scheme use the one the registry is
+    /// reached with. The service defaults to the registry host.
+    fn token_endpoint(
+        &self,
+        registry: &str,
+        challenge: &BearerChallenge,
+    ) -> anyhow::Result<(String, String)> {
+        let realm = challenge
+            .realm
+            .as_deref()
+            .filter(|r| !r.is_empty())
+            .ok_or_else(|| ClientError::MissingRealm(registry.to_owned()))?;
+        let scheme = self.config.scheme_for(registry);
+        let realm = if realm.contains("://") {
+            realm.to_owned()
+        } else if realm.starts_with('/') {
+            format!("{}://{}{}", scheme, registry.trim_matches('/'), realm)
+        } else if looks_like_host(realm.split('/').next().unwrap_or_default()) {
+            format!("{}://{}", scheme, realm)
+        } else {
+            format!("{}://{}/{}", scheme, registry.trim_matches('/'), realm)
+        };
+        let service = challenge
+            .service
+            .clone()
+            .unwrap_or_else(|| registry.to_owned());
+        Ok((realm, service))
+    }
+
+    /// The base URL of a registry's v2 API, ending in `/v2/`
+    ///
+    /// Stray slashes around the host, as in `registry.example.com/`, are
+    /// dropped.
+    fn to_v2_url(&self, registry: &str) -> String {
+        let registry = registry.trim_matches('/');
+        format!("{}://{}/v2/", self.config.scheme_for(registry), registry)
+    }
+
+    /// Convert a Reference to a v2 manifest URL.
+    fn to_v2_manifest_url(&self, reference: &Reference) -> String {
+        let tag_or_digest = match reference.digest() {
+            Some(digest) => digest,
+            None => reference.tag().unwrap_or("latest"),
+        };
+        format!(
+            "{}{}/manifests/{}",
+            self.to_v2_url(reference.registry()),
+            normalize_repository(reference.repository()),
+            tag_or_digest,
+        )
+    }
+
+    /// The URL listing the repositories of a registry
+    fn to_v2_catalog_url(&self, registry: &str) -> String {
+        format!("{}_catalog", self.to_v2_url(registry))
+    }
+
+    /// The URL listing the tags of the reference's repository
+    fn to_v2_tags_url(&self, reference: &Reference) -> String {
+        format!(
+            "{}{}/tags/list",
+            self.to_v2_url(reference.registry()),
+            normalize_repository(reference.repository()),
+        )
+    }
+
+    /// Convert a Reference to a v2 blob (layer) URL.
+    fn to_v2_blob_url(&self, registry: &str, repository: &str, digest: &Digest) -> String {
+        self.to_v2_blobs_url(registry, repository, &digest.to_string())
+    }
+
+    /// Builds a URL under a repository's `/v2/<name>/blobs/` path
+    fn to_v2_blobs_url(&self, registry: &str, repository: &str, path: &str) -> String {
+        format!(
+            "{}{}/blobs/{}",
+            self.to_v2_url(registry),
+            normalize_repository(repository),
+            path,
+        )
+    }
+
+    /// Convert a Reference to a v2 blob upload URL.
+    ///
+    /// This follows the specification's `/v2/<name>/blobs/uploads/` form
+    /// unless the registry is configured to drop the trailing slash.
+    fn to_v2_blob_upload_url(&self, reference: &Reference) -> String {
+        let without_slash = self
+            .config
+            .registry_overrides
+            .get(reference.registry())
+            .map_or(false, |o| o.upload_url_without_trailing_slash);
+        let uploads = if without_slash { "uploads" } else { "uploads/" };
+        self.to_v2_blobs_url(&reference.registry(), &reference.repository(), uploads)
+    }
+
+    /// Sends a request to a registry through the registry's circuit breaker,
+    /// if circuit breaking is enabled
+    ///
+    /// Connection failures, timeouts, and server errors count as failures
+    /// of the registry. Any other response closes the breaker.
+    ///
+    /// Only GET and HEAD requests are retried after a failure. Use
+    /// `send_idempotent` for other requests that are safe to send twice.
+    async fn send(
+        &self,
+        registry: &str,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_with(registry, request, false).await
+    }
+
+    /// Sends a request like `send`, retrying it after a failure whatever its
+    /// method, because sending it twice has the same effect as sending it once
+    async fn send_idempotent(
+        &self,
+        registry: &str,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_with(registry, request, true).await
+    }
+
+    async fn send_with(
+        &self,
+        registry: &str,
+        request: reqwest::RequestBuilder,
+        idempotent: bool,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut request = request.build()?;
+        // Renew an expired token before sending rather than waiting for the
+        // registry to refuse it
+        if request
+            .headers()
+            .contains_key(reqwest::header::AUTHORIZATION)
+        {
+            if let Some((authentication, token_request)) = self.expired_token_request(registry) {
+                debug!("Token for registry {} has expired, renewing it", registry);
+                let header = self
+                    .renew_token(registry, &token_request, &authentication)
+                    .await?;
+                request
+                    .headers_mut()
+                    .insert(reqwest::header::AUTHORIZATION, header);
+            }
+        }
+        // Requests with streamed bodies cannot be sent again
+        let retry = request.try_clone();
+        let res = self.dispatch(registry, request, idempotent).await?;
+        if res.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(res);
+        }
+        let mut retry = match retry {
+            Some(retry) => retry,
+            None => return Ok(res),
+        };
+
+        // Ask for a new token once, with the credentials the current token
+        // was obtained with. When the token lacks a scope the request needs,
+        // the registry says which; otherwise the token has most likely
+        // expired or been revoked, so ask for another like it.
+        let (authentication, cached_request) = match self.tokens.lock().unwrap().get(registry) {
+            Some(cached) => (cached.auth.clone(), cached.token.request().cloned()),
+            None => (RegistryAuth::Anonymous, None),
+        };
+        let token_request = match (insufficient_scope_challenge(res.headers()), cached_request) {
+            (Some(challenge), cached) if challenge.realm.is_some() && challenge.scope.is_some() => {
+                let required = challenge.scope.as_deref().unwrap_or_default();
+                debug!(
+                    "Registry {} requires scope {}, requesting a new token",
+                    registry, required
+                );
+                // The new token replaces the cached one, so it must keep the
+                // scopes the cached one was granted as well
+                let scope = match cached {
+                    Some(cached) => union_scopes(&cached.scope, required),
+                    None => required.to_owned(),
+                };
+                let (realm, service) = self.token_endpoint(registry, &challenge)?;
+                TokenRequest {
+                    realm,
+                    service: Some(service),
+                    scope,
+                }
+            }
+            (_, Some(token_request)) => {
+                debug!(
+                    "Registry {} refused the token, requesting a new one",
+                    registry
+                );
+                token_request
+            }
+            _ => return Ok(res),
+        };
+        let header = match self
+            .renew_token(registry, &token_request, &authentication)
+            .await
+        {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("Failed to renew token for registry {}: {:?}", registry, e);
+                return Ok(res);
+            }
+        };
+        retry
+            .headers_mut()
+            .insert(reqwest::header::AUTHORIZATION, header);
+        self.dispatch(registry, retry, idempotent).await
+    }
+
+    /// Returns the credentials and token request to renew the registry's
+    /// token with, if the cached token has expired
+    fn expired_token_request(&self, registry: &str) -> Option<(RegistryAuth, TokenRequest)> {
+        let tokens = self.tokens.lock().unwrap();
+        let cached = tokens.get(registry)?;
+        if !cached.token.is_expired(Instant::now()) {
+            return None;
+        }
+        let token_request = cached.token.request()?.clone();
+        Some((cached.auth.clone(), token_request))
+    }
+
+    /// Requests a new token for a registry and caches it, returning the
+    /// `Authorization` header to send it in
+    async fn renew_token(
+        &self,
+        registry: &str,
+        token_request: &TokenRequest,
+        authentication: &RegistryAuth,
+    ) -> anyhow::Result<reqwest::header::HeaderValue> {
+        let token = self
+            .request_token(
+                registry,
+                &token_request.realm,
+                token_request.service.as_deref(),
+                &token_request.scope,
+                authentication,
+            )
+            .await?;
+        let header = token.authorization_header();
+        self.store_token(registry, authentication, RegistryTokenType::Bearer(token));
+        Ok(header)
+    }
+
+    /// Sends a request to a registry through its circuit breaker, if any
+    ///
+    /// Failed and rate limited requests are sent again if they are GET or HEAD
+    /// requests, or are marked `idempotent`. Others may have taken effect
+    /// before failing, so sending them again could repeat that effect.
+    async fn dispatch(
+        &self,
+        registry: &str,
+        mut request: reqwest::Request,
+        idempotent: bool,
+    ) -> anyhow::Result<reqwest::Response> {
+        let retryable = idempotent
+            || matches!(
+                *request.method(),
+                reqwest::Method::GET | reqwest::Method::HEAD
+            );
+        let mut backoff = self.config.retry.as_ref().map(RetryPolicy::backoff);
+        let mut rate_limit_retries = self.config.rate_limit_retries;
+        let mut attempt = 1;
+        loop {
+            let retry = if retryable && (backoff.is_some() || rate_limit_retries > 0) {
+                request.try_clone()
+            } else {
+                None
+            };
+            let result = self.dispatch_once(registry, request).await;
+            if let Ok(res) = &result {
+                if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    let retry_after = retry_after(res.headers(), chrono::Utc::now());
+                    let wait = retry_after.unwrap_or(DEFAULT_RATE_LIMIT_WAIT);
+                    request = match retry {
+                        Some(retry)
+                            if rate_limit_retries > 0
+                                && wait <= self.config.max_rate_limit_wait =>
+                        {
+                            warn!(
+                                "Registry {} is rate limiting requests, retrying in {:?}",
+                                registry, wait
+                            );
+                            tokio::time::delay_for(wait).await;
+                            retry
+                        }
+                        _ => {
+                            return Err(ClientError::RateLimited {
+                                registry: registry.to_owned(),
+                                retry_after,
+                            }
+                            .into())
+                        }
+                    };
+                    rate_limit_retries -= 1;
+                    attempt += 1;
+                    continue;
+                }
+            }
+            let reason = match &result {
+                Ok(res) if is_transient_status(res.status()) => res.status().to_string(),
+                Err(e) if is_transient_failure(e) => format!("{:#}", e),
+                _ => return result,
+            };
+            let delay = backoff.as_mut().and_then(|b| b.next_delay());
+            request = match (retry, delay) {
+                (Some(retry), Some(delay)) => {
+                    warn!(
+                        "Request to registry {} failed on attempt {} ({}), retrying in {:?}",
+                        registry, attempt, reason, delay
+                    );
+                    tokio::time::delay_for(delay).await;
+                    retry
+                }
+                _ => {
+                    if attempt > 1 {
+                        warn!(
+                            "Request to registry {} failed on attempt {} ({}), giving up",
+                            registry, attempt, reason
+                        );
+                    }
+                    return result;
+                }
+            };
+            attempt += 1;
+        }
+    }
+
+    /// Sends a request once, through the registry's circuit breaker
+    async fn dispatch_once(
+        &self,
+        registry: &str,
+        request: reqwest::Request,
+    ) -> anyhow::Result<reqwest::Response> {
+        let breakers = match &self.circuit_breakers {
+            Some(b) => b,
+            None => {
+                return self
+                    .execute_request(request)
+                    .await
+                    .map_err(|e| transport_error(registry, e))
+            }
+        };
+        breakers.check(registry)?;
+        match self.execute_request(request).await {
+            Ok(res) => {
+                if res.status().is_server_error() {
+                    breakers.record_failure(registry);
+                } else {
+                    breakers.record_success(registry);
+                }
+                Ok(res)
+            }
+            Err(e) => {
+                if is_registry_failure(&e) {
+                    breakers.record_failure(registry);
+                }
+                Err(transport_error(registry, e))
+            }
+        }
+    }
+
+    /// Sends a request to a registry over the client's transport, bypassing
+    /// the circuit breaker
+    async fn execute(
+        &self,
+        registry: &str,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.execute_request(request.build()?)
+            .await
+            .map_err(|e| transport_error(registry, e))
+    }
+
+    /// Executes a request over the client's transport, failing with
+    /// `ClientError::TimedOut` if the connection or the response takes longer
+    /// than the configured timeouts
+    async fn execute_request(
+        &self,
+        request: reqwest::Request,
+    ) -> anyhow::Result<reqwest::Response> {
+        let response = self.transport.execute(request);
+        let result = match self.config.read_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, response).await {
+                Ok(result) => result,
+                Err(_) => return Err(ClientError::TimedOut(timeout).into()),
+            },
+            None => response.await,
+        };
+        result.map_err(|e| {
+            let connect_timed_out = e
+                .downcast_ref::<reqwest::Error>()
+                .map_or(false, |e| e.is_timeout());
+            match self.config.connect_timeout {
+                Some(timeout) if connect_timed_out => ClientError::TimedOut(timeout).into(),
+                _ => e,
+            }
+        })
+    }
+
+    /// Waits for a free request slot if `max_concurrent_requests` is set.
+    ///
+    /// The returned permit should be held until the response has been fully
+    /// read, so that streamed bodies count against the limit.
+    async fn request_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.request_limiter {
+            Some(limiter) => Some(limiter.clone().acquire_owned().await),
+            None => None,
+        }
+    }
+
+    /// Returns whether the client holds a token for the registry that was
+    /// obtained with the given credentials
+    ///
+    /// Tokens are cached per registry, so a token obtained for one registry
+    /// is never used for another, and different credentials for the same
+    /// registry, or an expired token, cause the client to authenticate
+    /// again.
+    fn has_token_for(&self, registry: &str, auth: &RegistryAuth) -> bool {
+        self.tokens
+            .lock()
+            .unwrap()
+            .get(registry)
+            .map(|cached| {
+                cached.credentials == credentials_fingerprint(auth)
+                    && !cached.token.is_expired(Instant::now())
+            })
+            .unwrap_or(false)
+    }
+
+    fn store_token(&self, registry: &str, auth: &RegistryAuth, token: RegistryTokenType) {
+        self.tokens.lock().unwrap().insert(
+            registry.to_owned(),
+            CachedToken {
+                token,
+                credentials: credentials_fingerprint(auth),
+                auth: auth.clone(),
+            },
+        );
+    }
+
+    /// Generate the headers necessary for authentication.
+    ///
+    /// If the struct has Some(bearer), this will insert the bearer token in an
+    /// Authorization header. It will also set the Accept header, which must
+    /// be set on all OCI Registry request.
+    fn auth_headers(&self, image: &Reference) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let accept = if self.config.manifest_media_types.is_empty() {
+            accept_header(MANIFEST_ACCEPT_TYPES)
+        } else {
+            accept_header(&self.config.manifest_media_types)
+        };
+        headers.insert("Accept", accept.parse().unwrap());
+
+        if let Some(cached) = self.tokens.lock().unwrap().get(image.registry()) {
+            match (&cached.token, &cached.auth) {
+                (RegistryTokenType::Bearer(token), _) => {
+                    headers.insert("Authorization", token.authorization_header());
+                }
+                (RegistryTokenType::Basic, RegistryAuth::Basic(username, password)) => {
+                    headers.insert(
+                        "Authorization",
+                        basic_authorization_header(username, password),
+                    );
+                }
+                _ => {}
+            }
+        }
+        headers
+    }
+}
+
+/// Creates a `reqwest::ClientBuilder` for the TLS backend selected by the
+/// crate features.
+///
+/// `native-tls` wins when it is enabled. When only `rustls-tls` is enabled,
+/// rustls is selected explicitly so that it is still used if some other crate
+/// in the build turns on reqwest's default TLS backend.
+#[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+fn http_client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().use_rustls_tls()
+}
+
+#[cfg(not(all(feature = "rustls-tls", not(feature = "native-tls"))))]
+fn http_client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+}
+
+/// A client configuration
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Which protocol the client should use
+    pub protocol: ClientProtocol,
+    /// The maximum number of requests the client will have in flight to
+    /// registries at once, across all operations.
+    ///
+    /// This includes auth, manifest, and blob requests. `None` means unlimited.
+    pub max_concurrent_requests: Option<usize>,
+    /// The maximum number of layers a single pull downloads at once.
+    ///
+    /// `None` or `Some(0)` downloads every layer of an image at once.
+    pub max_concurrent_downloads: Option<usize>,
+    /// Settings for individual registries, keyed by registry host.
+    ///
+    /// Any setting a registry's entry leaves unset falls back to the global
+    /// setting above.
+    pub registry_overrides: HashMap<String, RegistryConfig>,
+    /// How the client follows HTTP redirects, such as blob downloads that
+    /// redirect to a storage backend
+    pub redirect_policy: RedirectPolicy,
+    /// TLS settings for connections to registries, such as extra
+    /// certificates to trust for a private registry
+    pub tls: TlsConfig,
+    /// How long to wait for a connection to a registry to be established.
+    ///
+    /// `None` waits as long as the operating system does.
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for a registry to respond to a request, and for each
+    /// chunk of a downloaded blob.
+    ///
+    /// This bounds the time spent waiting on a registry that has stopped
+    /// sending, not the time a large download takes. `None` waits forever.
+    pub read_timeout: Option<Duration>,
+    /// How long a `pull` may take as a whole, including authentication,
+    /// retries and mirrors.
+    ///
+    /// `None` means no deadline.
+    pub pull_timeout: Option<Duration>,
+    /// The URL of an HTTP proxy to send registry requests through, such as
+    /// `http://proxy.example.com:3128`.
+    ///
+    /// `None` leaves it to the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY`
+    /// environment variables. Setting a proxy here makes the client ignore
+    /// them. Registry URLs keep the scheme that `protocol` selects, whether
+    /// or not they go through the proxy.
+    pub proxy: Option<String>,
+    /// Registry hosts to reach directly rather than through `proxy`. An entry
+    /// matches the host itself and its subdomains, and `*` matches every
+    /// host.
+    pub no_proxy: Vec<String>,
+    /// The credentials to give `proxy`, for proxies that require
+    /// authentication
+    pub proxy_credentials: Option<ProxyCredentials>,
+    /// The maximum number of downloaded bytes that `pull_to` holds in memory
+    /// before they are written to their sinks, across all layers.
+    ///
+    /// `None` means unlimited.
+    pub max_buffered_bytes: Option<usize>,
+    /// Decompress pulled layers whose media type has a registered
+    /// decompressor, storing them with the decompressed media type.
+    ///
+    /// This applies to `pull`. `pull_to` always writes layers as the registry
+    /// stores them.
+    pub decompress_layers: bool,
+    /// The decompressors consulted when `decompress_layers` is set
+    pub decompressors: Decompressors,
+    /// Called as layer data is downloaded, to report the progress of pulls
+    pub progress: Option<ProgressReporter>,
+    /// Allow pulling artifact manifests that have no layers, such as
+    /// metadata-only artifacts.
+    ///
+    /// A manifest is an artifact if it declares an `artifactType` or has the
+    /// empty config. Image manifests without layers are always rejected.
+    pub allow_empty_layers: bool,
+    /// Stop sending requests to a registry for a while after it fails
+    /// repeatedly, failing them with `ClientError::RegistryUnavailable`
+    /// instead.
+    ///
+    /// `None` disables circuit breaking.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Retry requests that fail with a gateway or unavailability error
+    /// (502, 503 or 504) or that cannot reach the registry, waiting between
+    /// attempts as the policy says.
+    ///
+    /// Other responses, including all 4xx errors, are never retried, nor are
+    /// requests whose body is streamed. Only requests that are safe to send
+    /// twice are retried: reads and manifest uploads, but not blob uploads or
+    /// deletions. `None`, or a policy with
+    /// `max_retries` of zero, sends each request once.
+    pub retry: Option<RetryPolicy>,
+    /// How many times a request that the registry refuses with `429 Too Many
+    /// Requests` is sent again, after waiting as long as the registry's
+    /// `Retry-After` header asks.
+    ///
+    /// A request still refused after that fails with
+    /// `ClientError::RateLimited`. Zero fails it at once. As with `retry`,
+    /// only requests that are safe to send twice are sent again.
+    pub rate_limit_retries: u32,
+    /// The longest the client will wait for a rate limit to clear before
+    /// retrying. A request asked to wait longer fails with
+    /// `ClientError::RateLimited` instead.
+    pub max_rate_limit_wait: Duration,
+    /// The most bytes per second the client will download layers at, across
+    /// all concurrent pulls. This keeps image pulls from saturating a link
+    /// shared with other traffic.
+    ///
+    /// `None` means unlimited.
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// The manifest media types to ask registries for, most preferred first.
+    ///
+    /// They are sent in the `Accept` header with descending quality values,
+    /// so that a registry holding several forms of an image, such as a
+    /// Docker manifest list and an OCI index, serves the preferred one. An
+    /// empty list asks for Docker manifests, Docker manifest lists, and OCI
+    /// manifests, in that order.
+    pub manifest_media_types: Vec<String>,
+    /// Check that pulled blobs hash to the digest they were requested by,
+    /// failing the pull with `ClientError::DigestMismatch` if they do not.
+    ///
+    /// This is on by default. Individual pulls can override it, for example
+    /// to skip the hashing for a trusted internal mirror.
+    pub verify_digests: bool,
+    /// The platform whose image is pulled when a reference points at an
+    /// image index or a Docker manifest list.
+    ///
+    /// `None` means the platform the client runs on. `pull_for_platform`
+    /// chooses the platform for a single pull.
+    pub platform: Option<Platform>,
+    /// The most bytes to send in each PATCH request of a chunked blob
+    /// upload.
+    ///
+    /// Larger layers are split into consecutive ranges, which keeps request
+    /// bodies under the limits some registries and proxies impose. `None`
+    /// sends each layer in a single request.
+    pub max_chunk_size: Option<usize>,
+    /// Mirrors to pull from when a registry is unavailable, keyed by the
+    /// registry host they mirror.
+    ///
+    /// Pulls that fail to reach the registry, or that it answers with a
+    /// server error, are tried against its mirrors in order. Only pulls by
+    /// digest are mirrored unless `mirror_tags` is set. Mirrors are accessed
+    /// anonymously: the credentials given for a pull are only ever sent to
+    /// the registry itself.
+    pub mirrors: HashMap<String, Vec<String>>,
+    /// Also fall back to mirrors for pulls by tag. Mirrors that lag behind
+    /// the registry may resolve a tag to an older image.
+    pub mirror_tags: bool,
+    /// Repositories on the registry being pushed to that may already hold
+    /// the layers of pushed images, such as the repository of a shared base
+    /// image.
+    ///
+    /// Before uploading, `push` asks the registry to mount each layer from
+    /// these repositories in order, and only uploads the layers none of them
+    /// have.
+    pub mount_from: Vec<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            protocol: ClientProtocol::default(),
+            max_concurrent_requests: None,
+            max_concurrent_downloads: None,
+            registry_overrides: HashMap::new(),
+            redirect_policy: RedirectPolicy::default(),
+            tls: TlsConfig::default(),
+            connect_timeout: None,
+            read_timeout: None,
+            pull_timeout: None,
+            proxy: None,
+            no_proxy: Vec::new(),
+            proxy_credentials: None,
+            max_buffered_bytes: None,
+            decompress_layers: false,
+            decompressors: Decompressors::default(),
+            progress: None,
+            allow_empty_layers: false,
+            circuit_breaker: None,
+            retry: None,
+            rate_limit_retries: 0,
+            max_rate_limit_wait: Duration::from_secs(60),
+            max_download_bytes_per_sec: None,
+            manifest_media_types: Vec::new(),
+            verify_digests: true,
+            platform: None,
+            max_chunk_size: None,
+            mirrors: HashMap::new(),
+            mirror_tags: false,
+            mount_from: Vec::new(),
+        }
+    }
+}
+
+/// How the client follows HTTP redirects
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedirectPolicy {
+    /// Follow at most this many redirects in a chain
+    Limited(usize),
+    /// Never follow redirects, returning the redirect response itself
+    None,
+}
+
+impl Default for RedirectPolicy {
+    /// Matches reqwest's default of following up to 10 redirects
+    fn default() -> Self {
+        RedirectPolicy::Limited(10)
+    }
+}
+
+impl RedirectPolicy {
+    fn to_reqwest(&self) -> reqwest::redirect::Policy {
+        match self {
+            RedirectPolicy::Limited(max) => reqwest::redirect::Policy::limited(*max),
+            RedirectPolicy::None => reqwest::redirect::Policy::none(),
+        }
+    }
+}
+
+/// TLS settings for connections to registries
+///
+/// These apply to clients created with `Client::new` or `Client::try_new`.
+/// A `reqwest::Client` passed to `Client::new_with_client` keeps its own.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// PEM-encoded certificates to trust in addition to the system's root
+    /// certificates, such as the CA that signed a private registry's
+    /// certificate
+    pub extra_root_certificates: Vec<Vec<u8>>,
+    /// The certificate and key to present to registries that require mutual
+    /// TLS
+    pub client_identity: Option<ClientIdentity>,
+    /// Accept any server certificate, including self-signed, expired and
+    /// mismatched ones.
+    ///
+    /// This is for development only. It lets anyone who can intercept the
+    /// connection impersonate the registry, so it is off by default; trust a
+    /// private CA with `extra_root_certificates` instead.
+    pub danger_accept_invalid_certs: bool,
+}
+
+impl TlsConfig {
+    fn apply(&self, mut builder: reqwest::ClientBuilder) -> anyhow::Result<reqwest::ClientBuilder> {
+        for (i, pem) in self.extra_root_certificates.iter().enumerate() {
+            let certificate = reqwest::Certificate::from_pem(pem)
+                .with_context(|| format!("Failed to parse extra root certificate {}", i))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        if let Some(identity) = &self.client_identity {
+            builder = builder.identity(identity.to_reqwest()?);
+        }
+        if self.danger_accept_invalid_certs {
+            warn!("TLS certificate validation is disabled, registries will not be authenticated");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
+/// A client certificate and private key for mutual TLS
+///
+/// Which form can be used depends on the TLS backend the crate is built
+/// with.
+#[derive(Clone)]
+pub enum ClientIdentity {
+    /// A PEM file holding the private key and certificate chain. This needs
+    /// the `rustls-tls` feature.
+    Pem(Vec<u8>),
+    /// A DER-encoded PKCS #12 archive and the password that protects it.
+    /// This needs the `native-tls` feature.
+    Pkcs12 {
+        /// The archive
+        der: Vec<u8>,
+        /// The password of the archive
+        password: String,
+    },
+}
+
+impl ClientIdentity {
+    fn to_reqwest(&self) -> anyhow::Result<reqwest::Identity> {
+        match self {
+            #[cfg(feature = "rustls-tls")]
+            ClientIdentity::Pem(pem) => {
+                reqwest::Identity::from_pem(pem).context("Failed to parse client identity")
+            }
+            #[cfg(feature = "native-tls")]
+            ClientIdentity::Pkcs12 { der, password } => {
+                reqwest::Identity::from_pkcs12_der(der, password)
+                    .context("Failed to parse client identity")
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(anyhow::anyhow!(
+                "the client identity is not supported by the TLS backend the crate was built with"
+            )),
+        }
+    }
+}
+
+// Keeps key material and passwords out of logs
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientIdentity::Pem(_) => write!(f, "ClientIdentity::Pem(..)"),
+            ClientIdentity::Pkcs12 { .. } => write!(f, "ClientIdentity::Pkcs12 {{ .. }}"),
+        }
+    }
+}
+
+impl ClientConfig {
+    fn scheme_for(&self, registry: &str) -> &str {
+        match self
+            .registry_overrides
+            .get(registry)
+            .and_then(|o| o.protocol.as_ref())
+        {
+            Some(protocol) => protocol.scheme_for(registry),
+            None => self.protocol.scheme_for(registry),
+        }
+    }
+
+    fn is_public(&self, registry: &str) -> bool {
+        self.registry_overrides
+            .get(registry)
+            .map_or(false, |o| o.public)
+    }
+
+    /// The proxy that `proxy`, `no_proxy` and `proxy_credentials` describe,
+    /// if a proxy is set
+    fn reqwest_proxy(&self) -> anyhow::Result<Option<reqwest::Proxy>> {
+        let proxy_url = match &self.proxy {
+            Some(url) => reqwest::Url::parse(url)
+                .with_context(|| format!("Failed to parse proxy URL {}", url))?,
+            None => return Ok(None),
+        };
+        let no_proxy = self.no_proxy.clone();
+        let mut proxy = reqwest::Proxy::custom(move |url| {
+            let host = url.host_str().unwrap_or_default();
+            if bypasses_proxy(host, &no_proxy) {
+                None
+            } else {
+                Some(proxy_url.clone())
+            }
+        });
+        if let Some(credentials) = &self.proxy_credentials {
+            proxy = proxy.basic_auth(&credentials.username, &credentials.password);
+        }
+        Ok(Some(proxy))
+    }
+}
+
+/// The username and password for an authenticating proxy
+#[derive(Clone)]
+pub struct ProxyCredentials {
+    /// The username
+    pub username: String,
+    /// The password
+    pub password: String,
+}
+
+// Keeps the password out of logs
+impl std::fmt::Debug for ProxyCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyCredentials")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+/// Returns whether requests to `host` skip the proxy, given the hosts in
+/// `ClientConfig::no_proxy`
+fn bypasses_proxy(host: &str, no_proxy: &[String]) -> bool {
+    no_proxy.iter().any(|entry| {
+        let entry = entry.trim().trim_start_matches('.');
+        entry == "*"
+            || host.eq_ignore_ascii_case(entry)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+    })
+}
+
+/// Settings that apply to a single registry
+#[derive(Debug, Clone, Default)]
+pub struct RegistryConfig {
+    /// Which protocol the client should use for this registry
+    pub protocol: Option<ClientProtocol>,
+    /// Start blob uploads at `/v2/<name>/blobs/uploads` instead of the
+    /// specification's `/v2/<name>/blobs/uploads/`, for registries that
+    /// answer 404 when the trailing slash is present
+    pub upload_url_without_trailing_slash: bool,
+    /// Assert that the registry serves anonymous requests without a token.
+    /// Anonymous requests then skip the `/v2/` request that would otherwise
+    /// detect this, saving a round trip. Requests with credentials still
+    /// authenticate as usual.
+    pub public: bool,
+}
+
+/// Keeps the locations of in-progress blob uploads, so that
+/// `Client::push_blob_resumable` can resume an interrupted upload
+///
+/// The caller decides where locations are kept. Keeping them in a file or
+/// database lets uploads resume after a restart.
+pub trait UploadStore: Send + Sync {
+    /// Saves the location of the upload with the given key, replacing any
+    /// previous one
+    fn save(&self, key: &str, location: &str) -> anyhow::Result<()>;
+    /// Loads the location of the upload with the given key, if one was saved
+    fn load(&self, key: &str) -> anyhow::Result<Option<String>>;
+    /// Removes the upload with the given key. Removing a missing upload is
+    /// not an error.
+    fn remove(&self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Where a push keeps the location of its upload session, so that an
+/// interrupted push can be resumed by a later one with the same key
+#[derive(Clone, Copy)]
+pub struct UploadSession<'a> {
+    /// Keeps the location of the session while the upload is in progress
+    pub store: &'a dyn UploadStore,
+    /// Identifies the push, acting as an idempotency key. Pushes of
+    /// different data must use different keys.
+    pub key: &'a str,
+}
+
+/// Optional settings for `Client::push_with_options`
+#[derive(Clone, Default)]
+pub struct PushOptions<'a> {
+    /// The manifest to push. If `None`, one is generated from the image and
+    /// config data, as `Client::push` does.
+    pub manifest: Option<OciManifest>,
+    /// Where to keep the upload sessions of the image layers, so that an
+    /// interrupted push can be resumed. Each layer is kept under the session
+    /// key followed by `/` and the layer digest.
+    pub session: Option<UploadSession<'a>>,
+    /// Only push if the reference does not already exist in the registry, as
+    /// `Client::push_if_absent` does
+    pub if_absent: bool,
+}
+
+/// An `UploadStore` that keeps locations in memory, so uploads can only be
+/// resumed by the same process
+#[derive(Debug, Default)]
+pub struct InMemoryUploadStore {
+    locations: std::sync::Mutex<HashMap<String, String>>,
+}
+
+impl UploadStore for InMemoryUploadStore {
+    fn save(&self, key: &str, location: &str) -> anyhow::Result<()> {
+        self.locations
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), location.to_owned());
+        Ok(())
+    }
+
+    fn load(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.locations.lock().unwrap().get(key).cloned())
+    }
+
+    fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.locations.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// A token used to cooperatively cancel a pull.
+///
+/// Clones share the same state, so cancelling any clone cancels them all.
+#[derive(Clone, Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    // Wakes the operations waiting in `cancelled` when the token is cancelled
+    sender: Arc<tokio::sync::watch::Sender<bool>>,
+    receiver: tokio::sync::watch::Receiver<bool>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        let (sender, receiver) = tokio::sync::watch::channel(false);
+        CancellationToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+
+impl CancellationToken {
+    /// Create a new token that has not been cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel every operation using this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Every receiver is held by a clone of this token, so the broadcast
+        // cannot fail
+        let _ = self.sender.broadcast(true);
+    }
+
+    /// Returns whether the token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the token is cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.receiver.clone();
+        while !self.is_cancelled() {
+            if receiver.recv().await.is_none() {
+                // The sender lives as long as this token, so this cannot
+                // happen, but a closed channel must not spin
+                future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// The protocol that the client should use to connect
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientProtocol {
+    #[allow(missing_docs)]
+    Http,
+    #[allow(missing_docs)]
+    Https,
+    #[allow(missing_docs)]
+    HttpsExcept(Vec<String>),
+}
+
+impl Default for ClientProtocol {
+    fn default() -> Self {
+        ClientProtocol::Https
+    }
+}
+
+impl ClientProtocol {
+    fn scheme_for(&self, registry: &str) -> &str {
+        match self {
+            ClientProtocol::Https => "https",
+            ClientProtocol::Http => "http",
+            ClientProtocol::HttpsExcept(exceptions) => {
+                if exceptions.contains(&registry.to_owned()) {
+                    "http"
+                } else {
+                    "https"
+                }
+            }
+        }
+    }
+}
+
+/// A token granted during the OAuth2-like workflow for OCI registries.
+///
+/// The `Debug` implementation redacts the token value so that tokens can be
+/// logged safely.
+#[derive(serde::Deserialize, Default)]
+struct RegistryToken {
+    #[serde(default)]
+    token: String,
+    /// The OAuth2 name for the token. Registries may send it as well as, or
+    /// instead of, `token`.
+    #[serde(default)]
+    access_token: Option<String>,
+    /// A token for getting new tokens, which is never sent as a bearer token
+    #[serde(default)]
+    refresh_token: Option<String>,
+    /// Lifetime of the token in seconds, if the registry reported one
+    #[serde(default)]
+    expires_in: Option<u64>,
+    /// Time at which the token was issued, if the registry reported it
+    #[serde(default)]
+    issued_at: Option<String>,
+    /// Scope granted to the token, if the registry reported it
+    #[serde(default)]
+    scope: Option<String>,
+    /// When the token stops being valid, worked out from `expires_in` and
+    /// `issued_at`. Tokens without a reported lifetime never expire.
+    #[serde(skip)]
+    expires_at: Option<Instant>,
+    /// The request the token was granted for, to request another like it
+    /// when it expires
+    #[serde(skip)]
+    request: Option<TokenRequest>,
+}
+
+/// How long before its expiry a token is renewed, so that it does not expire
+/// while a request is on its way
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(5);
+
+/// A request to a registry's token endpoint
+#[derive(Clone, Debug)]
+struct TokenRequest {
+    realm: String,
+    service: Option<String>,
+    /// The scopes asked for, separated by spaces
+    scope: String,
+}
+
+/// Adds `scope` to a space-separated list of scopes, unless it is already
+/// there
+fn union_scopes(scopes: &str, scope: &str) -> String {
+    let mut all: Vec<&str> = scopes.split_whitespace().collect();
+    for scope in scope.split_whitespace() {
+        if !all.contains(&scope) {
+            all.push(scope);
+        }
+    }
+    all.join(" ")
+}
+
+impl std::fmt::Debug for RegistryToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryToken")
+            .field("token", &"<redacted>")
+            .field(
+                "refresh_token",
+                &self.refresh_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("expires_in", &self.expires_in)
+            .field("issued_at", &self.issued_at)
+            .field("scope", &self.scope)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+impl RegistryToken {
+    /// Parses a token from the body of an auth response, rejecting responses
+    /// that do not carry a usable token value.
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut token: RegistryToken = serde_json::from_str(text)
+            .context("Failed to decode registry token from auth request")?;
+        if token.token.is_empty() {
+            token.token = token.access_token.take().unwrap_or_default();
+        }
+        if token.token.is_empty() {
+            return Err(anyhow::anyhow!("registry returned empty token"));
+        }
+        token.expires_at = token.expiry(Instant::now(), chrono::Utc::now());
+        Ok(token)
+    }
+
+    /// Works out when the token expires, given the current time
+    ///
+    /// The lifetime counts from `issued_at` when the registry reports it, so
+    /// that time spent getting the response does not extend it.
+    fn expiry(&self, now: Instant, utc_now: chrono::DateTime<chrono::Utc>) -> Option<Instant> {
+        let lifetime = Duration::from_secs(self.expires_in?);
+        let age = self
+            .issued_at
+            .as_ref()
+            .and_then(|issued_at| chrono::DateTime::parse_from_rfc3339(issued_at).ok())
+            .and_then(|issued_at| utc_now.signed_duration_since(issued_at).to_std().ok())
+            .unwrap_or_default();
+        Some(now + lifetime.checked_sub(age).unwrap_or_default())
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now + TOKEN_EXPIRY_MARGIN >= expires_at)
+    }
+
+    fn bearer_token(&self) -> String {
+        format!("Bearer {}", self.token)
+    }
+
+    /// Builds the `Authorization` header value for this token, marked as
+    /// sensitive so that it is not printed when headers are logged.
+    fn authorization_header(&self) -> reqwest::header::HeaderValue {
+        let mut value: reqwest::header::HeaderValue = self.bearer_token().parse().unwrap();
+        value.set_sensitive(true);
+        value
+    }
+}
+
+/// The outcome of authenticating against a registry
+#[derive(Debug)]
+enum RegistryTokenType {
+    /// The registry granted a bearer token
+    Bearer(RegistryToken),
+    /// The registry asked for the credentials to be sent with every request
+    /// using HTTP Basic authentication
+    Basic,
+    /// The registry did not ask for authentication
+    Anonymous,
+}
+
+impl RegistryTokenType {
+    fn is_expired(&self, now: Instant) -> bool {
+        match self {
+            RegistryTokenType::Bearer(token) => token.is_expired(now),
+            _ => false,
+        }
+    }
+
+    /// The request a bearer token was granted for
+    fn request(&self) -> Option<&TokenRequest> {
+        match self {
+            RegistryTokenType::Bearer(token) => token.request.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// The result of authenticating against a registry, along with the
+/// credentials that were used
+///
+/// The credentials are kept so that a token with another scope can be
+/// requested when a registry asks for one partway through an operation.
+struct CachedToken {
+    token: RegistryTokenType,
+    /// A fingerprint of the credentials, to compare against those of later
+    /// requests
+    credentials: String,
+    auth: RegistryAuth,
+}
+
+/// Identifies a set of credentials without holding on to the secret itself
+fn credentials_fingerprint(auth: &RegistryAuth) -> String {
+    match auth {
+        RegistryAuth::Anonymous => "anonymous".to_owned(),
+        RegistryAuth::Basic(username, password) => {
+            sha256_digest(format!("{}:{}", username, password).as_bytes())
+        }
+    }
+}
+
+/// Builds an `Authorization` header value that sends credentials with HTTP
+/// Basic authentication, marked as sensitive so that it is not logged
+fn basic_authorization_header(username: &str, password: &str) -> reqwest::header::HeaderValue {
+    let encoded = base64::encode(format!("{}:{}", username, password));
+    let mut value: reqwest::header::HeaderValue = format!("Basic {}", encoded).parse().unwrap();
+    value.set_sensitive(true);
+    value
+}
+
+/// Parses every `WWW-Authenticate` header of a response, if there are any
+fn www_authenticate(headers: &HeaderMap) -> anyhow::Result<Option<WwwAuthenticate>> {
+    let values: Vec<Vec<u8>> = headers
+        .get_all(reqwest::header::WWW_AUTHENTICATE)
+        .iter()
+        .map(|v| v.as_bytes().to_vec())
+        .collect();
+    if values.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(WwwAuthenticate::parse_header(&values.into())?))
+}
+
+/// Returns whether the first segment of a realm without a scheme names a host,
+/// such as `auth.example.com` or `localhost:5001`, rather than a path
+fn looks_like_host(segment: &str) -> bool {
+    segment.contains('.') || segment.contains(':') || segment == "localhost"
+}
+
+/// Extracts the Bearer challenge from the `WWW-Authenticate` headers, if any.
+fn bearer_challenge(headers: &HeaderMap) -> anyhow::Result<Option<BearerChallenge>> {
+    Ok(www_authenticate(headers)?
+        .and_then(|auth| auth.get::<BearerChallenge>())
+        .and_then(|challenges| challenges.into_iter().next()))
+}
+
+/// Extracts the Basic challenge from the `WWW-Authenticate` headers, if any.
+///
+/// Registries that do not issue tokens, such as some self-hosted registries
+/// behind a proxy, ask for credentials this way.
+fn basic_challenge(headers: &HeaderMap) -> anyhow::Result<Option<BasicChallenge>> {
+    Ok(www_authenticate(headers)?
+        .and_then(|auth| auth.get::<BasicChallenge>())
+        .and_then(|challenges| challenges.into_iter().next()))
+}
+
+/// Returns the challenge of a 401 response whose token lacked the scope the
+/// request needs
+fn insufficient_scope_challenge(headers: &HeaderMap) -> Option<BearerChallenge> {
+    bearer_challenge(headers)
+        .unwrap_or(None)
+        .filter(|c| c.error.as_deref() == Some("insufficient_scope"))
+}
+
+#[derive(Clone)]
+struct BearerChallenge {
+    pub realm: Option<String>,
+    pub service: Option<String>,
+    pub scope: Option<String>,
+    /// Why a request was refused, such as `insufficient_scope`
+    pub error: Option<String>,
+}
+
+impl Challenge for BearerChallenge {
+    fn challenge_name() -> &'static str {
+        "Bearer"
+    }
+
+    fn from_raw(raw: RawChallenge) -> Option<Self> {
+        match raw {
+            RawChallenge::Token68(_) => None,
+            RawChallenge::Fields(mut map) => Some(BearerChallenge {
+                realm: map.remove("realm"),
+                scope: map.remove("scope"),
+                service: map.remove("service"),
+                error: map.remove("error"),
+            }),
+        }
+    }
+
+    fn into_raw(self) -> RawChallenge {
+        let mut map = ChallengeFields::new();
+        if let Some(realm) = self.realm {
+            map.insert_static_quoting("realm", realm);
+        }
+        if let Some(scope) = self.scope {
+            map.insert_static_quoting("scope", scope);
+        }
+        if let Some(service) = self.service {
+            map.insert_static_quoting("service", service);
+        }
+        if let Some(error) = self.error {
+            map.insert_static_quoting("error", error);
+        }
+        RawChallenge::Fields(map)
+    }
+}
+
+#[derive(Clone)]
+struct BasicChallenge {
+    pub realm: Option<String>,
+}
+
+impl Challenge for BasicChallenge {
+    fn challenge_name() -> &'static str {
+        "Basic"
+    }
+
+    fn from_raw(raw: RawChallenge) -> Option<Self> {
+        match raw {
+            RawChallenge::Token68(_) => None,
+            RawChallenge::Fields(mut map) => Some(BasicChallenge {
+                realm: map.remove("realm"),
+            }),
+        }
+    }
+
+    fn into_raw(self) -> RawChallenge {
+        let mut map = ChallengeFields::new();
+        if let Some(realm) = self.realm {
+            map.insert_static_quoting("realm", realm);
+        }
+        RawChallenge::Fields(map)
+    }
+}
+
+fn digest_header_value(headers: &HeaderMap) -> anyhow::Result<Option<String>> {
+    match headers.get("Docker-Content-Digest") {
+        None => Ok(None),
+        Some(hv) => hv
+            .to_str()
+            .map(|s| Some(s.to_string()))
+            .map_err(anyhow::Error::new),
+    }
+}
+
+/// Reads the `Content-Length` header
+///
+/// This is read from the headers directly, since the body of a `HEAD`
+/// response is always empty whatever length the headers report.
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Returns the size the manifest records for the config or layer with the
+/// given digest
+fn descriptor_size(manifest: &OciManifest, digest: &str) -> anyhow::Result<u64> {
+    std::iter::once(&manifest.config)
+        .chain(manifest.layers.iter())
+        .find(|d| d.digest == digest)
+        .map(|d| d.size as u64)
+        .ok_or_else(|| anyhow::anyhow!("manifest does not reference blob {}", digest))
+}
+
+/// Returns the digest of a pulled manifest
+///
+/// The `Docker-Content-Digest` header is optional, so when a registry leaves
+/// it out the digest is computed from the manifest body instead.
+fn manifest_digest(headers: &HeaderMap, body: &[u8]) -> anyhow::Result<String> {
+    match digest_header_value(headers)? {
+        Some(digest) => Ok(digest),
+        None => {
+            debug!("Registry did not return a digest header, computing digest from the manifest");
+            Ok(sha256_digest(body))
+        }
+    }
+}
+
+/// Builds the error for a 4XX response from a registry
+///
+/// The OCI error code in the body tells `UNAUTHORIZED` apart from `DENIED`,
+/// so that callers can re-authenticate after the former but give up after
+/// the latter. Bodies that are not an OCI error fall back to the status.
+fn registry_error(status: reqwest::StatusCode, body: &str, url: &str) -> anyhow::Error {
+    let first = serde_json::from_str::<OciEnvelope>(body)
+        .ok()
+        .and_then(|envelope| envelope.errors.into_iter().next());
+    let message = match &first {
+        Some(err) => format!("{} on {}", err.message, url),
+        None => format!("{} on {}", status, url),
+    };
+    match (&first, status) {
+        (
+            Some(OciError {
+                code: OciErrorCode::Denied,
+                ..
+            }),
+            _,
+        )
+        | (None, reqwest::StatusCode::FORBIDDEN) => ClientError::Denied(message).into(),
+        (
+            Some(OciError {
+                code: OciErrorCode::Unauthorized,
+                ..
+            }),
+            _,
+        )
+        | (None, reqwest::StatusCode::UNAUTHORIZED) => ClientError::Unauthorized(message).into(),
+        (
+            Some(OciError {
+                code: OciErrorCode::ManifestUnknown,
+                ..
+            }),
+            _,
+        )
+        | (
+            Some(OciError {
+                code: OciErrorCode::NameUnknown,
+                ..
+            }),
+            _,
+        )
+        | (None, reqwest::StatusCode::NOT_FOUND) => ClientError::NotFound(message).into(),
+        (Some(err), _) => anyhow::anyhow!("{} on {}", err, url),
+        (None, _) => anyhow::anyhow!(
+            "An unexpected error occured: code={}, message='{}'",
+            status,
+            body
+        ),
+    }
+}
+
+/// Adds the `n` and `last` pagination parameters of a listing to its URL
+fn with_page_params(url: &str, n: Option<usize>, last: Option<&str>) -> anyhow::Result<String> {
+    let mut params = Vec::new();
+    if let Some(n) = n {
+        params.push(("n", n.to_string()));
+    }
+    if let Some(last) = last {
+        params.push(("last", last.to_owned()));
+    }
+    let mut url = reqwest::Url::parse(url)?;
+    if !params.is_empty() {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    Ok(url.to_string())
+}
+
+/// Returns the target of the `rel="next"` link in the `Link` header of a
+/// paginated listing, such as `</v2/hello/tags/list?n=2&last=b>; rel="next"`
+fn next_link(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all("Link")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .find(|link| {
+            link.split(';').skip(1).any(|param| {
+                let param = param.replace(' ', "");
+                param == "rel=\"next\"" || param == "rel=next"
+            })
+        })
+        .and_then(|link| {
+            let target = link.split(';').next()?.trim();
+            if target.starts_with('<') && target.ends_with('>') {
+                Some(target[1..target.len() - 1].to_owned())
+            } else {
+                None
+            }
+        })
+}
+
+/// Deserializes a `null` list, which some registries send for empty
+/// listings, as an empty one
+fn null_as_empty<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let list: Option<Vec<String>> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(list.unwrap_or_default())
+}
+
+/// The key a layer upload is kept under in an `UploadSession`
+fn layer_session_key(key: &str, digest: &str) -> String {
+    format!("{}/{}", key, digest)
+}
+
+/// Splits the concatenated data of the layers into one chunk per layer,
+/// skipping the first `offset` bytes that the registry already has
+///
+/// Returns each chunk with its offset in the concatenated data. Empty layers
+/// add no bytes, and the chunked PATCH cannot express a zero-length range, so
+/// they are left out.
+fn chunks_from(layers: &[ImageLayer], offset: usize) -> Vec<(usize, &[u8])> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    for layer in layers {
+        let end = start + layer.data.len();
+        if end > offset {
+            let skip = offset.saturating_sub(start);
+            chunks.push((start + skip, &layer.data[skip..]));
+        }
+        start = end;
+    }
+    chunks
+}
+
+/// Returns the offset to continue an upload from, given the `Range` header of
+/// an upload status response
+///
+/// The header holds the inclusive range received so far, such as `0-1023`.
+/// Some registries report an empty upload as `0--1`.
+fn upload_range_offset(range: &str) -> anyhow::Result<usize> {
+    let range = range.trim().trim_start_matches("bytes=");
+    let end = range
+        .splitn(2, '-')
+        .nth(1)
+        .and_then(|end| end.parse::<i64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("invalid upload range: {}", range))?;
+    Ok((end + 1).max(0) as usize)
+}
+
+/// Finds the layers of `manifest` that are already present in `previous`
+///
+/// Returns one entry per manifest layer, holding a copy of the matching
+/// layer from `previous` or `None` if it has to be downloaded.
+fn reusable_layers(manifest: &OciManifest, previous: &ImageData) -> Vec<Option<ImageLayer>> {
+    let by_digest: HashMap<&str, &ImageLayer> = previous
+        .layers
+        .iter()
+        .map(|layer| (layer.digest(), layer))
+        .collect();
+    manifest
+        .layers
+        .iter()
+        .map(|descriptor| {
+            by_digest
+                .get(descriptor.digest.as_str())
+                .map(|layer| ImageLayer {
+                    source: Some(LayerSource::Cache),
+                    ..ImageLayer::new(layer.data.clone(), descriptor.media_type.clone())
+                })
+        })
+        .collect()
+}
+
+/// Computes the digest of a manifest as it is serialized by `push_manifest`
+fn manifest_digest_of(manifest: &OciManifest) -> anyhow::Result<String> {
+    Ok(sha256_digest(serde_json::to_string(manifest)?.as_bytes()))
+}
+
+/// Generates a manifest describing the given layers and config
+///
+/// The layers are given no title annotation.
+pub(crate) fn generate_manifest(
+    image_data: &ImageData,
+    config_data: &[u8],
+    config_media_type: &str,
+) -> OciManifest {
+    generate_manifest_with_titles(image_data, config_data, config_media_type, |_, _| None)
+}
+
+/// Generates a manifest describing the given layers and config, titling
+/// each layer with the name `title_for` returns for it
+///
+/// `title_for` is called with the index and data of each layer. A returned
+/// name is set as the layer's `org.opencontainers.image.title` annotation,
+/// which artifact tools use as the file name of the layer. Layers it returns
+/// `None` for get no annotation.
+///
+/// If `image_data` was pulled and `config_data` is the config it was pulled
+/// with, the manifest keeps the pulled config and manifest media types, and
+/// `config_media_type` is ignored.
+///
+/// Pass the manifest to `Client::push` to push an image with titled layers.
+pub fn generate_manifest_with_titles<F>(
+    image_data: &ImageData,
+    config_data: &[u8],
+    config_media_type: &str,
+    mut title_for: F,
+) -> OciManifest
+where
+    F: FnMut(usize, &ImageLayer) -> Option<String>,
+{
+    let mut manifest = OciManifest::default();
+
+    manifest.config.media_type = config_media_type.to_string();
+    manifest.config.size = config_data.len() as i64;
+    manifest.config.digest = sha256_digest(config_data);
+    // Pulling an image and pushing it again should not change its media
+    // types, so a pulled image pushed with the config it was pulled with
+    // keeps them
+    if let Some(pulled) = image_data
+        .config_descriptor
+        .as_ref()
+        .filter(|d| d.digest == manifest.config.digest)
+    {
+        manifest.config.media_type = pulled.media_type.clone();
+        manifest.media_type = image_data.manifest_media_type.clone();
+    }
+
+    for (i, layer) in image_data.layers.iter().enumerate() {
+        let mut descriptor: OciDescriptor = OciDescriptor::default();
+        descriptor.size = layer.data.len() as i64;
+        descriptor.digest = layer.digest().to_owned();
+        descriptor.media_type = layer.media_type.clone();
+
+        if let Some(title) = title_for(i, layer) {
+            let mut annotations = HashMap::new();
+            annotations.insert(ANNOTATION_TITLE.to_owned(), title);
+            descriptor.annotations = Some(annotations);
+        }
+
+        manifest.layers.push(descriptor);
+    }
+
+    manifest
+}
+
+/// Generates an OCI image config for the given layers, to be pushed with
+/// the `application/vnd.oci.image.config.v1+json` media type
+///
+/// The config lists the digest of each layer's *uncompressed* tarball as
+/// its diff ID, which other tools such as docker and containerd check the
+/// unpacked layers against. Layers are decompressed with the default
+/// decompressors to compute these, and layers of other media types are
+/// taken to be uncompressed. Each layer gets a history entry, and the
+/// image and its history are stamped with the current time.
+pub fn generate_config(image_data: &ImageData, platform: &Platform) -> anyhow::Result<Vec<u8>> {
+    let decompressors = Decompressors::default();
+    let created = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+
+    let mut diff_ids = Vec::with_capacity(image_data.layers.len());
+    for layer in &image_data.layers {
+        let diff_id = match decompressors.decompress(&layer.media_type, &layer.data)? {
+            Some((uncompressed, _)) => sha256_digest(&uncompressed),
+            None => layer.digest().to_owned(),
+        };
+        diff_ids.push(diff_id);
+    }
+    let history = image_data
+        .layers
+        .iter()
+        .map(|_| History {
+            created: Some(created.clone()),
+            created_by: Some("oci-distribution".to_owned()),
+            ..Default::default()
+        })
+        .collect();
+
+    let config = OciImageConfig {
+        created: Some(created),
+        architecture: platform.architecture.clone(),
+        os: platform.os.clone(),
+        rootfs: RootFs {
+            fs_type: "layers".to_owned(),
+            diff_ids,
+        },
+        history,
+    };
+    Ok(serde_json::to_vec(&config)?)
+}
+
+/// Finds the data for each of the given blob digests among an image's layers
+/// and config
+fn blobs_for_digests<'a>(
+    digests: &[String],
+    image_data: &'a ImageData,
+    config_data: &'a [u8],
+) -> anyhow::Result<Vec<(&'a [u8], String)>> {
+    digests
+        .iter()
+        .map(|digest| {
+            let parsed: Digest = digest.parse()?;
+            let data = if parsed.verify(config_data) {
+                config_data
+            } else {
+                image_data
+                    .layers
+                    .iter()
+                    .find(|l| digest == l.digest() || parsed.verify(&l.data))
+                    .map(|l| l.data.as_slice())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "registry reported unknown blob {} that was not pushed",
+                            digest
+                        )
+                    })?
+            };
+            Ok((data, digest.clone()))
+        })
+        .collect()
+}
+
+/// Checks that the layers of a caller-supplied manifest correspond one-to-one,
+/// in order, with the layers being pushed
+fn validate_manifest_layers(manifest: &OciManifest, image_data: &ImageData) -> anyhow::Result<()> {
+    if manifest.layers.len() != image_data.layers.len() {
+        return Err(anyhow::anyhow!(
+            "manifest lists {} layers but {} layers are being pushed",
+            manifest.layers.len(),
+            image_data.layers.len()
+        ));
+    }
+    for (i, (descriptor, layer)) in manifest
+        .layers
+        .iter()
+        .zip(image_data.layers.iter())
+        .enumerate()
+    {
+        let digest = layer.digest();
+        if descriptor.digest != digest {
+            return Err(anyhow::anyhow!(
+                "manifest layer {} has digest {} but the pushed layer has digest {}",
+                i,
+                descriptor.digest,
+                digest
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Counts the bytes written to the writer it wraps
+struct CountingWriter<T> {
+    inner: T,
+    written: usize,
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingWriter<T> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let poll = std::pin::Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = &poll {
+            self.written += n;
+        }
+        poll
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reports the progress of layer downloads
+///
+/// The function is called with the layer's digest, the number of bytes
+/// downloaded so far, and the layer's total size if it is known. It is called
+/// once when a download starts and again as each chunk of data arrives, so it
+/// should return quickly.
+///
+/// ```rust
+/// use oci_distribution::client::{ClientConfig, ProgressReporter};
+///
+/// let config = ClientConfig {
+///     progress: Some(ProgressReporter::new(|digest, done, total| match total {
+///         Some(total) => println!("{}: {}/{} bytes", digest, done, total),
+///         None => println!("{}: {} bytes", digest, done),
+///     })),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct ProgressReporter(Arc<dyn Fn(&str, u64, Option<u64>) + Send + Sync>);
+
+impl ProgressReporter {
+    /// Creates a reporter that calls `report` with each update
+    pub fn new(report: impl Fn(&str, u64, Option<u64>) + Send + Sync + 'static) -> Self {
+        ProgressReporter(Arc::new(report))
+    }
+
+    /// Starts tracking a download of `digest` that begins at byte `done`,
+    /// reporting the start
+    fn track<'a>(&'a self, digest: &'a str, done: u64, total: Option<u64>) -> LayerProgress<'a> {
+        (self.0)(digest, done, total);
+        LayerProgress {
+            reporter: self,
+            digest,
+            done,
+            total,
+        }
+    }
+}
+
+impl std::fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ProgressReporter(..)")
+    }
+}
+
+/// The progress of one layer download
+struct LayerProgress<'a> {
+    reporter: &'a ProgressReporter,
+    digest: &'a str,
+    done: u64,
+    total: Option<u64>,
+}
+
+impl LayerProgress<'_> {
+    fn advance(&mut self, bytes: usize) {
+        self.done += bytes as u64;
+        (self.reporter.0)(self.digest, self.done, self.total);
+    }
+}
+
+/// The size a manifest gives for a layer, if it gives a valid one
+fn layer_size(layer: &OciDescriptor) -> Option<u64> {
+    if layer.size >= 0 {
+        Some(layer.size as u64)
+    } else {
+        None
+    }
+}
+
+/// Fails with a typed error unless the registry answered a blob download
+/// successfully
+async fn successful_blob_response(
+    res: reqwest::Response,
+    url: &str,
+) -> anyhow::Result<reqwest::Response> {
+    match res.status() {
+        s if s.is_success() => Ok(res),
+        s if s.is_client_error() => Err(registry_error(s, &res.text().await?, url)),
+        s if s.is_server_error() => Err(server_error(s, url)),
+        s => Err(anyhow::anyhow!(
+            "An unexpected error occured: code={}, message='{}'",
+            s,
+            res.text().await?
+        )),
+    }
+}
+
+/// How many times `pull_layer_to_file` resumes an interrupted download
+/// before giving up
+const MAX_RESUME_ATTEMPTS: u32 = 3;
+
+/// Returns whether a download failed because the connection was lost or
+/// stalled, so that it may be resumed
+fn is_interrupted(e: &anyhow::Error) -> bool {
+    is_transient_failure(e)
+        || e.downcast_ref::<reqwest::Error>()
+            .map_or(false, |e| e.is_body())
+}
+
+/// Computes the digest of the file at `path`
+async fn hash_file(path: &Path, algorithm: DigestAlgorithm) -> anyhow::Result<Digest> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {:?}", path))?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+/// Runs layer downloads with at most `limit` of them in flight, returning
+/// their results in order
+///
+/// The first failure is returned as soon as it happens, even if downloads
+/// that came before it are still running, and the downloads still running
+/// are dropped.
+async fn download_all<I, F, T>(downloads: I, limit: Option<usize>) -> anyhow::Result<Vec<T>>
+where
+    I: IntoIterator<Item = F>,
+    F: std::future::Future<Output = anyhow::Result<T>>,
+{
+    match limit {
+        Some(limit) if limit > 0 => {
+            // Downloads finish in any order, so each result carries its index
+            // to put them back in order
+            let mut results: Vec<(usize, T)> =
+                futures_util::stream::iter(downloads.into_iter().enumerate())
+                    .map(|(i, download)| async move { download.await.map(|result| (i, result)) })
+                    .buffer_unordered(limit)
+                    .try_collect()
+                    .await?;
+            results.sort_by_key(|(i, _)| *i);
+            Ok(results.into_iter().map(|(_, result)| result).collect())
+        }
+        _ => future::try_join_all(downloads).await,
+    }
+}
+
+/// Writes every chunk of `stream` to `out`, checking for cancellation between
+/// chunks
+///
+/// Fails with `ClientError::TimedOut` if the stream goes longer than
+/// `read_timeout` without producing a chunk.
+async fn write_stream<S, B, E, T>(
+    mut stream: S,
+    mut out: T,
+    cancel: Option<&CancellationToken>,
+    budget: Option<&ByteBudget>,
+    limiter: Option<&BandwidthLimiter>,
+    read_timeout: Option<Duration>,
+) -> anyhow::Result<u64>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::error::Error + Send + Sync + 'static,
+    T: AsyncWrite + Unpin,
+{
+    let mut written = 0;
+    loop {
+        let read = async {
+            match read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.next())
+                    .await
+                    .map_err(|_| ClientError::TimedOut(timeout)),
+                None => Ok(stream.next().await),
+            }
+        };
+        // A stalled body would otherwise hold up cancellation until the
+        // next chunk arrived, if it ever does
+        let next = match cancel {
+            Some(cancel) => tokio::select! {
+                next = read => next?,
+                _ = cancel.cancelled() => return Err(ClientError::Cancelled.into()),
+            },
+            None => read.await?,
+        };
+        let bytes = match next {
+            Some(bytes) => bytes,
+            None => break,
+        };
+        check_cancelled(cancel)?;
+        let bytes = bytes?;
+        let len = bytes.as_ref().len();
+        if let Some(limiter) = limiter {
+            // Holding off on the next read lets the connection's receive
+            // window fill, which slows the sender down
+            limiter.consume(len).await;
+        }
+        written += len as u64;
+        match budget {
+            // Waiting here stops this layer's body from being read, so a
+            // slow sink pushes back on the network instead of piling up
+            // chunks in memory
+            Some(budget) => {
+                let _share = budget.acquire(len).await;
+                out.write_all(bytes.as_ref()).await?;
+                out.flush().await?;
+            }
+            None => out.write_all(bytes.as_ref()).await?,
+        }
+    }
+    // Sinks such as files may still be writing in the background, so make
+    // sure everything has landed before the caller reads it back
+    out.flush().await?;
+    Ok(written)
+}
+
+/// A limit on the number of bytes held in memory at once, shared by
+/// concurrent layer downloads
+struct ByteBudget {
+    capacity: usize,
+    available: std::sync::Mutex<usize>,
+    released: Notify,
+    // Only one task waits for released bytes at a time, so that a single
+    // `Notify` permit is enough and large chunks are not starved by small ones
+    turn: tokio::sync::Mutex<()>,
+}
+
+/// A share of a `ByteBudget`, returned to it when dropped
+struct BudgetShare<'a> {
+    budget: &'a ByteBudget,
+    bytes: usize,
+}
+
+impl ByteBudget {
+    fn new(capacity: usize) -> Self {
+        ByteBudget {
+            capacity,
+            available: std::sync::Mutex::new(capacity),
+            released: Notify::new(),
+            turn: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Waits until `bytes` are available and takes them. Requests larger
+    /// than the whole budget wait for all of it instead, so that an
+    /// oversized chunk cannot block forever.
+    async fn acquire(&self, bytes: usize) -> BudgetShare<'_> {
+        let bytes = bytes.min(self.capacity);
+        let _turn = self.turn.lock().await;
+        loop {
+            {
+                let mut available = self.available.lock().unwrap();
+                if *available >= bytes {
+                    *available -= bytes;
+                    return BudgetShare {
+                        budget: self,
+                        bytes,
+                    };
+                }
+            }
+            self.released.notified().await;
+        }
+    }
+
+    #[cfg(test)]
+    fn available(&self) -> usize {
+        *self.available.lock().unwrap()
+    }
+}
+
+impl Drop for BudgetShare<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.released.notify();
+    }
+}
+
+/// The manifest media types the client advertises in `Accept` when pulling
+/// a manifest
+const MANIFEST_ACCEPT_TYPES: &[&str] = &[
+    IMAGE_MANIFEST_MEDIA_TYPE,
+    IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+    OCI_IMAGE_MEDIA_TYPE,
+];
+
+/// Returns whether `url` is on `registry`, comparing the host and any port
+fn is_registry_url(registry: &str, url: &reqwest::Url) -> bool {
+    let host = match url.host_str() {
+        Some(host) => host,
+        None => return false,
+    };
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_owned(),
+    };
+    authority.eq_ignore_ascii_case(registry.trim_matches('/'))
+}
+
+/// Drops leading, trailing and repeated slashes from a repository name, so
+/// that `/library//hello/` becomes `library/hello`
+fn normalize_repository(repository: &str) -> String {
+    repository
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Builds an `Accept` header value listing the media types in order of
+/// preference
+///
+/// The first type gets the implied quality of 1, and each later one a
+/// quality 0.1 lower, down to a floor of 0.001 so that no type is refused.
+fn accept_header<T: AsRef<str>>(media_types: &[T]) -> String {
+    media_types
+        .iter()
+        .enumerate()
+        .map(|(i, media_type)| {
+            let media_type = media_type.as_ref();
+            match 1000usize.saturating_sub(100 * i).max(1) {
+                1000 => media_type.to_owned(),
+                q => format!(
+                    "{};q={}",
+                    media_type,
+                    format!("0.{:03}", q).trim_end_matches('0')
+                ),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The manifest media types that parse as an `OciManifest`
+const IMAGE_MANIFEST_MEDIA_TYPES: &[&str] = &[IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE];
+
+/// Returns whether a transport error means the registry could not be
+/// reached, rather than that the request could not be sent
+fn is_registry_failure(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(e) => e.is_connect() || e.is_timeout(),
+        None => matches!(
+            e.downcast_ref::<ClientError>(),
+            Some(ClientError::RegistryUnreachable(_))
+                | Some(ClientError::RegistryUnresolvable(_))
+                | Some(ClientError::TimedOut(_))
+        ),
+    }
+}
+
+/// How long to wait for a rate limit to clear when the registry does not say
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(1);
+
+/// Returns how long a `Retry-After` header asks the client to wait
+///
+/// The header holds either a number of seconds or an HTTP date. Dates in the
+/// past mean no wait.
+fn retry_after(headers: &HeaderMap, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(date.signed_duration_since(now).to_std().unwrap_or_default())
+}
+
+/// The error for a 5xx response to a request for `url`
+fn server_error(status: reqwest::StatusCode, url: &str) -> anyhow::Error {
+    anyhow::Error::new(ClientError::ServerError(status.as_u16()))
+        .context(format!("Server error at {}", url))
+}
+
+static ANONYMOUS: RegistryAuth = RegistryAuth::Anonymous;
+
+/// The credentials to access `candidate`, one of the `mirror_candidates` of
+/// `image`
+///
+/// The caller's credentials belong to the image's own registry, so they are
+/// never sent to a mirror, which is accessed anonymously instead.
+fn mirror_auth<'a>(
+    image: &Reference,
+    candidate: &Reference,
+    auth: &'a RegistryAuth,
+) -> &'a RegistryAuth {
+    if candidate.registry() == image.registry() {
+        auth
+    } else {
+        &ANONYMOUS
+    }
+}
+
+/// Returns whether a request failed because the registry is unavailable, so
+/// that a mirror may serve it instead
+fn is_unavailable(e: &anyhow::Error) -> bool {
+    is_transient_failure(e)
+        || matches!(
+            e.downcast_ref::<ClientError>(),
+            Some(ClientError::ServerError(_)) | Some(ClientError::RegistryUnavailable(_))
+        )
+}
+
+/// Returns whether a response status indicates a failure that may clear up
+/// if the request is sent again
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    matches!(
+        status,
+        reqwest::StatusCode::BAD_GATEWAY
+            | reqwest::StatusCode::SERVICE_UNAVAILABLE
+            | reqwest::StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Returns whether sending a request failed in a way that may clear up if it
+/// is sent again, such as a refused or reset connection
+fn is_transient_failure(e: &anyhow::Error) -> bool {
+    is_registry_failure(e)
+        || e.downcast_ref::<reqwest::Error>()
+            .map_or(false, |e| e.is_request())
+}
+
+/// Types the connection failures among the errors a transport returns
+fn transport_error(registry: &str, e: anyhow::Error) -> anyhow::Error {
+    match e.downcast::<reqwest::Error>() {
+        Ok(e) => connection_error(registry, e),
+        Err(e) => e,
+    }
+}
+
+/// Converts a failed request to a registry into a typed error when the
+/// failure was in resolving or connecting to the host
+fn connection_error(registry: &str, e: reqwest::Error) -> anyhow::Error {
+    if !e.is_connect() {
+        return e.into();
+    }
+    // reqwest does not expose the kind of connect failure, but the resolver
+    // error is reported as a "dns error" somewhere in the source chain
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+    let mut dns = false;
+    while let Some(err) = source {
+        if err.to_string().contains("dns error") {
+            dns = true;
+            break;
+        }
+        source = err.source();
+    }
+    let typed = if dns {
+        ClientError::RegistryUnresolvable(registry.to_owned())
+    } else {
+        ClientError::RegistryUnreachable(registry.to_owned())
+    };
+    anyhow::Error::new(e).context(typed)
+}
+
+/// Returns whether a response to a ranged PATCH indicates that the registry
+/// does not support chunked uploads
+///
+/// That is a 416, or a 200 in place of the 202 that an accepted chunk gets,
+/// from a registry that ignored the `Content-Range` header. Other client
+/// errors, including 400, are failures of the upload itself.
+fn is_range_rejection(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE || status == reqwest::StatusCode::OK
+}
+
+fn is_content_range_unsupported(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<ClientError>(),
+        Some(ClientError::ContentRangeUnsupported(_))
+    )
+}
+
+fn check_cancelled(cancel: Option<&CancellationToken>) -> anyhow::Result<()> {
+    match cancel {
+        Some(c) if c.is_cancelled() => Err(ClientError::Cancelled.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the Content-Type to send when pushing the given manifest
+///
+/// This is the manifest's own media type, so that Docker manifests and
+/// manifest lists are sent with the correct header, falling back to the OCI
+/// image manifest type when none is set.
+pub(crate) fn manifest_content_type(manifest: &OciManifest) -> &str {
+    manifest
+        .media_type
+        .as_deref()
+        .unwrap_or(OCI_IMAGE_MEDIA_TYPE)
+}
+
+/// Returns the `type/subtype` portion of a media type in lowercase, discarding
+/// any parameters such as `; charset=utf-8`
+pub(crate) fn media_type_essence(media_type: &str) -> String {
+    media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Returns whether `media_type` is a `type/subtype` pair made of RFC 6838
+/// restricted names, ignoring any parameters
+fn is_valid_media_type(media_type: &str) -> bool {
+    fn is_restricted_name(name: &str) -> bool {
+        name.len() <= 127
+            && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    }
+    let essence = media_type.split(';').next().unwrap_or_default();
+    match essence.split('/').collect::<Vec<_>>().as_slice() {
+        [type_, subtype] => is_restricted_name(type_) && is_restricted_name(subtype),
+        _ => false,
+    }
+}
+
+/// Compares two media types case-insensitively, ignoring any parameters
+pub(crate) fn media_type_matches(a: &str, b: &str) -> bool {
+    media_type_essence(a) == media_type_essence(b)
+}
+
+/// Names a platform as `os/architecture`, with the variant if there is one
+fn platform_name(platform: &Platform) -> String {
+    match &platform.variant {
+        Some(variant) => format!("{}/{}/{}", platform.os, platform.architecture, variant),
+        None => format!("{}/{}", platform.os, platform.architecture),
+    }
+}
+
+/// Whether a manifest is an OCI image index or Docker manifest list, going
+/// by its own media type and falling back to the response's content type
+fn is_manifest_list(text: &str, content_type: Option<&str>) -> bool {
+    let media_type = serde_json::from_str::<Versioned>(text)
+        .ok()
+        .and_then(|v| v.media_type)
+        .or_else(|| content_type.map(|t| t.to_owned()));
+    media_type.map_or(false, |media_type| {
+        [IMAGE_MANIFEST_LIST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE]
+            .iter()
+            .any(|t| media_type_matches(&media_type, t))
+    })
+}
+
+/// Computes the SHA256 digest of a byte vector
+pub(crate) fn sha256_digest(bytes: &[u8]) -> String {
+    Digest::compute(DigestAlgorithm::Sha256, bytes).to_string()
+}
+
+/// Checks that `data` hashes to `digest`, with whichever algorithm the
+/// digest names
+pub(crate) fn verify_blob(digest: &str, data: &[u8]) -> anyhow::Result<()> {
+    let expected: Digest = digest.parse()?;
+    if !expected.verify(data) {
+        return Err(anyhow::anyhow!(
+            "digest mismatch for blob {}: computed {}",
+            digest,
+            Digest::compute(expected.algorithm(), data)
+        ));
+    }
+    Ok(())
+}
+
+/// Computes the SHA256 digest of everything read from `reader`, in the
+/// `sha256:<hex>` form used by registries.
+///
+/// The data is hashed as it is read, so it never has to be held in memory
+/// at once.
+pub async fn sha256_stream<R: AsyncRead + Unpin>(mut reader: R) -> anyhow::Result<String> {
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest;
+    use crate::mock::{MockRegistry, MockResponse};
+    use rstest::rstest;
+    use std::convert::TryFrom;
+
+    const HELLO_IMAGE_NO_TAG: &str = "webassembly.azurecr.io/hello-wasm";
+    const HELLO_IMAGE_TAG: &str = "webassembly.azurecr.io/hello-wasm:v1";
+    const HELLO_IMAGE_DIGEST: &str = "webassembly.azurecr.io/hello-wasm@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7";
+    const HELLO_IMAGE_TAG_AND_DIGEST: &str = "webassembly.azurecr.io/hello-wasm:v1@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7";
+    const TEST_IMAGES: &'static [&str] = &[
+        // TODO(jlegrone): this image cannot be pulled currently because no `latest`
+        //                 tag exists on the image repository. Re-enable this image
+        //                 in tests once `latest` is published.
+        // HELLO_IMAGE_NO_TAG,
+        HELLO_IMAGE_TAG,
+        HELLO_IMAGE_DIGEST,
+        HELLO_IMAGE_TAG_AND_DIGEST,
+    ];
+
+    #[test]
+    fn test_to_v2_blob_url() {
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let digest = Digest::compute(DigestAlgorithm::Sha256, b"hello");
+        let blob_url =
+            Client::default().to_v2_blob_url(image.registry(), image.repository(), &digest);
+        assert_eq!(
+            blob_url,
+            "https://webassembly.azurecr.io/v2/hello-wasm/blobs/sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        )
+    }
+
+    #[test]
+    fn blob_url_uses_sha512_digests() {
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let digest: Digest = "sha512:9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043"
+            .parse()
+            .expect("sha512 digest should parse");
+        assert_eq!(
+            format!(
+                "https://webassembly.azurecr.io/v2/hello-wasm/blobs/{}",
+                digest
+            ),
+            Client::default().to_v2_blob_url(image.registry(), image.repository(), &digest)
+        );
+    }
+
+    #[test]
+    fn blobs_verify_against_sha512_digests() {
+        let sha512 = Digest::compute(DigestAlgorithm::Sha512, b"layer").to_string();
+        assert!(verify_blob(&sha512, b"layer").is_ok());
+        assert!(verify_blob(&sha512, b"other").is_err());
+
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"layer".to_vec())],
+            ..Default::default()
+        };
+        let blobs = blobs_for_digests(&[sha512.clone()], &image_data, b"{}")
+            .expect("sha512 digest should resolve to the layer");
+        assert_eq!(vec![(&b"layer"[..], sha512)], blobs);
+    }
+
+    #[test]
+    fn test_to_v2_manifest() {
+        let c = Client::default();
+
+        for &(image, expected_uri) in [
+            (HELLO_IMAGE_NO_TAG, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/latest"), // TODO: confirm this is the right translation when no tag
+            (HELLO_IMAGE_TAG, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/v1"),
+            (HELLO_IMAGE_DIGEST, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7"),
+            (HELLO_IMAGE_TAG_AND_DIGEST, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7"),
+            ].iter() {
+                let reference = Reference::try_from(image).expect("failed to parse reference");
+                assert_eq!(c.to_v2_manifest_url(&reference), expected_uri);
+            }
+    }
+
+    #[test]
+    fn test_to_v2_blob_upload_url() {
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let blob_url = Client::default().to_v2_blob_upload_url(&image);
+
+        assert_eq!(
+            blob_url,
+            "https://webassembly.azurecr.io/v2/hello-wasm/blobs/uploads/"
+        )
+    }
+
+    #[test]
+    fn blob_upload_url_can_drop_trailing_slash() {
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let mut registry_overrides = HashMap::new();
+        registry_overrides.insert(
+            image.registry().to_owned(),
+            RegistryConfig {
+                upload_url_without_trailing_slash: true,
+                ..Default::default()
+            },
+        );
+        let c = Client::new(ClientConfig {
+            registry_overrides,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            c.to_v2_blob_upload_url(&image),
+            "https://webassembly.azurecr.io/v2/hello-wasm/blobs/uploads"
+        );
+        // Registries without the override keep the specification's form
+        let other = Reference::try_from("example.com/hello:v1").expect("failed to parse reference");
+        assert_eq!(
+            c.to_v2_blob_upload_url(&other),
+            "https://example.com/v2/hello/blobs/uploads/"
+        );
+    }
+
+    #[test]
+    fn stray_slashes_are_dropped_from_urls() {
+        let c = Client::default();
+        let digest = Digest::compute(DigestAlgorithm::Sha256, b"hello");
+        assert_eq!(
+            format!(
+                "https://registry.example.com/v2/library/hello/blobs/{}",
+                digest
+            ),
+            c.to_v2_blob_url("registry.example.com/", "/library//hello/", &digest)
+        );
+        assert_eq!(
+            "https://registry.example.com/v2/",
+            c.to_v2_url("registry.example.com//")
+        );
+        let image = Reference::try_from("registry.example.com/hello:v1")
+            .expect("failed to parse reference");
+        let location = reqwest::header::HeaderValue::from_static("/v2/hello/blobs/uploads/1");
+        assert_eq!(
+            "https://registry.example.com/v2/hello/blobs/uploads/1",
+            c.location_header_to_url(&image, &location).unwrap()
+        );
+    }
+
+    #[test]
+    fn accept_header_orders_types_by_quality() {
+        assert_eq!(
+            format!(
+                "{}, {};q=0.9, {};q=0.8",
+                IMAGE_MANIFEST_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE
+            ),
+            accept_header(MANIFEST_ACCEPT_TYPES)
+        );
+
+        let types: Vec<String> = (0..12).map(|i| format!("application/x-{}", i)).collect();
+        let header = accept_header(&types);
+        assert!(header.starts_with("application/x-0, application/x-1;q=0.9,"));
+        assert!(header.contains("application/x-9;q=0.1,"));
+        assert!(header.ends_with("application/x-10;q=0.001, application/x-11;q=0.001"));
+    }
+
+    #[test]
+    fn configured_manifest_types_set_accept_preference() {
+        let c = Client::new(ClientConfig {
+            manifest_media_types: vec![
+                OCI_IMAGE_INDEX_MEDIA_TYPE.to_owned(),
+                OCI_IMAGE_MEDIA_TYPE.to_owned(),
+                IMAGE_MANIFEST_MEDIA_TYPE.to_owned(),
+            ],
+            ..Default::default()
+        });
+        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        assert_eq!(
+            format!(
+                "{}, {};q=0.9, {};q=0.8",
+                OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE
+            ),
+            c.auth_headers(&image)["Accept"].to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn manifest_url_generation_respects_http_protocol() {
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "http://webassembly.azurecr.io/v2/hello/manifests/v1",
+            c.to_v2_manifest_url(&reference)
+        );
+    }
+
+    #[test]
+    fn blob_url_generation_respects_http_protocol() {
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("webassembly.azurecr.io/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "http://webassembly.azurecr.io/v2/hello/blobs/sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            c.to_v2_blob_url(
+                &reference.registry(),
+                reference.repository(),
+                &reference.digest().unwrap().parse().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn url_generation_respects_registry_overrides() {
+        let mut registry_overrides = HashMap::new();
+        registry_overrides.insert(
+            "oci.registry.local".to_owned(),
+            RegistryConfig {
+                protocol: Some(ClientProtocol::Http),
+                ..Default::default()
+            },
+        );
+        registry_overrides.insert(
+            "webassembly.azurecr.io".to_owned(),
+            RegistryConfig::default(),
+        );
+        let c = Client::new(ClientConfig {
+            registry_overrides,
+            ..Default::default()
+        });
+
+        let local = Reference::try_from("oci.registry.local/hello:v1".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "http://oci.registry.local/v2/hello/manifests/v1",
+            c.to_v2_manifest_url(&local)
+        );
+
+        // An entry without a protocol falls back to the global default
+        let remote = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "https://webassembly.azurecr.io/v2/hello/manifests/v1",
+            c.to_v2_manifest_url(&remote)
+        );
+
+        // Unlisted registries use the global default
+        let other = Reference::try_from("example.com/hello:v1".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "https://example.com/v2/hello/manifests/v1",
+            c.to_v2_manifest_url(&other)
+        );
+    }
+
+    #[test]
+    fn manifest_url_generation_uses_https_if_not_on_exception_list() {
+        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
+        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "https://webassembly.azurecr.io/v2/hello/manifests/v1",
+            c.to_v2_manifest_url(&reference)
+        );
+    }
+
+    #[test]
+    fn manifest_url_generation_uses_http_if_on_exception_list() {
+        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
+        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("oci.registry.local/hello:v1".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "http://oci.registry.local/v2/hello/manifests/v1",
+            c.to_v2_manifest_url(&reference)
+        );
+    }
+
+    #[test]
+    fn blob_url_generation_uses_https_if_not_on_exception_list() {
+        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
+        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("webassembly.azurecr.io/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "https://webassembly.azurecr.io/v2/hello/blobs/sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            c.to_v2_blob_url(
+                &reference.registry(),
+                reference.repository(),
+                &reference.digest().unwrap().parse().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn blob_url_generation_uses_http_if_on_exception_list() {
+        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
+        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
+        let c = Client::new(ClientConfig {
+            protocol,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("oci.registry.local/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
+            .expect("Could not parse reference");
+        assert_eq!(
+            "http://oci.registry.local/v2/hello/blobs/sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            c.to_v2_blob_url(
+                &reference.registry(),
+                reference.repository(),
+                &reference.digest().unwrap().parse().unwrap()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn request_permits_are_bounded_by_config() {
+        let c = Client::new(ClientConfig {
+            max_concurrent_requests: Some(1),
+            ..Default::default()
+        });
+        let limiter = c.request_limiter.clone().expect("limiter is configured");
+
+        let permit = c.request_permit().await;
+        assert!(permit.is_some());
+        assert_eq!(limiter.available_permits(), 0);
+
+        drop(permit);
+        assert_eq!(limiter.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_permits_are_unlimited_by_default() {
+        let c = Client::default();
+        assert!(c.request_limiter.is_none());
+        assert!(c.request_permit().await.is_none());
+    }
+
+    #[test]
+    fn ranged_patch_rejections_are_detected() {
+        assert!(is_range_rejection(
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+        ));
+        assert!(is_range_rejection(reqwest::StatusCode::OK));
+        assert!(!is_range_rejection(reqwest::StatusCode::BAD_REQUEST));
+        assert!(!is_range_rejection(reqwest::StatusCode::ACCEPTED));
+        assert!(!is_range_rejection(reqwest::StatusCode::NOT_FOUND));
+
+        let err: anyhow::Error = ClientError::ContentRangeUnsupported(416).into();
+        assert!(is_content_range_unsupported(&err));
+        assert!(!is_content_range_unsupported(&anyhow::anyhow!(
+            "some other failure"
+        )));
+    }
+
+    #[test]
+    fn image_kind_is_classified_from_media_types() {
+        let wasm = ImageData {
+            layers: vec![ImageLayer::new(
+                b"module".to_vec(),
+                manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+            )],
+            ..Default::default()
+        };
+        assert_eq!(ImageKind::Wasm, wasm.kind());
+
+        let container = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1(b"base".to_vec()),
+                ImageLayer::oci_v1_gzip(b"app".to_vec()),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(ImageKind::Container, container.kind());
+
+        let wasm_config = ImageData {
+            layers: vec![ImageLayer::new(
+                b"module".to_vec(),
+                "application/octet-stream".to_owned(),
+            )],
+            config_descriptor: Some(OciDescriptor {
+                media_type: manifest::WASM_CONFIG_MEDIA_TYPE.to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(ImageKind::Wasm, wasm_config.kind());
+
+        let mixed = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1(b"base".to_vec()),
+                ImageLayer::new(
+                    b"module".to_vec(),
+                    manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+                ),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(ImageKind::Unknown, mixed.kind());
+        assert_eq!(ImageKind::Unknown, ImageData::default().kind());
+    }
+
+    #[test]
+    fn generated_manifests_title_layers_as_asked() {
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::wasm(b"iamawebassemblymodule".to_vec()),
+                ImageLayer::oci_v1(b"untitled".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let manifest = generate_manifest_with_titles(
+            &image_data,
+            b"{}",
+            manifest::WASM_CONFIG_MEDIA_TYPE,
+            |i, _| {
+                if i == 0 {
+                    Some("hello.wasm".to_owned())
+                } else {
+                    None
+                }
+            },
+        );
+        assert_eq!(
+            Some(&"hello.wasm".to_owned()),
+            manifest.layers[0]
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(ANNOTATION_TITLE))
+        );
+        assert_eq!(None, manifest.layers[1].annotations);
+
+        let untitled = generate_manifest(&image_data, b"{}", manifest::WASM_CONFIG_MEDIA_TYPE);
+        assert!(untitled.layers.iter().all(|l| l.annotations.is_none()));
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn layers_are_decompressed_by_media_type() {
+        let data = b"iamawebassemblymodule";
+        assert_eq!(
+            data.to_vec(),
+            ImageLayer::oci_v1_gzip(gzip(data)).decompressed().unwrap()
+        );
+        let zstd_layer = ImageLayer::new(
+            zstd::encode_all(&data[..], 0).unwrap(),
+            manifest::IMAGE_LAYER_ZSTD_MEDIA_TYPE.to_owned(),
+        );
+        assert_eq!(data.to_vec(), zstd_layer.decompressed().unwrap());
+        // Types the default decompressors do not know are matched by suffix
+        let custom = ImageLayer::new(gzip(data), "application/vnd.example.module+gzip".to_owned());
+        assert_eq!(data.to_vec(), custom.decompressed().unwrap());
+
+        assert_eq!(
+            data.to_vec(),
+            ImageLayer::wasm(data.to_vec()).decompressed().unwrap()
+        );
+        assert!(ImageLayer::oci_v1_gzip(data.to_vec())
+            .decompressed()
+            .is_err());
+    }
+
+    #[test]
+    fn generated_configs_list_uncompressed_diff_ids() {
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1_gzip(gzip(b"compressed layer")),
+                ImageLayer::oci_v1(b"plain layer".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let platform = Platform {
+            architecture: "amd64".to_owned(),
+            os: "linux".to_owned(),
+            os_version: None,
+            os_features: None,
+            variant: None,
+            features: None,
+        };
+        let config: OciImageConfig = serde_json::from_slice(
+            &generate_config(&image_data, &platform).expect("config should generate"),
+        )
+        .expect("config should parse");
+
+        assert_eq!("amd64", config.architecture);
+        assert_eq!("linux", config.os);
+        assert_eq!("layers", config.rootfs.fs_type);
+        assert_eq!(
+            vec![
+                sha256_digest(b"compressed layer"),
+                sha256_digest(b"plain layer")
+            ],
+            config.rootfs.diff_ids
+        );
+        assert_eq!(2, config.history.len());
+        let created = config.created.expect("config should have a creation time");
+        assert!(chrono::DateTime::parse_from_rfc3339(&created).is_ok());
+        assert!(config
+            .history
+            .iter()
+            .all(|h| h.created.as_ref() == Some(&created)));
+    }
+
+    #[test]
+    fn unknown_manifest_blobs_are_found_in_pushed_data() {
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1(b"first".to_vec()),
+                ImageLayer::oci_v1(b"second".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let config_data = b"{}";
+        let digests = vec![sha256_digest(b"second"), sha256_digest(config_data)];
+
+        let blobs = blobs_for_digests(&digests, &image_data, config_data)
+            .expect("all blobs should be found");
+        assert_eq!(
+            vec![
+                (&b"second"[..], digests[0].clone()),
+                (&config_data[..], digests[1].clone())
+            ],
+            blobs
+        );
+
+        assert!(
+            blobs_for_digests(&[sha256_digest(b"never pushed")], &image_data, config_data).is_err()
+        );
+    }
+
+    #[test]
+    fn layer_digest_is_cached_by_borrow() {
+        let layer = ImageLayer::oci_v1(b"iamawebassemblymodule".to_vec());
+        let expected = sha256_digest(b"iamawebassemblymodule");
+
+        let first = layer.digest();
+        assert_eq!(expected, first);
+        // The second call returns the same cached string rather than hashing again
+        assert!(std::ptr::eq(first, layer.digest()));
+
+        let cloned = layer.clone();
+        assert_eq!(expected, cloned.digest());
+        assert_eq!(expected, layer.sha256_digest());
+    }
+
+    #[test]
+    fn layer_digest_follows_replaced_data() {
+        let mut layer = ImageLayer::oci_v1(b"before".to_vec());
+        assert_eq!(sha256_digest(b"before"), layer.digest());
+
+        layer.set_data(b"after".to_vec());
+        assert_eq!(&b"after"[..], layer.data());
+        assert_eq!(sha256_digest(b"after"), layer.digest());
+        assert_eq!(b"after".to_vec(), layer.into_data());
+    }
+
+    #[test]
+    fn manifest_layers_must_match_pushed_layers() {
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1(b"first".to_vec()),
+                ImageLayer::oci_v1(b"second".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let manifest = generate_manifest(&image_data, b"{}", manifest::IMAGE_CONFIG_MEDIA_TYPE);
+        validate_manifest_layers(&manifest, &image_data).expect("generated manifest matches");
+
+        let mut reordered = manifest.clone();
+        reordered.layers.reverse();
+        assert!(validate_manifest_layers(&reordered, &image_data).is_err());
+
+        let mut truncated = manifest;
+        truncated.layers.pop();
+        assert!(validate_manifest_layers(&truncated, &image_data).is_err());
+    }
+
+    #[test]
+    fn generated_manifests_keep_the_media_types_of_pulled_images() {
+        let config = br#"{"architecture": "wasm"}"#;
+        let pulled = ImageData {
+            layers: vec![ImageLayer::new(
+                b"module".to_vec(),
+                manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+            )],
+            config_descriptor: Some(OciDescriptor {
+                media_type: manifest::WASM_CONFIG_MEDIA_TYPE.to_owned(),
+                digest: sha256_digest(config),
+                size: config.len() as i64,
+                ..Default::default()
+            }),
+            manifest_media_type: Some(manifest::OCI_IMAGE_MEDIA_TYPE.to_owned()),
+            ..Default::default()
+        };
+
+        let manifest = generate_manifest(&pulled, config, manifest::IMAGE_CONFIG_MEDIA_TYPE);
+        assert_eq!(manifest::WASM_CONFIG_MEDIA_TYPE, manifest.config.media_type);
+        assert_eq!(
+            Some(manifest::OCI_IMAGE_MEDIA_TYPE),
+            manifest.media_type.as_deref()
+        );
+
+        // A different config is described as the caller says
+        let manifest = generate_manifest(&pulled, b"{}", manifest::IMAGE_CONFIG_MEDIA_TYPE);
+        assert_eq!(
+            manifest::IMAGE_CONFIG_MEDIA_TYPE,
+            manifest.config.media_type
+        );
+        assert_eq!(None, manifest.media_type);
+    }
+
+    #[tokio::test]
+    async fn sha256_stream_matches_buffered_digest() {
+        let data: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut file = tempfile::NamedTempFile::new().expect("create temp file");
+        std::io::Write::write_all(&mut file, &data).expect("write temp file");
+
+        let reader = tokio::fs::File::open(file.path())
+            .await
+            .expect("open temp file");
+        let digest = sha256_stream(reader).await.expect("hash stream");
+        assert_eq!(sha256_digest(&data), digest);
+    }
+
+    #[tokio::test]
+    async fn write_stream_stops_when_cancelled() {
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+            vec![Ok(b"first".to_vec()), Ok(b"second".to_vec())];
+        let mut count = 0;
+        // Cancel as soon as the second chunk arrives, like a pod deleted
+        // in the middle of a slow pull
+        let stream = futures_util::stream::iter(chunks).map(move |c| {
+            count += 1;
+            if count == 2 {
+                trigger.cancel();
+            }
+            c
+        });
+
+        let mut out: Vec<u8> = Vec::new();
+        let err = write_stream(stream, &mut out, Some(&cancel), None, None, None)
+            .await
+            .expect_err("write should be cancelled");
+
+        assert_eq!(
+            Some(&ClientError::Cancelled),
+            err.downcast_ref::<ClientError>()
+        );
+        assert_eq!(b"first".to_vec(), out);
+    }
+
+    #[tokio::test]
+    async fn write_stream_stops_waiting_for_a_stalled_stream_when_cancelled() {
+        let cancel = CancellationToken::new();
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![Ok(b"first".to_vec())];
+        let stream = futures_util::stream::iter(chunks).chain(futures_util::stream::pending());
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            trigger.cancel();
+        });
+
+        let mut out: Vec<u8> = Vec::new();
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            write_stream(stream, &mut out, Some(&cancel), None, None, None),
+        )
+        .await
+        .expect("cancellation should not wait for the next chunk")
+        .expect_err("write should be cancelled");
+        assert_eq!(
+            Some(&ClientError::Cancelled),
+            err.downcast_ref::<ClientError>()
+        );
+        assert_eq!(b"first".to_vec(), out);
+    }
+
+    #[tokio::test]
+    async fn slow_pulls_can_be_cancelled() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+        let digest = registry.add_blob(&image_data.layers[0].data);
+        registry.stall_blob(&digest);
+        let manifest = generate_manifest(&image_data, b"{}", manifest::WASM_CONFIG_MEDIA_TYPE);
+        registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+        let cancel = CancellationToken::new();
+        let trigger = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            trigger.cancel();
+        });
+
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            registry.client().pull_with_cancellation(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &cancel,
+            ),
+        )
+        .await
+        .expect("cancelled pull should not wait for the stalled layer")
+        .expect_err("pull should be cancelled");
+        assert_eq!(
+            Some(&ClientError::Cancelled),
+            err.downcast_ref::<ClientError>()
+        );
+        assert!(registry
+            .requests()
+            .contains(&format!("GET /v2/hello/blobs/{}", digest)));
+    }
+
+    #[tokio::test]
+    async fn write_stream_copies_all_chunks_without_cancellation() {
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+            vec![Ok(b"first".to_vec()), Ok(b"second".to_vec())];
+        let mut out: Vec<u8> = Vec::new();
+        let written = write_stream(
+            futures_util::stream::iter(chunks),
+            &mut out,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        .expect("write stream");
+        assert_eq!(b"firstsecond".to_vec(), out);
+        assert_eq!(11, written);
+    }
+
+    #[tokio::test]
+    async fn write_stream_is_held_to_bandwidth_limit() {
+        let limiter = BandwidthLimiter::new(100_000);
+        // One second's worth is let through at once, so the remaining half
+        // second's worth should take about half a second
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+            (0..15).map(|_| Ok(vec![0; 10_000])).collect();
+        let mut out: Vec<u8> = Vec::new();
+        let started = Instant::now();
+        write_stream(
+            futures_util::stream::iter(chunks),
+            &mut out,
+            None,
+            None,
+            Some(&limiter),
+            None,
+        )
+        .await
+        .expect("write stream");
+        let elapsed = started.elapsed();
+
+        assert_eq!(150_000, out.len());
+        assert!(
+            elapsed >= std::time::Duration::from_millis(400)
+                && elapsed < std::time::Duration::from_secs(2),
+            "150KB at 100KB/s took {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn write_stream_returns_budget_after_writing() {
+        let budget = ByteBudget::new(8);
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> =
+            vec![Ok(b"first".to_vec()), Ok(b"second".to_vec())];
+        let mut out: Vec<u8> = Vec::new();
+        write_stream(
+            futures_util::stream::iter(chunks),
+            &mut out,
+            None,
+            Some(&budget),
+            None,
+            None,
+        )
+        .await
+        .expect("write stream");
+        assert_eq!(b"firstsecond".to_vec(), out);
+        assert_eq!(8, budget.available());
+    }
+
+    #[tokio::test]
+    async fn downloads_are_limited_to_the_configured_concurrency() {
+        // (in flight now, most in flight at once)
+        let counts = Mutex::new((0, 0));
+        let downloads = (0..6).map(|i| {
+            let counts = &counts;
+            async move {
+                {
+                    let mut counts = counts.lock().unwrap();
+                    counts.0 += 1;
+                    counts.1 = counts.1.max(counts.0);
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                counts.lock().unwrap().0 -= 1;
+                Ok::<_, anyhow::Error>(i)
+            }
+        });
+
+        let results = download_all(downloads, Some(2)).await.expect("downloads");
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], results);
+        assert_eq!(2, counts.lock().unwrap().1);
+    }
+
+    #[tokio::test]
+    async fn the_first_failed_download_stops_the_rest() {
+        for limit in vec![None, Some(0), Some(2)] {
+            let downloads = (0..3).map(|i| async move {
+                if i == 0 {
+                    Err(anyhow::anyhow!("layer {} failed", i))
+                } else {
+                    // These would never finish if they were waited for
+                    future::pending::<anyhow::Result<usize>>().await
+                }
+            });
+            let err = download_all(downloads, limit)
+                .await
+                .expect_err("first download fails");
+            assert_eq!("layer 0 failed", err.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_download_does_not_wait_for_earlier_ones() {
+        for limit in vec![None, Some(2)] {
+            let downloads = (0..3).map(|i| async move {
+                if i == 1 {
+                    Err(anyhow::anyhow!("layer {} failed", i))
+                } else {
+                    future::pending::<anyhow::Result<usize>>().await
+                }
+            });
+            let err = tokio::time::timeout(Duration::from_secs(5), download_all(downloads, limit))
+                .await
+                .expect("the failure should be returned without waiting")
+                .expect_err("second download fails");
+            assert_eq!("layer 1 failed", err.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn write_stream_times_out_when_the_stream_stalls() {
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = vec![Ok(b"first".to_vec())];
+        let stream = futures_util::stream::iter(chunks).chain(futures_util::stream::pending());
+        let mut out: Vec<u8> = Vec::new();
+        let timeout = Duration::from_millis(50);
+        let err = write_stream(stream, &mut out, None, None, None, Some(timeout))
+            .await
+            .expect_err("stalled stream should time out");
+        assert_eq!(
+            Some(&ClientError::TimedOut(timeout)),
+            err.downcast_ref::<ClientError>()
+        );
+        assert_eq!(b"first".to_vec(), out);
+    }
+
+    #[tokio::test]
+    async fn byte_budget_waits_for_released_bytes() {
+        use futures_util::FutureExt;
+
+        let budget = ByteBudget::new(10);
+        let share = budget.acquire(8).await;
+        assert!(budget.acquire(4).now_or_never().is_none());
+        assert!(budget.acquire(2).now_or_never().is_some());
+
+        drop(share);
+        let share = budget.acquire(4).now_or_never();
+        assert!(share.is_some());
+        drop(share);
+
+        // Oversized requests wait for the whole budget rather than forever
+        assert!(budget.acquire(100).now_or_never().is_some());
+        assert_eq!(10, budget.available());
+    }
+
+    #[test]
+    fn can_generate_valid_digest() {
+        let bytes = b"hellobytes";
+        let hash = sha256_digest(&bytes.to_vec());
+
+        let combination = vec![b"hello".to_vec(), b"bytes".to_vec()];
+        let combination_hash =
+            sha256_digest(&combination.into_iter().flatten().collect::<Vec<u8>>());
+
+        assert_eq!(
+            hash,
+            "sha256:fdbd95aafcbc814a2600fcc54c1e1706f52d2f9bf45cf53254f25bcd7599ce99"
+        );
+        assert_eq!(
+            combination_hash,
+            "sha256:fdbd95aafcbc814a2600fcc54c1e1706f52d2f9bf45cf53254f25bcd7599ce99"
+        );
+    }
+
+    #[test]
+    fn media_types_match_case_insensitively_and_ignore_parameters() {
+        assert!(media_type_matches(
+            manifest::WASM_LAYER_MEDIA_TYPE,
+            "Application/Vnd.Wasm.Content.Layer.v1+WASM"
+        ));
+        assert!(media_type_matches(
+            manifest::IMAGE_MANIFEST_MEDIA_TYPE,
+            "application/vnd.docker.distribution.manifest.v2+json; charset=utf-8"
+        ));
+        assert!(!media_type_matches(
+            manifest::IMAGE_LAYER_MEDIA_TYPE,
+            manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE
+        ));
+    }
+
+    #[test]
+    fn manifest_content_type_follows_manifest_media_type() {
+        let mut m = OciManifest::default();
+        assert_eq!(manifest_content_type(&m), manifest::OCI_IMAGE_MEDIA_TYPE);
+
+        for media_type in &[
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            manifest::IMAGE_MANIFEST_MEDIA_TYPE,
+            manifest::IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+        ] {
+            m.media_type = Some(media_type.to_string());
+            assert_eq!(manifest_content_type(&m), *media_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn validate_layers_tolerates_parameterized_and_mixed_case_types() {
+        let mut manifest = OciManifest::default();
+        manifest.layers.push(OciDescriptor {
+            media_type: "application/VND.wasm.content.layer.v1+wasm; foo=bar".to_owned(),
+            ..Default::default()
+        });
+        Client::default()
+            .validate_layers(&manifest, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .expect("layer media type should be accepted");
+    }
+
+    #[tokio::test]
+    async fn validate_layers_names_incompatible_media_types() {
+        let mut manifest = OciManifest::default();
+        for media_type in &[
+            manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+            manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+            manifest::WASM_LAYER_MEDIA_TYPE,
+        ] {
+            manifest.layers.push(OciDescriptor {
+                media_type: (*media_type).to_owned(),
+                ..Default::default()
+            });
+        }
+
+        let err = Client::default()
+            .validate_layers(&manifest, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .expect_err("container layers should be rejected");
+        assert_eq!(
+            Some(&ClientError::IncompatibleMediaTypes {
+                found: vec![
+                    manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE.to_owned(),
+                    manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+                ],
+                accepted: vec![manifest::WASM_LAYER_MEDIA_TYPE.to_owned()],
+            }),
+            err.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_layers_allows_empty_artifacts_only_when_enabled() {
+        let artifact: OciManifest = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "artifactType": "application/vnd.example.sbom.v1",
+                "config": {
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                    "size": 2
+                }
+            }"#,
+        )
+        .expect("artifact manifest should parse");
+        assert!(artifact.is_artifact());
+        assert!(artifact.layers.is_empty());
+
+        let c = Client::new(ClientConfig {
+            allow_empty_layers: true,
+            ..Default::default()
+        });
+        c.validate_layers(&artifact, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .expect("empty artifact should be allowed");
+        assert!(Client::default()
+            .validate_layers(&artifact, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .is_err());
+
+        let image = OciManifest {
+            config: OciDescriptor {
+                media_type: manifest::IMAGE_CONFIG_MEDIA_TYPE.to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(!image.is_artifact());
+        assert!(c
+            .validate_layers(&image, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn validate_image_manifest_tolerates_parameterized_media_type() {
+        let text = r#"{"schemaVersion": 2, "mediaType": "application/vnd.docker.distribution.manifest.v2+JSON; charset=utf-8"}"#;
+        Client::default()
+            .validate_image_manifest(text, None)
+            .await
+            .expect("manifest media type should be accepted");
+    }
+
+    const MANIFEST_BODY: &str = r#"{
+        "schemaVersion": 2,
+        MEDIA_TYPE
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+            "size": 2
+        },
+        "layers": [
+            {
+                "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip",
+                "digest": "sha256:b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c",
+                "size": 4
+            }
+        ]
+    }"#;
+
+    #[tokio::test]
+    async fn validate_image_manifest_accepts_docker_and_oci_manifests() {
+        for media_type in &[
+            manifest::IMAGE_MANIFEST_MEDIA_TYPE,
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+        ] {
+            let text =
+                MANIFEST_BODY.replace("MEDIA_TYPE", &format!(r#""mediaType": "{}","#, media_type));
+            Client::default()
+                .validate_image_manifest(&text, None)
+                .await
+                .expect("manifest media type should be accepted");
+            let manifest: OciManifest = serde_json::from_str(&text).expect("manifest should parse");
+            assert_eq!(Some(media_type.to_string()), manifest.media_type);
+            assert_eq!(1, manifest.layers.len());
+
+            // Registries often leave the media type out of the body and only
+            // send it as the content type
+            let text = MANIFEST_BODY.replace("MEDIA_TYPE", "");
+            Client::default()
+                .validate_image_manifest(&text, Some(media_type))
+                .await
+                .expect("content type should be accepted");
+            serde_json::from_str::<OciManifest>(&text).expect("manifest should parse");
+        }
+    }
+
+    #[tokio::test]
+    async fn validate_image_manifest_rejects_other_media_types() {
+        for media_type in &[
+            manifest::IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+            "application/vnd.docker.distribution.manifest.v1+json",
+        ] {
+            let text =
+                MANIFEST_BODY.replace("MEDIA_TYPE", &format!(r#""mediaType": "{}","#, media_type));
+            assert!(Client::default()
+                .validate_image_manifest(&text, None)
+                .await
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn registry_token_accepts_known_aliases() {
+        for body in &[
+            r#"{"token": "abc"}"#,
+            r#"{"access_token": "abc"}"#,
+            r#"{"token": "abc", "access_token": "abc"}"#,
+            r#"{"access_token": "abc", "refresh_token": "refresh"}"#,
+        ] {
+            let token = RegistryToken::parse(body).expect("token should parse");
+            assert_eq!(token.bearer_token(), "Bearer abc");
+        }
+    }
+
+    #[test]
+    fn registry_token_never_uses_refresh_token_as_bearer() {
+        assert!(RegistryToken::parse(r#"{"refresh_token": "refresh"}"#).is_err());
+
+        let token = RegistryToken::parse(r#"{"access_token": "abc", "refresh_token": "refresh"}"#)
+            .expect("token should parse");
+        assert_eq!(Some("refresh"), token.refresh_token.as_deref());
+        assert!(!format!("{:?}", token).contains("refresh\""));
+    }
+
+    #[test]
+    fn registry_token_debug_redacts_token() {
+        let token = RegistryToken::parse(
+            r#"{"token": "supersecret", "expires_in": 300, "issued_at": "2020-01-01T00:00:00Z"}"#,
+        )
+        .expect("token should parse");
+        let logged = format!("{:?}", token);
+        assert!(!logged.contains("supersecret"));
+        assert!(logged.contains("<redacted>"));
+        assert!(logged.contains("300"));
+        assert!(logged.contains("2020-01-01T00:00:00Z"));
+
+        let header = token.authorization_header();
+        assert!(header.is_sensitive());
+        assert!(!format!("{:?}", header).contains("supersecret"));
+    }
+
+    #[test]
+    fn registry_token_expiry_counts_from_issue_time() {
+        let now = Instant::now();
+        let utc_now = chrono::DateTime::parse_from_rfc3339("2020-01-01T00:01:40Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let mut token = RegistryToken::parse(
+            r#"{"token": "abc", "expires_in": 300, "issued_at": "2020-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(now + Duration::from_secs(200)),
+            token.expiry(now, utc_now)
+        );
+
+        // Without an issue time, the lifetime counts from now
+        token.issued_at = None;
+        assert_eq!(
+            Some(now + Duration::from_secs(300)),
+            token.expiry(now, utc_now)
+        );
+
+        token.expires_at = token.expiry(now, utc_now);
+        assert!(!token.is_expired(now));
+        assert!(token.is_expired(now + Duration::from_secs(296)));
+
+        // Tokens without a lifetime never expire
+        let token = RegistryToken::parse(r#"{"token": "abc"}"#).unwrap();
+        assert_eq!(None, token.expires_at);
+        assert!(!token.is_expired(now + Duration::from_secs(86400)));
+    }
+
+    #[rstest(header, realm, service,
+        case::docker_hub(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io""#,
+            "https://auth.docker.io/token",
+            "registry.docker.io"
+        ),
+        case::ghcr(
+            r#"Bearer realm="https://ghcr.io/token",service="ghcr.io",scope="repository:user/image:pull""#,
+            "https://ghcr.io/token",
+            "ghcr.io"
+        ),
+        case::harbor(
+            r#"Bearer realm="https://harbor.example.com/service/token",service="harbor-registry""#,
+            "https://harbor.example.com/service/token",
+            "harbor-registry"
+        ),
+        case::relative_path(
+            r#"Bearer realm="/v2/token""#,
+            "https://registry.example.com:5000/v2/token",
+            "registry.example.com:5000"
+        ),
+        case::bare_path(
+            r#"Bearer realm="token",service="internal""#,
+            "https://registry.example.com:5000/token",
+            "internal"
+        ),
+        case::port_qualified_host(
+            r#"Bearer realm="auth.example.com:5001/auth",service="registry""#,
+            "https://auth.example.com:5001/auth",
+            "registry"
+        ),
+    )]
+    fn token_endpoints_are_resolved_from_challenges(header: &str, realm: &str, service: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::WWW_AUTHENTICATE, header.parse().unwrap());
+        let challenge = bearer_challenge(&headers)
+            .expect("header should parse")
+            .expect("challenge should be present");
+        let c = Client::default();
+        assert_eq!(
+            (realm.to_owned(), service.to_owned()),
+            c.token_endpoint("registry.example.com:5000", &challenge)
+                .expect("challenge has a realm")
+        );
+    }
+
+    #[test]
+    fn challenges_without_a_realm_are_an_error() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::WWW_AUTHENTICATE,
+            r#"Bearer service="registry.example.com""#.parse().unwrap(),
+        );
+        let challenge = bearer_challenge(&headers)
+            .expect("header should parse")
+            .expect("challenge should be present");
+        let err = Client::default()
+            .token_endpoint("registry.example.com", &challenge)
+            .expect_err("no realm");
+        assert_eq!(
+            Some(&ClientError::MissingRealm(
+                "registry.example.com".to_owned()
+            )),
+            err.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticated_gets_only_send_credentials_to_the_registry() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        c.store_token(
+            image.registry(),
+            &RegistryAuth::Anonymous,
+            mock_token("secret", "repository:hello:pull"),
+        );
+
+        c.authenticated_get(
+            &image,
+            &RegistryAuth::Anonymous,
+            "https://mock.example.com/v2/hello/tags/list",
+        )
+        .await
+        .expect("get from the registry");
+        c.authenticated_get(
+            &image,
+            &RegistryAuth::Anonymous,
+            "https://storage.example.com/v2/hello/tags/list",
+        )
+        .await
+        .expect("get from another host");
+
+        assert_eq!(
+            vec![Some("Bearer secret".to_owned()), None],
+            registry.authorizations()
+        );
+    }
+
+    #[test]
+    fn registry_urls_match_host_and_port() {
+        let url = |u: &str| reqwest::Url::parse(u).unwrap();
+        assert!(is_registry_url("ghcr.io", &url("https://ghcr.io/v2/")));
+        assert!(is_registry_url("GHCR.io", &url("https://ghcr.io/v2/")));
+        assert!(is_registry_url(
+            "localhost:5000",
+            &url("http://localhost:5000/v2/")
+        ));
+        assert!(!is_registry_url(
+            "localhost:5000",
+            &url("http://localhost/v2/")
+        ));
+        assert!(!is_registry_url(
+            "ghcr.io",
+            &url("https://ghcr.io.evil.com/v2/")
+        ));
+        assert!(!is_registry_url(
+            "ghcr.io",
+            &url("https://ghcr.io:8443/v2/")
+        ));
+    }
+
+    #[tokio::test]
+    async fn credentials_are_exchanged_for_a_scoped_token_on_pull() {
+        let registry = MockRegistry::new();
+        registry.add_manifest(
+            "private",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            br#"{"schemaVersion": 2, "config": {"mediaType": "application/vnd.wasm.config.v1+json", "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a", "size": 2}, "layers": []}"#,
+        );
+        registry.enqueue(MockResponse::new(401).header(
+            "WWW-Authenticate",
+            r#"Bearer realm="https://mock.example.com/token",service="mock.example.com""#,
+        ));
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "scoped"}"#));
+        let c = registry.client();
+        let image: Reference = "mock.example.com/private:v1".parse().unwrap();
+        let auth = RegistryAuth::Basic("user".to_owned(), "pat".to_owned());
+
+        c.pull_manifest(&image, &auth).await.expect("first pull");
+        c.pull_manifest(&image, &auth).await.expect("second pull");
+
+        assert_eq!(
+            vec![
+                "GET /v2/",
+                "GET /token?service=mock.example.com&scope=repository%3Aprivate%3Apull",
+                "GET /v2/private/manifests/v1",
+                "GET /v2/private/manifests/v1",
+            ],
+            registry.requests()
+        );
+        // Only the token endpoint sees the credentials, and the token is
+        // reused for the second pull
+        assert_eq!(
+            vec![
+                None,
+                Some(format!("Basic {}", base64::encode("user:pat"))),
+                Some("Bearer scoped".to_owned()),
+                Some("Bearer scoped".to_owned()),
+            ],
+            registry.authorizations()
+        );
+    }
+
+    #[tokio::test]
+    async fn relative_realms_are_requested_from_the_registry() {
+        let registry = MockRegistry::new();
+        registry
+            .enqueue(MockResponse::new(401).header("WWW-Authenticate", r#"Bearer realm="/token""#));
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "abc"}"#));
+        let c = registry.client();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Pull)
+            .await
+            .expect("auth with relative realm");
+        assert_eq!(
+            vec![
+                "GET /v2/".to_owned(),
+                "GET /token?service=mock.example.com&scope=repository%3Ahello%3Apull".to_owned(),
+            ],
+            registry.requests()
+        );
+    }
+
+    #[test]
+    fn bearer_challenge_is_read_from_headers() {
+        // The challenge is parsed from the headers alone, so a registry that
+        // sends it with a 200 is handled the same as one that sends a 401.
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::WWW_AUTHENTICATE,
+            r#"Bearer realm="https://auth.example.com/token",service="example.com""#
+                .parse()
+                .unwrap(),
+        );
+        let challenge = bearer_challenge(&headers)
+            .expect("header should parse")
+            .expect("challenge should be present");
+        assert_eq!(
+            Some("https://auth.example.com/token"),
+            challenge.realm.as_deref()
+        );
+        assert_eq!(Some("example.com"), challenge.service.as_deref());
+
+        assert!(bearer_challenge(&HeaderMap::new())
+            .expect("no header is not an error")
+            .is_none());
+    }
+
+    #[test]
+    fn insufficient_scope_challenges_are_recognized() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::WWW_AUTHENTICATE,
+            r#"Bearer realm="https://auth.example.com/token",service="example.com",scope="repository:base:pull",error="insufficient_scope""#
+                .parse()
+                .unwrap(),
+        );
+        let challenge =
+            insufficient_scope_challenge(&headers).expect("challenge should be recognized");
+        assert_eq!(Some("repository:base:pull"), challenge.scope.as_deref());
+
+        // An ordinary challenge is left to the caller
+        headers.insert(
+            reqwest::header::WWW_AUTHENTICATE,
+            r#"Bearer realm="https://auth.example.com/token",service="example.com",error="invalid_token""#
+                .parse()
+                .unwrap(),
+        );
+        assert!(insufficient_scope_challenge(&headers).is_none());
+    }
+
+    #[test]
+    fn anonymous_registries_get_no_authorization_header() {
+        let c = Client::default();
+        let reference = Reference::try_from("webassembly.azurecr.io/hello:v1")
+            .expect("Could not parse reference");
+        c.store_token(
+            reference.registry(),
+            &RegistryAuth::Anonymous,
+            RegistryTokenType::Anonymous,
+        );
+        assert!(c.auth_headers(&reference).get("Authorization").is_none());
+
+        c.store_token(
+            reference.registry(),
+            &RegistryAuth::Anonymous,
+            RegistryTokenType::Bearer(RegistryToken::parse(r#"{"token": "abc"}"#).unwrap()),
+        );
+        assert!(c.auth_headers(&reference).get("Authorization").is_some());
+    }
+
+    #[test]
+    fn tokens_are_cached_per_registry_and_credentials() {
+        let c = Client::default();
+        let first =
+            Reference::try_from("first.example.com/hello:v1").expect("Could not parse reference");
+        let second =
+            Reference::try_from("second.example.com/hello:v1").expect("Could not parse reference");
+        let first_auth = RegistryAuth::Basic("first".to_owned(), "secret".to_owned());
+        let second_auth = RegistryAuth::Basic("second".to_owned(), "secret".to_owned());
+
+        c.store_token(
+            first.registry(),
+            &first_auth,
+            RegistryTokenType::Bearer(RegistryToken::parse(r#"{"token": "abc"}"#).unwrap()),
+        );
+
+        assert!(c.has_token_for(first.registry(), &first_auth));
+        // Another registry needs its own token, even with the same credentials
+        assert!(!c.has_token_for(second.registry(), &first_auth));
+        assert!(!c.has_token_for(second.registry(), &second_auth));
+        assert!(c.auth_headers(&second).get("Authorization").is_none());
+        // Different credentials for the same registry need a new token
+        assert!(!c.has_token_for(first.registry(), &second_auth));
+        assert!(!c.has_token_for(first.registry(), &RegistryAuth::Anonymous));
+
+        c.store_token(
+            second.registry(),
+            &second_auth,
+            RegistryTokenType::Bearer(RegistryToken::parse(r#"{"token": "def"}"#).unwrap()),
+        );
+        assert_eq!(
+            "Bearer abc",
+            c.auth_headers(&first)["Authorization"].to_str().unwrap()
+        );
+        assert_eq!(
+            "Bearer def",
+            c.auth_headers(&second)["Authorization"].to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn cleared_tokens_require_new_authentication() {
+        let c = Client::default();
+        for registry in &["first.example.com", "second.example.com"] {
+            c.store_token(
+                registry,
+                &RegistryAuth::Anonymous,
+                RegistryTokenType::Anonymous,
+            );
+        }
+
+        c.clear_token("first.example.com");
+        assert!(!c.has_token_for("first.example.com", &RegistryAuth::Anonymous));
+        assert!(c.has_token_for("second.example.com", &RegistryAuth::Anonymous));
+
+        c.clear_all_tokens();
+        assert!(!c.has_token_for("second.example.com", &RegistryAuth::Anonymous));
+    }
+
+    #[test]
+    fn clones_share_cached_tokens() {
+        fn assert_shareable<T: Clone + Send + Sync>() {}
+        assert_shareable::<Client>();
+
+        let c = Client::default();
+        let clone = c.clone();
+        clone.store_token(
+            "first.example.com",
+            &RegistryAuth::Anonymous,
+            RegistryTokenType::Anonymous,
+        );
+        assert!(c.has_token_for("first.example.com", &RegistryAuth::Anonymous));
+
+        c.clear_all_tokens();
+        assert!(!clone.has_token_for("first.example.com", &RegistryAuth::Anonymous));
+    }
+
+    #[test]
+    fn registry_token_rejects_empty_token() {
+        for body in &["{}", r#"{"token": ""}"#, r#"{"token": null}"#] {
+            assert!(RegistryToken::parse(body).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn anonymous_auth_to_public_registry_skips_ping() {
+        let mut registry_overrides = HashMap::new();
+        registry_overrides.insert(
+            "public.invalid".to_owned(),
+            RegistryConfig {
+                public: true,
+                ..Default::default()
+            },
+        );
+        let c = Client::new(ClientConfig {
+            registry_overrides,
+            ..Default::default()
+        });
+        let reference = Reference::try_from("public.invalid/hello:v1").unwrap();
+
+        // The host doesn't resolve, so this only succeeds without a request
+        c.auth(
+            &reference,
+            &RegistryAuth::Anonymous,
+            &RegistryOperation::Pull,
+        )
+        .await
+        .expect("anonymous auth to a public registry needs no request");
+        assert!(c.has_token_for("public.invalid", &RegistryAuth::Anonymous));
+
+        // Credentials are still exchanged for a token
+        assert!(c
+            .auth(
+                &reference,
+                &RegistryAuth::Basic("user".to_owned(), "pass".to_owned()),
+                &RegistryOperation::Pull,
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        for &image in TEST_IMAGES {
+            let reference = Reference::try_from(image).expect("failed to parse reference");
+            let c = Client::default();
+            c.auth(
+                &reference,
+                &RegistryAuth::Anonymous,
+                &RegistryOperation::Pull,
+            )
+            .await
+            .expect("result from auth request");
+
+            let tokens = c.tokens.lock().unwrap();
+            let tok = match &tokens
+                .get(reference.registry())
+                .expect("token is available")
+                .token
+            {
+                RegistryTokenType::Bearer(tok) => tok,
+                _ => panic!("expected a bearer token"),
+            };
+            // We test that the token is longer than a minimal hash.
+            assert!(tok.token.len() > 64);
+        }
+    }
+
+    #[test]
+    fn manifest_digest_prefers_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Docker-Content-Digest",
+            "sha256:fromheader".parse().unwrap(),
+        );
+        assert_eq!(
+            "sha256:fromheader",
+            manifest_digest(&headers, b"{}").expect("digest")
+        );
+    }
+
+    #[test]
+    fn manifest_digest_is_computed_without_header() {
+        assert_eq!(
+            sha256_digest(b"{}"),
+            manifest_digest(&HeaderMap::new(), b"{}").expect("digest")
+        );
+    }
+
+    #[test]
+    fn image_layer_constructors_set_media_types() {
+        assert_eq!(
+            manifest::WASM_LAYER_MEDIA_TYPE,
+            ImageLayer::wasm(vec![]).media_type
+        );
+        let layer = ImageLayer::with_media_type(
+            vec![],
+            "application/vnd.example.artifact.v1+json; charset=utf-8",
+        )
+        .expect("media type should be valid");
+        assert_eq!(
+            "application/vnd.example.artifact.v1+json; charset=utf-8",
+            layer.media_type
+        );
+    }
+
+    #[test]
+    fn image_layer_rejects_malformed_media_types() {
+        for media_type in &[
+            "",
+            "application",
+            "application/",
+            "/wasm",
+            "application/vnd.wasm/extra",
+            "application/vnd wasm",
+            "application/.wasm",
+        ] {
+            assert!(
+                ImageLayer::with_media_type(vec![], media_type).is_err(),
+                "{:?} should be rejected",
+                media_type
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn check_succeeds_for_public_registry() {
+        Client::default()
+            .check("webassembly.azurecr.io", &RegistryAuth::Anonymous)
+            .await
+            .expect("registry should be reachable");
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_short_circuits_failing_registry() {
+        let c = Client::new(ClientConfig {
+            circuit_breaker: Some(CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown: std::time::Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+        let image: Reference = "registry.invalid/hello:v1".parse().unwrap();
+        for _ in 0..2 {
+            let err = c
+                .fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+                .await
+                .expect_err("registry should not resolve");
+            assert_eq!(
+                Some(&ClientError::RegistryUnresolvable(
+                    "registry.invalid".to_owned()
+                )),
+                err.downcast_ref::<ClientError>()
+            );
+        }
+
+        let err = c
+            .fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("breaker should be open");
+        assert_eq!(
+            Some(&ClientError::RegistryUnavailable(
+                "registry.invalid".to_owned()
+            )),
+            err.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[tokio::test]
+    async fn pushed_images_can_be_pulled_from_mock_registry() {
+        let registry = MockRegistry::new();
+        let c = registry.client();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+
+        let response = c
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push to mock registry");
+        assert_eq!(
+            Some(b"iamawebassemblymodule".to_vec()),
+            registry.blob(image_data.layers[0].digest())
+        );
+        assert!(registry.manifest("hello", "v1").is_some());
+
+        let pulled = c
+            .pull(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("pull from mock registry");
+        assert_eq!(image_data.layers[0].data, pulled.layers[0].data);
+        assert_eq!(Some(response.manifest_digest), pulled.digest);
+    }
+
+    #[tokio::test]
+    async fn estimated_size_resolves_the_current_platform_in_an_index() {
+        let registry = MockRegistry::new();
+        let platform = Platform::current();
+        let child = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": manifest::IMAGE_CONFIG_MEDIA_TYPE,
+                "digest": sha256_digest(b"config"),
+                "size": 100,
+            },
+            "layers": [
+                {
+                    "mediaType": manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                    "digest": sha256_digest(b"layer0"),
+                    "size": 1000,
+                },
+                {
+                    "mediaType": manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                    "digest": sha256_digest(b"layer1"),
+                    "size": 20000,
+                },
+            ],
+        })
+        .to_string();
+        let child_digest = registry.add_manifest(
+            "multi",
+            "current",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            child.as_bytes(),
+        );
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": [
+                {
+                    "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                    "digest": sha256_digest(b"other"),
+                    "size": 10,
+                    "platform": { "os": "plan9", "architecture": "mips" },
+                },
+                {
+                    "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                    "digest": child_digest,
+                    "size": child.len(),
+                    "platform": { "os": platform.os, "architecture": platform.architecture },
+                },
+            ],
+        })
+        .to_string();
+        registry.add_manifest(
+            "multi",
+            "v1",
+            manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            index.as_bytes(),
+        );
+
+        let image: Reference = "mock.example.com/multi:v1".parse().unwrap();
+        let size = registry
+            .client()
+            .estimated_size(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect("estimate size");
+        assert_eq!(21100, size);
+        // Only manifests are fetched
+        assert!(registry.requests().iter().all(|r| !r.contains("/blobs/")));
+    }
+
+    #[tokio::test]
+    async fn estimated_size_rejects_negative_and_overflowing_sizes() {
+        let registry = MockRegistry::new();
+        let manifest_with_sizes = |config_size: i64, layer_size: i64| {
+            serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                "config": {
+                    "mediaType": manifest::IMAGE_CONFIG_MEDIA_TYPE,
+                    "digest": sha256_digest(b"config"),
+                    "size": config_size,
+                },
+                "layers": [
+                    {
+                        "mediaType": manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                        "digest": sha256_digest(b"layer0"),
+                        "size": layer_size,
+                    },
+                    {
+                        "mediaType": manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                        "digest": sha256_digest(b"layer1"),
+                        "size": i64::MAX,
+                    },
+                ],
+            })
+            .to_string()
+        };
+        registry.add_manifest(
+            "sizes",
+            "negative",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest_with_sizes(100, -1).as_bytes(),
+        );
+        registry.add_manifest(
+            "sizes",
+            "overflowing",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest_with_sizes(i64::MAX, i64::MAX).as_bytes(),
+        );
+        let client = registry.client();
+
+        let image: Reference = "mock.example.com/sizes:negative".parse().unwrap();
+        let err = client
+            .estimated_size(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("a negative size should be rejected");
+        assert!(err.to_string().contains("negative size -1"), "{}", err);
+
+        let image: Reference = "mock.example.com/sizes:overflowing".parse().unwrap();
+        let err = client
+            .estimated_size(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("an overflowing total should be rejected");
+        assert!(err.to_string().contains("add up to more than"), "{}", err);
+    }
+
+    /// Stores a single-layer WASM image for each platform and an index of
+    /// them tagged `v1`, returning the index digest
+    fn add_multi_platform_image(registry: &MockRegistry, platforms: &[Platform]) -> String {
+        let config = registry.add_blob(b"{}");
+        let entries: Vec<serde_json::Value> = platforms
+            .iter()
+            .map(|platform| {
+                let name = platform_name(platform);
+                let layer = registry.add_blob(name.as_bytes());
+                let manifest = serde_json::json!({
+                    "schemaVersion": 2,
+                    "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                    "config": {
+                        "mediaType": manifest::WASM_CONFIG_MEDIA_TYPE,
+                        "digest": config,
+                        "size": 2,
+                    },
+                    "layers": [{
+                        "mediaType": manifest::WASM_LAYER_MEDIA_TYPE,
+                        "digest": layer,
+                        "size": name.len(),
+                    }],
+                })
+                .to_string();
+                let digest = registry.add_manifest(
+                    "multi",
+                    &name.replace('/', "-"),
+                    manifest::OCI_IMAGE_MEDIA_TYPE,
+                    manifest.as_bytes(),
+                );
+                serde_json::json!({
+                    "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+                    "digest": digest,
+                    "size": manifest.len(),
+                    "platform": platform,
+                })
+            })
+            .collect();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            "manifests": entries,
+        })
+        .to_string();
+        registry.add_manifest(
+            "multi",
+            "v1",
+            manifest::OCI_IMAGE_INDEX_MEDIA_TYPE,
+            index.as_bytes(),
+        )
+    }
+
+    fn linux(architecture: &str, variant: Option<&str>) -> Platform {
+        Platform {
+            architecture: architecture.to_owned(),
+            os: "linux".to_owned(),
+            os_version: None,
+            os_features: None,
+            variant: variant.map(|v| v.to_owned()),
+            features: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn image_indexes_are_pulled_for_the_requested_platform() {
+        let registry = MockRegistry::new();
+        let index_digest = add_multi_platform_image(
+            &registry,
+            &[
+                linux("amd64", None),
+                linux("arm", Some("v7")),
+                linux("arm64", Some("v8")),
+            ],
+        );
+        let image: Reference = "mock.example.com/multi:v1".parse().unwrap();
+
+        let image_data = registry
+            .client()
+            .pull_for_platform(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &linux("arm", Some("v7")),
+            )
+            .await
+            .expect("pull for platform");
+        assert_eq!(b"linux/arm/v7".to_vec(), image_data.layers[0].data);
+        // The digest is that of the index the tag points at
+        assert_eq!(Some(index_digest), image_data.digest);
+
+        // Other pulls use the configured platform
+        let c = registry.client_with_config(ClientConfig {
+            platform: Some(linux("arm64", None)),
+            ..Default::default()
+        });
+        let image_data = c
+            .pull(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("pull for configured platform");
+        assert_eq!(b"linux/arm64/v8".to_vec(), image_data.layers[0].data);
+
+        let err = c
+            .pull_for_platform(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &linux("s390x", None),
+            )
+            .await
+            .expect_err("no image for the platform");
+        assert!(err.to_string().contains("linux/s390x"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn platform_manifests_must_match_their_index_entries() {
+        let registry = MockRegistry::new();
+        add_multi_platform_image(&registry, &[linux("amd64", None)]);
+        let child = registry
+            .manifest("multi", "linux-amd64")
+            .expect("platform manifest");
+        let child_digest = sha256_digest(&child);
+        // The registry serves something else under the digest the index names
+        let mut tampered: serde_json::Value = serde_json::from_slice(&child).unwrap();
+        tampered["annotations"] = serde_json::json!({ "tampered": "true" });
+        let tampered = serde_json::to_vec(&tampered).unwrap();
+        registry.add_manifest(
+            "multi",
+            &child_digest,
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            &tampered,
+        );
+        let image: Reference = "mock.example.com/multi:v1".parse().unwrap();
+
+        let err = registry
+            .client()
+            .pull_for_platform(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &linux("amd64", None),
+            )
+            .await
+            .expect_err("tampered platform manifest should be rejected");
+        assert_eq!(
+            Some(&ClientError::DigestMismatch {
+                expected: child_digest,
+                actual: sha256_digest(&tampered),
+            }),
+            err.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[tokio::test]
+    async fn single_layers_are_pulled_and_verified() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let digest = registry.add_blob(b"layer");
+        let c = registry.client();
+
+        let mut out: Vec<u8> = Vec::new();
+        c.pull_layer(&image, &RegistryAuth::Anonymous, &digest, &mut out)
+            .await
+            .expect("pull layer");
+        assert_eq!(b"layer".to_vec(), out);
+
+        registry.enqueue(MockResponse::new(200).body("tampered"));
+        let err = c
+            .pull_layer(
+                &image,
+                &RegistryAuth::Anonymous,
+                &digest,
+                &mut Vec::<u8>::new(),
+            )
+            .await
+            .expect_err("tampered layer should fail verification");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::DigestMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn layers_are_uploaded_in_chunks_of_the_configured_size() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/chunked:v1".parse().unwrap();
+        let c = registry.client_with_config(ClientConfig {
+            max_chunk_size: Some(4),
+            ..Default::default()
+        });
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"0123456789".to_vec())],
+            ..Default::default()
+        };
+
+        c.push(
+            &image,
+            &image_data,
+            b"{}",
+            manifest::WASM_CONFIG_MEDIA_TYPE,
+            &RegistryAuth::Anonymous,
+            None,
+        )
+        .await
+        .expect("push in chunks");
+        assert_eq!(
+            Some(b"0123456789".to_vec()),
+            registry.blob(&sha256_digest(b"0123456789"))
+        );
+        // Three chunks for the layer, while the config is sent whole
+        let patches = registry
+            .requests()
+            .iter()
+            .filter(|r| r.starts_with("PATCH"))
+            .count();
+        assert_eq!(3, patches);
+    }
+
+    fn manifest_blob_unknown(digest: &str) -> MockResponse {
+        MockResponse::new(400)
+            .header("Content-Type", "application/json")
+            .body(
+                serde_json::json!({ "errors": [{
+                    "code": "MANIFEST_BLOB_UNKNOWN",
+                    "message": "blob unknown to registry",
+                    "detail": { "digest": digest },
+                }] })
+                .to_string(),
+            )
+    }
+
+    fn blob_upload_completions(registry: &MockRegistry, digest: &str) -> usize {
+        let suffix = format!("digest={}", digest);
+        registry
+            .requests()
+            .iter()
+            .filter(|r| r.starts_with("PUT /v2/hello/blobs/uploads/") && r.ends_with(&suffix))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn unknown_manifest_blobs_are_pushed_again_before_retrying() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let layer_digest = sha256_digest(b"layer");
+        registry.enqueue_for(
+            "PUT /v2/hello/manifests/v1",
+            manifest_blob_unknown(&layer_digest),
+        );
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"layer".to_vec())],
+            ..Default::default()
+        };
+
+        let response = registry
+            .client()
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push after repairing blobs");
+        assert_eq!(2, blob_upload_completions(&registry, &layer_digest));
+        assert_eq!(
+            1,
+            response
+                .uploaded
+                .iter()
+                .filter(|d| **d == layer_digest)
+                .count()
+        );
+        assert_eq!(
+            2,
+            registry
+                .requests()
+                .iter()
+                .filter(|r| r.as_str() == "PUT /v2/hello/manifests/v1")
+                .count()
+        );
+        assert!(registry.manifest("hello", "v1").is_some());
+    }
+
+    #[tokio::test]
+    async fn a_failed_manifest_put_after_repair_is_returned() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let layer_digest = sha256_digest(b"layer");
+        for _ in 0..2 {
+            registry.enqueue_for(
+                "PUT /v2/hello/manifests/v1",
+                manifest_blob_unknown(&layer_digest),
+            );
+        }
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"layer".to_vec())],
+            ..Default::default()
+        };
+
+        let err = registry
+            .client()
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect_err("the retried manifest PUT fails");
+        match err.downcast_ref::<ClientError>() {
+            Some(ClientError::ManifestBlobUnknown(digests)) => {
+                assert_eq!(&vec![layer_digest.clone()], digests)
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        // The manifest is retried only once
+        assert_eq!(
+            2,
+            registry
+                .requests()
+                .iter()
+                .filter(|r| r.as_str() == "PUT /v2/hello/manifests/v1")
+                .count()
+        );
+        assert_eq!(2, blob_upload_completions(&registry, &layer_digest));
+        assert!(registry.manifest("hello", "v1").is_none());
+    }
+
+    #[tokio::test]
+    async fn manifests_are_pulled_parsed_and_raw() {
+        let registry = MockRegistry::new();
+        let manifest = format!(
+            r#"{{
+  "schemaVersion": 2,
+  "mediaType": "{}",
+  "config": {{"mediaType": "{}", "digest": "{}", "size": 2}},
+  "layers": []
+}}"#,
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest::WASM_CONFIG_MEDIA_TYPE,
+            sha256_digest(b"{}")
+        );
+        let digest = registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest.as_bytes(),
+        );
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+
+        let (parsed, parsed_digest) = registry
+            .client()
+            .pull_manifest(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect("pull manifest");
+        assert_eq!(digest, parsed_digest);
+        assert_eq!(manifest::WASM_CONFIG_MEDIA_TYPE, parsed.config.media_type);
+        assert!(parsed.layers.is_empty());
+
+        let (raw, content_type) = registry
+            .client()
+            .pull_manifest_raw(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect("pull raw manifest");
+        assert_eq!(manifest.as_bytes(), raw.as_slice());
+        assert_eq!(
+            Some(manifest::OCI_IMAGE_MEDIA_TYPE),
+            content_type.as_deref()
+        );
+    }
+
+    #[tokio::test]
+    async fn images_are_pulled_with_their_config() {
+        let registry = MockRegistry::new();
+        let config_data = br#"{"architecture": "wasm"}"#;
+        let layer = registry.add_blob(b"iamawebassemblymodule");
+        let config = registry.add_blob(config_data);
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": manifest::WASM_CONFIG_MEDIA_TYPE,
+                "digest": config,
+                "size": config_data.len(),
+            },
+            "layers": [{
+                "mediaType": manifest::WASM_LAYER_MEDIA_TYPE,
+                "digest": layer,
+                "size": 21,
+            }],
+        })
+        .to_string();
+        registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest.as_bytes(),
+        );
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+
+        let (image_data, config_bytes) = registry
+            .client()
+            .pull_manifest_and_config(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("pull image and config");
+        assert_eq!(b"iamawebassemblymodule".to_vec(), image_data.layers[0].data);
+        assert_eq!(config_data.to_vec(), config_bytes);
+    }
+
+    #[tokio::test]
+    async fn manifests_are_deleted_by_digest() {
+        let registry = MockRegistry::new();
+        let c = registry.client();
+        let digest = registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            br#"{"schemaVersion": 2}"#,
+        );
+
+        let by_tag: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        c.delete_manifest(&by_tag, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("tags cannot be deleted");
+        assert!(registry.requests().is_empty());
+
+        let by_digest: Reference = format!("mock.example.com/hello@{}", digest)
+            .parse()
+            .unwrap();
+        c.delete_manifest(&by_digest, &RegistryAuth::Anonymous)
+            .await
+            .expect("delete manifest");
+        assert!(registry.manifest("hello", "v1").is_none());
+        assert!(registry.manifest("hello", &digest).is_none());
+
+        let err = c
+            .delete_manifest(&by_digest, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("manifest is gone");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn blobs_are_deleted_unless_the_registry_forbids_it() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let digest = registry.add_blob(b"layer");
+
+        c.delete_blob(&image, &digest).await.expect("delete blob");
+        assert_eq!(None, registry.blob(&digest));
+
+        registry.enqueue(MockResponse::new(405));
+        let err = c
+            .delete_blob(&image, &digest)
+            .await
+            .expect_err("deletion is disabled");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::DeleteUnsupported(_))
+        ));
+        c.delete_blob(&image, &digest)
+            .await
+            .expect_err("blob is gone");
+    }
+
+    #[test]
+    fn next_links_are_read_from_the_link_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Link",
+            r#"</v2/hello/tags/list?n=2&last=b>; rel="next""#.parse().unwrap(),
+        );
+        assert_eq!(
+            Some("/v2/hello/tags/list?n=2&last=b".to_owned()),
+            next_link(&headers)
+        );
+        headers.insert(
+            "Link",
+            r#"</v2/hello/tags/list>; rel="prev""#.parse().unwrap(),
+        );
+        assert_eq!(None, next_link(&headers));
+        assert_eq!(None, next_link(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn tags_are_listed_page_by_page() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        for tag in &["c", "a", "b"] {
+            registry.add_manifest(
+                "hello",
+                tag,
+                manifest::OCI_IMAGE_MEDIA_TYPE,
+                format!(r#"{{"tag": "{}"}}"#, tag).as_bytes(),
+            );
+        }
+
+        let page = c
+            .list_tags(&image, &RegistryAuth::Anonymous, Some(2), None)
+            .await
+            .expect("list first page");
+        assert_eq!("hello", page.name);
+        assert_eq!(vec!["a", "b"], page.tags);
+        let page = c
+            .list_tags(&image, &RegistryAuth::Anonymous, Some(2), Some("b"))
+            .await
+            .expect("list second page");
+        assert_eq!(vec!["c"], page.tags);
+
+        let tags = c
+            .list_all_tags(&image, &RegistryAuth::Anonymous, Some(1))
+            .await
+            .expect("list all tags");
+        assert_eq!(vec!["a", "b", "c"], tags);
+        assert!(registry
+            .requests()
+            .contains(&"GET /v2/hello/tags/list?n=1&last=b".to_owned()));
+
+        let missing: Reference = "mock.example.com/missing:v1".parse().unwrap();
+        let err = c
+            .list_tags(&missing, &RegistryAuth::Anonymous, None, None)
+            .await
+            .expect_err("repository does not exist");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn repositories_are_listed_from_the_catalog() {
+        let registry = MockRegistry::new();
+        let c = registry.client();
+        for repository in &["wasm/hello", "base", "wasm/goodbye"] {
+            registry.add_manifest(
+                repository,
+                "v1",
+                manifest::OCI_IMAGE_MEDIA_TYPE,
+                br#"{"schemaVersion": 2}"#,
+            );
+        }
+
+        let catalog = c
+            .list_repositories("mock.example.com", &RegistryAuth::Anonymous, Some(2), None)
+            .await
+            .expect("list first page");
+        assert_eq!(vec!["base", "wasm/goodbye"], catalog.repositories);
+
+        let repositories = c
+            .list_all_repositories("mock.example.com", &RegistryAuth::Anonymous, Some(2))
+            .await
+            .expect("list all repositories");
+        assert_eq!(vec!["base", "wasm/goodbye", "wasm/hello"], repositories);
+        assert!(registry
+            .requests()
+            .contains(&"GET /v2/_catalog?n=2&last=wasm/goodbye".to_owned()));
+    }
+
+    fn quick_retries(max_retries: u32) -> ClientConfig {
+        ClientConfig {
+            retry: Some(RetryPolicy {
+                max_retries,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                jitter: crate::retry::JitterStrategy::None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_registries_are_retried_with_backoff() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let digest = registry.add_blob(b"layer");
+        let c = registry.client_with_config(quick_retries(2));
+
+        registry.enqueue(MockResponse::new(503));
+        registry.enqueue(MockResponse::new(502));
+        let mut out: Vec<u8> = Vec::new();
+        c.pull_layer(&image, &RegistryAuth::Anonymous, &digest, &mut out)
+            .await
+            .expect("pull after retries");
+        assert_eq!(b"layer".to_vec(), out);
+
+        // The third failure exhausts the retries
+        for _ in 0..3 {
+            registry.enqueue(MockResponse::new(504));
+        }
+        c.pull_layer(&image, &RegistryAuth::Anonymous, &digest, &mut Vec::new())
+            .await
+            .expect_err("retries are exhausted");
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client_with_config(quick_retries(3));
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Pull)
+            .await
+            .expect("authenticated");
+
+        registry.enqueue(MockResponse::new(404));
+        c.resolve_manifest(&image)
+            .await
+            .expect_err("missing manifest");
+        assert_eq!(2, registry.requests().len());
+
+        // Without a policy, server errors fail at once
+        let c = registry.client();
+        registry.enqueue(MockResponse::new(503));
+        c.resolve_manifest(&image)
+            .await
+            .expect_err("unavailable registry");
+        assert_eq!(3, registry.requests().len());
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_are_not_retried() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client_with_config(quick_retries(3));
+        let location = c.begin_push_session(&image).await.expect("begin session");
+
+        // The registry may have stored the chunk before failing, so sending
+        // it again could append it twice
+        registry.enqueue(MockResponse::new(503));
+        c.push_layer(&location, &image, b"layer".to_vec(), 0)
+            .await
+            .expect_err("unavailable registry");
+        let patches = registry
+            .requests()
+            .iter()
+            .filter(|r| r.starts_with("PATCH"))
+            .count();
+        assert_eq!(1, patches);
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_dates() {
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "120".parse().unwrap());
+        assert_eq!(Some(Duration::from_secs(120)), retry_after(&headers, now));
+        headers.insert(
+            "Retry-After",
+            "Wed, 21 Oct 2015 07:28:30 GMT".parse().unwrap(),
+        );
+        assert_eq!(Some(Duration::from_secs(30)), retry_after(&headers, now));
+        headers.insert(
+            "Retry-After",
+            "Wed, 21 Oct 2015 07:27:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(Some(Duration::from_secs(0)), retry_after(&headers, now));
+        headers.insert("Retry-After", "soon".parse().unwrap());
+        assert_eq!(None, retry_after(&headers, now));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_wait_and_retry() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let digest = registry.add_blob(b"layer");
+        let c = registry.client_with_config(ClientConfig {
+            rate_limit_retries: 1,
+            ..Default::default()
+        });
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Pull)
+            .await
+            .expect("authenticated");
+
+        registry.enqueue(MockResponse::new(429).header("Retry-After", "0"));
+        let mut out: Vec<u8> = Vec::new();
+        c.pull_layer(&image, &RegistryAuth::Anonymous, &digest, &mut out)
+            .await
+            .expect("pull after the limit cleared");
+        assert_eq!(b"layer".to_vec(), out);
+
+        registry.enqueue(MockResponse::new(429).header("Retry-After", "0"));
+        registry.enqueue(MockResponse::new(429).header("Retry-After", "5"));
+        let err = c
+            .pull_layer(&image, &RegistryAuth::Anonymous, &digest, &mut Vec::new())
+            .await
+            .expect_err("limit never cleared");
+        assert_eq!(
+            Some(&ClientError::RateLimited {
+                registry: "mock.example.com".to_owned(),
+                retry_after: Some(Duration::from_secs(5)),
+            }),
+            err.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[test]
+    fn no_proxy_entries_match_hosts_and_subdomains() {
+        let no_proxy = vec!["internal.example.com".to_owned(), ".corp".to_owned()];
+        assert!(bypasses_proxy("internal.example.com", &no_proxy));
+        assert!(bypasses_proxy("registry.internal.example.com", &no_proxy));
+        assert!(bypasses_proxy("registry.corp", &no_proxy));
+        assert!(!bypasses_proxy("example.com", &no_proxy));
+        assert!(!bypasses_proxy("notinternal.example.com", &no_proxy));
+        assert!(bypasses_proxy("anything", &["*".to_owned()]));
+    }
+
+    #[test]
+    fn proxies_are_validated_and_keep_registry_schemes() {
+        let config = ClientConfig {
+            proxy: Some("not a url".to_owned()),
+            ..Default::default()
+        };
+        assert!(Client::try_new(config).is_err());
+
+        let config = ClientConfig {
+            protocol: ClientProtocol::HttpsExcept(vec!["insecure.example.com".to_owned()]),
+            proxy: Some("http://proxy.example.com:3128".to_owned()),
+            proxy_credentials: Some(ProxyCredentials {
+                username: "user".to_owned(),
+                password: "secret".to_owned(),
+            }),
+            ..Default::default()
+        };
+        assert!(!format!("{:?}", config).contains("secret"));
+        let c = Client::try_new(config).expect("client with proxy");
+        assert_eq!(
+            "http://insecure.example.com/v2/",
+            c.to_v2_url("insecure.example.com")
+        );
+        assert_eq!(
+            "https://secure.example.com/v2/",
+            c.to_v2_url("secure.example.com")
+        );
+    }
+
+    #[test]
+    fn invalid_tls_settings_fail_client_creation() {
+        let config = ClientConfig {
+            tls: TlsConfig {
+                extra_root_certificates: vec![b"not a certificate".to_vec()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(Client::try_new(config).is_err());
+
+        let config = ClientConfig {
+            tls: TlsConfig {
+                danger_accept_invalid_certs: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(Client::try_new(config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn pull_progress_is_reported_per_layer() {
+        let registry = MockRegistry::new();
+        let layer = registry.add_blob(b"iamawebassemblymodule");
+        let config = registry.add_blob(b"{}");
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": manifest::WASM_CONFIG_MEDIA_TYPE,
+                "digest": config,
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": manifest::WASM_LAYER_MEDIA_TYPE,
+                "digest": layer,
+                "size": 21,
+            }],
+        })
+        .to_string();
+        registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest.as_bytes(),
+        );
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let recorded = updates.clone();
+        let c = registry.client_with_config(ClientConfig {
+            progress: Some(ProgressReporter::new(move |digest, done, total| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((digest.to_owned(), done, total))
+            })),
+            ..Default::default()
+        });
+
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        c.pull(
+            &image,
+            &RegistryAuth::Anonymous,
+            vec![manifest::WASM_LAYER_MEDIA_TYPE],
+        )
+        .await
+        .expect("pull");
+
+        let updates = updates.lock().unwrap();
+        assert_eq!((layer.clone(), 0, Some(21)), updates[0]);
+        assert_eq!(
+            Some(&(layer.clone(), 21, Some(21))),
+            updates.last(),
+            "the last update reports the whole layer"
+        );
+        assert!(updates.iter().all(|(digest, _, _)| digest == &layer));
+    }
+
+    #[tokio::test]
+    async fn layers_pulled_to_files_resume_from_what_the_file_holds() {
+        let registry = MockRegistry::new();
+        let data = b"0123456789abcdef";
+        let digest = registry.add_blob(data);
+        let c = registry.client();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("layer");
+
+        // The registry sends only the missing part
+        std::fs::write(&path, b"01234").unwrap();
+        c.pull_layer_to_file(&image, &RegistryAuth::Anonymous, &digest, &path, true)
+            .await
+            .expect("resume download");
+        assert_eq!(data.to_vec(), std::fs::read(&path).unwrap());
+
+        // The registry ignores the range and sends everything
+        std::fs::write(&path, b"01234").unwrap();
+        registry.enqueue(MockResponse::new(200).body(data.to_vec()));
+        c.pull_layer_to_file(&image, &RegistryAuth::Anonymous, &digest, &path, true)
+            .await
+            .expect("download everything");
+        assert_eq!(data.to_vec(), std::fs::read(&path).unwrap());
+
+        // Without resuming, whatever the file held is replaced
+        std::fs::write(&path, b"a much longer file than the blob itself").unwrap();
+        c.pull_layer_to_file(&image, &RegistryAuth::Anonymous, &digest, &path, false)
+            .await
+            .expect("download into existing file");
+        assert_eq!(data.to_vec(), std::fs::read(&path).unwrap());
+
+        // A file that does not start with the blob fails verification
+        std::fs::write(&path, b"XXXXX").unwrap();
+        let err = c
+            .pull_layer_to_file(&image, &RegistryAuth::Anonymous, &digest, &path, true)
+            .await
+            .expect_err("resumed onto the wrong data");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::DigestMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn unavailable_registries_fall_back_to_mirrors_for_digest_pulls() {
+        let registry = MockRegistry::new();
+        let layer = registry.add_blob(b"iamawebassemblymodule");
+        let config = registry.add_blob(b"{}");
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": manifest::OCI_IMAGE_MEDIA_TYPE,
+            "config": {
+                "mediaType": manifest::WASM_CONFIG_MEDIA_TYPE,
+                "digest": config,
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": manifest::WASM_LAYER_MEDIA_TYPE,
+                "digest": layer,
+                "size": 21,
+            }],
+        })
+        .to_string();
+        let digest = registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            manifest.as_bytes(),
+        );
+        let mut mirrors = HashMap::new();
+        mirrors.insert(
+            "primary.example.com".to_owned(),
+            vec!["mirror.example.com".to_owned()],
+        );
+        let c = registry.client_with_config(ClientConfig {
+            mirrors,
+            ..Default::default()
+        });
+
+        // The mock serves every host, so the primary fails only while there
+        // are queued responses: one for its ping and one for the manifest
+        let by_digest: Reference = format!("primary.example.com/hello@{}", digest)
+            .parse()
+            .unwrap();
+        registry.enqueue(MockResponse::new(503));
+        registry.enqueue(MockResponse::new(503));
+        let image_data = c
+            .pull(
+                &by_digest,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("pull from mirror");
+        assert_eq!(b"iamawebassemblymodule".to_vec(), image_data.layers[0].data);
+
+        registry.enqueue(MockResponse::new(503));
+        let mut out = Vec::new();
+        c.pull_layer(&by_digest, &RegistryAuth::Anonymous, &layer, &mut out)
+            .await
+            .expect("pull layer from mirror");
+        assert_eq!(b"iamawebassemblymodule".to_vec(), out);
+
+        // Tags are not mirrored without opting in
+        let by_tag: Reference = "primary.example.com/hello:v1".parse().unwrap();
+        registry.enqueue(MockResponse::new(503));
+        let err = c
+            .pull(
+                &by_tag,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect_err("tag pulls are not mirrored");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::ServerError(503))
+        ));
+    }
+
+    #[tokio::test]
+    async fn manifests_and_blobs_fall_back_to_mirrors_for_tags_when_enabled() {
+        let registry = MockRegistry::new();
+        let layer = registry.add_blob(b"iamawebassemblymodule");
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+        let manifest = generate_manifest(&image_data, b"{}", manifest::WASM_CONFIG_MEDIA_TYPE);
+        let data = serde_json::to_vec(&manifest).unwrap();
+        let digest = registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, &data);
+        let mut mirrors = HashMap::new();
+        mirrors.insert(
+            "primary.example.com".to_owned(),
+            vec!["mirror.example.com".to_owned()],
+        );
+        let c = registry.client_with_config(ClientConfig {
+            mirrors,
+            mirror_tags: true,
+            ..Default::default()
+        });
+        let by_tag: Reference = "primary.example.com/hello:v1".parse().unwrap();
+
+        // One queued failure for the primary's ping and one for the manifest
+        registry.enqueue(MockResponse::new(503));
+        registry.enqueue(MockResponse::new(503));
+        let (pulled, pulled_digest) = c
+            .pull_manifest(&by_tag, &RegistryAuth::Anonymous)
+            .await
+            .expect("pull manifest from mirror");
+        assert_eq!(manifest.layers[0].digest, pulled.layers[0].digest);
+        assert_eq!(digest, pulled_digest);
+
+        registry.enqueue(MockResponse::new(503));
+        let (raw, _) = c
+            .pull_manifest_raw(&by_tag, &RegistryAuth::Anonymous)
+            .await
+            .expect("pull raw manifest from mirror");
+        assert_eq!(data, raw);
+
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("layer");
+        registry.enqueue(MockResponse::new(503));
+        c.pull_layer_to_file(&by_tag, &RegistryAuth::Anonymous, &layer, &path, false)
+            .await
+            .expect("pull layer to file from mirror");
+        assert_eq!(
+            b"iamawebassemblymodule".to_vec(),
+            std::fs::read(&path).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn credentials_are_not_sent_to_mirrors() {
+        let registry = MockRegistry::new();
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+        let manifest = generate_manifest(&image_data, b"{}", manifest::WASM_CONFIG_MEDIA_TYPE);
+        let data = serde_json::to_vec(&manifest).unwrap();
+        let digest = registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, &data);
+        let mut mirrors = HashMap::new();
+        mirrors.insert(
+            "primary.example.com".to_owned(),
+            vec!["mirror.example.com".to_owned()],
+        );
+        let c = registry.client_with_config(ClientConfig {
+            mirrors,
+            ..Default::default()
+        });
+        let by_digest: Reference = format!("primary.example.com/hello@{}", digest)
+            .parse()
+            .unwrap();
+
+        // The primary's ping and manifest fail, then the mirror asks for
+        // the basic credentials that the primary's would be
+        registry.enqueue(MockResponse::new(503));
+        registry.enqueue(MockResponse::new(503));
+        registry
+            .enqueue(MockResponse::new(401).header("WWW-Authenticate", r#"Basic realm="mirror""#));
+        let auth = RegistryAuth::Basic("user".to_owned(), "secret".to_owned());
+        let (_, pulled_digest) = c
+            .pull_manifest(&by_digest, &auth)
+            .await
+            .expect("pull manifest from mirror");
+        assert_eq!(digest, pulled_digest);
+        assert_eq!(4, registry.requests().len());
+        assert!(registry.authorizations().iter().all(Option::is_none));
+    }
+
+    #[tokio::test]
+    async fn configs_are_pushed_in_a_single_request() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let digest = sha256_digest(b"{}");
+
+        let mut response = PushResponse::default();
+        c.push_config(&image, b"{}", &digest, &mut response)
+            .await
+            .expect("push config");
+        assert_eq!(Some(b"{}".to_vec()), registry.blob(&digest));
+        assert_eq!(vec![digest.clone()], response.uploaded);
+        assert_eq!(
+            vec![
+                format!("HEAD /v2/hello/blobs/{}", digest),
+                format!("POST /v2/hello/blobs/uploads/?digest={}", digest),
+            ],
+            registry.requests()
+        );
+    }
+
+    #[tokio::test]
+    async fn blobs_already_in_the_repository_are_not_pushed_again() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let layer = registry.add_blob(b"iamawebassemblymodule");
+        let config = registry.add_blob(b"{}");
+        assert!(c.blob_exists(&image, &layer).await.expect("check layer"));
+        assert!(!c
+            .blob_exists(&image, &sha256_digest(b"missing"))
+            .await
+            .expect("check missing blob"));
+
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+        let response = c
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push over existing blobs");
+        assert_eq!(vec![layer, config], response.skipped);
+        assert!(response.uploaded.is_empty());
+        assert!(registry
+            .requests()
+            .iter()
+            .all(|r| !r.starts_with("POST") && !r.starts_with("PATCH")));
+        assert!(registry.manifest("hello", "v1").is_some());
+    }
+
+    #[tokio::test]
+    async fn layers_are_mounted_from_other_repositories() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client_with_config(ClientConfig {
+            mount_from: vec!["library/base".to_owned()],
+            ..Default::default()
+        });
+        let digest = registry.add_blob(b"iamawebassemblymodule");
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+        // The mock shares blobs between repositories, so have it report the
+        // layer missing from this one
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Push)
+            .await
+            .expect("authenticated");
+        registry.enqueue(MockResponse::new(404));
+
+        let response = c
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push with mount");
+        assert_eq!(vec![digest.clone()], response.mounted);
+        assert_eq!(vec![sha256_digest(b"{}")], response.uploaded);
+        let requests = registry.requests();
+        assert!(requests.contains(&format!(
+            "POST /v2/hello/blobs/uploads/?mount={}&from=library/base",
+            digest
+        )));
+        assert!(!requests.iter().any(|r| r.starts_with("PATCH")));
+    }
+
+    #[tokio::test]
+    async fn layers_are_uploaded_when_they_cannot_be_mounted() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client_with_config(ClientConfig {
+            mount_from: vec!["library/base".to_owned()],
+            ..Default::default()
+        });
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+
+        let response = c
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push after failed mount");
+        assert!(response.mounted.is_empty());
+        assert_eq!(
+            vec![image_data.digest(), sha256_digest(b"{}")],
+            response.uploaded
+        );
+        assert_eq!(
+            Some(b"iamawebassemblymodule".to_vec()),
+            registry.blob(&image_data.digest())
+        );
+    }
+
+    /// An uncompressed layer tarball holding the given files
+    fn tar_layer(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, data.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn later_layers_are_extracted_over_earlier_ones() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1(tar_layer(&[
+                    ("app/config.toml", "old"),
+                    ("app/cache.db", "stale"),
+                    ("app/main.wasm", "module"),
+                ])),
+                ImageLayer::oci_v1(tar_layer(&[
+                    ("app/config.toml", "new"),
+                    ("app/.wh.cache.db", ""),
+                ])),
+            ],
+            ..Default::default()
+        };
+        for layer in &image_data.layers {
+            registry.add_blob(&layer.data);
+        }
+        registry.add_blob(b"{}");
+        let manifest = generate_manifest(&image_data, b"{}", manifest::IMAGE_CONFIG_MEDIA_TYPE);
+        registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+
+        let dest = tempfile::tempdir().expect("create temp dir");
+        registry
+            .client()
+            .pull_and_extract(&image, &RegistryAuth::Anonymous, dest.path())
+            .await
+            .expect("pull and extract");
+
+        let read = |path: &str| std::fs::read_to_string(dest.path().join(path)).ok();
+        assert_eq!(Some("new".to_owned()), read("app/config.toml"));
+        assert_eq!(Some("module".to_owned()), read("app/main.wasm"));
+        assert_eq!(None, read("app/cache.db"));
+        assert_eq!(None, read("app/.wh.cache.db"));
+    }
+
+    #[tokio::test]
+    async fn pulled_layers_are_decompressed_into_their_sinks() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let tarball = tar_layer(&[("app/main.wasm", "module")]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &tarball).unwrap();
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::oci_v1_gzip(encoder.finish().unwrap()),
+                ImageLayer::oci_v1(tar_layer(&[("app/config.toml", "plain")])),
+            ],
+            ..Default::default()
+        };
+        for layer in &image_data.layers {
+            registry.add_blob(&layer.data);
+        }
+        registry.add_blob(b"{}");
+        let manifest = generate_manifest(&image_data, b"{}", manifest::IMAGE_CONFIG_MEDIA_TYPE);
+        registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+        let c = registry.client_with_config(ClientConfig {
+            decompress_layers: true,
+            ..Default::default()
+        });
+
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let mut paths = Vec::new();
+        c.pull_to(
+            &image,
+            &RegistryAuth::Anonymous,
+            vec![
+                manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE,
+                manifest::IMAGE_LAYER_MEDIA_TYPE,
+            ],
+            |_| {
+                let path = dir.path().join(paths.len().to_string());
+                let file = std::fs::File::create(&path)?;
+                paths.push(path);
+                Ok(tokio::fs::File::from_std(file))
+            },
+        )
+        .await
+        .expect("pull to sinks");
+        assert_eq!(tarball, std::fs::read(&paths[0]).unwrap());
+        assert_eq!(image_data.layers[1].data, std::fs::read(&paths[1]).unwrap());
+
+        // Extraction must not decompress the layers a second time
+        let dest = tempfile::tempdir().expect("create temp dir");
+        c.pull_and_extract(&image, &RegistryAuth::Anonymous, dest.path())
+            .await
+            .expect("pull and extract");
+        let read = |path: &str| std::fs::read_to_string(dest.path().join(path)).ok();
+        assert_eq!(Some("module".to_owned()), read("app/main.wasm"));
+        assert_eq!(Some("plain".to_owned()), read("app/config.toml"));
+    }
+
+    #[tokio::test]
+    async fn rejected_chunk_ranges_fall_back_to_monolithic_uploads() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let layer = ImageLayer::wasm(b"iamawebassemblymodule".to_vec());
+        registry.enqueue(
+            MockResponse::new(202).header("Location", "/v2/hello/blobs/uploads/0?_state=0"),
+        );
+        registry.enqueue(MockResponse::new(416));
+
+        registry
+            .client()
+            .push_image_layer(&image, &layer, None)
+            .await
+            .expect("monolithic upload after rejected range");
+        assert_eq!(
+            vec![
+                "POST /v2/hello/blobs/uploads/".to_owned(),
+                "PATCH /v2/hello/blobs/uploads/0".to_owned(),
+                format!("POST /v2/hello/blobs/uploads/?digest={}", layer.digest()),
+            ],
+            registry
+                .requests()
+                .into_iter()
+                .map(|r| r.replace("?_state=0", ""))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(layer.data.clone()), registry.blob(layer.digest()));
+    }
+
+    #[tokio::test]
+    async fn bad_chunk_requests_are_not_taken_for_rejected_ranges() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let layer = ImageLayer::wasm(b"iamawebassemblymodule".to_vec());
+        registry.enqueue(
+            MockResponse::new(202).header("Location", "/v2/hello/blobs/uploads/0?_state=0"),
+        );
+        registry.enqueue(
+            MockResponse::new(400)
+                .header("Content-Type", "application/json")
+                .body(r#"{"errors": [{"code": "BLOB_UPLOAD_INVALID", "message": "blob upload invalid"}]}"#),
+        );
+
+        let err = registry
+            .client()
+            .push_image_layer(&image, &layer, None)
+            .await
+            .expect_err("bad chunk should fail the upload");
+        assert!(!is_content_range_unsupported(&err));
+        assert!(
+            format!("{:#}", err).contains("blob upload invalid"),
+            "{:#}",
+            err
+        );
+        assert_eq!(2, registry.requests().len());
+        assert_eq!(None, registry.blob(layer.digest()));
+    }
+
+    #[tokio::test]
+    async fn only_missing_layers_are_uploaded() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let present = registry.add_blob(b"base layer");
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::wasm(b"base layer".to_vec()),
+                ImageLayer::wasm(b"first new layer".to_vec()),
+                ImageLayer::wasm(b"second new layer".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let first = sha256_digest(b"first new layer");
+        let second = sha256_digest(b"second new layer");
+        let config = sha256_digest(b"{}");
+
+        let response = c
+            .push(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("push with a present layer");
+        assert_eq!(vec![present.clone()], response.skipped);
+        assert_eq!(
+            vec![first.clone(), second.clone(), config.clone()],
+            response.uploaded
+        );
+        assert_eq!(Some(b"first new layer".to_vec()), registry.blob(&first));
+        assert_eq!(Some(b"second new layer".to_vec()), registry.blob(&second));
+
+        let layer_requests: Vec<String> = registry
+            .requests()
+            .into_iter()
+            .filter(|r| r.contains("/blobs/") && !r.contains(&config))
+            .collect();
+        assert_eq!(
+            vec![
+                format!("HEAD /v2/hello/blobs/{}", present),
+                format!("HEAD /v2/hello/blobs/{}", first),
+                "POST /v2/hello/blobs/uploads/".to_owned(),
+                "PATCH /v2/hello/blobs/uploads/0?_state=0".to_owned(),
+                format!("PUT /v2/hello/blobs/uploads/0?_state=0&digest={}", first),
+                format!("HEAD /v2/hello/blobs/{}", second),
+                "POST /v2/hello/blobs/uploads/".to_owned(),
+                "PATCH /v2/hello/blobs/uploads/1?_state=1".to_owned(),
+                format!("PUT /v2/hello/blobs/uploads/1?_state=1&digest={}", second),
+            ],
+            layer_requests
+        );
+    }
+
+    #[tokio::test]
+    async fn monolithic_pushes_fall_back_to_chunks_when_a_session_is_started() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let digest = sha256_digest(b"{}");
+        // Stand in for a registry that ignores the data and opens a session
+        // instead: start one, then hand out its location for the POST
+        let location = c.begin_push_session(&image).await.expect("begin session");
+        registry.enqueue(MockResponse::new(202).header("Location", &location));
+
+        c.push_blob_monolithic(&image, b"{}", &digest)
+            .await
+            .expect("push after fallback");
+        assert_eq!(Some(b"{}".to_vec()), registry.blob(&digest));
+        let requests = registry.requests();
+        assert_eq!(
+            vec![
+                format!("POST /v2/hello/blobs/uploads/?digest={}", digest),
+                "PATCH /v2/hello/blobs/uploads/0?_state=0".to_owned(),
+                format!("PUT /v2/hello/blobs/uploads/0?_state=0&digest={}", digest),
+            ],
+            requests[1..].to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn pulled_layers_can_override_verification_per_call() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let digest = sha256_digest(b"expected");
+        let auth = RegistryAuth::Anonymous;
+        let tampered = || MockResponse::new(200).body("tampered");
+
+        let c = registry.client();
+        c.auth(&image, &auth, &RegistryOperation::Pull)
+            .await
+            .expect("authenticated");
+        registry.enqueue(tampered());
+        let mut out: Vec<u8> = Vec::new();
+        c.pull_layer_with_verification(&image, &auth, &digest, &mut out, Some(false))
+            .await
+            .expect("verification is skipped for this call");
+        assert_eq!(b"tampered".to_vec(), out);
+
+        registry.enqueue(tampered());
+        let err = c
+            .pull_layer_with_verification(&image, &auth, &digest, &mut Vec::new(), None)
+            .await
+            .expect_err("the client verifies by default");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::DigestMismatch { .. })
+        ));
+
+        let c = registry.client_with_config(ClientConfig {
+            verify_digests: false,
+            ..Default::default()
+        });
+        c.auth(&image, &auth, &RegistryOperation::Pull)
+            .await
+            .expect("authenticated");
+        registry.enqueue(tampered());
+        let err = c
+            .pull_layer_with_verification(&image, &auth, &digest, &mut Vec::new(), Some(true))
+            .await
+            .expect_err("verification is asked for this call");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::DigestMismatch { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn pulled_blobs_are_verified_unless_the_call_opts_out() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let digest = sha256_digest(b"expected");
+        let tampered = || MockResponse::new(200).body("tampered");
+
+        let c = registry.client();
+        registry.enqueue(tampered());
+        let err = c
+            .download_layer(&image, &digest, &mut Vec::<u8>::new(), None, None, None)
+            .await
+            .expect_err("tampered blob should fail verification");
+        assert_eq!(
+            Some(&ClientError::DigestMismatch {
+                expected: digest.clone(),
+                actual: sha256_digest(b"tampered"),
+            }),
+            err.downcast_ref::<ClientError>()
+        );
+
+        registry.enqueue(tampered());
+        let mut out: Vec<u8> = Vec::new();
+        c.download_layer(&image, &digest, &mut out, None, None, Some(false))
+            .await
+            .expect("verification is skipped for this call");
+        assert_eq!(b"tampered".to_vec(), out);
+
+        // A client that skips verification can still ask for it
+        let c = registry.client_with_config(ClientConfig {
+            verify_digests: false,
+            ..Default::default()
+        });
+        registry.enqueue(tampered());
+        c.download_layer(&image, &digest, &mut Vec::<u8>::new(), None, None, None)
+            .await
+            .expect("verification is off for the client");
+        registry.enqueue(tampered());
+        assert!(c
+            .download_layer(
+                &image,
+                &digest,
+                &mut Vec::<u8>::new(),
+                None,
+                None,
+                Some(true)
+            )
+            .await
+            .is_err());
     }
-}
 
-fn digest_header_value(response: &reqwest::Response) -> anyhow::Result<String> {
-    let headers = response.headers();
-    let digest_header = headers.get("Docker-Content-Digest");
-    match digest_header {
-        None => Err(anyhow::anyhow!("resgistry did not return a digest header")),
-        Some(hv) => hv
-            .to_str()
-            .map(|s| s.to_string())
-            .map_err(anyhow::Error::new),
+    struct UnreachableTransport;
+
+    #[async_trait::async_trait]
+    impl Transport for UnreachableTransport {
+        async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+            Err(
+                ClientError::RegistryUnreachable(request.url().host_str().unwrap().to_owned())
+                    .into(),
+            )
+        }
     }
-}
 
-/// Computes the SHA256 digest of a byte vector
-fn sha256_digest(bytes: &[u8]) -> String {
-    format!("sha256:{:x}", sha2::Sha256::digest(bytes))
-}
+    /// A transport for a registry that accepts requests but never answers
+    struct UnresponsiveTransport;
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::manifest;
-    use std::convert::TryFrom;
+    #[async_trait::async_trait]
+    impl Transport for UnresponsiveTransport {
+        async fn execute(&self, _: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+            future::pending().await
+        }
+    }
 
-    const HELLO_IMAGE_NO_TAG: &str = "webassembly.azurecr.io/hello-wasm";
-    const HELLO_IMAGE_TAG: &str = "webassembly.azurecr.io/hello-wasm:v1";
-    const HELLO_IMAGE_DIGEST: &str = "webassembly.azurecr.io/hello-wasm@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7";
-    const HELLO_IMAGE_TAG_AND_DIGEST: &str = "webassembly.azurecr.io/hello-wasm:v1@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7";
-    const TEST_IMAGES: &'static [&str] = &[
-        // TODO(jlegrone): this image cannot be pulled currently because no `latest`
-        //                 tag exists on the image repository. Re-enable this image
-        //                 in tests once `latest` is published.
-        // HELLO_IMAGE_NO_TAG,
-        HELLO_IMAGE_TAG,
-        HELLO_IMAGE_DIGEST,
-        HELLO_IMAGE_TAG_AND_DIGEST,
-    ];
+    #[tokio::test]
+    async fn unresponsive_registries_time_out() {
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let timeout = Duration::from_millis(50);
+
+        let c = Client::with_transport(
+            ClientConfig {
+                read_timeout: Some(timeout),
+                ..Default::default()
+            },
+            UnresponsiveTransport,
+        );
+        let err = c
+            .pull(&image, &RegistryAuth::Anonymous, vec![])
+            .await
+            .expect_err("registry never answers");
+        assert_eq!(
+            Some(&ClientError::TimedOut(timeout)),
+            err.downcast_ref::<ClientError>()
+        );
 
-    #[test]
-    fn test_to_v2_blob_url() {
-        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
-        let blob_url = Client::default().to_v2_blob_url(
-            image.registry(),
-            image.repository(),
-            "sha256:deadbeef",
+        let c = Client::with_transport(
+            ClientConfig {
+                pull_timeout: Some(timeout),
+                ..Default::default()
+            },
+            UnresponsiveTransport,
         );
+        let err = c
+            .pull(&image, &RegistryAuth::Anonymous, vec![])
+            .await
+            .expect_err("pull misses its deadline");
         assert_eq!(
-            blob_url,
-            "https://webassembly.azurecr.io/v2/hello-wasm/blobs/sha256:deadbeef"
-        )
+            Some(&ClientError::TimedOut(timeout)),
+            err.downcast_ref::<ClientError>()
+        );
+        assert_eq!(
+            "Failed to pull mock.example.com/hello:v1 in time",
+            err.to_string()
+        );
     }
 
-    #[test]
-    fn test_to_v2_manifest() {
-        let c = Client::default();
+    #[tokio::test]
+    async fn transport_errors_name_the_operation_and_url() {
+        let c = Client::with_transport(ClientConfig::default(), UnreachableTransport);
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let digest = sha256_digest(b"layer");
 
-        for &(image, expected_uri) in [
-            (HELLO_IMAGE_NO_TAG, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/latest"), // TODO: confirm this is the right translation when no tag
-            (HELLO_IMAGE_TAG, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/v1"),
-            (HELLO_IMAGE_DIGEST, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7"),
-            (HELLO_IMAGE_TAG_AND_DIGEST, "https://webassembly.azurecr.io/v2/hello-wasm/manifests/sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7"),
-            ].iter() {
-                let reference = Reference::try_from(image).expect("failed to parse reference");
-                assert_eq!(c.to_v2_manifest_url(&reference), expected_uri);
-            }
+        let err = c
+            .download_layer(&image, &digest, &mut Vec::<u8>::new(), None, None, None)
+            .await
+            .expect_err("registry is unreachable");
+        assert_eq!(
+            format!(
+                "Failed to download blob {} from https://mock.example.com/v2/hello/blobs/{}",
+                digest, digest
+            ),
+            err.to_string()
+        );
+        // The typed cause is still available to callers
+        assert_eq!(
+            Some(&ClientError::RegistryUnreachable(
+                "mock.example.com".to_owned()
+            )),
+            err.downcast_ref::<ClientError>()
+        );
+
+        let err = c
+            .push_manifest(&image, &OciManifest::default())
+            .await
+            .expect_err("registry is unreachable");
+        assert_eq!(
+            "Failed to push manifest to https://mock.example.com/v2/hello/manifests/v1",
+            err.to_string()
+        );
     }
 
-    #[test]
-    fn test_to_v2_blob_upload_url() {
-        let image = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
-        let blob_url = Client::default().to_v2_blob_upload_url(&image);
+    #[tokio::test]
+    async fn mock_registry_reports_missing_manifests() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/missing:v1".parse().unwrap();
+        let err = registry
+            .client()
+            .pull(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect_err("manifest should be missing");
+        assert!(matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::NotFound(_))
+        ));
+    }
 
+    #[tokio::test]
+    async fn queued_mock_responses_simulate_registry_failures() {
+        let registry = MockRegistry::new();
+        let digest = registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
         assert_eq!(
-            blob_url,
-            "https://webassembly.azurecr.io/v2/hello-wasm/blobs/uploads/"
-        )
-    }
+            digest,
+            c.fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+                .await
+                .expect("fetch digest")
+        );
 
-    #[test]
-    fn manifest_url_generation_respects_http_protocol() {
-        let c = Client::new(ClientConfig {
-            protocol: ClientProtocol::Http,
-        });
-        let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
-            .expect("Could not parse reference");
+        registry.enqueue(MockResponse::new(500));
+        let err = c
+            .fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("queued server error should fail the request");
+        assert!(err.to_string().contains("Server error"), "{}", err);
         assert_eq!(
-            "http://webassembly.azurecr.io/v2/hello/manifests/v1",
-            c.to_v2_manifest_url(&reference)
+            vec![
+                "GET /v2/".to_owned(),
+                "GET /v2/hello/manifests/v1".to_owned(),
+                "GET /v2/hello/manifests/v1".to_owned(),
+            ],
+            registry.requests()
         );
     }
 
-    #[test]
-    fn blob_url_generation_respects_http_protocol() {
-        let c = Client::new(ClientConfig {
-            protocol: ClientProtocol::Http,
-        });
-        let reference = Reference::try_from("webassembly.azurecr.io/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
-            .expect("Could not parse reference");
+    #[tokio::test]
+    async fn insufficient_scope_is_retried_with_the_requested_scope() {
+        let registry = MockRegistry::new();
+        let digest = registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        c.store_token(
+            image.registry(),
+            &RegistryAuth::Anonymous,
+            RegistryTokenType::Anonymous,
+        );
+
+        registry.enqueue(
+            MockResponse::new(401).header(
+                "WWW-Authenticate",
+                r#"Bearer realm="https://mock.example.com/token",service="mock.example.com",scope="repository:hello:pull",error="insufficient_scope""#,
+            ),
+        );
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "scoped"}"#));
         assert_eq!(
-            "http://webassembly.azurecr.io/v2/hello/blobs/sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
-            c.to_v2_blob_url(
-                &reference.registry(),
-                reference.repository(),
-                reference.digest().unwrap()
-            )
+            digest,
+            c.fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+                .await
+                .expect("request should be retried with the new token")
         );
+        assert_eq!(
+            vec![
+                "GET /v2/hello/manifests/v1".to_owned(),
+                "GET /token?service=mock.example.com&scope=repository%3Ahello%3Apull".to_owned(),
+                "GET /v2/hello/manifests/v1".to_owned(),
+            ],
+            registry.requests()
+        );
+        // Later requests use the new token
+        assert_eq!("Bearer scoped", c.auth_headers(&image)["Authorization"]);
     }
 
-    #[test]
-    fn manifest_url_generation_uses_https_if_not_on_exception_list() {
-        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
-        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
-        let reference = Reference::try_from("webassembly.azurecr.io/hello:v1".to_owned())
-            .expect("Could not parse reference");
+    #[tokio::test]
+    async fn insufficient_scope_tokens_keep_the_scopes_of_the_cached_token() {
+        let registry = MockRegistry::new();
+        registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        c.store_token(
+            image.registry(),
+            &RegistryAuth::Anonymous,
+            mock_token("narrow", "repository:hello:pull"),
+        );
+
+        registry.enqueue(
+            MockResponse::new(401).header(
+                "WWW-Authenticate",
+                r#"Bearer realm="https://mock.example.com/token",service="mock.example.com",scope="repository:base:pull",error="insufficient_scope""#,
+            ),
+        );
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "wider"}"#));
+        c.fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect("request should be retried with the new token");
         assert_eq!(
-            "https://webassembly.azurecr.io/v2/hello/manifests/v1",
-            c.to_v2_manifest_url(&reference)
+            "GET /token?service=mock.example.com&scope=repository%3Ahello%3Apull&scope=repository%3Abase%3Apull",
+            registry.requests()[1]
         );
+        assert_eq!("Bearer wider", c.auth_headers(&image)["Authorization"]);
+
+        // The cached token renews with both scopes
+        let cached = c.tokens.lock().unwrap()[image.registry()]
+            .token
+            .request()
+            .cloned()
+            .expect("token request should be kept");
+        assert_eq!("repository:hello:pull repository:base:pull", cached.scope);
     }
 
     #[test]
-    fn manifest_url_generation_uses_http_if_on_exception_list() {
-        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
-        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
-        let reference = Reference::try_from("oci.registry.local/hello:v1".to_owned())
-            .expect("Could not parse reference");
-        assert_eq!(
-            "http://oci.registry.local/v2/hello/manifests/v1",
-            c.to_v2_manifest_url(&reference)
+    fn basic_and_bearer_challenges_are_recognized() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::WWW_AUTHENTICATE,
+            r#"Basic realm="Registry Realm""#.parse().unwrap(),
+        );
+        let basic = basic_challenge(&headers).unwrap().expect("basic challenge");
+        assert_eq!(Some("Registry Realm".to_owned()), basic.realm);
+        assert!(bearer_challenge(&headers).unwrap().is_none());
+
+        // Challenges in separate headers are all seen
+        headers.append(
+            reqwest::header::WWW_AUTHENTICATE,
+            r#"Bearer realm="https://example.com/token",service="example.com""#
+                .parse()
+                .unwrap(),
         );
+        assert!(basic_challenge(&headers).unwrap().is_some());
+        assert!(bearer_challenge(&headers).unwrap().is_some());
     }
 
-    #[test]
-    fn blob_url_generation_uses_https_if_not_on_exception_list() {
-        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
-        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
-        let reference = Reference::try_from("webassembly.azurecr.io/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
-            .expect("Could not parse reference");
+    #[tokio::test]
+    async fn basic_challenges_send_the_credentials_with_each_request() {
+        let registry = MockRegistry::new();
+        let digest = registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let auth = RegistryAuth::Basic("user".to_owned(), "pass".to_owned());
+
+        registry
+            .enqueue(MockResponse::new(401).header("WWW-Authenticate", r#"Basic realm="mock""#));
         assert_eq!(
-            "https://webassembly.azurecr.io/v2/hello/blobs/sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
-            c.to_v2_blob_url(
-                &reference.registry(),
-                reference.repository(),
-                reference.digest().unwrap()
-            )
+            digest,
+            c.fetch_manifest_digest(&image, &auth)
+                .await
+                .expect("basic credentials should be accepted")
+        );
+        assert_eq!(
+            vec![
+                "GET /v2/".to_owned(),
+                "GET /v2/hello/manifests/v1".to_owned()
+            ],
+            registry.requests()
+        );
+        // base64("user:pass")
+        assert_eq!(
+            "Basic dXNlcjpwYXNz",
+            c.auth_headers(&image)["Authorization"]
         );
     }
 
-    #[test]
-    fn blob_url_generation_uses_http_if_on_exception_list() {
-        let insecure_registries = vec!["localhost".to_owned(), "oci.registry.local".to_owned()];
-        let protocol = ClientProtocol::HttpsExcept(insecure_registries);
-        let c = Client::new(ClientConfig { protocol });
-        let reference = Reference::try_from("oci.registry.local/hello@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff".to_owned())
-            .expect("Could not parse reference");
-        assert_eq!(
-            "http://oci.registry.local/v2/hello/blobs/sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
-            c.to_v2_blob_url(
-                &reference.registry(),
-                reference.repository(),
-                reference.digest().unwrap()
+    #[tokio::test]
+    async fn anonymous_auth_prefers_bearer_over_basic() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+
+        registry.enqueue(MockResponse::new(401).header(
+            "WWW-Authenticate",
+            r#"Basic realm="mock", Bearer realm="https://mock.example.com/token",service="mock.example.com""#,
+        ));
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "anonymous"}"#));
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Pull)
+            .await
+            .expect("anonymous token should be granted");
+        assert_eq!("Bearer anonymous", c.auth_headers(&image)["Authorization"]);
+    }
+
+    /// A bearer token for the mock registry that was granted for `scope`
+    fn mock_token(value: &str, scope: &str) -> RegistryTokenType {
+        let mut token = RegistryToken::parse(&format!(r#"{{"token": "{}"}}"#, value)).unwrap();
+        token.request = Some(TokenRequest {
+            realm: "https://mock.example.com/token".to_owned(),
+            service: Some("mock.example.com".to_owned()),
+            scope: scope.to_owned(),
+        });
+        RegistryTokenType::Bearer(token)
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_renewed_before_sending() {
+        let registry = MockRegistry::new();
+        registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let mut token = mock_token("stale", "repository:hello:pull");
+        if let RegistryTokenType::Bearer(t) = &mut token {
+            t.expires_at = Some(Instant::now());
+        }
+        c.store_token(image.registry(), &RegistryAuth::Anonymous, token);
+        assert!(!c.has_token_for(image.registry(), &RegistryAuth::Anonymous));
+
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "fresh", "expires_in": 300}"#));
+        let url = c.to_v2_manifest_url(&image);
+        let res = c
+            .send(
+                image.registry(),
+                c.client.get(&url).headers(c.auth_headers(&image)),
             )
+            .await
+            .expect("request should be sent with a renewed token");
+        assert_eq!(reqwest::StatusCode::OK, res.status());
+        assert_eq!(
+            vec![
+                "GET /token?service=mock.example.com&scope=repository%3Ahello%3Apull".to_owned(),
+                "GET /v2/hello/manifests/v1".to_owned(),
+            ],
+            registry.requests()
         );
+        assert_eq!("Bearer fresh", c.auth_headers(&image)["Authorization"]);
+        assert!(c.has_token_for(image.registry(), &RegistryAuth::Anonymous));
     }
 
-    #[test]
-    fn can_generate_valid_digest() {
-        let bytes = b"hellobytes";
-        let hash = sha256_digest(&bytes.to_vec());
-
-        let combination = vec![b"hello".to_vec(), b"bytes".to_vec()];
-        let combination_hash =
-            sha256_digest(&combination.into_iter().flatten().collect::<Vec<u8>>());
+    #[tokio::test]
+    async fn refused_tokens_are_renewed_and_the_request_retried_once() {
+        let registry = MockRegistry::new();
+        let digest = registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        c.store_token(
+            image.registry(),
+            &RegistryAuth::Anonymous,
+            mock_token("revoked", "repository:hello:pull"),
+        );
 
+        registry.enqueue(MockResponse::new(401));
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "renewed"}"#));
         assert_eq!(
-            hash,
-            "sha256:fdbd95aafcbc814a2600fcc54c1e1706f52d2f9bf45cf53254f25bcd7599ce99"
+            digest,
+            c.fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+                .await
+                .expect("request should be retried with a renewed token")
         );
         assert_eq!(
-            combination_hash,
-            "sha256:fdbd95aafcbc814a2600fcc54c1e1706f52d2f9bf45cf53254f25bcd7599ce99"
+            vec![
+                "GET /v2/hello/manifests/v1".to_owned(),
+                "GET /token?service=mock.example.com&scope=repository%3Ahello%3Apull".to_owned(),
+                "GET /v2/hello/manifests/v1".to_owned(),
+            ],
+            registry.requests()
         );
+        assert_eq!("Bearer renewed", c.auth_headers(&image)["Authorization"]);
+
+        // A second refusal is returned rather than retried again
+        registry.enqueue(MockResponse::new(401));
+        registry.enqueue(MockResponse::new(200).body(r#"{"token": "again"}"#));
+        registry.enqueue(MockResponse::new(401));
+        c.fetch_manifest_digest(&image, &RegistryAuth::Anonymous)
+            .await
+            .expect_err("request should fail after one retry");
+        assert_eq!(6, registry.requests().len());
     }
 
     #[tokio::test]
-    async fn test_auth() {
+    async fn check_reports_unresolvable_registry() {
+        let err = Client::default()
+            .check("registry.invalid", &RegistryAuth::Anonymous)
+            .await
+            .expect_err("registry should not resolve");
+        assert_eq!(
+            Some(&ClientError::RegistryUnresolvable(
+                "registry.invalid".to_owned()
+            )),
+            err.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_manifest() {
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
-            let mut c = Client::default();
+            // Currently, pull_manifest does not perform Authz, so this will fail.
+            let c = Client::default();
+            c.resolve_manifest(&reference)
+                .await
+                .expect_err("pull manifest should fail");
+
+            // But this should pass
+            let c = Client::default();
             c.auth(
                 &reference,
                 &RegistryAuth::Anonymous,
                 &RegistryOperation::Pull,
             )
             .await
-            .expect("result from auth request");
+            .expect("authenticated");
+            let (manifest, _) = c
+                .resolve_manifest(&reference)
+                .await
+                .expect("pull manifest should not fail");
 
-            let tok = c
-                .tokens
-                .get(reference.registry())
-                .expect("token is available");
-            // We test that the token is longer than a minimal hash.
-            assert!(tok.token.len() > 64);
+            // The test on the manifest checks all fields. This is just a brief sanity check.
+            assert_eq!(manifest.schema_version, 2);
+            assert!(!manifest.layers.is_empty());
         }
     }
 
     #[tokio::test]
-    async fn test_pull_manifest() {
+    async fn test_blob_size() {
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
-            // Currently, pull_manifest does not perform Authz, so this will fail.
             let c = Client::default();
-            c.pull_manifest(&reference)
-                .await
-                .expect_err("pull manifest should fail");
-
-            // But this should pass
-            let mut c = Client::default();
             c.auth(
                 &reference,
                 &RegistryAuth::Anonymous,
@@ -1019,19 +9333,61 @@ mod test {
             .await
             .expect("authenticated");
             let (manifest, _) = c
-                .pull_manifest(&reference)
+                .resolve_manifest(&reference)
                 .await
                 .expect("pull manifest should not fail");
 
-            // The test on the manifest checks all fields. This is just a brief sanity check.
-            assert_eq!(manifest.schema_version, 2);
-            assert!(!manifest.layers.is_empty());
+            for layer in &manifest.layers {
+                let size = c
+                    .blob_size(&reference, &layer.digest)
+                    .await
+                    .expect("blob size should be available");
+                assert_eq!(layer.size as u64, size);
+            }
         }
     }
 
+    #[test]
+    fn blob_sizes_fall_back_to_manifest_descriptors() {
+        let manifest: OciManifest = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "config": {
+                    "mediaType": "application/vnd.wasm.config.v1+json",
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                    "size": 2
+                },
+                "layers": [{
+                    "mediaType": "application/vnd.wasm.content.layer.v1+wasm",
+                    "digest": "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                    "size": 1615998
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            1_615_998,
+            descriptor_size(
+                &manifest,
+                "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            2,
+            descriptor_size(&manifest, &manifest.config.digest).unwrap()
+        );
+        assert!(descriptor_size(&manifest, &sha256_digest(b"missing")).is_err());
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, content_length(&headers));
+        headers.insert(reqwest::header::CONTENT_LENGTH, "42".parse().unwrap());
+        assert_eq!(Some(42), content_length(&headers));
+    }
+
     #[tokio::test]
     async fn test_fetch_digest() {
-        let mut c = Client::default();
+        let c = Client::default();
 
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
@@ -1041,7 +9397,7 @@ mod test {
 
             // This should pass
             let reference = Reference::try_from(image).expect("failed to parse reference");
-            let mut c = Client::default();
+            let c = Client::default();
             c.auth(
                 &reference,
                 &RegistryAuth::Anonymous,
@@ -1061,9 +9417,274 @@ mod test {
         }
     }
 
+    #[test]
+    fn reusable_layers_match_overlapping_digests() {
+        let previous = ImageData {
+            layers: vec![
+                ImageLayer::wasm(b"shared".to_vec()),
+                ImageLayer::wasm(b"removed".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let next = ImageData {
+            layers: vec![
+                ImageLayer::wasm(b"added".to_vec()),
+                ImageLayer::wasm(b"shared".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let manifest = generate_manifest(&next, b"{}", manifest::WASM_CONFIG_MEDIA_TYPE);
+
+        let reused = reusable_layers(&manifest, &previous);
+        assert_eq!(2, reused.len());
+        assert!(reused[0].is_none());
+        let shared = reused[1].as_ref().expect("shared layer should be reused");
+        assert_eq!(b"shared".to_vec(), shared.data);
+        assert_eq!(Some(LayerSource::Cache), shared.source);
+        assert_eq!(manifest.layers[1].digest, shared.digest());
+    }
+
+    #[tokio::test]
+    async fn pull_delta_reuses_unchanged_layers() {
+        let reference = Reference::try_from(HELLO_IMAGE_TAG).expect("failed to parse reference");
+        let c = Client::default();
+        let previous = c
+            .pull(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+            )
+            .await
+            .expect("failed to pull image");
+
+        let image_data = c
+            .pull_delta(
+                &reference,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &previous,
+            )
+            .await
+            .expect("failed to pull delta");
+
+        assert_eq!(previous.layers.len(), image_data.layers.len());
+        for (old, new) in previous.layers.iter().zip(image_data.layers.iter()) {
+            assert_eq!(old.data, new.data);
+            assert_eq!(Some(LayerSource::Cache), new.source);
+        }
+        assert_eq!(previous.digest, image_data.digest);
+    }
+
+    #[tokio::test]
+    async fn pull_delta_rejects_incompatible_media_types() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let image_data = ImageData {
+            layers: vec![ImageLayer::oci_v1(b"container layer".to_vec())],
+            ..Default::default()
+        };
+        registry.add_blob(&image_data.layers[0].data);
+        let manifest = generate_manifest(&image_data, b"{}", manifest::IMAGE_CONFIG_MEDIA_TYPE);
+        registry.add_manifest(
+            "hello",
+            "v1",
+            manifest::OCI_IMAGE_MEDIA_TYPE,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+
+        let err = registry
+            .client()
+            .pull_delta(
+                &image,
+                &RegistryAuth::Anonymous,
+                vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                &ImageData::default(),
+            )
+            .await
+            .expect_err("container layers should be rejected");
+        assert_eq!(
+            Some(&ClientError::IncompatibleMediaTypes {
+                found: vec![manifest::IMAGE_LAYER_MEDIA_TYPE.to_owned()],
+                accepted: vec![manifest::WASM_LAYER_MEDIA_TYPE.to_owned()],
+            }),
+            err.downcast_ref::<ClientError>()
+        );
+        assert!(!registry.requests().iter().any(|r| r.contains("/blobs/")));
+    }
+
+    #[tokio::test]
+    async fn pulled_layers_are_decompressed_only_when_enabled() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, b"hello").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let gzip_type = manifest::IMAGE_LAYER_GZIP_MEDIA_TYPE.to_owned();
+
+        let (data, media_type) = Client::default()
+            .maybe_decompress(gzipped.clone(), gzip_type.clone())
+            .await
+            .unwrap();
+        assert_eq!(gzipped, data);
+        assert_eq!(gzip_type, media_type);
+
+        let c = Client::new(ClientConfig {
+            decompress_layers: true,
+            ..Default::default()
+        });
+        let (data, media_type) = c.maybe_decompress(gzipped, gzip_type).await.unwrap();
+        assert_eq!(b"hello".to_vec(), data);
+        assert_eq!(manifest::IMAGE_LAYER_MEDIA_TYPE, media_type);
+    }
+
+    #[test]
+    fn denied_responses_are_told_apart_from_unauthorized() {
+        let url = "https://example.com/v2/hello/manifests/v1";
+        let denied = registry_error(
+            reqwest::StatusCode::FORBIDDEN,
+            r#"{"errors": [{"code": "DENIED", "message": "requested access to the resource is denied"}]}"#,
+            url,
+        );
+        assert_eq!(
+            Some(&ClientError::Denied(format!(
+                "requested access to the resource is denied on {}",
+                url
+            ))),
+            denied.downcast_ref::<ClientError>()
+        );
+
+        let unauthorized = registry_error(
+            reqwest::StatusCode::UNAUTHORIZED,
+            r#"{"errors": [{"code": "UNAUTHORIZED", "message": "authentication required", "detail": null}]}"#,
+            url,
+        );
+        assert_eq!(
+            Some(&ClientError::Unauthorized(format!(
+                "authentication required on {}",
+                url
+            ))),
+            unauthorized.downcast_ref::<ClientError>()
+        );
+    }
+
+    #[test]
+    fn registry_errors_fall_back_to_status() {
+        let url = "https://example.com/v2/hello/blobs/uploads/";
+        assert_eq!(
+            Some(&ClientError::Denied(format!("403 Forbidden on {}", url))),
+            registry_error(reqwest::StatusCode::FORBIDDEN, "forbidden", url)
+                .downcast_ref::<ClientError>()
+        );
+        assert!(matches!(
+            registry_error(reqwest::StatusCode::UNAUTHORIZED, "", url).downcast_ref(),
+            Some(ClientError::Unauthorized(_))
+        ));
+
+        assert!(matches!(
+            registry_error(reqwest::StatusCode::NOT_FOUND, "", url).downcast_ref(),
+            Some(ClientError::NotFound(_))
+        ));
+
+        // The OCI code wins over the status
+        let unknown = registry_error(
+            reqwest::StatusCode::FORBIDDEN,
+            r#"{"errors": [{"code": "MANIFEST_UNKNOWN", "message": "manifest unknown"}]}"#,
+            url,
+        );
+        assert_eq!(
+            Some(&ClientError::NotFound(format!(
+                "manifest unknown on {}",
+                url
+            ))),
+            unknown.downcast_ref::<ClientError>()
+        );
+        let invalid = registry_error(
+            reqwest::StatusCode::NOT_FOUND,
+            r#"{"errors": [{"code": "NAME_INVALID", "message": "invalid repository name"}]}"#,
+            url,
+        );
+        assert!(invalid.downcast_ref::<ClientError>().is_none());
+        assert!(invalid.to_string().contains("invalid repository name"));
+    }
+
+    #[test]
+    fn chunks_skip_bytes_the_registry_has() {
+        let layers = vec![
+            ImageLayer::oci_v1(b"first".to_vec()),
+            ImageLayer::oci_v1(Vec::new()),
+            ImageLayer::oci_v1(b"second".to_vec()),
+        ];
+        assert_eq!(
+            vec![(0, &b"first"[..]), (5, &b"second"[..])],
+            chunks_from(&layers, 0)
+        );
+        assert_eq!(
+            vec![(3, &b"st"[..]), (5, &b"second"[..])],
+            chunks_from(&layers, 3)
+        );
+        assert_eq!(vec![(7, &b"cond"[..])], chunks_from(&layers, 7));
+        assert!(chunks_from(&layers, 11).is_empty());
+    }
+
+    #[test]
+    fn upload_range_offset_continues_after_received_bytes() {
+        assert_eq!(1024, upload_range_offset("0-1023").unwrap());
+        assert_eq!(1024, upload_range_offset("bytes=0-1023").unwrap());
+        assert_eq!(0, upload_range_offset("0--1").unwrap());
+        assert!(upload_range_offset("garbage").is_err());
+    }
+
+    #[test]
+    fn in_memory_upload_store_roundtrips_locations() {
+        let store = InMemoryUploadStore::default();
+        assert_eq!(None, store.load("upload").unwrap());
+        store.save("upload", "https://example.com/one").unwrap();
+        store.save("upload", "https://example.com/two").unwrap();
+        assert_eq!(
+            Some("https://example.com/two".to_owned()),
+            store.load("upload").unwrap()
+        );
+        store.remove("upload").unwrap();
+        assert_eq!(None, store.load("upload").unwrap());
+        store.remove("upload").unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    /// Requires local registry resolveable at `oci.registry.local`
+    async fn push_blob_resumable_continues_interrupted_upload() {
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let image: Reference = "oci.registry.local/resumable:v1".parse().unwrap();
+        let data = b"iamawebassemblymodule".to_vec();
+        let digest = sha256_digest(&data);
+
+        // Simulate an upload that was interrupted after the first chunk
+        let location = c.begin_push_session(&image).await.expect("begin session");
+        let (location, _) = c
+            .push_layer(&location, &image, data[..8].to_vec(), 0)
+            .await
+            .expect("push first chunk");
+        let uploads = InMemoryUploadStore::default();
+        uploads.save(&digest, &location).unwrap();
+
+        let pushed = c
+            .push_blob_resumable(&image, &data, &uploads, None)
+            .await
+            .expect("resume upload");
+        assert_eq!(digest, pushed);
+        assert_eq!(None, uploads.load(&digest).unwrap());
+
+        let mut out: Vec<u8> = Vec::new();
+        c.download_layer(&image, &digest, &mut out, None, None, None)
+            .await
+            .expect("pull blob");
+        assert_eq!(data, out);
+    }
+
     #[tokio::test]
     async fn test_pull_layer() {
-        let mut c = Client::default();
+        let c = Client::default();
 
         for &image in TEST_IMAGES {
             let reference = Reference::try_from(image).expect("failed to parse reference");
@@ -1075,7 +9696,7 @@ mod test {
             .await
             .expect("authenticated");
             let (manifest, _) = c
-                .pull_manifest(&reference)
+                .resolve_manifest(&reference)
                 .await
                 .expect("failed to pull manifest");
 
@@ -1083,7 +9704,7 @@ mod test {
             let mut file: Vec<u8> = Vec::new();
             let layer0 = &manifest.layers[0];
 
-            c.pull_layer(&reference, &layer0.digest, &mut file)
+            c.download_layer(&reference, &layer0.digest, &mut file, None, None, None)
                 .await
                 .expect("Pull layer into vec");
 
@@ -1092,6 +9713,33 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_estimated_size() {
+        for &image in TEST_IMAGES {
+            let reference = Reference::try_from(image).expect("failed to parse reference");
+            let c = Client::default();
+            let size = c
+                .estimated_size(&reference, &RegistryAuth::Anonymous)
+                .await
+                .expect("failed to estimate size");
+
+            let image_data = c
+                .pull(
+                    &reference,
+                    &RegistryAuth::Anonymous,
+                    vec![manifest::WASM_LAYER_MEDIA_TYPE],
+                )
+                .await
+                .expect("failed to pull image");
+            let (manifest, _) = c
+                .resolve_manifest(&reference)
+                .await
+                .expect("failed to pull manifest");
+            let layers: usize = image_data.layers.iter().map(|l| l.data.len()).sum();
+            assert_eq!(manifest.config.size as u64 + layers as u64, size);
+        }
+    }
+
     #[tokio::test]
     async fn test_pull() {
         for &image in TEST_IMAGES {
@@ -1108,6 +9756,7 @@ mod test {
 
             assert!(!image_data.layers.is_empty());
             assert!(image_data.digest.is_some());
+            assert!(image_data.config_descriptor.is_some());
         }
     }
 
@@ -1139,8 +9788,9 @@ mod test {
     #[ignore]
     /// Requires local registry resolveable at `oci.registry.local`
     async fn can_push_layer() {
-        let mut c = Client::new(ClientConfig {
+        let c = Client::new(ClientConfig {
             protocol: ClientProtocol::Http,
+            ..Default::default()
         });
         let url = "oci.registry.local/hello-wasm:v1";
         let image: Reference = url.parse().unwrap();
@@ -1180,8 +9830,9 @@ mod test {
     #[ignore]
     /// Requires local registry resolveable at `oci.registry.local`
     async fn can_push_multiple_layers() {
-        let mut c = Client::new(ClientConfig {
+        let c = Client::new(ClientConfig {
             protocol: ClientProtocol::Http,
+            ..Default::default()
         });
         let sample_uuid = "6987887f-0196-45ee-91a1-2dfad901bea0";
         let url = "oci.registry.local/hello-wasm:v1";
@@ -1237,12 +9888,68 @@ mod test {
         assert_eq!(layer_location, "http://oci.registry.local/v2/hello-wasm/blobs/sha256:5aef3de484a7d350ece6f5483047712be7c9a228998ba16242b3e50b5f16605a");
     }
 
+    #[tokio::test]
+    #[ignore]
+    /// Requires local registry resolveable at `oci.registry.local`
+    async fn can_push_blob() {
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let image: Reference = "oci.registry.local/hello-wasm:v1".parse().unwrap();
+
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Push)
+            .await
+            .expect("result from auth request");
+
+        let digest = c
+            .push_blob(&image, b"iamawebassemblymodule")
+            .await
+            .expect("failed to push blob");
+
+        assert_eq!(
+            digest,
+            "sha256:6165c4ad43c0803798b6f2e49d6348c915d52c999a5f890846cee77ea65d230b"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore]
+    /// Requires local registry resolveable at `oci.registry.local`
+    async fn can_push_and_pull_empty_blob() {
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let image: Reference = "oci.registry.local/hello-wasm:v1".parse().unwrap();
+
+        c.auth(&image, &RegistryAuth::Anonymous, &RegistryOperation::Push)
+            .await
+            .expect("result from auth request");
+
+        let digest = c
+            .push_blob(&image, &[])
+            .await
+            .expect("failed to push empty blob");
+        assert_eq!(
+            digest,
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let mut out: Vec<u8> = Vec::new();
+        c.download_layer(&image, &digest, &mut out, None, None, None)
+            .await
+            .expect("failed to pull empty blob");
+        assert!(out.is_empty());
+    }
+
     #[tokio::test]
     #[ignore]
     /// Requires local registry resolveable at `oci.registry.local`
     async fn test_image_roundtrip() {
-        let mut c = Client::new(ClientConfig {
+        let c = Client::new(ClientConfig {
             protocol: ClientProtocol::HttpsExcept(vec!["oci.registry.local".to_string()]),
+            ..Default::default()
         });
 
         let image: Reference = HELLO_IMAGE_TAG_AND_DIGEST.parse().unwrap();
@@ -1251,7 +9958,7 @@ mod test {
             .expect("authenticated");
 
         let (manifest, _digest) = c
-            .pull_manifest(&image)
+            .resolve_manifest(&image)
             .await
             .expect("failed to pull manifest");
 
@@ -1275,19 +9982,30 @@ mod test {
 
         let config_data = b"{}".to_vec();
 
-        c.push(
-            &push_image,
-            &image_data,
-            &config_data,
-            manifest::WASM_CONFIG_MEDIA_TYPE,
-            &RegistryAuth::Anonymous,
-            None,
-        )
-        .await
-        .expect("failed to push image");
+        let response = c
+            .push(
+                &push_image,
+                &image_data,
+                &config_data,
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect("failed to push image");
 
         let new_manifest =
-            c.generate_manifest(&image_data, &config_data, manifest::WASM_CONFIG_MEDIA_TYPE);
+            generate_manifest(&image_data, &config_data, manifest::WASM_CONFIG_MEDIA_TYPE);
+        assert_eq!(
+            vec![image_data.digest(), sha256_digest(&config_data)],
+            response.uploaded
+        );
+        assert!(response.mounted.is_empty());
+        assert!(response.skipped.is_empty());
+        assert_eq!(
+            manifest_digest_of(&new_manifest).unwrap(),
+            response.manifest_digest
+        );
 
         c.push_manifest(&push_image, &new_manifest)
             .await
@@ -1303,7 +10021,7 @@ mod test {
             .expect("failed to pull pushed image");
 
         let (pulled_manifest, _digest) = c
-            .pull_manifest(&push_image)
+            .resolve_manifest(&push_image)
             .await
             .expect("failed to pull pushed image manifest");
 
@@ -1319,4 +10037,174 @@ mod test {
         assert_eq!(manifest.schema_version, pulled_manifest.schema_version);
         assert_eq!(manifest.config.digest, pulled_manifest.config.digest);
     }
+
+    #[tokio::test]
+    #[ignore]
+    /// Requires local registry resolveable at `oci.registry.local`
+    async fn generated_config_roundtrips_through_registry() {
+        let c = Client::new(ClientConfig {
+            protocol: ClientProtocol::Http,
+            ..Default::default()
+        });
+        let image: Reference = "oci.registry.local/generated-config:v1".parse().unwrap();
+        let image_data = ImageData {
+            layers: vec![ImageLayer::oci_v1_gzip(gzip(b"layer data"))],
+            ..Default::default()
+        };
+        let config_data =
+            generate_config(&image_data, &Platform::current()).expect("generate config");
+
+        c.push(
+            &image,
+            &image_data,
+            &config_data,
+            IMAGE_CONFIG_MEDIA_TYPE,
+            &RegistryAuth::Anonymous,
+            None,
+        )
+        .await
+        .expect("failed to push image");
+
+        let (manifest, _digest) = c
+            .resolve_manifest(&image)
+            .await
+            .expect("failed to pull pushed manifest");
+        assert_eq!(IMAGE_CONFIG_MEDIA_TYPE, manifest.config.media_type);
+        let mut pulled_config: Vec<u8> = Vec::new();
+        c.download_layer(
+            &image,
+            &manifest.config.digest,
+            &mut pulled_config,
+            None,
+            None,
+            None,
+        )
+        .await
+        .expect("failed to pull config");
+        assert_eq!(config_data, pulled_config);
+
+        let config: OciImageConfig =
+            serde_json::from_slice(&pulled_config).expect("config should parse");
+        assert_eq!(vec![sha256_digest(b"layer data")], config.rootfs.diff_ids);
+    }
+
+    #[tokio::test]
+    async fn push_resumes_upload_session_with_same_key() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let image_data = ImageData {
+            layers: vec![
+                ImageLayer::wasm(b"iamawebassemblymodule".to_vec()),
+                ImageLayer::wasm(b"anotherlayer".to_vec()),
+            ],
+            ..Default::default()
+        };
+        let digest = image_data.layers[1].digest().to_owned();
+        let uploads = InMemoryUploadStore::default();
+        let session = UploadSession {
+            store: &uploads,
+            key: "push-1",
+        };
+        let layer_key = layer_session_key(session.key, &digest);
+
+        // Stand in for a push that uploaded the first layer and was
+        // interrupted partway through the second
+        registry.add_blob(b"iamawebassemblymodule");
+        let location = c.begin_push_session(&image).await.expect("begin session");
+        let (location, _) = c
+            .push_layer(&location, &image, b"another".to_vec(), 0)
+            .await
+            .expect("push part of the layer");
+        uploads.save(&layer_key, &location).unwrap();
+        let interrupted = registry.requests().len();
+
+        let response = c
+            .push_with_options(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                PushOptions {
+                    session: Some(session),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to resume push");
+        assert_eq!(None, uploads.load(&layer_key).unwrap());
+        assert_eq!(Some(b"anotherlayer".to_vec()), registry.blob(&digest));
+        assert_eq!(
+            vec![digest.clone(), sha256_digest(b"{}")],
+            response.uploaded
+        );
+
+        // The session was resumed with the rest of the layer instead of
+        // restarted
+        let resumed: Vec<String> = registry.requests()[interrupted..]
+            .iter()
+            .filter(|r| r.contains("/blobs/uploads/") && !r.contains(&sha256_digest(b"{}")))
+            .cloned()
+            .collect();
+        assert_eq!(
+            vec![
+                "GET /v2/hello/blobs/uploads/0?_state=0".to_owned(),
+                "PATCH /v2/hello/blobs/uploads/0?_state=0".to_owned(),
+                format!("PUT /v2/hello/blobs/uploads/0?_state=0&digest={}", digest),
+            ],
+            resumed
+        );
+    }
+
+    #[tokio::test]
+    async fn push_if_absent_refuses_existing_tag() {
+        let registry = MockRegistry::new();
+        let image: Reference = "mock.example.com/hello:v1".parse().unwrap();
+        let c = registry.client();
+        let image_data = ImageData {
+            layers: vec![ImageLayer::wasm(b"iamawebassemblymodule".to_vec())],
+            ..Default::default()
+        };
+        registry.add_manifest("hello", "v1", manifest::OCI_IMAGE_MEDIA_TYPE, b"{}");
+
+        let err = c
+            .push_if_absent(
+                &image,
+                &image_data,
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                &RegistryAuth::Anonymous,
+                None,
+            )
+            .await
+            .expect_err("push over an existing tag should fail");
+        assert_eq!(
+            Some(&ClientError::AlreadyExists(image.whole())),
+            err.downcast_ref::<ClientError>()
+        );
+        let requests = registry.requests();
+        assert!(
+            requests.iter().all(|r| !r.starts_with("PUT")
+                && !r.starts_with("POST")
+                && !r.starts_with("PATCH")),
+            "nothing should be uploaded: {:?}",
+            requests
+        );
+        assert_eq!(None, registry.blob(&image_data.digest()));
+
+        // Once the tag is free, the image is pushed
+        let image: Reference = "mock.example.com/hello:v2".parse().unwrap();
+        c.push_if_absent(
+            &image,
+            &image_data,
+            b"{}",
+            manifest::WASM_CONFIG_MEDIA_TYPE,
+            &RegistryAuth::Anonymous,
+            None,
+        )
+        .await
+        .expect("push to a new tag");
+        assert!(registry.manifest("hello", "v2").is_some());
+    }
 }
@@ -0,0 +1,249 @@
+//! Pluggable decompression of pulled layers
+//!
+//! Each compressed layer media type maps to a `Decompressor` and the media
+//! type of the layer once it is decompressed. Gzip and zstd are registered by
+//! default, and callers can register their own schemes.
+
+use crate::client::media_type_essence;
+use crate::manifest::{
+    IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_DOCKER_LAYER_MEDIA_TYPE, IMAGE_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_MEDIA_TYPE, IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE, IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE,
+    IMAGE_LAYER_ZSTD_MEDIA_TYPE,
+};
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+
+/// A compression scheme for layer data
+pub trait Decompressor: Send + Sync {
+    /// Wraps a reader of compressed data in a reader of the decompressed data
+    fn decoder<'a>(&self, compressed: Box<dyn Read + 'a>) -> anyhow::Result<Box<dyn Read + 'a>>;
+}
+
+/// Decompresses gzip layers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gzip;
+
+impl Decompressor for Gzip {
+    fn decoder<'a>(&self, compressed: Box<dyn Read + 'a>) -> anyhow::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(flate2::read::GzDecoder::new(compressed)))
+    }
+}
+
+/// Decompresses zstd layers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Zstd;
+
+impl Decompressor for Zstd {
+    fn decoder<'a>(&self, compressed: Box<dyn Read + 'a>) -> anyhow::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(zstd::stream::read::Decoder::new(compressed)?))
+    }
+}
+
+#[derive(Clone)]
+struct Registration {
+    decompressor: Arc<dyn Decompressor>,
+    decompressed_media_type: String,
+}
+
+/// The decompressors the client consults, keyed by compressed media type
+#[derive(Clone)]
+pub struct Decompressors {
+    registrations: HashMap<String, Registration>,
+}
+
+impl Decompressors {
+    /// Create a set with no decompressors registered
+    pub fn empty() -> Self {
+        Decompressors {
+            registrations: HashMap::new(),
+        }
+    }
+
+    /// Register a decompressor for layers of `media_type`, replacing any
+    /// earlier registration for it. Decompressed layers are given
+    /// `decompressed_media_type`.
+    pub fn register(
+        &mut self,
+        media_type: &str,
+        decompressed_media_type: &str,
+        decompressor: Arc<dyn Decompressor>,
+    ) {
+        self.registrations.insert(
+            media_type_essence(media_type),
+            Registration {
+                decompressor,
+                decompressed_media_type: decompressed_media_type.to_owned(),
+            },
+        );
+    }
+
+    /// Returns whether a decompressor is registered for `media_type`
+    pub fn handles(&self, media_type: &str) -> bool {
+        self.registrations
+            .contains_key(&media_type_essence(media_type))
+    }
+
+    /// Returns the media type that layers of `media_type` have once they are
+    /// decompressed, or `None` if no decompressor is registered for it
+    pub fn decompressed_media_type(&self, media_type: &str) -> Option<&str> {
+        self.registrations
+            .get(&media_type_essence(media_type))
+            .map(|r| r.decompressed_media_type.as_str())
+    }
+
+    /// Wraps a reader of layer data of the given media type in a reader of
+    /// the decompressed data
+    ///
+    /// If no decompressor is registered for the media type, the reader is
+    /// returned as it is.
+    pub fn decoder<'a>(
+        &self,
+        media_type: &str,
+        data: Box<dyn Read + 'a>,
+    ) -> anyhow::Result<Box<dyn Read + 'a>> {
+        match self.registrations.get(&media_type_essence(media_type)) {
+            Some(r) => r.decompressor.decoder(data),
+            None => Ok(data),
+        }
+    }
+
+    /// Decompresses layer data of the given media type
+    ///
+    /// Returns the decompressed data and its media type, or `None` if no
+    /// decompressor is registered for the media type.
+    pub fn decompress(
+        &self,
+        media_type: &str,
+        data: &[u8],
+    ) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        let registration = match self.registrations.get(&media_type_essence(media_type)) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let mut decoder = registration.decompressor.decoder(Box::new(data))?;
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        Ok(Some((
+            decompressed,
+            registration.decompressed_media_type.clone(),
+        )))
+    }
+}
+
+impl Default for Decompressors {
+    /// Gzip and zstd decompressors for the OCI and Docker layer media types
+    fn default() -> Self {
+        let mut decompressors = Decompressors::empty();
+        let gzip: Arc<dyn Decompressor> = Arc::new(Gzip);
+        let zstd: Arc<dyn Decompressor> = Arc::new(Zstd);
+        decompressors.register(
+            IMAGE_LAYER_GZIP_MEDIA_TYPE,
+            IMAGE_LAYER_MEDIA_TYPE,
+            gzip.clone(),
+        );
+        decompressors.register(
+            IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE,
+            IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE,
+            gzip.clone(),
+        );
+        decompressors.register(
+            IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+            IMAGE_DOCKER_LAYER_MEDIA_TYPE,
+            gzip,
+        );
+        decompressors.register(
+            IMAGE_LAYER_ZSTD_MEDIA_TYPE,
+            IMAGE_LAYER_MEDIA_TYPE,
+            zstd.clone(),
+        );
+        decompressors.register(
+            IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE,
+            IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE,
+            zstd,
+        );
+        decompressors
+    }
+}
+
+impl std::fmt::Debug for Decompressors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.registrations.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn default_decompressors_handle_gzip_and_zstd() {
+        let decompressors = Decompressors::default();
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"hello").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        assert_eq!(
+            Some((b"hello".to_vec(), IMAGE_LAYER_MEDIA_TYPE.to_owned())),
+            decompressors
+                .decompress(IMAGE_LAYER_GZIP_MEDIA_TYPE, &gzipped)
+                .expect("gzip layer should decompress")
+        );
+
+        let zstded = zstd::stream::encode_all(&b"hello"[..], 0).unwrap();
+        assert_eq!(
+            Some((b"hello".to_vec(), IMAGE_LAYER_MEDIA_TYPE.to_owned())),
+            decompressors
+                .decompress(IMAGE_LAYER_ZSTD_MEDIA_TYPE, &zstded)
+                .expect("zstd layer should decompress")
+        );
+
+        assert_eq!(
+            None,
+            decompressors
+                .decompress(IMAGE_LAYER_MEDIA_TYPE, b"hello")
+                .expect("uncompressed layer is left alone")
+        );
+    }
+
+    #[test]
+    fn custom_decompressors_can_be_registered() {
+        // Reverses the data, standing in for a proprietary scheme
+        struct Reverse;
+        impl Decompressor for Reverse {
+            fn decoder<'a>(
+                &self,
+                mut compressed: Box<dyn Read + 'a>,
+            ) -> anyhow::Result<Box<dyn Read + 'a>> {
+                let mut data = Vec::new();
+                compressed.read_to_end(&mut data)?;
+                data.reverse();
+                Ok(Box::new(std::io::Cursor::new(data)))
+            }
+        }
+
+        let mut decompressors = Decompressors::empty();
+        decompressors.register(
+            "application/vnd.example.layer.v1.tar+reverse",
+            IMAGE_LAYER_MEDIA_TYPE,
+            Arc::new(Reverse),
+        );
+        assert!(decompressors.handles("application/vnd.example.layer.v1.tar+REVERSE; x=y"));
+        assert_eq!(
+            Some((b"hello".to_vec(), IMAGE_LAYER_MEDIA_TYPE.to_owned())),
+            decompressors
+                .decompress("application/vnd.example.layer.v1.tar+reverse", b"olleh")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn corrupt_layers_fail_to_decompress() {
+        assert!(Decompressors::default()
+            .decompress(IMAGE_LAYER_GZIP_MEDIA_TYPE, b"not gzip")
+            .is_err());
+    }
+}
@@ -0,0 +1,243 @@
+//! Content digests in the `algorithm:hex` form used by registries
+//!
+//! sha256 is used for everything the client hashes itself, but registries
+//! may address content by sha512 as well, so both are accepted when parsing.
+
+use sha2::Digest as _;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A hash algorithm that content can be addressed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DigestAlgorithm {
+    /// SHA-256, the default
+    Sha256,
+    /// SHA-512
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// The algorithm's name as it appears in a digest
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// The length of the algorithm's hex-encoded hash
+    fn hex_len(self) -> usize {
+        match self {
+            DigestAlgorithm::Sha256 => 64,
+            DigestAlgorithm::Sha512 => 128,
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> String {
+        match self {
+            DigestAlgorithm::Sha256 => format!("{:x}", sha2::Sha256::digest(data)),
+            DigestAlgorithm::Sha512 => format!("{:x}", sha2::Sha512::digest(data)),
+        }
+    }
+}
+
+impl Default for DigestAlgorithm {
+    fn default() -> Self {
+        DigestAlgorithm::Sha256
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "sha512" => Ok(DigestAlgorithm::Sha512),
+            _ => Err(anyhow::anyhow!("unsupported digest algorithm: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A validated content digest, such as `sha256:<64 hex characters>`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: DigestAlgorithm,
+    hex: String,
+}
+
+impl Digest {
+    /// Computes the digest of `data` with the given algorithm
+    pub fn compute(algorithm: DigestAlgorithm, data: &[u8]) -> Self {
+        Digest {
+            algorithm,
+            hex: algorithm.hash(data),
+        }
+    }
+
+    /// The algorithm the digest was computed with
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    /// The hex-encoded hash, without the algorithm prefix
+    pub fn hex(&self) -> &str {
+        &self.hex
+    }
+
+    /// Returns whether `data` hashes to this digest
+    pub fn verify(&self, data: &[u8]) -> bool {
+        self.algorithm.hash(data) == self.hex
+    }
+}
+
+impl FromStr for Digest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, hex) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(anyhow::anyhow!("invalid digest {:?}: missing algorithm", s)),
+        };
+        let algorithm: DigestAlgorithm = algorithm.parse()?;
+        if hex.len() != algorithm.hex_len() {
+            return Err(anyhow::anyhow!(
+                "invalid digest {:?}: {} digests have {} hex characters",
+                s,
+                algorithm,
+                algorithm.hex_len()
+            ));
+        }
+        if !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(anyhow::anyhow!(
+                "invalid digest {:?}: hash must be lowercase hex",
+                s
+            ));
+        }
+        Ok(Digest {
+            algorithm,
+            hex: hex.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<&str> for Digest {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+/// Computes a digest over data that arrives in pieces
+pub(crate) enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Digest {
+        match self {
+            Hasher::Sha256(h) => Digest {
+                algorithm: DigestAlgorithm::Sha256,
+                hex: format!("{:x}", h.finalize()),
+            },
+            Hasher::Sha512(h) => Digest {
+                algorithm: DigestAlgorithm::Sha512,
+                hex: format!("{:x}", h.finalize()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // printf hello | sha512sum
+    const HELLO_SHA512: &str = "sha512:9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043";
+    // printf hello | sha256sum
+    const HELLO_SHA256: &str =
+        "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn compute_matches_reference_digests() {
+        assert_eq!(
+            HELLO_SHA256,
+            Digest::compute(DigestAlgorithm::Sha256, b"hello").to_string()
+        );
+        assert_eq!(
+            HELLO_SHA512,
+            Digest::compute(DigestAlgorithm::Sha512, b"hello").to_string()
+        );
+    }
+
+    #[test]
+    fn parsed_digests_verify_with_their_algorithm() {
+        let sha512: Digest = HELLO_SHA512.parse().expect("sha512 digest should parse");
+        assert_eq!(DigestAlgorithm::Sha512, sha512.algorithm());
+        assert!(sha512.verify(b"hello"));
+        assert!(!sha512.verify(b"goodbye"));
+
+        let sha256: Digest = HELLO_SHA256.parse().expect("sha256 digest should parse");
+        assert_eq!(DigestAlgorithm::Sha256, sha256.algorithm());
+        assert!(sha256.verify(b"hello"));
+        assert_eq!(HELLO_SHA256, sha256.to_string());
+    }
+
+    #[test]
+    fn hasher_matches_computed_digests() {
+        for algorithm in &[DigestAlgorithm::Sha256, DigestAlgorithm::Sha512] {
+            let mut hasher = Hasher::new(*algorithm);
+            hasher.update(b"he");
+            hasher.update(b"llo");
+            assert_eq!(Digest::compute(*algorithm, b"hello"), hasher.finish());
+        }
+    }
+
+    #[test]
+    fn malformed_digests_are_rejected() {
+        for digest in &[
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            "md5:5d41402abc4b2a76b9719d911017c592",
+            "sha256:deadbeef",
+            // A sha256 hash under the sha512 algorithm
+            "sha512:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            "sha256:2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824",
+        ] {
+            assert!(
+                digest.parse::<Digest>().is_err(),
+                "{} should not parse",
+                digest
+            );
+        }
+    }
+}
@@ -11,6 +11,7 @@ pub struct OciError {
     /// A message associated with the error
     pub message: String,
     /// Unstructured data associated with the error
+    #[serde(default)]
     pub detail: serde_json::Value,
 }
 
@@ -25,6 +26,152 @@ impl std::fmt::Display for OciError {
     }
 }
 
+/// Errors from client operations that callers may want to handle specifically.
+///
+/// These are returned wrapped in an `anyhow::Error`, and can be recovered with
+/// `anyhow::Error::downcast_ref::<ClientError>()`.
+#[derive(Debug, PartialEq)]
+pub enum ClientError {
+    /// The operation was cancelled through its `CancellationToken`
+    Cancelled,
+    /// The registry rejected a chunked upload's `Content-Range` with the
+    /// given HTTP status
+    ContentRangeUnsupported(u16),
+    /// A conditional push found that the reference already exists in the
+    /// registry
+    AlreadyExists(String),
+    /// The registry rejected a manifest because it references blobs that it
+    /// does not know, listed by digest
+    ManifestBlobUnknown(Vec<String>),
+    /// The host name of the named registry could not be resolved
+    RegistryUnresolvable(String),
+    /// A connection to the named registry could not be established
+    RegistryUnreachable(String),
+    /// Requests to the named registry are short-circuited because it failed
+    /// repeatedly
+    RegistryUnavailable(String),
+    /// The named registry asked for a token without saying where to get it:
+    /// its Bearer challenge has no realm
+    MissingRealm(String),
+    /// The registry rejected the supplied credentials, for the given reason
+    AuthenticationFailed(String),
+    /// The registry answered `UNAUTHORIZED`: the request needs a token, or
+    /// the token was rejected. Authenticating again may let it succeed.
+    Unauthorized(String),
+    /// The registry answered `DENIED`: the client is authenticated but not
+    /// permitted to perform the operation, so retrying will not help
+    Denied(String),
+    /// The registry answered `MANIFEST_UNKNOWN` or `NAME_UNKNOWN`: the image
+    /// or repository does not exist
+    NotFound(String),
+    /// The registry did not respond within the configured timeout, or an
+    /// operation did not finish before its deadline
+    TimedOut(std::time::Duration),
+    /// The registry answered with the given 5xx status
+    ServerError(u16),
+    /// The named registry kept refusing requests with `429 Too Many
+    /// Requests`
+    RateLimited {
+        /// The registry that refused the requests
+        registry: String,
+        /// How long the registry asked the client to wait, if it said
+        retry_after: Option<std::time::Duration>,
+    },
+    /// The registry does not allow deleting the content at the given URL,
+    /// typically because deletion is disabled in its configuration
+    DeleteUnsupported(String),
+    /// A pulled blob did not hash to the digest it was requested by
+    DigestMismatch {
+        /// The digest the blob was requested by
+        expected: String,
+        /// The digest of the data the registry sent
+        actual: String,
+    },
+    /// An image has layers whose media types the caller does not accept
+    IncompatibleMediaTypes {
+        /// The layer media types of the image
+        found: Vec<String>,
+        /// The media types the caller accepts
+        accepted: Vec<String>,
+    },
+}
+
+impl std::error::Error for ClientError {}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Cancelled => write!(f, "operation was cancelled"),
+            ClientError::ContentRangeUnsupported(status) => write!(
+                f,
+                "registry does not support chunked uploads (status {})",
+                status
+            ),
+            ClientError::AlreadyExists(reference) => {
+                write!(f, "{} already exists in the registry", reference)
+            }
+            ClientError::ManifestBlobUnknown(digests) => write!(
+                f,
+                "manifest references blobs unknown to the registry: {}",
+                digests.join(", ")
+            ),
+            ClientError::RegistryUnresolvable(registry) => {
+                write!(f, "unable to resolve registry host {}", registry)
+            }
+            ClientError::RegistryUnreachable(registry) => {
+                write!(f, "unable to connect to registry {}", registry)
+            }
+            ClientError::RegistryUnavailable(registry) => write!(
+                f,
+                "registry {} is unavailable after repeated failures",
+                registry
+            ),
+            ClientError::AuthenticationFailed(reason) => {
+                write!(f, "failed to authenticate: {}", reason)
+            }
+            ClientError::Unauthorized(message) => write!(f, "unauthorized: {}", message),
+            ClientError::Denied(message) => write!(f, "access denied: {}", message),
+            ClientError::NotFound(message) => write!(f, "not found: {}", message),
+            ClientError::MissingRealm(registry) => write!(
+                f,
+                "registry {} sent an authentication challenge without a realm",
+                registry
+            ),
+            ClientError::TimedOut(after) => write!(f, "timed out after {:?}", after),
+            ClientError::ServerError(status) => {
+                write!(f, "registry answered with server error {}", status)
+            }
+            ClientError::RateLimited {
+                registry,
+                retry_after: Some(wait),
+            } => write!(
+                f,
+                "registry {} is rate limiting requests, retry in {} seconds",
+                registry,
+                wait.as_secs()
+            ),
+            ClientError::RateLimited {
+                registry,
+                retry_after: None,
+            } => write!(f, "registry {} is rate limiting requests", registry),
+            ClientError::DeleteUnsupported(url) => {
+                write!(f, "registry does not support deleting {}", url)
+            }
+            ClientError::DigestMismatch { expected, actual } => write!(
+                f,
+                "digest mismatch: expected {}, computed {}",
+                expected, actual
+            ),
+            ClientError::IncompatibleMediaTypes { found, accepted } => write!(
+                f,
+                "incompatible layer media types: image has [{}], accepted are [{}]",
+                found.join(", "),
+                accepted.join(", ")
+            ),
+        }
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub(crate) struct OciEnvelope {
     pub(crate) errors: Vec<OciError>,
@@ -81,6 +228,30 @@ pub enum OciErrorCode {
     Unsupported,
 }
 
+impl OciEnvelope {
+    /// Returns the blob digests named by `MANIFEST_BLOB_UNKNOWN` errors, if
+    /// the envelope holds any
+    pub(crate) fn manifest_blob_unknown_digests(&self) -> Option<Vec<String>> {
+        let digests: Vec<String> = self
+            .errors
+            .iter()
+            .filter(|e| e.code == OciErrorCode::ManifestBlobUnknown)
+            .filter_map(|e| match &e.detail {
+                serde_json::Value::String(digest) => Some(digest.clone()),
+                detail => detail
+                    .get("digest")
+                    .and_then(|d| d.as_str())
+                    .map(|d| d.to_owned()),
+            })
+            .collect();
+        if digests.is_empty() {
+            None
+        } else {
+            Some(digests)
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -96,4 +267,23 @@ mod test {
         assert_eq!(OciErrorCode::Unauthorized, e.code);
         assert_eq!("authentication required", e.message);
     }
+
+    #[test]
+    fn manifest_blob_unknown_digests_are_extracted() {
+        let envelope: OciEnvelope = serde_json::from_str(
+            r#"{"errors":[
+                {"code":"MANIFEST_BLOB_UNKNOWN","message":"blob unknown to registry","detail":{"digest":"sha256:aaaa"}},
+                {"code":"MANIFEST_BLOB_UNKNOWN","message":"blob unknown to registry","detail":"sha256:bbbb"}
+            ]}"#,
+        )
+        .expect("parse error");
+        assert_eq!(
+            Some(vec!["sha256:aaaa".to_owned(), "sha256:bbbb".to_owned()]),
+            envelope.manifest_blob_unknown_digests()
+        );
+
+        let envelope: OciEnvelope =
+            serde_json::from_str(EXAMPLE_ERROR).expect("parse example error");
+        assert_eq!(None, envelope.manifest_blob_unknown_digests());
+    }
 }
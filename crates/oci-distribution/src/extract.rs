@@ -0,0 +1,313 @@
+//! Extraction of image layers into a root filesystem
+//!
+//! Each layer is a changeset applied on top of the layers before it, as
+//! described in the OCI image specification:
+//! https://github.com/opencontainers/image-spec/blob/master/layer.md
+//! Files in later layers replace those in earlier ones, and whiteout files
+//! delete what earlier layers added.
+
+use crate::archive::check_entry_path;
+use crate::decompress::Decompressors;
+
+use anyhow::Context;
+use log::warn;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Decompresses a layer of the given media type and applies it to `dest`
+///
+/// Layers of media types without a registered decompressor are treated as
+/// uncompressed tarballs.
+pub(crate) fn extract_layer<'a>(
+    decompressors: &Decompressors,
+    media_type: &str,
+    data: Box<dyn Read + 'a>,
+    dest: &Path,
+) -> anyhow::Result<()> {
+    let tar = decompressors.decoder(media_type, data)?;
+    apply_layer(tar, dest)
+}
+
+/// Unpacks an uncompressed layer tarball on top of the contents of `dest`,
+/// applying its whiteouts
+///
+/// Entries that would be written, or would delete files, outside of `dest`
+/// fail the extraction. This includes paths that lead through a symlink
+/// added by an earlier layer.
+pub(crate) fn apply_layer<R: Read>(layer: R, dest: &Path) -> anyhow::Result<()> {
+    let dest = dest
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve extraction directory {:?}", dest))?;
+    let mut archive = tar::Archive::new(layer);
+    archive.set_preserve_permissions(true);
+
+    // Paths added by this layer, and their parents. An opaque whiteout only
+    // hides what earlier layers added, so it must leave these alone.
+    let mut added = HashSet::new();
+    for entry in archive.entries().context("Failed to read layer")? {
+        let mut entry = entry.context("Failed to read layer entry")?;
+        let path = entry
+            .path()
+            .context("Failed to read layer entry path")?
+            .into_owned();
+        check_entry_path(&path)?;
+        // Drop any `./` so that paths compare equal to those read back from
+        // the directory
+        let path: PathBuf = path
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_owned(),
+            // The root directory itself
+            None => continue,
+        };
+        let parent = dest.join(path.parent().unwrap_or_else(|| Path::new("")));
+        ensure_inside(&dest, &parent)?;
+
+        if name == OPAQUE_WHITEOUT {
+            remove_earlier_entries(&parent, &added)?;
+        } else if name.starts_with(WHITEOUT_PREFIX) {
+            remove_path(&parent.join(&name[WHITEOUT_PREFIX.len()..]))?;
+        } else {
+            let target = parent.join(&name);
+            let is_dir = entry.header().entry_type().is_dir();
+            // A directory only replaces a directory, so merge into it.
+            // Anything else is replaced outright, so that a file is never
+            // written through a symlink left by an earlier layer.
+            if !(is_dir && target.is_dir()) {
+                remove_path(&target)?;
+            }
+            let unpacked = entry
+                .unpack_in(&dest)
+                .with_context(|| format!("Failed to extract {}", path.display()))?;
+            if !unpacked {
+                return Err(anyhow::anyhow!(
+                    "tar entry {} escapes the extraction directory",
+                    path.display()
+                ));
+            }
+            for ancestor in target.ancestors().take_while(|a| *a != dest.as_path()) {
+                added.insert(ancestor.to_owned());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fails if `dir` exists and resolves to somewhere outside of `dest`
+fn ensure_inside(dest: &Path, dir: &Path) -> anyhow::Result<()> {
+    let resolved = match dir.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to resolve {:?}", dir)),
+    };
+    if !resolved.starts_with(dest) {
+        return Err(anyhow::anyhow!(
+            "{:?} resolves outside of the extraction directory",
+            dir
+        ));
+    }
+    Ok(())
+}
+
+/// Removes everything in `dir` that the current layer did not add
+fn remove_earlier_entries(dir: &Path, added: &HashSet<PathBuf>) -> anyhow::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", dir)),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if !added.contains(&path) {
+            remove_path(&path)?;
+        } else if std::fs::symlink_metadata(&path)?.is_dir() {
+            remove_earlier_entries(&path, added)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes a file, symlink or directory tree, if there is one at `path`
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    result.with_context(|| format!("Failed to remove {:?}", path))
+}
+
+/// A uniquely named directory under the system temp directory, removed with
+/// everything in it when dropped
+pub(crate) struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let path =
+            std::env::temp_dir().join(format!("oci-distribution-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&path)
+            .with_context(|| format!("Failed to create staging directory {:?}", path))?;
+        Ok(StagingDir { path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove staging directory {:?}: {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::{IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE};
+    use std::io::Write;
+
+    fn layer(entries: &[(&str, Option<&str>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            match data {
+                Some(data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    header.set_mode(0o644);
+                    header.set_cksum();
+                    builder
+                        .append_data(&mut header, path, data.as_bytes())
+                        .unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    header.set_cksum();
+                    builder.append_data(&mut header, path, &b""[..]).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read(dest: &Path, path: &str) -> Option<String> {
+        std::fs::read_to_string(dest.join(path)).ok()
+    }
+
+    #[test]
+    fn layers_are_merged_in_order_with_whiteouts() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let decompressors = Decompressors::default();
+
+        let base = layer(&[
+            ("etc/", None),
+            ("etc/hosts", Some("base")),
+            ("etc/passwd", Some("root")),
+            ("var/cache/", None),
+            ("var/cache/old", Some("stale")),
+            ("var/cache/nested/old", Some("stale")),
+            ("bin/sh", Some("shell")),
+        ]);
+        let update = gzip(&layer(&[
+            ("etc/hosts", Some("update")),
+            ("etc/.wh.passwd", Some("")),
+            ("var/cache/fresh", Some("new")),
+            ("var/cache/.wh..wh..opq", Some("")),
+        ]));
+
+        extract_layer(
+            &decompressors,
+            IMAGE_LAYER_MEDIA_TYPE,
+            Box::new(base.as_slice()),
+            dir.path(),
+        )
+        .expect("base layer should extract");
+        extract_layer(
+            &decompressors,
+            IMAGE_LAYER_GZIP_MEDIA_TYPE,
+            Box::new(update.as_slice()),
+            dir.path(),
+        )
+        .expect("gzipped layer should extract");
+
+        assert_eq!(Some("update".to_owned()), read(dir.path(), "etc/hosts"));
+        assert_eq!(Some("shell".to_owned()), read(dir.path(), "bin/sh"));
+        assert!(!dir.path().join("etc/passwd").exists());
+        assert!(!dir.path().join("etc/.wh.passwd").exists());
+        // The opaque whiteout hides the base layer's cache but keeps the
+        // file added alongside it
+        assert_eq!(Some("new".to_owned()), read(dir.path(), "var/cache/fresh"));
+        assert!(!dir.path().join("var/cache/old").exists());
+        assert!(!dir.path().join("var/cache/nested").exists());
+        assert!(!dir.path().join("var/cache/.wh..wh..opq").exists());
+    }
+
+    #[test]
+    fn files_replace_directories_from_earlier_layers() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        apply_layer(
+            layer(&[("app/", None), ("app/data", Some("x"))]).as_slice(),
+            dir.path(),
+        )
+        .unwrap();
+        apply_layer(layer(&[("app", Some("file"))]).as_slice(), dir.path()).unwrap();
+        assert_eq!(Some("file".to_owned()), read(dir.path(), "app"));
+    }
+
+    #[test]
+    fn path_traversal_entries_are_rejected() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let dest = dir.path().join("rootfs");
+        std::fs::create_dir(&dest).unwrap();
+
+        // The tar builder refuses `..` paths, so write the name directly
+        let mut header = tar::Header::new_old();
+        let name = b"../escaped";
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(4);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b"evil"[..]).unwrap();
+        let data = builder.into_inner().unwrap();
+
+        assert!(apply_layer(data.as_slice(), &dest).is_err());
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn whiteouts_through_symlinks_are_rejected() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("keep"), b"keep").unwrap();
+        let dest = dir.path().join("rootfs");
+        std::fs::create_dir(&dest).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("link")).unwrap();
+
+        let whiteout = layer(&[("link/.wh.keep", Some(""))]);
+        assert!(apply_layer(whiteout.as_slice(), &dest).is_err());
+        assert!(outside.join("keep").exists());
+    }
+}
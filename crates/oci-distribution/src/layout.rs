@@ -0,0 +1,380 @@
+//! Reading and writing images in an on-disk OCI image layout
+//!
+//! This allows images to be loaded from, or saved to, a directory without
+//! talking to a registry. The layout is described in the OCI image
+//! specification: https://github.com/opencontainers/image-spec/blob/master/image-layout.md
+
+use crate::client::{
+    generate_manifest, manifest_content_type, media_type_matches, sha256_digest, verify_blob,
+    ImageData, ImageLayer, LayerSource,
+};
+use crate::digest::Digest;
+use crate::manifest::{OciDescriptor, OciImageIndex, OciManifest, ANNOTATION_REF_NAME};
+use crate::Reference;
+
+use anyhow::Context;
+use log::debug;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const INDEX_FILE: &str = "index.json";
+const BLOBS_DIR: &str = "blobs";
+const IMAGE_LAYOUT_VERSION: &str = "1.0.0";
+
+#[derive(serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OciLayoutMarker {
+    image_layout_version: String,
+}
+
+/// An OCI image layout directory.
+///
+/// This mirrors the pull and push API of the `Client`, but reads and writes
+/// images under a local directory instead of a registry. Images are looked up
+/// in the layout's `index.json` by the tag of the reference (or `latest`), or
+/// by digest when the reference has one. Every blob read from the layout is
+/// verified against its digest.
+pub struct OciLayout {
+    root: PathBuf,
+}
+
+impl OciLayout {
+    /// Creates a handle to the layout rooted at the given directory.
+    ///
+    /// The directory is not touched until an image is pulled or pushed.
+    pub fn new<T: AsRef<Path>>(root: T) -> Self {
+        Self {
+            root: root.as_ref().into(),
+        }
+    }
+
+    /// Pull an image from the layout
+    ///
+    /// Layer media types are validated against `accepted_media_types` in the
+    /// same way as `Client::pull`.
+    pub async fn pull(
+        &self,
+        image: &Reference,
+        accepted_media_types: Vec<&str>,
+    ) -> anyhow::Result<ImageData> {
+        debug!("Loading image {:?} from layout {:?}", image, self.root);
+        self.check_layout_version().await?;
+
+        let index = self.read_index().await?;
+        let descriptor = index
+            .manifests
+            .iter()
+            .find(|d| descriptor_matches(d, image))
+            .ok_or_else(|| anyhow::anyhow!("image {} not found in OCI layout", image))?;
+
+        let manifest_bytes = self.read_blob(&descriptor.digest).await?;
+        let manifest: OciManifest = serde_json::from_slice(&manifest_bytes)
+            .with_context(|| format!("Failed to parse manifest for '{:?}'", image))?;
+
+        if manifest.layers.is_empty() {
+            return Err(anyhow::anyhow!("no layers to pull"));
+        }
+        for layer in &manifest.layers {
+            if !accepted_media_types
+                .iter()
+                .any(|i| media_type_matches(i, &layer.media_type))
+            {
+                return Err(anyhow::anyhow!(
+                    "incompatible layer media type: {}",
+                    layer.media_type
+                ));
+            }
+        }
+
+        let mut layers = Vec::with_capacity(manifest.layers.len());
+        for layer in &manifest.layers {
+            let data = self.read_blob(&layer.digest).await?;
+            let mut image_layer = ImageLayer::new(data, layer.media_type.clone());
+            image_layer.source = Some(LayerSource::Cache);
+            layers.push(image_layer);
+        }
+
+        Ok(ImageData {
+            layers,
+            digest: Some(descriptor.digest.clone()),
+            config_descriptor: Some(manifest.config),
+            manifest_media_type: manifest.media_type,
+        })
+    }
+
+    /// Push an image into the layout
+    ///
+    /// If a manifest is not provided, one is generated from the provided image
+    /// and config data. Any existing image with the same tag is replaced.
+    ///
+    /// Returns the digest of the stored manifest
+    pub async fn push(
+        &self,
+        image_ref: &Reference,
+        image_data: &ImageData,
+        config_data: &[u8],
+        config_media_type: &str,
+        image_manifest: Option<OciManifest>,
+    ) -> anyhow::Result<String> {
+        debug!("Storing image {:?} in layout {:?}", image_ref, self.root);
+        self.init().await?;
+
+        for layer in &image_data.layers {
+            self.write_blob(layer.data()).await?;
+        }
+        self.write_blob(config_data).await?;
+
+        let manifest = match image_manifest {
+            Some(m) => m,
+            None => generate_manifest(image_data, config_data, config_media_type),
+        };
+        let manifest_bytes = serde_json::to_vec(&manifest)?;
+        let manifest_digest = self.write_blob(&manifest_bytes).await?;
+
+        let tag = image_ref.tag().unwrap_or("latest").to_owned();
+        let mut annotations = HashMap::new();
+        annotations.insert(ANNOTATION_REF_NAME.to_owned(), tag.clone());
+
+        let mut index = self.read_index().await?;
+        index
+            .manifests
+            .retain(|d| ref_name(d) != Some(tag.as_str()));
+        index.manifests.push(OciDescriptor {
+            media_type: manifest_content_type(&manifest).to_owned(),
+            digest: manifest_digest.clone(),
+            size: manifest_bytes.len() as i64,
+            urls: None,
+            annotations: Some(annotations),
+            platform: None,
+        });
+        self.write_index(&index).await?;
+
+        Ok(manifest_digest)
+    }
+
+    /// Reads a blob from the layout, verifying it against its digest
+    pub async fn read_blob(&self, digest: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self.blob_path(digest)?;
+        let data = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read blob {:?}", path))?;
+        verify_blob(digest, &data)?;
+        Ok(data)
+    }
+
+    /// Writes a blob into the layout
+    ///
+    /// Returns the `sha256:...` digest under which the blob was stored
+    pub async fn write_blob(&self, data: &[u8]) -> anyhow::Result<String> {
+        let digest = sha256_digest(data);
+        let path = self.blob_path(&digest)?;
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            tokio::fs::write(&path, data).await?;
+        }
+        Ok(digest)
+    }
+
+    /// Creates the layout marker and an empty index if they don't exist yet
+    async fn init(&self) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(self.root.join(BLOBS_DIR)).await?;
+        let marker_path = self.root.join(OCI_LAYOUT_FILE);
+        if !marker_path.exists() {
+            let marker = OciLayoutMarker {
+                image_layout_version: IMAGE_LAYOUT_VERSION.to_owned(),
+            };
+            tokio::fs::write(&marker_path, serde_json::to_vec(&marker)?).await?;
+        }
+        let index_path = self.root.join(INDEX_FILE);
+        if !index_path.exists() {
+            self.write_index(&OciImageIndex::default()).await?;
+        }
+        self.check_layout_version().await
+    }
+
+    async fn check_layout_version(&self) -> anyhow::Result<()> {
+        let marker_path = self.root.join(OCI_LAYOUT_FILE);
+        let text = tokio::fs::read(&marker_path)
+            .await
+            .with_context(|| format!("{:?} is not an OCI image layout", self.root))?;
+        let marker: OciLayoutMarker = serde_json::from_slice(&text)
+            .with_context(|| format!("Failed to parse {:?}", marker_path))?;
+        if marker.image_layout_version != IMAGE_LAYOUT_VERSION {
+            return Err(anyhow::anyhow!(
+                "unsupported image layout version: {}",
+                marker.image_layout_version
+            ));
+        }
+        Ok(())
+    }
+
+    async fn read_index(&self) -> anyhow::Result<OciImageIndex> {
+        let path = self.root.join(INDEX_FILE);
+        let text = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        serde_json::from_slice(&text).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    async fn write_index(&self, index: &OciImageIndex) -> anyhow::Result<()> {
+        let path = self.root.join(INDEX_FILE);
+        tokio::fs::write(&path, serde_json::to_vec(index)?).await?;
+        Ok(())
+    }
+
+    /// Maps a digest to its path under `blobs/<algorithm>/<hex>`
+    fn blob_path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        let digest: Digest = digest.parse()?;
+        Ok(self
+            .root
+            .join(BLOBS_DIR)
+            .join(digest.algorithm().name())
+            .join(digest.hex()))
+    }
+}
+
+fn ref_name(descriptor: &OciDescriptor) -> Option<&str> {
+    descriptor
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(ANNOTATION_REF_NAME))
+        .map(|s| s.as_str())
+}
+
+fn descriptor_matches(descriptor: &OciDescriptor, image: &Reference) -> bool {
+    match image.digest() {
+        Some(digest) => descriptor.digest == digest,
+        None => ref_name(descriptor) == Some(image.tag().unwrap_or("latest")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::digest::DigestAlgorithm;
+    use crate::manifest;
+
+    fn image_data() -> ImageData {
+        ImageData {
+            layers: vec![ImageLayer::new(
+                b"iamawebassemblymodule".to_vec(),
+                manifest::WASM_LAYER_MEDIA_TYPE.to_owned(),
+            )],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn can_roundtrip_an_image() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let layout = OciLayout::new(dir.path());
+        let image: Reference = "webassembly.azurecr.io/hello-wasm:v1".parse().unwrap();
+
+        let digest = layout
+            .push(
+                &image,
+                &image_data(),
+                b"{}",
+                manifest::WASM_CONFIG_MEDIA_TYPE,
+                None,
+            )
+            .await
+            .expect("push to layout");
+
+        assert!(dir.path().join("oci-layout").exists());
+        assert!(dir.path().join("index.json").exists());
+
+        let pulled = layout
+            .pull(&image, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .expect("pull from layout");
+        assert_eq!(Some(digest.clone()), pulled.digest);
+        assert_eq!(1, pulled.layers.len());
+        assert_eq!(&b"iamawebassemblymodule"[..], pulled.layers[0].data());
+        assert_eq!(Some(LayerSource::Cache), pulled.layers[0].source);
+        assert_eq!(
+            manifest::WASM_CONFIG_MEDIA_TYPE,
+            pulled.config_descriptor.unwrap().media_type
+        );
+
+        let by_digest: Reference = format!("webassembly.azurecr.io/hello-wasm@{}", digest)
+            .parse()
+            .unwrap();
+        layout
+            .pull(&by_digest, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .expect("pull from layout by digest");
+    }
+
+    #[tokio::test]
+    async fn pushing_a_tag_again_replaces_it() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let layout = OciLayout::new(dir.path());
+        let image: Reference = "webassembly.azurecr.io/hello-wasm:v1".parse().unwrap();
+
+        layout
+            .push(&image, &image_data(), b"{}", "a/b", None)
+            .await
+            .expect("first push");
+        layout
+            .push(&image, &image_data(), b"{ }", "a/b", None)
+            .await
+            .expect("second push");
+
+        assert_eq!(1, layout.read_index().await.unwrap().manifests.len());
+    }
+
+    #[tokio::test]
+    async fn corrupted_blobs_are_rejected() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let layout = OciLayout::new(dir.path());
+        let image: Reference = "webassembly.azurecr.io/hello-wasm:v1".parse().unwrap();
+
+        layout
+            .push(&image, &image_data(), b"{}", "a/b", None)
+            .await
+            .expect("push to layout");
+
+        let layer_digest = sha256_digest(b"iamawebassemblymodule");
+        let path = layout.blob_path(&layer_digest).unwrap();
+        std::fs::write(&path, b"tampered").unwrap();
+
+        assert!(layout
+            .pull(&image, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn blob_paths_reject_traversal() {
+        let layout = OciLayout::new("/tmp/layout");
+        assert!(layout.blob_path("sha256:../../etc/passwd").is_err());
+        assert!(layout.blob_path("md5:abcdef").is_err());
+        assert!(layout.blob_path("abcdef").is_err());
+        assert!(layout.blob_path("sha256:abc123").is_err());
+        let sha256 = sha256_digest(b"blob");
+        assert_eq!(
+            layout.blob_path(&sha256).unwrap(),
+            PathBuf::from(format!("/tmp/layout/blobs/sha256/{}", &sha256[7..]))
+        );
+        let sha512 = Digest::compute(DigestAlgorithm::Sha512, b"blob");
+        assert_eq!(
+            layout.blob_path(&sha512.to_string()).unwrap(),
+            PathBuf::from(format!("/tmp/layout/blobs/sha512/{}", sha512.hex()))
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_layout_is_an_error() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let layout = OciLayout::new(dir.path());
+        let image: Reference = "webassembly.azurecr.io/hello-wasm:v1".parse().unwrap();
+        assert!(layout
+            .pull(&image, vec![manifest::WASM_LAYER_MEDIA_TYPE])
+            .await
+            .is_err());
+    }
+}
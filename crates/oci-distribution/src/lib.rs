@@ -1,12 +1,24 @@
 //! An OCI Distribution client for fetching oci images from an OCI compliant remote store
 #![deny(missing_docs)]
 
+mod archive;
+mod bandwidth;
+pub mod builder;
+pub mod circuit_breaker;
 pub mod client;
+pub mod decompress;
+pub mod digest;
 pub mod errors;
+mod extract;
+pub mod layout;
 pub mod manifest;
+#[cfg(test)]
+mod mock;
 mod reference;
 mod regexp;
+pub mod retry;
 pub mod secrets;
+pub mod transport;
 
 #[doc(inline)]
 pub use client::Client;
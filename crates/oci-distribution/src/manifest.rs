@@ -7,6 +7,13 @@ pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+w
 pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v1+json";
 /// The mediatype for an OCI manifest.
 pub const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// The mediatype for a Docker v2 manifest list.
+pub const IMAGE_MANIFEST_LIST_MEDIA_TYPE: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+/// The mediatype for an OCI image manifest.
+pub const OCI_IMAGE_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// The mediatype for an OCI image index.
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
 /// The mediatype for an image config (manifest).
 pub const IMAGE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
 /// The mediatype that Docker uses for image configs.
@@ -21,6 +28,59 @@ pub const IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE: &str =
 /// The mediatype for a layer that is nondistributable and gzipped.
 pub const IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE: &str =
     "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+/// The mediatype for a layer that is compressed with zstd.
+pub const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+/// The mediatype for a layer that is nondistributable and compressed with zstd.
+pub const IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE: &str =
+    "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
+/// The mediatype that Docker uses for gzipped layers.
+pub const IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE: &str =
+    "application/vnd.docker.image.rootfs.diff.tar.gzip";
+/// The mediatype that Docker uses for uncompressed layers.
+pub const IMAGE_DOCKER_LAYER_MEDIA_TYPE: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+/// The layer mediatypes of container images, in both OCI and Docker form.
+pub const IMAGE_LAYER_MEDIA_TYPES: &[&str] = &[
+    IMAGE_LAYER_MEDIA_TYPE,
+    IMAGE_LAYER_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_ZSTD_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE,
+    IMAGE_LAYER_NONDISTRIBUTABLE_ZSTD_MEDIA_TYPE,
+    IMAGE_DOCKER_LAYER_MEDIA_TYPE,
+    IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+];
+/// The layer mediatypes of WASM modules.
+pub const WASM_LAYER_MEDIA_TYPES: &[&str] = &[WASM_LAYER_MEDIA_TYPE];
+
+/// The layer mediatypes to accept when pulling a container image.
+///
+/// The result can be passed straight to `Client::pull`, or extended first.
+pub fn default_image_layer_media_types() -> Vec<&'static str> {
+    IMAGE_LAYER_MEDIA_TYPES.to_vec()
+}
+
+/// The layer mediatypes to accept when pulling a WASM module.
+///
+/// The result can be passed straight to `Client::pull`, or extended first.
+pub fn wasm_layer_media_types() -> Vec<&'static str> {
+    WASM_LAYER_MEDIA_TYPES.to_vec()
+}
+
+/// The mediatype of the empty descriptor, used as the config of artifacts
+/// that have no configuration.
+pub const OCI_EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+
+/// The annotation key for the name of a reference within an image layout.
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+/// The annotation key for the human-readable title of a blob, such as the
+/// file name of a layer.
+pub const ANNOTATION_TITLE: &str = "org.opencontainers.image.title";
+
+/// The annotation BuildKit sets on image index entries that are not images,
+/// such as `attestation-manifest`
+const ANNOTATION_DOCKER_REFERENCE_TYPE: &str = "vnd.docker.reference.type";
 
 // TODO: Annotation key constants. https://github.com/opencontainers/image-spec/blob/master/annotations.md#pre-defined-annotation-keys
 
@@ -52,9 +112,16 @@ pub struct OciManifest {
     /// The OCI image layers
     ///
     /// The specification is unclear whether this is required. We have left it
-    /// required, assuming an empty vector can be used if necessary.
+    /// required, assuming an empty vector can be used if necessary. Artifact
+    /// manifests may leave it out, so a missing list parses as empty.
+    #[serde(default)]
     pub layers: Vec<OciDescriptor>,
 
+    /// The type of artifact this manifest describes, if it is an artifact
+    /// rather than an image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+
     /// The annotations for this manifest
     ///
     /// The specification says "If there are no annotations then this property
@@ -70,11 +137,130 @@ impl Default for OciManifest {
             media_type: None,
             config: OciDescriptor::default(),
             layers: vec![],
+            artifact_type: None,
+            annotations: None,
+        }
+    }
+}
+
+impl OciManifest {
+    /// Returns whether this manifest describes an artifact rather than an
+    /// image, either by declaring an artifact type or by having an empty
+    /// config
+    pub fn is_artifact(&self) -> bool {
+        self.artifact_type.is_some()
+            || crate::client::media_type_matches(&self.config.media_type, OCI_EMPTY_MEDIA_TYPE)
+    }
+}
+
+/// The OCI image index points to manifests for one or more images.
+///
+/// It is part of the OCI specification, and is defined here:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciImageIndex {
+    /// This is a schema version.
+    ///
+    /// The only version allowed by the specification is `2`.
+    pub schema_version: u8,
+
+    /// This is an optional media type describing this index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+
+    /// The manifests referenced by this index.
+    pub manifests: Vec<OciDescriptor>,
+
+    /// The annotations for this index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl Default for OciImageIndex {
+    fn default() -> Self {
+        OciImageIndex {
+            schema_version: 2,
+            media_type: None,
+            manifests: vec![],
             annotations: None,
         }
     }
 }
 
+impl OciImageIndex {
+    /// Returns the platforms this index has images for, in the order they
+    /// are listed
+    ///
+    /// Entries that are not images for a platform, such as the attestation
+    /// manifests BuildKit adds with an `unknown/unknown` platform, are left
+    /// out.
+    pub fn platforms(&self) -> Vec<&Platform> {
+        self.image_manifests()
+            .filter_map(|m| m.platform.as_ref())
+            .collect()
+    }
+
+    /// Returns the image manifests for an operating system, such as `linux`
+    pub fn manifests_for_os(&self, os: &str) -> Vec<&OciDescriptor> {
+        self.image_manifests()
+            .filter(|m| m.platform.as_ref().map_or(false, |p| p.os == os))
+            .collect()
+    }
+
+    /// Returns the image manifest that best matches a platform
+    ///
+    /// The operating system and architecture must be the same. A variant or
+    /// OS version is only compared when both the platform and the entry
+    /// give one, and entries with the same variant are preferred over
+    /// entries without one.
+    pub fn manifest_for_platform(&self, platform: &Platform) -> Option<&OciDescriptor> {
+        let candidates: Vec<&OciDescriptor> = self
+            .image_manifests()
+            .filter(|m| {
+                m.platform.as_ref().map_or(false, |p| {
+                    p.os == platform.os
+                        && p.architecture == platform.architecture
+                        && both_or_equal(&p.variant, &platform.variant)
+                        && both_or_equal(&p.os_version, &platform.os_version)
+                })
+            })
+            .collect();
+        candidates
+            .iter()
+            .find(|m| {
+                m.platform
+                    .as_ref()
+                    .map_or(false, |p| p.variant == platform.variant)
+            })
+            .or_else(|| candidates.first())
+            .copied()
+    }
+
+    fn image_manifests(&self) -> impl Iterator<Item = &OciDescriptor> {
+        self.manifests.iter().filter(|m| {
+            let attestation = m.annotations.as_ref().map_or(false, |a| {
+                a.get(ANNOTATION_DOCKER_REFERENCE_TYPE).map(String::as_str)
+                    == Some("attestation-manifest")
+            });
+            let unknown = m
+                .platform
+                .as_ref()
+                .map_or(false, |p| p.os == "unknown" || p.architecture == "unknown");
+            !attestation && !unknown
+        })
+    }
+}
+
+/// Whether two optional values agree, treating a missing value as matching
+/// anything
+fn both_or_equal(a: &Option<String>, b: &Option<String>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
 /// Versioned provides a struct with the manifest's schemaVersion and mediaType.
 /// Incoming content with unknown schema versions can be decoded against this
 /// struct to check the version.
@@ -127,6 +313,11 @@ pub struct OciDescriptor {
     /// This OPTIONAL property MUST use the annotation rules.
     /// https://github.com/opencontainers/image-spec/blob/master/annotations.md#rules
     pub annotations: Option<HashMap<String, String>>,
+
+    /// This OPTIONAL property describes the minimum runtime requirements of
+    /// the image. It is set on the manifests listed in an image index.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
 }
 
 impl Default for OciDescriptor {
@@ -137,10 +328,139 @@ impl Default for OciDescriptor {
             size: 0,
             urls: None,
             annotations: None,
+            platform: None,
         }
     }
 }
 
+/// The platform an image runs on.
+///
+/// It is defined in the OCI Image Specification:
+/// https://github.com/opencontainers/image-spec/blob/master/image-index.md#image-index-property-descriptions
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Platform {
+    /// The CPU architecture, using the values of Go's `GOARCH`, such as
+    /// `amd64` or `arm64`.
+    pub architecture: String,
+    /// The operating system, using the values of Go's `GOOS`, such as
+    /// `linux` or `windows`.
+    pub os: String,
+    /// The version of the operating system, such as `10.0.14393.1066` on
+    /// Windows.
+    #[serde(
+        rename = "os.version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub os_version: Option<String>,
+    /// Features the operating system must support, such as `win32k` on
+    /// Windows.
+    #[serde(
+        rename = "os.features",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub os_features: Option<Vec<String>>,
+    /// The variant of the CPU, such as `v7` or `v8` for ARM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// Features the CPU must support. The specification reserves this
+    /// property for future use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+}
+
+impl Platform {
+    /// Returns the platform of the running host.
+    pub fn current() -> Self {
+        Self::from_rust_target(std::env::consts::OS, std::env::consts::ARCH)
+    }
+
+    /// Maps Rust's target OS and architecture names, as found in
+    /// `std::env::consts`, to their OCI equivalents.
+    fn from_rust_target(os: &str, arch: &str) -> Self {
+        let os = match os {
+            "macos" => "darwin",
+            other => other,
+        };
+        let little_endian = cfg!(target_endian = "little");
+        let (architecture, variant) = match arch {
+            "x86_64" => ("amd64", None),
+            "x86" => ("386", None),
+            "aarch64" => ("arm64", Some("v8")),
+            "arm" => ("arm", Some("v7")),
+            "powerpc64" if little_endian => ("ppc64le", None),
+            "powerpc64" => ("ppc64", None),
+            "mips64" if little_endian => ("mips64le", None),
+            "mips" if little_endian => ("mipsle", None),
+            other => (other, None),
+        };
+        Platform {
+            architecture: architecture.to_owned(),
+            os: os.to_owned(),
+            os_version: None,
+            os_features: None,
+            variant: variant.map(|v| v.to_owned()),
+            features: None,
+        }
+    }
+}
+
+/// The OCI image configuration, which describes how to run an image and
+/// the filesystem its layers build up.
+///
+/// It is part of the OCI specification, and is defined here:
+/// https://github.com/opencontainers/image-spec/blob/master/config.md
+///
+/// Only the properties this client generates are modelled. Execution
+/// parameters such as the entrypoint are left to the runtime.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct OciImageConfig {
+    /// When the image was created, as an RFC 3339 timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The CPU architecture the image's binaries are built for
+    pub architecture: String,
+    /// The operating system the image is built to run on
+    pub os: String,
+    /// The layers of the image's root filesystem
+    pub rootfs: RootFs,
+    /// How each layer was created, in the order the layers are applied
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<History>,
+}
+
+/// The layers that make up an image's root filesystem
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct RootFs {
+    /// Always `layers`
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    /// The digests of the *uncompressed* layer tarballs, in order.
+    ///
+    /// These differ from the layer digests in the manifest when the layers
+    /// are compressed.
+    pub diff_ids: Vec<String>,
+}
+
+/// How a layer of an image was created
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct History {
+    /// When the layer was created, as an RFC 3339 timestamp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The command that created the layer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// A comment on the layer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Whether this entry created no layer, such as a change to the
+    /// config only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub empty_layer: Option<bool>,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -165,6 +485,169 @@ mod test {
     }
     "#;
 
+    #[test]
+    fn platform_maps_rust_target_names() {
+        let arm64 = Platform::from_rust_target("linux", "aarch64");
+        assert_eq!("arm64", arm64.architecture);
+        assert_eq!(Some("v8".to_owned()), arm64.variant);
+        assert_eq!("linux", arm64.os);
+
+        let arm = Platform::from_rust_target("linux", "arm");
+        assert_eq!("arm", arm.architecture);
+        assert_eq!(Some("v7".to_owned()), arm.variant);
+
+        let amd64 = Platform::from_rust_target("macos", "x86_64");
+        assert_eq!("amd64", amd64.architecture);
+        assert_eq!(None, amd64.variant);
+        assert_eq!("darwin", amd64.os);
+
+        assert_eq!(
+            "386",
+            Platform::from_rust_target("windows", "x86").architecture
+        );
+        assert_eq!(
+            Platform::from_rust_target(std::env::consts::OS, std::env::consts::ARCH),
+            Platform::current()
+        );
+    }
+
+    #[test]
+    fn platform_roundtrips_through_descriptor_json() {
+        let descriptor: OciDescriptor = serde_json::from_str(
+            r#"{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 7143,
+                "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+                "platform": {
+                    "architecture": "arm64",
+                    "os": "linux",
+                    "variant": "v8",
+                    "os.features": ["sse4"]
+                }
+            }"#,
+        )
+        .expect("parsed descriptor");
+        let platform = descriptor.platform.expect("platform is present");
+        assert_eq!("arm64", platform.architecture);
+        assert_eq!(Some("v8".to_owned()), platform.variant);
+        assert_eq!(Some(vec!["sse4".to_owned()]), platform.os_features);
+
+        let json = serde_json::to_value(&platform).expect("serialized platform");
+        assert_eq!("sse4", json["os.features"][0]);
+        assert!(json.get("os.version").is_none());
+    }
+
+    const TEST_INDEX: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.index.v1+json",
+        "manifests": [
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1,
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000001",
+                "platform": { "architecture": "amd64", "os": "linux" }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1,
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000002",
+                "platform": { "architecture": "arm", "os": "linux", "variant": "v6" }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1,
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000003",
+                "platform": { "architecture": "arm", "os": "linux", "variant": "v7" }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1,
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000004",
+                "platform": { "architecture": "arm64", "os": "linux" }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1,
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000005",
+                "platform": { "architecture": "amd64", "os": "windows", "os.version": "10.0.17763.1879" }
+            },
+            {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 1,
+                "digest": "sha256:0000000000000000000000000000000000000000000000000000000000000006",
+                "annotations": {
+                    "vnd.docker.reference.digest": "sha256:0000000000000000000000000000000000000000000000000000000000000001",
+                    "vnd.docker.reference.type": "attestation-manifest"
+                },
+                "platform": { "architecture": "unknown", "os": "unknown" }
+            }
+        ]
+    }"#;
+
+    fn platform(os: &str, architecture: &str, variant: Option<&str>) -> Platform {
+        Platform {
+            architecture: architecture.to_owned(),
+            os: os.to_owned(),
+            os_version: None,
+            os_features: None,
+            variant: variant.map(|v| v.to_owned()),
+            features: None,
+        }
+    }
+
+    fn digest_suffix(descriptor: Option<&OciDescriptor>) -> Option<char> {
+        descriptor.and_then(|d| d.digest.chars().last())
+    }
+
+    #[test]
+    fn index_lists_platforms_without_attestations() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        let platforms: Vec<String> = index
+            .platforms()
+            .iter()
+            .map(|p| format!("{}/{}", p.os, p.architecture))
+            .collect();
+        assert_eq!(
+            vec![
+                "linux/amd64",
+                "linux/arm",
+                "linux/arm",
+                "linux/arm64",
+                "windows/amd64"
+            ],
+            platforms
+        );
+        assert_eq!(4, index.manifests_for_os("linux").len());
+        assert_eq!(1, index.manifests_for_os("windows").len());
+        assert!(index.manifests_for_os("unknown").is_empty());
+    }
+
+    #[test]
+    fn index_finds_the_manifest_for_a_platform() {
+        let index: OciImageIndex = serde_json::from_str(TEST_INDEX).expect("parsed index");
+        let find = |p: Platform| digest_suffix(index.manifest_for_platform(&p));
+
+        assert_eq!(Some('1'), find(platform("linux", "amd64", None)));
+        assert_eq!(Some('3'), find(platform("linux", "arm", Some("v7"))));
+        assert_eq!(Some('2'), find(platform("linux", "arm", Some("v6"))));
+        // Without a variant, the first entry for the architecture wins
+        assert_eq!(Some('2'), find(platform("linux", "arm", None)));
+        // An entry without a variant matches a platform with one
+        assert_eq!(Some('4'), find(platform("linux", "arm64", Some("v8"))));
+        assert_eq!(Some('5'), find(platform("windows", "amd64", None)));
+        assert_eq!(None, find(platform("linux", "s390x", None)));
+        assert_eq!(None, find(platform("unknown", "unknown", None)));
+    }
+
+    #[test]
+    fn index_omits_unset_optional_fields() {
+        let json = serde_json::to_value(OciImageIndex::default()).expect("serialized index");
+        let fields = json.as_object().expect("index object");
+        assert!(!fields.contains_key("mediaType"));
+        assert!(!fields.contains_key("annotations"));
+        assert_eq!(Some(2), fields["schemaVersion"].as_u64());
+    }
+
     #[test]
     fn test_manifest() {
         let manifest: OciManifest = serde_json::from_str(TEST_MANIFEST).expect("parsed manifest");
@@ -195,4 +678,13 @@ mod test {
                 .len()
         );
     }
+
+    #[test]
+    fn default_media_type_sets_cover_their_layers() {
+        let image_types = default_image_layer_media_types();
+        assert!(image_types.contains(&IMAGE_LAYER_GZIP_MEDIA_TYPE));
+        assert!(image_types.contains(&IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE));
+        assert!(!image_types.contains(&WASM_LAYER_MEDIA_TYPE));
+        assert_eq!(vec![WASM_LAYER_MEDIA_TYPE], wasm_layer_media_types());
+    }
 }
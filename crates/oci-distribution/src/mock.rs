@@ -0,0 +1,522 @@
+//! An in-process registry for testing the client without a network
+//!
+//! `MockRegistry` implements the pull and push endpoints of the distribution
+//! API over in-memory storage, for any host name. Tests can also queue
+//! canned responses to simulate failures, and inspect the requests the
+//! client made.
+
+use crate::client::{Client, ClientConfig};
+use crate::digest::Digest;
+use crate::transport::Transport;
+
+use futures_util::stream::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// A canned response, returned instead of routing the next request
+pub(crate) struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    stalls: bool,
+}
+
+impl MockResponse {
+    pub(crate) fn new(status: u16) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            stalls: false,
+        }
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub(crate) fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends the body and then hangs without ending it, like a stalled
+    /// connection
+    pub(crate) fn stall(mut self) -> Self {
+        self.stalls = true;
+        self
+    }
+
+    fn json(status: u16, body: &serde_json::Value) -> Self {
+        MockResponse::new(status)
+            .header("Content-Type", "application/json")
+            .body(body.to_string())
+    }
+
+    fn error(status: u16, code: &str, message: &str) -> Self {
+        MockResponse::json(
+            status,
+            &serde_json::json!({ "errors": [{ "code": code, "message": message }] }),
+        )
+    }
+
+    fn into_response(self) -> reqwest::Response {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let body = if self.stalls {
+            let chunks = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(self.body)]);
+            reqwest::Body::wrap_stream(chunks.chain(futures_util::stream::pending()))
+        } else {
+            reqwest::Body::from(self.body)
+        };
+        builder
+            .body(body)
+            .expect("mock response should be valid")
+            .into()
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    blobs: HashMap<String, Vec<u8>>,
+    stalled_blobs: HashSet<String>,
+    // Keyed by repository and then by tag or digest
+    manifests: HashMap<(String, String), (String, Vec<u8>)>,
+    uploads: HashMap<String, Vec<u8>>,
+    next_upload: u64,
+    queued: VecDeque<MockResponse>,
+    // Keyed by `METHOD /path`, without the query string
+    queued_for: HashMap<String, VecDeque<MockResponse>>,
+    requests: Vec<String>,
+    authorizations: Vec<Option<String>>,
+}
+
+/// An in-memory registry that a `Client` can send its requests to
+#[derive(Default)]
+pub(crate) struct MockRegistry {
+    state: Mutex<MockState>,
+}
+
+impl MockRegistry {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(MockRegistry::default())
+    }
+
+    /// A client with the default configuration that talks to this registry
+    pub(crate) fn client(self: &Arc<Self>) -> Client {
+        self.client_with_config(ClientConfig::default())
+    }
+
+    /// A client with the given configuration that talks to this registry
+    pub(crate) fn client_with_config(self: &Arc<Self>, config: ClientConfig) -> Client {
+        Client::with_transport(config, self.clone())
+    }
+
+    /// Stores a blob, returning its digest
+    pub(crate) fn add_blob(&self, data: &[u8]) -> String {
+        let digest = crate::client::sha256_digest(data);
+        self.state
+            .lock()
+            .unwrap()
+            .blobs
+            .insert(digest.clone(), data.to_vec());
+        digest
+    }
+
+    /// Stores a manifest under a tag and under its digest, returning the
+    /// digest
+    pub(crate) fn add_manifest(
+        &self,
+        repository: &str,
+        tag: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> String {
+        let digest = crate::client::sha256_digest(data);
+        let mut state = self.state.lock().unwrap();
+        for reference in &[tag, digest.as_str()] {
+            state.manifests.insert(
+                (repository.to_owned(), (*reference).to_owned()),
+                (media_type.to_owned(), data.to_vec()),
+            );
+        }
+        digest
+    }
+
+    /// Makes GETs of the blob with the given digest send its data and then
+    /// hang without ending the body, like a stalled connection
+    pub(crate) fn stall_blob(&self, digest: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .stalled_blobs
+            .insert(digest.to_owned());
+    }
+
+    /// The stored blob with the given digest
+    pub(crate) fn blob(&self, digest: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().blobs.get(digest).cloned()
+    }
+
+    /// The stored manifest for a tag or digest
+    pub(crate) fn manifest(&self, repository: &str, reference: &str) -> Option<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .manifests
+            .get(&(repository.to_owned(), reference.to_owned()))
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Returns `response` for the next request instead of handling it.
+    /// Queued responses are returned in order.
+    pub(crate) fn enqueue(&self, response: MockResponse) {
+        self.state.lock().unwrap().queued.push_back(response);
+    }
+
+    /// Returns `response` for the next request matching `request`, given as
+    /// `METHOD /path` without the query string, instead of handling it.
+    /// Responses queued for the same request are returned in order.
+    pub(crate) fn enqueue_for(&self, request: &str, response: MockResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .queued_for
+            .entry(request.to_owned())
+            .or_default()
+            .push_back(response);
+    }
+
+    /// The requests received so far, as `METHOD /path`, followed by the
+    /// query string if there is one
+    pub(crate) fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The `Authorization` header of each request received so far, in the
+    /// same order as `requests`
+    pub(crate) fn authorizations(&self) -> Vec<Option<String>> {
+        self.state.lock().unwrap().authorizations.clone()
+    }
+
+    fn handle(&self, request: &reqwest::Request) -> MockResponse {
+        let mut state = self.state.lock().unwrap();
+        let method = request.method().as_str().to_owned();
+        let path = request.url().path().to_owned();
+        match request.url().query() {
+            Some(query) => state
+                .requests
+                .push(format!("{} {}?{}", method, path, query)),
+            None => state.requests.push(format!("{} {}", method, path)),
+        }
+        let authorization = request
+            .headers()
+            .get(reqwest::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned());
+        state.authorizations.push(authorization);
+        if let Some(response) = state.queued.pop_front() {
+            return response;
+        }
+        let line = format!("{} {}", method, path);
+        if let Some(response) = state
+            .queued_for
+            .get_mut(&line)
+            .and_then(|queued| queued.pop_front())
+        {
+            return response;
+        }
+
+        let query: HashMap<String, String> = request.url().query_pairs().into_owned().collect();
+        let body = request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .unwrap_or_default()
+            .to_vec();
+        if !path.starts_with("/v2/") {
+            return MockResponse::new(404);
+        }
+        let rest = &path["/v2/".len()..];
+        if rest.is_empty() {
+            return MockResponse::json(200, &serde_json::json!({}));
+        }
+
+        if let Some(i) = rest.find("/manifests/") {
+            let repository = &rest[..i];
+            let reference = &rest[i + "/manifests/".len()..];
+            return state.manifest_request(&method, repository, reference, request, body);
+        }
+        if rest == "_catalog" {
+            return state.catalog_request(&query);
+        }
+        if rest.ends_with("/tags/list") {
+            let repository = &rest[..rest.len() - "/tags/list".len()];
+            return state.tags_request(repository, &query);
+        }
+        if let Some(i) = rest.find("/blobs/uploads") {
+            let repository = &rest[..i];
+            let id = rest[i + "/blobs/uploads".len()..].trim_start_matches('/');
+            return state.upload_request(&method, repository, id, &query, body);
+        }
+        if let Some(i) = rest.find("/blobs/") {
+            let digest = &rest[i + "/blobs/".len()..];
+            if method == "DELETE" {
+                return match state.blobs.remove(digest) {
+                    Some(_) => MockResponse::new(202),
+                    None => MockResponse::error(404, "BLOB_UNKNOWN", "blob unknown to registry"),
+                };
+            }
+            let range_start = request
+                .headers()
+                .get("Range")
+                .and_then(|r| r.to_str().ok())
+                .and_then(|r| {
+                    r.trim_start_matches("bytes=")
+                        .trim_end_matches('-')
+                        .parse::<usize>()
+                        .ok()
+                });
+            return match (state.blobs.get(digest), range_start) {
+                (Some(data), Some(start)) if method == "GET" => {
+                    if start >= data.len() {
+                        return MockResponse::new(416);
+                    }
+                    MockResponse::new(206)
+                        .header(
+                            "Content-Range",
+                            &format!("bytes {}-{}/{}", start, data.len() - 1, data.len()),
+                        )
+                        .body(data[start..].to_vec())
+                }
+                (Some(data), _) => {
+                    let response = MockResponse::new(200)
+                        .header("Content-Length", &data.len().to_string())
+                        .header("Docker-Content-Digest", digest);
+                    if method == "HEAD" {
+                        response
+                    } else if state.stalled_blobs.contains(digest) {
+                        response.body(data.clone()).stall()
+                    } else {
+                        response.body(data.clone())
+                    }
+                }
+                (None, _) => MockResponse::error(404, "BLOB_UNKNOWN", "blob unknown to registry"),
+            };
+        }
+        MockResponse::new(404)
+    }
+}
+
+impl MockState {
+    fn manifest_request(
+        &mut self,
+        method: &str,
+        repository: &str,
+        reference: &str,
+        request: &reqwest::Request,
+        body: Vec<u8>,
+    ) -> MockResponse {
+        let key = (repository.to_owned(), reference.to_owned());
+        match method {
+            "GET" | "HEAD" => match self.manifests.get(&key) {
+                Some((media_type, data)) => {
+                    let response = MockResponse::new(200)
+                        .header("Content-Type", media_type)
+                        .header("Content-Length", &data.len().to_string())
+                        .header("Docker-Content-Digest", &crate::client::sha256_digest(data));
+                    if method == "HEAD" {
+                        response
+                    } else {
+                        response.body(data.clone())
+                    }
+                }
+                None => MockResponse::error(404, "MANIFEST_UNKNOWN", "manifest unknown"),
+            },
+            "PUT" => {
+                let media_type = request
+                    .headers()
+                    .get("Content-Type")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned();
+                let digest = crate::client::sha256_digest(&body);
+                for reference in &[reference, digest.as_str()] {
+                    self.manifests.insert(
+                        (repository.to_owned(), (*reference).to_owned()),
+                        (media_type.clone(), body.clone()),
+                    );
+                }
+                MockResponse::new(201)
+                    .header(
+                        "Location",
+                        &format!("/v2/{}/manifests/{}", repository, digest),
+                    )
+                    .header("Docker-Content-Digest", &digest)
+            }
+            "DELETE" => match self.manifests.get(&key) {
+                Some((_, data)) => {
+                    // Remove the manifest under its digest and every tag
+                    let data = data.clone();
+                    self.manifests
+                        .retain(|(repo, _), (_, d)| repo != repository || *d != data);
+                    MockResponse::new(202)
+                }
+                None => MockResponse::error(404, "MANIFEST_UNKNOWN", "manifest unknown"),
+            },
+            _ => MockResponse::new(405),
+        }
+    }
+
+    /// Lists the tags of a repository, `n` at a time, with a `Link` to the
+    /// next page
+    fn tags_request(&self, repository: &str, query: &HashMap<String, String>) -> MockResponse {
+        let tags: Vec<&String> = self
+            .manifests
+            .keys()
+            .filter(|(repo, reference)| repo == repository && !reference.contains(':'))
+            .map(|(_, tag)| tag)
+            .collect();
+        if tags.is_empty() {
+            return MockResponse::error(404, "NAME_UNKNOWN", "repository name not known");
+        }
+        let path = format!("/v2/{}/tags/list", repository);
+        let (tags, link) = paginate(tags, query, &path);
+        let response = MockResponse::json(
+            200,
+            &serde_json::json!({ "name": repository, "tags": tags }),
+        );
+        match link {
+            Some(link) => response.header("Link", &link),
+            None => response,
+        }
+    }
+
+    /// Lists the repositories holding manifests, like `tags_request`
+    fn catalog_request(&self, query: &HashMap<String, String>) -> MockResponse {
+        let mut repositories: Vec<&String> = self.manifests.keys().map(|(repo, _)| repo).collect();
+        repositories.sort();
+        repositories.dedup();
+        let (repositories, link) = paginate(repositories, query, "/v2/_catalog");
+        let response =
+            MockResponse::json(200, &serde_json::json!({ "repositories": repositories }));
+        match link {
+            Some(link) => response.header("Link", &link),
+            None => response,
+        }
+    }
+
+    fn upload_request(
+        &mut self,
+        method: &str,
+        repository: &str,
+        id: &str,
+        query: &HashMap<String, String>,
+        mut body: Vec<u8>,
+    ) -> MockResponse {
+        match method {
+            "POST" => {
+                if let Some(digest) = query.get("digest") {
+                    return self.complete_upload(repository, digest, body);
+                }
+                if let Some(digest) = query.get("mount") {
+                    if self.blobs.contains_key(digest) {
+                        return MockResponse::new(201)
+                            .header("Location", &format!("/v2/{}/blobs/{}", repository, digest));
+                    }
+                }
+                let id = self.next_upload.to_string();
+                self.next_upload += 1;
+                self.uploads.insert(id.clone(), body);
+                self.upload_progress(202, repository, &id)
+            }
+            "PATCH" => match self.uploads.get_mut(id) {
+                Some(data) => {
+                    data.append(&mut body);
+                    self.upload_progress(202, repository, id)
+                }
+                None => MockResponse::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown"),
+            },
+            "GET" => match self.uploads.get(id) {
+                Some(_) => self.upload_progress(204, repository, id),
+                None => MockResponse::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown"),
+            },
+            "PUT" => match (self.uploads.remove(id), query.get("digest")) {
+                (Some(mut data), Some(digest)) => {
+                    data.append(&mut body);
+                    self.complete_upload(repository, digest, data)
+                }
+                (None, _) => MockResponse::error(404, "BLOB_UPLOAD_UNKNOWN", "upload unknown"),
+                (Some(_), None) => {
+                    MockResponse::error(400, "DIGEST_INVALID", "digest parameter missing")
+                }
+            },
+            _ => MockResponse::new(405),
+        }
+    }
+
+    fn upload_progress(&self, status: u16, repository: &str, id: &str) -> MockResponse {
+        let len = self.uploads.get(id).map_or(0, |d| d.len());
+        // Like most registries, the location carries the session state in
+        // its query
+        let response = MockResponse::new(status).header(
+            "Location",
+            &format!("/v2/{}/blobs/uploads/{}?_state={}", repository, id, id),
+        );
+        if len == 0 {
+            response
+        } else {
+            response.header("Range", &format!("0-{}", len - 1))
+        }
+    }
+
+    fn complete_upload(&mut self, repository: &str, digest: &str, data: Vec<u8>) -> MockResponse {
+        match digest.parse::<Digest>() {
+            Ok(parsed) if parsed.verify(&data) => {
+                self.blobs.insert(digest.to_owned(), data);
+                MockResponse::new(201)
+                    .header("Location", &format!("/v2/{}/blobs/{}", repository, digest))
+                    .header("Docker-Content-Digest", digest)
+            }
+            _ => MockResponse::error(
+                400,
+                "DIGEST_INVALID",
+                "provided digest did not match uploaded content",
+            ),
+        }
+    }
+}
+
+/// Sorts a listing and returns the page that the `n` and `last` query
+/// parameters ask for, and the `Link` header value for the next page if there
+/// is one
+fn paginate<'a>(
+    mut items: Vec<&'a String>,
+    query: &HashMap<String, String>,
+    path: &str,
+) -> (Vec<&'a String>, Option<String>) {
+    items.sort();
+    if let Some(last) = query.get("last") {
+        items.retain(|item| *item > last);
+    }
+    let n = query
+        .get("n")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(items.len());
+    let more = items.len() > n;
+    items.truncate(n);
+    let link = match items.last() {
+        Some(last) if more => Some(format!("<{}?n={}&last={}>; rel=\"next\"", path, n, last)),
+        _ => None,
+    };
+    (items, link)
+}
+
+#[async_trait::async_trait]
+impl Transport for MockRegistry {
+    async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+        Ok(self.handle(&request).into_response())
+    }
+}
@@ -5,14 +5,25 @@ use std::str::FromStr;
 
 use crate::regexp;
 
-/// NAME_TOTAL_LENGTH_MAX is the maximum total number of characters in a repository name.
+/// NAME_TOTAL_LENGTH_MAX is the maximum total number of characters in a repository name,
+/// including the registry.
 const NAME_TOTAL_LENGTH_MAX: usize = 255;
 
+/// The registry that references without one refer to
+const DEFAULT_REGISTRY: &str = "docker.io";
+/// Other names Docker Hub goes by
+const DEFAULT_REGISTRY_ALIASES: &[&str] = &["index.docker.io", "registry-1.docker.io"];
+/// The namespace of single-component repositories on Docker Hub
+const DEFAULT_NAMESPACE: &str = "library";
+/// The tag that references without a tag or digest refer to
+const DEFAULT_TAG: &str = "latest";
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseError {
     DigestInvalidFormat,
     DigestInvalidLength,
     DigestUnsupported,
+    NameComponentInvalid(String),
     NameContainsUppercase,
     NameEmpty,
     NameTooLong,
@@ -26,6 +37,11 @@ impl fmt::Display for ParseError {
             ParseError::DigestInvalidFormat => write!(f, "invalid checksum digest format"),
             ParseError::DigestInvalidLength => write!(f, "invalid checksum digest length"),
             ParseError::DigestUnsupported => write!(f, "unsupported digest algorithm"),
+            ParseError::NameComponentInvalid(component) => write!(
+                f,
+                "invalid repository name component {:?}: components must be lowercase letters and digits, separated by '.', '_', '__' or dashes",
+                component
+            ),
             ParseError::NameContainsUppercase => write!(f, "repository name must be lowercase"),
             ParseError::NameEmpty => write!(f, "repository name must have at least one component"),
             ParseError::NameTooLong => write!(
@@ -87,6 +103,26 @@ impl Reference {
         self.digest.as_deref()
     }
 
+    /// A reference to a registry as a whole, for requests that concern no
+    /// single repository, such as listing the repositories
+    pub(crate) fn registry_root(registry: &str) -> Self {
+        Reference {
+            registry: registry.to_owned(),
+            repository: String::new(),
+            tag: None,
+            digest: None,
+        }
+    }
+
+    /// The same repository, tag and digest on another registry, such as a
+    /// mirror
+    pub(crate) fn with_registry(&self, registry: &str) -> Self {
+        Reference {
+            registry: registry.to_owned(),
+            ..self.clone()
+        }
+    }
+
     /// full_name returns the full repository name and path.
     fn full_name(&self) -> String {
         if self.registry() == "" {
@@ -113,6 +149,44 @@ impl Reference {
         }
         s
     }
+
+    /// canonical returns the fully qualified form of the reference, pinned
+    /// to its digest if it has one.
+    ///
+    /// Docker's defaults are filled in, so that `busybox`,
+    /// `library/busybox:latest` and `docker.io/library/busybox` all give
+    /// `docker.io/library/busybox:latest`. A reference with a digest gives
+    /// `registry/repository@digest`, leaving out any tag, since the digest
+    /// alone identifies the content.
+    pub fn canonical(&self) -> String {
+        // The parser takes the first of several components as the registry,
+        // but Docker only treats it as one if it looks like a host name
+        let (registry, repository) = if self.registry.is_empty() {
+            (DEFAULT_REGISTRY.to_owned(), self.repository.clone())
+        } else if !is_registry_host(&self.registry) {
+            (DEFAULT_REGISTRY.to_owned(), self.full_name())
+        } else if DEFAULT_REGISTRY_ALIASES.contains(&self.registry.as_str()) {
+            (DEFAULT_REGISTRY.to_owned(), self.repository.clone())
+        } else {
+            (self.registry.clone(), self.repository.clone())
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("{}/{}", DEFAULT_NAMESPACE, repository)
+        } else {
+            repository
+        };
+        match (self.digest(), self.tag()) {
+            (Some(digest), _) => format!("{}/{}@{}", registry, repository, digest),
+            (None, tag) => format!("{}/{}:{}", registry, repository, tag.unwrap_or(DEFAULT_TAG)),
+        }
+    }
+}
+
+/// Whether the first component of a name is a registry host rather than
+/// part of a Docker Hub repository name, following Docker's rule that hosts
+/// contain a `.` or a port, or are `localhost`
+fn is_registry_host(component: &str) -> bool {
+    component.contains('.') || component.contains(':') || component == "localhost"
 }
 
 impl std::fmt::Debug for Reference {
@@ -149,10 +223,13 @@ impl TryFrom<String> for Reference {
         match RE.captures(&s) {
             Some(caps) => captures = caps,
             None => {
-                return Err(ParseError::ReferenceInvalidFormat);
+                return Err(invalid_reference_error(&s, &RE));
             }
         }
         let name = &captures[1];
+        if name.len() > NAME_TOTAL_LENGTH_MAX {
+            return Err(ParseError::NameTooLong);
+        }
         let tag = captures.get(2).map(|m| m.as_str().to_owned());
         let digest = captures.get(3).map(|m| m.as_str().to_owned());
         let (registry, repository) = split_domain(name);
@@ -162,9 +239,6 @@ impl TryFrom<String> for Reference {
             tag,
             digest,
         };
-        if reference.repository().len() > NAME_TOTAL_LENGTH_MAX {
-            return Err(ParseError::NameTooLong);
-        }
         // Digests much always be hex-encoded, ensuring that their hex portion will always be
         // size*2
         if reference.digest().is_some() {
@@ -213,6 +287,35 @@ impl Into<String> for Reference {
     }
 }
 
+/// Works out why a reference does not match the reference grammar, so that
+/// the error says what to fix rather than only that the format is invalid
+fn invalid_reference_error(s: &str, reference_re: &regex::Regex) -> ParseError {
+    lazy_static! {
+        static ref COMPONENT_RE: regex::Regex =
+            regexp::must_compile(regexp::ANCHORED_NAME_COMPONENT_REGEXP);
+    };
+    if reference_re.is_match(&s.to_lowercase()) {
+        return ParseError::NameContainsUppercase;
+    }
+    // Drop the digest and tag, leaving the name
+    let name = s.splitn(2, '@').next().unwrap_or_default();
+    let name = match name.rfind(':') {
+        Some(i) if !name[i..].contains('/') => &name[..i],
+        _ => name,
+    };
+    // As in `split_domain`, the first of several components is the registry
+    let mut components = name.split('/');
+    if name.contains('/') {
+        components.next();
+    }
+    for component in components {
+        if !COMPONENT_RE.is_match(component) {
+            return ParseError::NameComponentInvalid(component.to_owned());
+        }
+    }
+    ParseError::ReferenceInvalidFormat
+}
+
 fn split_domain(name: &str) -> (String, String) {
     lazy_static! {
         static ref RE: regex::Regex = regexp::must_compile(regexp::ANCHORED_NAME_REGEXP);
@@ -282,18 +385,62 @@ mod test {
             case("@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", ParseError::ReferenceInvalidFormat),
             case("repo@sha256:ffffffffffffffffffffffffffffffffff", ParseError::DigestInvalidLength),
             case("validname@invaliddigest:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", ParseError::DigestUnsupported),
-            // FIXME: should really pass a ParseError::NameContainsUppercase, but "invalid format" is good enough for now.
-            case("Uppercase:tag", ParseError::ReferenceInvalidFormat),
+            case("Uppercase:tag", ParseError::NameContainsUppercase),
             // FIXME: "Uppercase" is incorrectly handled as a domain-name here, and therefore passes.
             // https://github.com/docker/distribution/blob/master/reference/reference_test.go#L104-L109
             // case("Uppercase/lowercase:tag", ParseError::NameContainsUppercase),
-            // FIXME: should really pass a ParseError::NameContainsUppercase, but "invalid format" is good enough for now.
-            case("test:5000/Uppercase/lowercase:tag", ParseError::ReferenceInvalidFormat),
+            case("test:5000/Uppercase/lowercase:tag", ParseError::NameContainsUppercase),
             case("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", ParseError::NameTooLong),
-            case("aa/asdf$$^/aa", ParseError::ReferenceInvalidFormat)
+            case("aa/asdf$$^/aa", ParseError::NameComponentInvalid("asdf$$^".to_owned())),
+            case("registry.example.com/repo_/name", ParseError::NameComponentInvalid("repo_".to_owned())),
+            case("registry.example.com/repo/-name:tag", ParseError::NameComponentInvalid("-name".to_owned())),
+            case("registry.example.com/repo___name", ParseError::NameComponentInvalid("repo___name".to_owned())),
+            case("registry.example.com/repo..name", ParseError::NameComponentInvalid("repo..name".to_owned())),
+            case("registry.example.com//name", ParseError::NameComponentInvalid("".to_owned())),
+            case("registry.example.com/repo/name/", ParseError::NameComponentInvalid("".to_owned())),
+            case("registry.example.com/répo", ParseError::NameComponentInvalid("répo".to_owned())),
+            // The length limit covers the registry as well as the repository
+            case(&format!("registry.example.com/{}", "a".repeat(240)), ParseError::NameTooLong),
+            case("registry.example.com/repo:-tag", ParseError::ReferenceInvalidFormat)
         )]
         fn parse_bad_reference(input: &str, err: ParseError) {
             assert_eq!(Reference::try_from(input).unwrap_err(), err)
         }
     }
+
+    mod canonical {
+        use super::*;
+        use rstest::rstest;
+
+        #[rstest(input, canonical,
+            case("busybox", "docker.io/library/busybox:latest"),
+            case("busybox:latest", "docker.io/library/busybox:latest"),
+            case("library/busybox", "docker.io/library/busybox:latest"),
+            case("docker.io/busybox", "docker.io/library/busybox:latest"),
+            case("docker.io/library/busybox:latest", "docker.io/library/busybox:latest"),
+            case("index.docker.io/library/busybox", "docker.io/library/busybox:latest"),
+            case("registry-1.docker.io/busybox:latest", "docker.io/library/busybox:latest"),
+            case("deislabs/hello:v1", "docker.io/deislabs/hello:v1"),
+            case("docker.io/deislabs/hello:v1", "docker.io/deislabs/hello:v1"),
+            case("localhost/hello", "localhost/hello:latest"),
+            case("localhost:5000/hello:v1", "localhost:5000/hello:v1"),
+            case("webassembly.azurecr.io/hello-wasm:v1", "webassembly.azurecr.io/hello-wasm:v1"),
+            case("webassembly.azurecr.io/hello-wasm@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7", "webassembly.azurecr.io/hello-wasm@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7"),
+            case("webassembly.azurecr.io/hello-wasm:v1@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7", "webassembly.azurecr.io/hello-wasm@sha256:51d9b231d5129e3ffc267c9d455c49d789bf3167b611a07ab6e4b3304c96b0e7"),
+            case("busybox@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff", "docker.io/library/busybox@sha256:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+        )]
+        fn canonical_form(input: &str, canonical: &str) {
+            let reference = Reference::try_from(input).expect("could not parse reference");
+            assert_eq!(canonical, reference.canonical());
+        }
+
+        #[test]
+        fn canonical_form_parses_back_to_itself() {
+            let reference = Reference::try_from("busybox").expect("could not parse reference");
+            let canonical = Reference::try_from(reference.canonical())
+                .expect("could not parse canonical reference");
+            assert_eq!(reference.canonical(), canonical.canonical());
+            assert_eq!(reference.canonical(), canonical.whole());
+        }
+    }
 }
@@ -8,6 +8,11 @@ pub const REFERENCE_REGEXP: &str = r"^((?:(?:[a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]
 /// trailing components.
 pub const ANCHORED_NAME_REGEXP: &str = r"^(?:((?:[a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9])(?:(?:\.(?:[a-zA-Z0-9]|[a-zA-Z0-9][a-zA-Z0-9-]*[a-zA-Z0-9]))+)?(?::[0-9]+)?)/)?([a-z0-9]+(?:(?:(?:[._]|__|[-]*)[a-z0-9]+)+)?(?:(?:/[a-z0-9]+(?:(?:(?:[._]|__|[-]*)[a-z0-9]+)+)?)+)?)$";
 
+/// ANCHORED_NAME_COMPONENT_REGEXP matches a single path component of a
+/// repository name: lowercase alphanumerics, separated by a period, one or two
+/// underscores, or any number of dashes.
+pub const ANCHORED_NAME_COMPONENT_REGEXP: &str = r"^[a-z0-9]+(?:(?:[._]|__|[-]*)[a-z0-9]+)*$";
+
 pub fn must_compile(r: &str) -> Regex {
     RegexBuilder::new(r)
         .size_limit(10 * (1 << 21))
@@ -0,0 +1,168 @@
+//! Backoff policies for retrying registry requests
+
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// How retries of a failed request are spaced out
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// How many times a request is retried after the first attempt fails.
+    /// Zero disables retries.
+    pub max_retries: u32,
+    /// The delay that backoff starts from
+    pub base_delay: Duration,
+    /// The longest the client will wait between two attempts
+    pub max_delay: Duration,
+    /// How delays are randomized
+    pub jitter: JitterStrategy,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            jitter: JitterStrategy::default(),
+        }
+    }
+}
+
+/// How backoff delays are randomized
+///
+/// Randomizing delays keeps many clients that failed at the same moment from
+/// retrying in lockstep and overwhelming a recovering registry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JitterStrategy {
+    /// Plain exponential backoff: `base * 2^attempt`
+    None,
+    /// A random delay between zero and the exponential backoff delay
+    Full,
+    /// A random delay between the base delay and three times the previous
+    /// delay. This spreads retries out well while still growing quickly.
+    Decorrelated,
+}
+
+impl Default for JitterStrategy {
+    fn default() -> Self {
+        JitterStrategy::Decorrelated
+    }
+}
+
+impl RetryPolicy {
+    /// Start a backoff sequence for one request, randomized from system entropy
+    pub fn backoff(&self) -> Backoff<rand::rngs::StdRng> {
+        self.backoff_with_rng(rand::rngs::StdRng::from_entropy())
+    }
+
+    /// Start a backoff sequence for one request that draws its randomness
+    /// from `rng`
+    pub fn backoff_with_rng<R: Rng>(&self, rng: R) -> Backoff<R> {
+        Backoff {
+            policy: self.clone(),
+            rng,
+            attempt: 0,
+            previous: self.base_delay,
+        }
+    }
+}
+
+/// The delays between the attempts of a single request
+#[derive(Debug)]
+pub struct Backoff<R> {
+    policy: RetryPolicy,
+    rng: R,
+    attempt: u32,
+    previous: Duration,
+}
+
+impl<R: Rng> Backoff<R> {
+    /// Returns how long to wait before the next attempt, or `None` once the
+    /// policy's retries are exhausted
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempt >= self.policy.max_retries {
+            return None;
+        }
+        let base = millis(self.policy.base_delay);
+        let max = millis(self.policy.max_delay);
+        let exponential = base
+            .saturating_mul(2u64.saturating_pow(self.attempt))
+            .min(max);
+        let delay = match self.policy.jitter {
+            JitterStrategy::None => exponential,
+            JitterStrategy::Full => self.rng.gen_range(0, exponential + 1),
+            JitterStrategy::Decorrelated => {
+                let upper = millis(self.previous).saturating_mul(3).max(base);
+                self.rng.gen_range(base, upper + 1).min(max)
+            }
+        };
+        self.attempt += 1;
+        self.previous = Duration::from_millis(delay);
+        Some(self.previous)
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+
+    fn policy(jitter: JitterStrategy) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter,
+        }
+    }
+
+    fn delays(policy: &RetryPolicy, seed: u64) -> Vec<Duration> {
+        let mut backoff = policy.backoff_with_rng(StdRng::seed_from_u64(seed));
+        std::iter::from_fn(|| backoff.next_delay()).collect()
+    }
+
+    #[test]
+    fn no_jitter_is_capped_exponential() {
+        let expected: Vec<Duration> = [100, 200, 400, 800, 1000]
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect();
+        assert_eq!(expected, delays(&policy(JitterStrategy::None), 0));
+    }
+
+    #[test]
+    fn full_jitter_stays_below_exponential_delay() {
+        let p = policy(JitterStrategy::Full);
+        let delays = delays(&p, 42);
+        assert_eq!(5, delays.len());
+        for (attempt, delay) in delays.iter().enumerate() {
+            let cap = (100u64 << attempt).min(1000);
+            assert!(*delay <= Duration::from_millis(cap));
+        }
+    }
+
+    #[test]
+    fn decorrelated_jitter_is_bounded_and_deterministic() {
+        let p = policy(JitterStrategy::Decorrelated);
+        let first = delays(&p, 7);
+        assert_eq!(first, delays(&p, 7));
+        assert_eq!(5, first.len());
+        for delay in &first {
+            assert!(*delay >= p.base_delay);
+            assert!(*delay <= p.max_delay);
+        }
+    }
+
+    #[test]
+    fn zero_retries_never_delays() {
+        let p = RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        };
+        assert!(p.backoff().next_delay().is_none());
+    }
+}
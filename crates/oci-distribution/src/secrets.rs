@@ -1,6 +1,7 @@
 //! Types for working with registry access secrets
 
 /// A method for authenticating to a registry
+#[derive(Clone)]
 pub enum RegistryAuth {
     /// Access the registry anonymously
     Anonymous,
@@ -14,6 +15,27 @@ pub enum RegistryOperation {
     Push,
     /// Authenticate for pull operations
     Pull,
+    /// Authenticate for deleting manifests and blobs
+    Delete,
+    /// Authenticate for listing the repositories of a registry
+    Catalog,
+    /// Authenticate with a caller-supplied scope string, such as
+    /// `registry:catalog:*`, for operations not covered by push or pull
+    Custom(String),
+}
+
+impl RegistryOperation {
+    /// Returns the token scope to request for this operation against the
+    /// given repository
+    pub(crate) fn scope(&self, repository: &str) -> String {
+        match self {
+            RegistryOperation::Pull => format!("repository:{}:pull", repository),
+            RegistryOperation::Push => format!("repository:{}:pull,push", repository),
+            RegistryOperation::Delete => format!("repository:{}:delete", repository),
+            RegistryOperation::Catalog => "registry:catalog:*".to_owned(),
+            RegistryOperation::Custom(scope) => scope.clone(),
+        }
+    }
 }
 
 pub(crate) trait Authenticable {
@@ -28,3 +50,38 @@ impl Authenticable for reqwest::RequestBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scope_defaults_to_repository_pull_and_push() {
+        assert_eq!(
+            RegistryOperation::Pull.scope("hello-wasm"),
+            "repository:hello-wasm:pull"
+        );
+        assert_eq!(
+            RegistryOperation::Push.scope("hello-wasm"),
+            "repository:hello-wasm:pull,push"
+        );
+        assert_eq!(
+            RegistryOperation::Delete.scope("hello-wasm"),
+            "repository:hello-wasm:delete"
+        );
+    }
+
+    #[test]
+    fn catalog_scope_ignores_the_repository() {
+        assert_eq!(
+            RegistryOperation::Catalog.scope("hello-wasm"),
+            "registry:catalog:*"
+        );
+    }
+
+    #[test]
+    fn custom_scope_is_used_verbatim() {
+        let op = RegistryOperation::Custom("registry:catalog:*".to_owned());
+        assert_eq!(op.scope("hello-wasm"), "registry:catalog:*");
+    }
+}
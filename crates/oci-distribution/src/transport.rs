@@ -0,0 +1,47 @@
+//! The HTTP transport the client sends registry requests over
+//!
+//! The client builds every request with `reqwest` and hands it to a
+//! `Transport` to be sent. The default transport sends requests over the
+//! network, and others can answer them in-process, for example to test code
+//! that uses the client without a registry.
+
+use std::sync::Arc;
+
+/// Sends HTTP requests to registries
+#[async_trait::async_trait]
+pub trait Transport: Send + Sync {
+    /// Sends a request and returns the registry's response
+    ///
+    /// Failures to reach the registry should be returned as the
+    /// `reqwest::Error` that caused them, or as
+    /// `ClientError::RegistryUnreachable`, so that they count against the
+    /// registry's circuit breaker.
+    async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response>;
+}
+
+/// Sends requests over the network with a `reqwest::Client`
+#[derive(Clone)]
+pub struct ReqwestTransport {
+    client: reqwest::Client,
+}
+
+impl ReqwestTransport {
+    /// Create a transport that sends requests with the given client
+    pub fn new(client: reqwest::Client) -> Self {
+        ReqwestTransport { client }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for ReqwestTransport {
+    async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+        Ok(self.client.execute(request).await?)
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+        (**self).execute(request).await
+    }
+}
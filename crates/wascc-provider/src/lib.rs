@@ -40,6 +40,7 @@ use kubelet::pod::{Handle, Pod, PodKey};
 use kubelet::provider::Provider;
 use kubelet::provider::ProviderError;
 use kubelet::state::common::registered::Registered;
+use kubelet::state::common::state_store::{Checkpoint, FileStateStore, StateStore};
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{
     BackoffSequence, GenericPodState, GenericProvider, GenericProviderState, ThresholdTrigger,
@@ -84,6 +85,9 @@ const FS_CONFIG_ROOTDIR: &str = "ROOT";
 
 /// The root directory of waSCC volumes.
 const VOLUME_DIR: &str = "volumes";
+/// The name of the directory, under the kubelet data directory, in which pod
+/// checkpoints are kept.
+const CHECKPOINT_DIR: &str = "checkpoints";
 
 /// Kubernetes' view of environment variables is an unordered map of string to string.
 type EnvVars = std::collections::HashMap<String, String>;
@@ -150,6 +154,7 @@ pub struct ProviderState {
     handles: Arc<RwLock<BTreeMap<PodKey, Handle<ActorHandle, LogHandleFactory>>>>,
     store: Arc<dyn Store + Sync + Send>,
     volume_path: PathBuf,
+    state_store: Arc<dyn StateStore>,
     log_path: PathBuf,
     host: Arc<Mutex<Host>>,
     port_map: Arc<TokioMutex<BTreeMap<u16, PodKey>>>,
@@ -166,6 +171,9 @@ impl GenericProviderState for ProviderState {
     fn volume_path(&self) -> PathBuf {
         self.volume_path.clone()
     }
+    fn state_store(&self) -> Arc<dyn StateStore> {
+        self.state_store.clone()
+    }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
         let key = PodKey::from(pod);
         let mut handle_writer = self.handles.write().await;
@@ -189,6 +197,7 @@ impl WasccProvider {
         let host = Arc::new(Mutex::new(Host::new()));
         let log_path = config.data_dir.join(LOG_DIR_NAME);
         let volume_path = config.data_dir.join(VOLUME_DIR);
+        let state_store = Arc::new(FileStateStore::new(config.data_dir.join(CHECKPOINT_DIR)));
         let port_map = Arc::new(TokioMutex::new(BTreeMap::<u16, PodKey>::new()));
         tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
@@ -236,6 +245,7 @@ impl WasccProvider {
                 handles: Default::default(),
                 store,
                 volume_path,
+                state_store,
                 log_path,
                 host,
                 port_map,
@@ -256,6 +266,7 @@ pub struct PodState {
     errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
     crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    checkpoint: Checkpoint,
 }
 
 #[async_trait::async_trait]
@@ -289,6 +300,9 @@ impl GenericPodState for PodState {
             ThresholdTrigger::Untriggered
         }
     }
+    fn checkpoint(&mut self) -> &mut Checkpoint {
+        &mut self.checkpoint
+    }
 }
 
 // No cleanup state needed, we clean up when dropping PodState.
@@ -351,6 +365,7 @@ impl Provider for WasccProvider {
             errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            checkpoint: Checkpoint::default(),
         })
     }
 
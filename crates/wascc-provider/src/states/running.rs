@@ -50,11 +50,9 @@ impl State<ProviderState, PodState> for Running {
                 }
             })
             .collect();
-        Ok(make_status_with_containers(
-            Phase::Running,
-            "Running",
-            container_statuses,
-            vec![],
-        ))
+        let mut patch =
+            make_status_with_containers(Phase::Running, "Running", container_statuses, vec![]);
+        patch["status"]["conditions"] = serde_json::to_value(make_pod_conditions(pod, true, true))?;
+        Ok(patch)
     }
 }
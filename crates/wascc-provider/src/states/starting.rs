@@ -175,7 +175,7 @@ impl State<ProviderState, PodState> for Starting {
             let port_assigned =
                 match assign_container_port(Arc::clone(&port_map), &pod, &container).await {
                     Ok(port) => port,
-                    Err(e) => transition_to_error!(self, e),
+                    Err(e) => transition_to_error!(self, "Starting", e),
                 };
             debug!(
                 "New port assigned to {} is: {}",
@@ -212,9 +212,13 @@ impl State<ProviderState, PodState> for Starting {
     async fn json_status(
         &self,
         _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "Starting")
+        make_status_with_conditions(
+            Phase::Pending,
+            "Starting",
+            make_pod_conditions(pod, true, false),
+        )
     }
 }
 
@@ -43,6 +43,7 @@ use kubelet::node::Builder;
 use kubelet::pod::{Handle, Pod, PodKey};
 use kubelet::provider::{Provider, ProviderError};
 use kubelet::state::common::registered::Registered;
+use kubelet::state::common::state_store::{Checkpoint, FileStateStore, StateStore};
 use kubelet::state::common::terminated::Terminated;
 use kubelet::state::common::{
     BackoffSequence, GenericPodState, GenericProvider, GenericProviderState, ThresholdTrigger,
@@ -59,6 +60,7 @@ mod states;
 const TARGET_WASM32_WASI: &str = "wasm32-wasi";
 const LOG_DIR_NAME: &str = "wasi-logs";
 const VOLUME_DIR: &str = "volumes";
+const CHECKPOINT_DIR: &str = "checkpoints";
 
 /// WasiProvider provides a Kubelet runtime implementation that executes WASM
 /// binaries conforming to the WASI spec.
@@ -75,6 +77,7 @@ pub struct ProviderState {
     log_path: PathBuf,
     kubeconfig: kube::Config,
     volume_path: PathBuf,
+    state_store: Arc<dyn StateStore>,
 }
 
 #[async_trait]
@@ -88,6 +91,9 @@ impl GenericProviderState for ProviderState {
     fn volume_path(&self) -> PathBuf {
         self.volume_path.clone()
     }
+    fn state_store(&self) -> Arc<dyn StateStore> {
+        self.state_store.clone()
+    }
     async fn stop(&self, pod: &Pod) -> anyhow::Result<()> {
         let key = PodKey::from(pod);
         let mut handle_writer = self.handles.write().await;
@@ -108,6 +114,7 @@ impl WasiProvider {
     ) -> anyhow::Result<Self> {
         let log_path = config.data_dir.join(LOG_DIR_NAME);
         let volume_path = config.data_dir.join(VOLUME_DIR);
+        let state_store = Arc::new(FileStateStore::new(config.data_dir.join(CHECKPOINT_DIR)));
         tokio::fs::create_dir_all(&log_path).await?;
         tokio::fs::create_dir_all(&volume_path).await?;
         Ok(Self {
@@ -116,6 +123,7 @@ impl WasiProvider {
                 store,
                 log_path,
                 volume_path,
+                state_store,
                 kubeconfig,
             },
         })
@@ -136,6 +144,7 @@ pub struct PodState {
     errors: usize,
     image_pull_backoff_strategy: ExponentialBackoffStrategy,
     crash_loop_backoff_strategy: ExponentialBackoffStrategy,
+    checkpoint: Checkpoint,
 }
 
 // No cleanup state needed, we clean up when dropping PodState.
@@ -181,6 +190,9 @@ impl GenericPodState for PodState {
             ThresholdTrigger::Untriggered
         }
     }
+    fn checkpoint(&mut self) -> &mut Checkpoint {
+        &mut self.checkpoint
+    }
 }
 
 #[async_trait::async_trait]
@@ -218,6 +230,7 @@ impl Provider for WasiProvider {
             errors: 0,
             image_pull_backoff_strategy: ExponentialBackoffStrategy::default(),
             crash_loop_backoff_strategy: ExponentialBackoffStrategy::default(),
+            checkpoint: Checkpoint::default(),
         })
     }
 
@@ -4,14 +4,15 @@ pub(crate) mod running;
 pub(crate) mod starting;
 
 /// When called in a state's `next` function, exits the current state
-/// and transitions to the Error state.
+/// and transitions to the Error state, recording the named state as
+/// the one that failed.
 #[macro_export]
 macro_rules! transition_to_error {
-    ($slf:ident, $err:ident) => {{
+    ($slf:ident, $state:expr, $err:ident) => {{
         let aerr = anyhow::Error::from($err);
         log::error!("{:?}", aerr);
         let error_state =
-            kubelet::state::common::error::Error::<crate::WasiProvider>::new(aerr.to_string());
+            kubelet::state::common::error::Error::<crate::WasiProvider>::from_state($state, aerr);
         return Transition::next($slf, error_state);
     }};
 }
@@ -19,8 +19,13 @@ impl State<ProviderState, PodState> for Completed {
     async fn json_status(
         &self,
         _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Succeeded, "Completed")
+        // The containers have exited, so they are no longer ready
+        make_status_with_conditions(
+            Phase::Succeeded,
+            "Completed",
+            make_pod_conditions(pod, true, false),
+        )
     }
 }
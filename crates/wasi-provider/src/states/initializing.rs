@@ -93,7 +93,15 @@ impl State<ProviderState, PodState> for Initializing {
                             .patch_status(pod.name(), &PatchParams::default(), status_json)
                             .await
                         {
-                            Ok(_) => return Transition::next(self, Error::<_>::new(message)),
+                            Ok(_) => {
+                                return Transition::next(
+                                    self,
+                                    Error::<_>::from_state(
+                                        "Initializing",
+                                        anyhow::anyhow!(message),
+                                    ),
+                                )
+                            }
                             Err(e) => fail_fatal!(e),
                         };
                     } else {
@@ -110,9 +118,13 @@ impl State<ProviderState, PodState> for Initializing {
     async fn json_status(
         &self,
         _pod_state: &mut PodState,
-        _pmeod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Running, "Initializing")
+        make_status_with_conditions(
+            Phase::Running,
+            "Initializing",
+            make_pod_conditions(pod, false, false),
+        )
     }
 }
 
@@ -64,9 +64,13 @@ impl State<ProviderState, PodState> for Running {
     async fn json_status(
         &self,
         _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Running, "Running")
+        make_status_with_conditions(
+            Phase::Running,
+            "Running",
+            make_pod_conditions(pod, true, true),
+        )
     }
 }
 
@@ -144,8 +144,13 @@ impl State<ProviderState, PodState> for Starting {
     async fn json_status(
         &self,
         _pod_state: &mut PodState,
-        _pod: &Pod,
+        pod: &Pod,
     ) -> anyhow::Result<serde_json::Value> {
-        make_status(Phase::Pending, "Starting")
+        // Init containers have finished by the time containers are started
+        make_status_with_conditions(
+            Phase::Pending,
+            "Starting",
+            make_pod_conditions(pod, true, false),
+        )
     }
 }
@@ -1,6 +1,7 @@
+use anyhow::Context;
 use kubelet::config::Config;
 use kubelet::store::composite::ComposableStore;
-use kubelet::store::oci::FileStore;
+use kubelet::store::oci::{FileStore, SharedClient, SharedClientLimits};
 use kubelet::Kubelet;
 use std::sync::Arc;
 use wasi_provider::WasiProvider;
@@ -16,24 +17,28 @@ async fn main() -> anyhow::Result<()> {
 
     let kubeconfig = kubelet::bootstrap(&config, &config.bootstrap_file, notify_bootstrap).await?;
 
-    let store = make_store(&config);
+    let store = make_store(&config)?;
 
     let provider = WasiProvider::new(store, &config, kubeconfig.clone()).await?;
     let kubelet = Kubelet::new(provider, kubeconfig, config).await?;
     kubelet.start().await
 }
 
-fn make_store(config: &Config) -> Arc<dyn kubelet::store::Store + Send + Sync> {
-    let client = oci_distribution::Client::from_source(config);
+fn make_store(config: &Config) -> anyhow::Result<Arc<dyn kubelet::store::Store + Send + Sync>> {
+    // Invalid TLS settings in the config are reported rather than panicking
+    let client = oci_distribution::Client::try_from_source(config)
+        .context("Failed to set up the registry client")?;
+    let client = SharedClient::new(client, SharedClientLimits::default());
     let mut store_path = config.data_dir.join(".oci");
     store_path.push("modules");
     let file_store = Arc::new(FileStore::new(client, &store_path));
 
-    if config.allow_local_modules {
+    let store: Arc<dyn kubelet::store::Store + Send + Sync> = if config.allow_local_modules {
         file_store.with_override(Arc::new(kubelet::store::fs::FileSystemStore {}))
     } else {
         file_store
-    }
+    };
+    Ok(store)
 }
 
 fn notify_bootstrap(message: String) {